@@ -41,7 +41,7 @@ pub fn partial(
   let derive = if derives.is_empty() {
     TokenStream::new()
   } else {
-    TokenStream::from_iter(derives.into_iter())
+    TokenStream::from_iter(derives)
   };
   let partial_ident = Ident::new(&f!("Partial{}", ty), Span::call_site());
 
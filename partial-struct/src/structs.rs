@@ -60,13 +60,9 @@ fn filter_fields<'a>(
   fields_vec: &'a mut FieldsVec,
 ) -> &'a FieldsVec {
   fields_vec.extend(fields.into_iter().filter_map(|field| {
-    if field.ident.is_some() {
+    if let Some(field_ident) = &field.ident {
       let field_vis = field.vis.clone();
-      let field_ident = field
-        .ident
-        .as_ref()
-        .expect("Failed to filter fields")
-        .clone();
+      let field_ident = field_ident.clone();
       let field_ty = field.ty.clone();
       Some((field_vis, field_ident, field_ty, field.attrs.clone()))
     } else {
@@ -1,37 +1,61 @@
 use super::{APIError, APIResult};
 use crate::{
-  env_var,
-  http::{json_response, JsonResult},
+  cache::TtlCache,
+  env_var, env_var_or,
+  http::{
+    download_video, json_response, send_with_retry, stream_video, ClientPool,
+    RETRY_POLICY,
+  },
   GracefulExit,
 };
+use axum::{async_trait, http::HeaderMap, response::Response};
 use format as f;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::{fmt::Display, str::FromStr};
+use std::{fmt::Display, str::FromStr, sync::Arc, time::Duration};
 
-const DRIVE_API: &str = "https://www.googleapis.com/drive/v3";
+pub const DRIVE_API: &str = "https://www.googleapis.com/drive/v3";
 const DRIVE_FILE_FIELDS: &str = "name,size,videoMediaMetadata,mimeType";
+const DRIVE_LIST_FIELDS: &str =
+  "nextPageToken,files(id,name,size,videoMediaMetadata,mimeType)";
+const DRIVE_VIDEOS_QUERY: &str =
+  "mimeType contains 'video/' and trashed = false";
+const DRIVE_MAX_PAGE_SIZE: u32 = 1000;
 
 static API_KEY: Lazy<String> = Lazy::new(|| {
   env_var("GOOGLE_API_KEY").unwrap_or_exit("Could not initialize google API")
 });
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Drive file metadata by account and file id, see `file_cache_key`. Expires
+/// so renames and permission changes are eventually picked up.
+static FILE_CACHE: Lazy<TtlCache<DriveFile, APIError>> = Lazy::new(|| {
+  TtlCache::new(Duration::from_secs(env_var_or("DRIVE_CACHE_TTL_SECS", 300)))
+});
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct DriveVideoMetadata {
   pub width: u16,
   pub height: u16,
-  #[serde(deserialize_with = "deserialize_number_from_string")]
-  pub duration_millis: u64,
+  /// Google sends this as a string and omits it while a video is processing.
+  #[serde(
+    default,
+    deserialize_with = "deserialize_option_number_from_string",
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub duration_millis: Option<u64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct DriveFile {
+  #[serde(default, skip_serializing_if = "String::is_empty")]
+  pub id: String,
   pub mime_type: String,
   pub name: String,
   #[serde(
     alias = "size",
+    default,
     deserialize_with = "deserialize_option_number_from_string",
     skip_serializing_if = "Option::is_none"
   )]
@@ -43,28 +67,198 @@ pub struct DriveFile {
   pub video_metadata: Option<DriveVideoMetadata>,
 }
 
+/// Paging params forwarded to Google's files listing.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DrivePageQuery {
+  pub page_token: Option<String>,
+  pub page_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveFilePage {
+  #[serde(default)]
+  pub files: Vec<DriveFile>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next_page_token: Option<String>,
+}
+
+/// List one page of videos in the Drive owned by `access_token` from the
+/// Drive API at `api_url`.
+pub async fn list_videos(
+  api_url: &str,
+  access_token: &str,
+  page: &DrivePageQuery,
+  request_client: &reqwest::Client,
+) -> APIResult<DriveFilePage> {
+  let mut query = vec![
+    ("q", DRIVE_VIDEOS_QUERY.to_string()),
+    ("orderBy", "name".to_string()),
+    ("fields", DRIVE_LIST_FIELDS.to_string()),
+  ];
+  if let Some(page_size) = page.page_size {
+    let page_size = page_size.clamp(1, DRIVE_MAX_PAGE_SIZE);
+    query.push(("pageSize", page_size.to_string()));
+  }
+  if let Some(page_token) = &page.page_token {
+    query.push(("pageToken", page_token.clone()));
+  }
+  let response = send_with_retry(
+    request_client
+      .get(f!("{api_url}/files"))
+      .bearer_auth(access_token)
+      .query(&query),
+    &RETRY_POLICY,
+  )
+  .await?;
+
+  json_response(response).await
+}
+
+/// Drive operations behind the files routes, so tests can stand in for
+/// Google.
+#[async_trait]
+pub trait DriveClient: std::fmt::Debug + Send + Sync {
+  /// Metadata of `file_id` as seen by `account`.
+  async fn get_file(
+    &self,
+    file_id: &str,
+    account: DriveAccount<'_>,
+  ) -> APIResult<DriveFile>;
+  /// Stream the content of `file_id` honoring the `Range` in `headers`, open
+  /// ended ranges span `chunk_size` bytes if given. Ranges are clamped to
+  /// `content_length` when it's known.
+  async fn stream_range(
+    &self,
+    file_id: &str,
+    headers: HeaderMap,
+    content_length: Option<usize>,
+    chunk_size: Option<usize>,
+  ) -> APIResult<Response>;
+  /// Relay the whole content of `file_id`.
+  async fn download(&self, file_id: &str) -> APIResult<Response>;
+}
+
+/// `DriveClient` talking to Google over reqwest.
+#[derive(Debug, Clone)]
+pub struct ReqwestDriveClient {
+  pub request_client: reqwest::Client,
+  pub video_clients: ClientPool,
+}
+
+#[async_trait]
+impl DriveClient for ReqwestDriveClient {
+  async fn get_file(
+    &self,
+    file_id: &str,
+    account: DriveAccount<'_>,
+  ) -> APIResult<DriveFile> {
+    get_file(DRIVE_API, file_id, account, &self.request_client).await
+  }
+
+  async fn stream_range(
+    &self,
+    file_id: &str,
+    headers: HeaderMap,
+    content_length: Option<usize>,
+    chunk_size: Option<usize>,
+  ) -> APIResult<Response> {
+    stream_video(
+      self.video_clients.get(),
+      &download_url(file_id),
+      headers,
+      content_length,
+      chunk_size,
+    )
+    .await
+  }
+
+  async fn download(&self, file_id: &str) -> APIResult<Response> {
+    download_video(self.video_clients.get(), &download_url(file_id)).await
+  }
+}
+
+fn download_url(file_id: &str) -> String {
+  f!("https://drive.google.com/uc?export=download&confirm=yTib&id={file_id}")
+}
+
 pub fn thumbnail_url(video_id: &str) -> String {
   f!("https://drive.google.com/thumbnail?id={video_id}")
 }
 
+/// Account a Drive file is fetched as, files are cached separately for each
+/// since what they can see differs.
+#[derive(Debug, Clone, Copy)]
+pub enum DriveAccount<'a> {
+  /// Anyone, through `API_KEY`.
+  Public,
+  /// A linked account through its access token.
+  Linked { id: &'a str, access_token: &'a str },
+}
+
+impl DriveAccount<'_> {
+  /// Key of `file_id` in `FILE_CACHE` when fetched as this account.
+  pub fn file_cache_key(&self, file_id: &str) -> String {
+    let account_id = match self {
+      Self::Public => "public",
+      Self::Linked { id, .. } => id,
+    };
+    f!("{}{file_id}", file_cache_prefix(account_id))
+  }
+}
+
+/// Start of the `FILE_CACHE` keys of every file fetched as `account_id`.
+fn file_cache_prefix(account_id: &str) -> String {
+  f!("{account_id}/")
+}
+
+/// Forget the files cached for the linked account `account_id`, so they're
+/// no longer served once it's unlinked.
+pub async fn evict_account_files(account_id: &str) {
+  FILE_CACHE
+    .remove_prefix(&file_cache_prefix(account_id))
+    .await;
+}
+
+/// Metadata of `file_id` from the Drive API at `api_url` as seen by
+/// `account`.
 pub async fn get_file(
+  api_url: &str,
   file_id: &str,
+  account: DriveAccount<'_>,
   request_client: &reqwest::Client,
 ) -> APIResult<DriveFile> {
-  let response = request_client
-    .get(&f!(
-      "{DRIVE_API}/files/{file_id}?fields={DRIVE_FILE_FIELDS}&trashed=false&key={}",
-      *API_KEY
-    ))
-    .send()
-    .await?;
-
-  match json_response(response).await? {
-    JsonResult::Typed(file) => Ok(file),
-    JsonResult::Untyped(file) => Err(APIError::JsonParsing(file)),
+  FILE_CACHE
+    .get_or_fetch(&account.file_cache_key(file_id), || {
+      fetch_file(file_request(api_url, file_id, account, request_client))
+    })
+    .await
+    .map_err(|error| Arc::try_unwrap(error).unwrap_or_else(APIError::Shared))
+}
+
+fn file_request(
+  api_url: &str,
+  file_id: &str,
+  account: DriveAccount<'_>,
+  request_client: &reqwest::Client,
+) -> reqwest::RequestBuilder {
+  let request = request_client.get(f!(
+    "{api_url}/files/{file_id}?fields={DRIVE_FILE_FIELDS}&trashed=false"
+  ));
+  match account {
+    DriveAccount::Public => request.query(&[("key", &*API_KEY)]),
+    DriveAccount::Linked { access_token, .. } => {
+      request.bearer_auth(access_token)
+    }
   }
 }
 
+async fn fetch_file(request: reqwest::RequestBuilder) -> APIResult<DriveFile> {
+  let response = send_with_retry(request, &RETRY_POLICY).await?;
+  json_response(response).await
+}
+
 pub fn deserialize_option_number_from_string<'de, T, D>(
   deserializer: D,
 ) -> Result<Option<T>, D::Error>
@@ -75,39 +269,18 @@ where
 {
   #[derive(Deserialize)]
   #[serde(untagged)]
-  enum NumericOrNull<'a, T> {
-    Str(&'a str),
+  enum NumericOrNull<T> {
+    Str(String),
     FromStr(T),
     Null,
   }
 
   match NumericOrNull::<T>::deserialize(deserializer)? {
-    NumericOrNull::Str(s) => match s {
+    NumericOrNull::Str(s) => match s.trim() {
       "" => Ok(None),
-      _ => T::from_str(s).map(Some).map_err(serde::de::Error::custom),
+      s => T::from_str(s).map(Some).map_err(serde::de::Error::custom),
     },
     NumericOrNull::FromStr(i) => Ok(Some(i)),
     NumericOrNull::Null => Ok(None),
   }
 }
-
-pub fn deserialize_number_from_string<'de, T, D>(
-  deserializer: D,
-) -> Result<T, D::Error>
-where
-  D: Deserializer<'de>,
-  T: FromStr + serde::Deserialize<'de>,
-  <T as FromStr>::Err: Display,
-{
-  #[derive(Deserialize)]
-  #[serde(untagged)]
-  enum StringOrInt<T> {
-    String(String),
-    Number(T),
-  }
-
-  match StringOrInt::<T>::deserialize(deserializer)? {
-    StringOrInt::String(s) => s.parse::<T>().map_err(serde::de::Error::custom),
-    StringOrInt::Number(i) => Ok(i),
-  }
-}
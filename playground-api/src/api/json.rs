@@ -0,0 +1,31 @@
+use super::APIError;
+use axum::{
+  async_trait,
+  extract::{rejection::JsonRejection, FromRequest},
+  http::Request,
+  Json,
+};
+use serde::de::DeserializeOwned;
+
+/// JSON body extractor whose errors report the path of the offending field.
+pub struct ValidJson<T>(pub T);
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for ValidJson<T>
+where
+  T: DeserializeOwned,
+  Json<serde_json::Value>: FromRequest<S, B, Rejection = JsonRejection>,
+  S: Send + Sync,
+  B: Send + 'static,
+{
+  type Rejection = APIError;
+
+  async fn from_request(
+    request: Request<B>,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    let Json(value) =
+      Json::<serde_json::Value>::from_request(request, state).await?;
+    Ok(Self(serde_path_to_error::deserialize(value)?))
+  }
+}
@@ -1,8 +1,12 @@
 pub mod google;
+pub mod json;
 
 use crate::{
   auth::{jwt::JWTError, oauth::OAuthError},
+  console::Colorize,
   db::{files::system::FileSystemError, DBError},
+  http::redact_error,
+  log,
   string::StringError,
   websockets::channel::EventSendError,
 };
@@ -12,11 +16,12 @@ use axum::{
 };
 use reqwest::{header::InvalidHeaderValue, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::{fmt::Display, sync::Arc};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum APIError {
-  #[error("External Request for {:?} failed: {0}", .0.url())]
+  #[error("External request failed: {}", redact_error(.0))]
   ExternalRequest(#[from] reqwest::Error),
   #[error("Could not parse header value into a str: {0}")]
   HeaderValueParsing(#[from] reqwest::header::ToStrError),
@@ -28,20 +33,26 @@ pub enum APIError {
   BadPath(#[from] axum::extract::rejection::PathRejection),
   #[error("Bad JSON: {0}")]
   BadJson(#[from] axum::extract::rejection::JsonRejection),
-  #[error("JSON structure did not match type")]
-  JsonParsing(serde_json::Value),
+  #[error("Bad JSON at {:?}: {}", .0.path().to_string(), .0.inner())]
+  BadJsonField(#[from] serde_path_to_error::Error<serde_json::Error>),
+  #[error("Unexpected response from upstream")]
+  UnexpectedResponse,
   #[error("Failed to parse header value: {0}")]
   HeaderParsing(#[from] InvalidHeaderValue),
   #[error("External request returned bad status code {0}")]
-  StatusCode(StatusCode, Option<serde_json::Value>),
+  StatusCode(StatusCode),
   #[error("Bad Request: {0}")]
   BadRequest(String),
+  #[error("Invalid fields: {}", join_fields(.0))]
+  Validation(Vec<FieldError>),
   #[error("Internal Server Error: {0}")]
   Internal(String),
   #[error("Unauthorized: {0}")]
   UnauthorizedMessage(String),
   #[error("Unauthorized")]
   Unauthorized,
+  #[error("Forbidden: {0}")]
+  Forbidden(String),
   #[error("JWT Error: {0}")]
   Jwt(#[from] JWTError),
   #[error("OAuth Error: {0}")]
@@ -51,28 +62,64 @@ pub enum APIError {
   #[error("{0}")]
   NotFound(String),
   #[error("Event send error: {0}")]
-  EventSend(#[from] EventSendError),
+  EventSend(Box<EventSendError>),
   #[error("String Error: {0}")]
   String(#[from] StringError),
   #[error("File System Error: {0}")]
   FileSystem(#[from] FileSystemError),
+  #[error("Too Many Requests: {0}")]
+  TooManyRequests(String),
+  #[error("Range Not Satisfiable: {0}")]
+  RangeNotSatisfiable(String),
+  #[error("Timed out: {0}")]
+  Timeout(String),
+  #[error("{0}")]
+  Shared(Arc<APIError>),
 }
 
-impl IntoResponse for APIError {
-  fn into_response(self) -> Response {
-    let (status, body) = match self {
+/// Boxed since the unsent message would make every `APIError` as large as it.
+impl From<EventSendError> for APIError {
+  fn from(error: EventSendError) -> Self {
+    Self::EventSend(Box::new(error))
+  }
+}
+
+impl APIError {
+  /// `Validation` error for a single `field` rejected with `error`.
+  pub fn invalid_field(field: &str, error: impl Display) -> Self {
+    Self::Validation(vec![FieldError {
+      field: field.to_string(),
+      message: error.to_string(),
+    }])
+  }
+
+  /// Status code and optional details sent back to the client.
+  fn status(&self) -> (StatusCode, Option<serde_json::Value>) {
+    match *self {
       Self::NotFound(_) => (StatusCode::NOT_FOUND, None),
+      Self::Forbidden(_) => (StatusCode::FORBIDDEN, None),
+      Self::TooManyRequests(_) => (StatusCode::TOO_MANY_REQUESTS, None),
+      Self::RangeNotSatisfiable(_) => (StatusCode::RANGE_NOT_SATISFIABLE, None),
       Self::BadRequest(_)
       | Self::BadQuery(_)
       | Self::BadPath(_)
-      | Self::BadJson(_)
-      | Self::FileSystem(_)
-      | Self::String(_) => (StatusCode::BAD_REQUEST, None),
-      Self::JsonParsing(ref data) => {
-        (StatusCode::NOT_ACCEPTABLE, Some(data.clone()))
+      | Self::BadJson(_) => (StatusCode::BAD_REQUEST, None),
+      Self::Validation(ref fields) => validation_status(fields),
+      // File names are the only strings validated
+      Self::String(ref error) | Self::Database(DBError::String(ref error)) => {
+        Self::invalid_field("name", error).status()
       }
+      Self::BadJsonField(ref error) => (
+        StatusCode::BAD_REQUEST,
+        Some(serde_json::json!({
+          "path": error.path().to_string(),
+          "error": error.inner().to_string(),
+        })),
+      ),
+      Self::FileSystem(ref error) => file_system_status(error),
+      Self::UnexpectedResponse => (StatusCode::BAD_GATEWAY, None),
       Self::InvalidJson(_) => (StatusCode::NOT_ACCEPTABLE, None),
-      Self::StatusCode(ref code, ref data) => (*code, data.clone()),
+      Self::StatusCode(ref code) => (*code, None),
       Self::Jwt(_)
       | Self::Unauthorized
       | Self::UnauthorizedMessage(_)
@@ -82,19 +129,103 @@ impl IntoResponse for APIError {
       | Self::Database(_)
       | Self::HeaderValueParsing(_)
       | Self::EventSend(_) => (StatusCode::INTERNAL_SERVER_ERROR, None),
+      Self::Timeout(_) => (StatusCode::GATEWAY_TIMEOUT, None),
+      Self::ExternalRequest(ref request) if request.is_timeout() => {
+        (StatusCode::GATEWAY_TIMEOUT, None)
+      }
       Self::ExternalRequest(ref request) => (
         request
           .status()
           .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
         None,
       ),
+      Self::Shared(ref error) => error.status(),
+    }
+  }
+
+  /// Whether the error comes from an upstream request, whose details may
+  /// carry urls or credentials and are kept out of responses.
+  fn is_upstream(&self) -> bool {
+    match self {
+      Self::ExternalRequest(_) | Self::StatusCode(_) => true,
+      Self::Shared(error) => error.is_upstream(),
+      _ => false,
+    }
+  }
+}
+
+fn file_system_status(
+  error: &FileSystemError,
+) -> (StatusCode, Option<serde_json::Value>) {
+  match error {
+    FileSystemError::FolderLoop | FileSystemError::NotEmpty => {
+      (StatusCode::CONFLICT, None)
+    }
+    FileSystemError::ReadOnly => (StatusCode::FORBIDDEN, None),
+    FileSystemError::NotFound => (StatusCode::NOT_FOUND, None),
+    FileSystemError::NotVideo => (StatusCode::BAD_REQUEST, None),
+    FileSystemError::NameConflict(name, folder_id) => (
+      StatusCode::CONFLICT,
+      Some(serde_json::json!({ "name": name, "folderId": folder_id })),
+    ),
+    FileSystemError::Conflict { actual, .. } => (
+      StatusCode::CONFLICT,
+      Some(serde_json::json!({ "version": actual })),
+    ),
+    FileSystemError::BadString(error)
+    | FileSystemError::Internal(DBError::String(error)) => {
+      APIError::invalid_field("name", error).status()
+    }
+    FileSystemError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, None),
+  }
+}
+
+fn validation_status(
+  fields: &[FieldError],
+) -> (StatusCode, Option<serde_json::Value>) {
+  (
+    StatusCode::UNPROCESSABLE_ENTITY,
+    Some(serde_json::json!(fields)),
+  )
+}
+
+/// Field of a request that failed validation, listed in the details of
+/// `APIError::Validation`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FieldError {
+  pub field: String,
+  pub message: String,
+}
+
+impl Display for FieldError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}: {}", self.field, self.message)
+  }
+}
+
+fn join_fields(fields: &[FieldError]) -> String {
+  fields
+    .iter()
+    .map(FieldError::to_string)
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+impl IntoResponse for APIError {
+  fn into_response(self) -> Response {
+    let (status, body) = self.status();
+    let message = if self.is_upstream() {
+      log!(err@"{self}");
+      "Upstream request failed".to_string()
+    } else {
+      self.to_string()
     };
     (
       status,
       Json(APIErrorBody {
         status_code: status.as_u16(),
         error: status.to_string(),
-        message: self.to_string(),
+        message,
         details: body,
       }),
     )
@@ -104,11 +235,6 @@ impl IntoResponse for APIError {
 
 pub type APIResult<T = ()> = Result<T, APIError>;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct APIErrorResponse {
-  error: String,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct APIErrorBody {
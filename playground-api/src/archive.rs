@@ -0,0 +1,142 @@
+use crate::{
+  api::{google::DriveClient, APIError, APIResult},
+  console::Colorize,
+  env_var_or,
+  http::attachment,
+  log,
+};
+use async_zip::{base::write::ZipFileWriter, Compression, ZipEntryBuilder};
+use axum::{
+  body::{HttpBody, StreamBody},
+  http::{header, HeaderValue},
+  response::{IntoResponse, Response},
+};
+use format as f;
+use futures::{stream, AsyncWriteExt, StreamExt};
+use once_cell::sync::Lazy;
+use std::{io, sync::Arc};
+use thiserror::Error;
+use tokio::{
+  io::{duplex, AsyncWrite},
+  sync::oneshot,
+};
+use tokio_util::io::ReaderStream;
+
+/// Most videos a single archive can hold.
+pub static ZIP_MAX_FILES: Lazy<usize> =
+  Lazy::new(|| env_var_or("ZIP_MAX_FILES", 100));
+
+/// Most MiB of video a single archive can hold.
+pub static ZIP_MAX_MIB: Lazy<u64> =
+  Lazy::new(|| env_var_or("ZIP_MAX_MIB", 4096));
+
+/// Bytes buffered between the archive and the response, so only this much of
+/// it is held in memory while the client reads it.
+const ZIP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Drive video stored in an archive at `path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+  pub path: String,
+  pub video_id: String,
+  pub size_bytes: u64,
+}
+
+/// Fail unless `entries` fit within `ZIP_MAX_FILES` and `ZIP_MAX_MIB`.
+pub fn check_archive_limits(entries: &[ArchiveEntry]) -> APIResult {
+  if entries.len() > *ZIP_MAX_FILES {
+    return Err(APIError::BadRequest(f!(
+      "Can't zip {} videos, the limit is {}",
+      entries.len(),
+      *ZIP_MAX_FILES
+    )));
+  }
+  let size_bytes = entries.iter().map(|entry| entry.size_bytes).sum::<u64>();
+  if size_bytes > *ZIP_MAX_MIB * 1024 * 1024 {
+    return Err(APIError::BadRequest(f!(
+      "Can't zip {} MiB of videos, the limit is {} MiB",
+      size_bytes.div_ceil(1024 * 1024),
+      *ZIP_MAX_MIB
+    )));
+  }
+  Ok(())
+}
+
+/// Zip archive saved as `file_name` with every entry streamed from `drive` as
+/// the client reads it. Entries are stored uncompressed since videos barely
+/// compress, if one fails the response is aborted so the client sees an error
+/// instead of a truncated archive.
+pub fn zip_response(
+  drive: Arc<dyn DriveClient>,
+  entries: Vec<ArchiveEntry>,
+  file_name: &str,
+) -> APIResult<Response> {
+  let disposition = attachment(file_name)?;
+  let (writer, reader) = duplex(ZIP_BUFFER_SIZE);
+  let (result_sender, result_receiver) = oneshot::channel();
+  tokio::spawn(async move {
+    let result = write_archive(&*drive, &entries, writer).await;
+    if let Err(error) = &result {
+      log!(err@"Failed to zip videos: {error}");
+    }
+    result_sender.send(result).ok();
+  });
+  // Runs once the archive's bytes are exhausted, failing the body if writing
+  // them stopped early.
+  let failure = stream::once(result_receiver).filter_map(|result| async {
+    match result {
+      Ok(Err(error)) => Some(Err(io::Error::other(error))),
+      _ => None,
+    }
+  });
+  Ok(
+    (
+      [
+        (
+          header::CONTENT_TYPE,
+          HeaderValue::from_static("application/zip"),
+        ),
+        (header::CONTENT_DISPOSITION, disposition),
+      ],
+      StreamBody::new(ReaderStream::new(reader).chain(failure)),
+    )
+      .into_response(),
+  )
+}
+
+async fn write_archive(
+  drive: &dyn DriveClient,
+  entries: &[ArchiveEntry],
+  writer: impl AsyncWrite + Unpin,
+) -> ArchiveResult {
+  let mut archive = ZipFileWriter::with_tokio(writer);
+  for entry in entries {
+    let mut body = drive.download(&entry.video_id).await?.into_body();
+    let mut entry_writer = archive
+      .write_entry_stream(ZipEntryBuilder::new(
+        entry.path.clone().into(),
+        Compression::Stored,
+      ))
+      .await?;
+    while let Some(chunk) = body.data().await {
+      entry_writer.write_all(&chunk?).await?;
+    }
+    entry_writer.close().await?;
+  }
+  archive.close().await?;
+  Ok(())
+}
+
+#[derive(Error, Debug)]
+enum ArchiveError {
+  #[error("Failed to download video: {0}")]
+  Download(#[from] APIError),
+  #[error("Failed to read video: {0}")]
+  Body(#[from] axum::Error),
+  #[error("Failed to write archive: {0}")]
+  Zip(#[from] async_zip::error::ZipError),
+  #[error("Failed to send archive: {0}")]
+  Io(#[from] io::Error),
+}
+
+type ArchiveResult<T = ()> = Result<T, ArchiveError>;
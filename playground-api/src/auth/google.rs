@@ -1,28 +1,47 @@
-use super::{oauth::Token, session::Session, AuthorizedQuery};
+use super::{
+  oauth::{Token, TOKEN_REFRESH_BUFFER},
+  session::{session_cookie, Session},
+  AuthorizedQuery,
+};
 use crate::{
   api::{APIError, APIResult},
   db::{
-    users::{save_user, User},
+    oauth_states::{save_oauth_state, take_oauth_state, OAuthState},
+    providers::{save_provider, update_provider_token, Provider},
+    users::{add_provider_to_user, is_linked_to_other_user, save_user, User},
     Database,
   },
   env_var,
-  http::{json_response, JsonResult},
+  http::{self, json_response},
   AppResult, AppState,
 };
 use format as f;
 
 use axum::{
   extract::{Query, State},
-  response::Redirect,
+  headers::{Cookie, HeaderMapExt},
+  http::{header, HeaderMap, HeaderValue},
+  response::{IntoResponse, Redirect, Response},
   routing::get,
   Router,
 };
 use oauth2::{
-  basic::BasicClient, AuthUrl, ClientId, ClientSecret, CsrfToken, RedirectUrl,
-  Scope, TokenUrl,
+  basic::BasicClient, AuthUrl, ClientId, ClientSecret, CsrfToken,
+  PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
 
+/// Cookie tying a login's callback to the browser that started it.
+pub const OAUTH_STATE_COOKIE: &str = "oauth_state";
+
+/// Keeps the state cookie out of reach of scripts, `Lax` so it's still sent
+/// when Google redirects back.
+const OAUTH_STATE_COOKIE_ATTRIBUTES: &str =
+  "Path=/; HttpOnly; Secure; SameSite=Lax";
+
+/// Seconds a login has to come back from Google.
+const OAUTH_STATE_COOKIE_MAX_AGE: u32 = 600;
+
 /// Setup API endpoints for google services.
 pub fn api() -> AppResult<Router<AppState>> {
   Ok(
@@ -32,18 +51,88 @@ pub fn api() -> AppResult<Router<AppState>> {
   )
 }
 
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+  /// Where to send the user back to, must be `LOGIN_REDIRECT` or in
+  /// `LOGIN_REDIRECT_ALLOWLIST`.
+  redirect: Option<String>,
+}
+
 /// Redirect to Google's OAuth consent screen.
-async fn authenticate(State(state): State<GoogleState>) -> Redirect {
-  let (auth_url, _) = state
+async fn authenticate(
+  Query(query): Query<LoginQuery>,
+  State(state): State<GoogleState>,
+  State(database): State<Database>,
+) -> APIResult<Response> {
+  let login_redirect = pick_login_redirect(
+    &state.login_redirect,
+    &state.login_redirect_allowlist,
+    query.redirect.as_deref(),
+  )?;
+  let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+  let (auth_url, csrf_token) = state
     .oauth_client
     .authorize_url(CsrfToken::new_random)
+    .set_pkce_challenge(pkce_challenge)
     .add_scope(scope("auth/userinfo.email"))
     .add_scope(scope("auth/userinfo.profile"))
+    .add_scope(scope("auth/drive.readonly"))
     .add_scope(Scope::new("openid".to_string()))
     .url();
+  let oauth_state = OAuthState {
+    login_redirect: Some(login_redirect),
+    ..OAuthState::new(csrf_token.secret(), pkce_verifier.secret())
+  };
+  save_oauth_state(&oauth_state, &database).await?;
+  let cookie = HeaderValue::from_str(&f!(
+    "{OAUTH_STATE_COOKIE}={}; Max-Age={OAUTH_STATE_COOKIE_MAX_AGE}; \
+     {OAUTH_STATE_COOKIE_ATTRIBUTES}",
+    csrf_token.secret()
+  ))?;
 
   // Redirect to Google's oauth service
-  Redirect::to(auth_url.as_ref())
+  Ok(
+    (
+      [(header::SET_COOKIE, cookie)],
+      Redirect::to(auth_url.as_ref()),
+    )
+      .into_response(),
+  )
+}
+
+/// Fail unless the `oauth_state` cookie in `headers` matches the `state`
+/// Google sent back, so a login can't be finished in another browser.
+pub fn check_oauth_state_cookie(headers: &HeaderMap, state: &str) -> APIResult {
+  let cookie = headers.typed_get::<Cookie>();
+  let expected = cookie
+    .as_ref()
+    .and_then(|cookie| cookie.get(OAUTH_STATE_COOKIE));
+  if expected == Some(state) {
+    Ok(())
+  } else {
+    Err(APIError::UnauthorizedMessage(
+      "Login was started in another browser".into(),
+    ))
+  }
+}
+
+/// `requested` if it's `default` or in `allowlist`, `default` if missing.
+/// Anything else is rejected so logins can't send users to other sites.
+pub fn pick_login_redirect(
+  default: &str,
+  allowlist: &[String],
+  requested: Option<&str>,
+) -> APIResult<String> {
+  let Some(requested) = requested else {
+    return Ok(default.to_string());
+  };
+  if requested == default || allowlist.iter().any(|uri| uri == requested) {
+    Ok(requested.to_string())
+  } else {
+    Err(APIError::BadRequest(f!(
+      "Redirect {requested:?} is not allowed"
+    )))
+  }
 }
 
 /// Create google API scope.
@@ -51,18 +140,30 @@ fn scope(scope_name: &str) -> Scope {
   Scope::new(f!("https://www.googleapis.com/{scope_name}"))
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct APITokenResponse {
-  token: String,
-}
-
-/// Add/update provider and user.
+/// Add/update provider and user, if the login was started by this API.
+///
+/// Logins finished while already logged in link the account to the current
+/// user instead of switching to the user it was created for.
 async fn login_authorized(
+  session: Option<Session>,
   Query(query): Query<AuthorizedQuery>,
   State(state): State<GoogleState>,
   State(database): State<Database>,
-) -> APIResult<Redirect> {
-  let token = Token::exchange(&state.oauth_client, query.code).await?;
+  headers: HeaderMap,
+) -> APIResult<Response> {
+  check_oauth_state_cookie(&headers, &query.state)?;
+  let oauth_state = take_oauth_state(&query.state, &database)
+    .await?
+    .ok_or(APIError::Unauthorized)?;
+  let login_redirect = oauth_state
+    .login_redirect
+    .unwrap_or_else(|| state.login_redirect.clone());
+  let token = Token::exchange(
+    &state.oauth_client,
+    query.code,
+    PkceCodeVerifier::new(oauth_state.pkce_verifier),
+  )
+  .await?;
 
   let profile = google_user_info(&token.access_token).await?;
   let id = f!(
@@ -77,16 +178,79 @@ async fn login_authorized(
       .0
   );
 
-  let token =
-    save_user(&User::new(&id, &profile.name, &profile.picture), &database)
-      .await?;
+  let provider = Provider {
+    _id: id,
+    email: profile.email,
+    picture: profile.picture,
+    token,
+  };
+  let mut response =
+    match save_login(&provider, &profile.name, session.as_ref(), &database)
+      .await?
+    {
+      Some(token) => {
+        login_response(&login_redirect, &token, state.login_token_in_query)?
+      }
+      None => Redirect::to(&login_redirect).into_response(),
+    };
+  response.headers_mut().append(
+    header::SET_COOKIE,
+    HeaderValue::from_str(&f!(
+      "{OAUTH_STATE_COOKIE}=; Max-Age=0; {OAUTH_STATE_COOKIE_ATTRIBUTES}"
+    ))?,
+  );
+  Ok(response)
+}
 
-  Session::save(&token).await;
+/// Save `provider` and log in as the user it was created for, creating them
+/// named `name` if needed, returns the token of the new session.
+///
+/// With a `session` the provider is linked to its user instead, who stays
+/// logged in so `None` is returned. Accounts of other users can't be linked.
+pub async fn save_login(
+  provider: &Provider,
+  name: &str,
+  session: Option<&Session>,
+  database: &Database,
+) -> APIResult<Option<String>> {
+  let Some(session) = session else {
+    save_provider(provider, database).await?;
+    let user = User::new(&provider._id, name, &provider.picture);
+    let token = save_user(&user, database).await?;
+    Session::save(&token, &provider._id).await;
+    return Ok(Some(token));
+  };
+  if is_linked_to_other_user(&session.user_id, &provider._id, database).await? {
+    return Err(APIError::BadRequest(f!(
+      "Provider {:?} belongs to another user",
+      provider._id
+    )));
+  }
+  save_provider(provider, database).await?;
+  add_provider_to_user(&session.user_id, &provider._id, database)
+    .await?
+    .ok_or(APIError::Unauthorized)?;
+  Ok(None)
+}
 
-  Ok(Redirect::to(&f!(
-    "{}?access_token={token}",
-    state.login_redirect
-  )))
+/// Send the user back to `login_redirect` with their session `token` in a
+/// cookie, or in an `access_token` query parameter if `token_in_query`.
+pub fn login_response(
+  login_redirect: &str,
+  token: &str,
+  token_in_query: bool,
+) -> APIResult<Response> {
+  if token_in_query {
+    return Ok(
+      Redirect::to(&f!("{login_redirect}?access_token={token}"))
+        .into_response(),
+    );
+  }
+  let cookie = HeaderValue::from_str(&session_cookie(token))?;
+  Ok(
+    ([(header::SET_COOKIE, cookie)], Redirect::to(login_redirect))
+      .into_response(),
+  )
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -98,29 +262,60 @@ struct GoogleUserInfo {
 
 /// Request auth protected basic user info from google.
 async fn google_user_info(access_token: &str) -> APIResult<GoogleUserInfo> {
-  let client = reqwest::Client::new();
+  let client = http::client();
   let url = f!(
     "https://www.googleapis.com/oauth2/v3/userinfo?access_token={access_token}"
   );
   let response = client.get(url).bearer_auth(access_token).send().await?;
 
-  match json_response::<GoogleUserInfo>(response).await? {
-    JsonResult::Typed(profile) => Ok(profile),
-    JsonResult::Untyped(file) => Err(APIError::JsonParsing(file)),
-  }
+  json_response(response).await
 }
 
 #[derive(Debug, Clone)]
 pub struct GoogleState {
   oauth_client: BasicClient,
   login_redirect: String,
+  /// Other redirects logins may ask for.
+  login_redirect_allowlist: Vec<String>,
+  /// Hand out session tokens in the redirect url instead of a cookie.
+  login_token_in_query: bool,
 }
 
 impl GoogleState {
+  /// Access token of `provider`, refreshed and saved first if it's about to
+  /// expire.
+  pub async fn fresh_access_token(
+    &self,
+    provider: &Provider,
+    database: &Database,
+  ) -> APIResult<String> {
+    let token = provider
+      .token
+      .refresh_if_expiring(&self.oauth_client, *TOKEN_REFRESH_BUFFER)
+      .await?;
+    let Some(token) = token else {
+      return Ok(provider.token.access_token.clone());
+    };
+    update_provider_token(&provider._id, &token, database).await?;
+    Ok(token.access_token)
+  }
+
   pub fn new() -> AppResult<Self> {
     Ok(Self {
       oauth_client: Self::create_client()?,
       login_redirect: env_var("LOGIN_REDIRECT")?,
+      login_redirect_allowlist: env_var("LOGIN_REDIRECT_ALLOWLIST")
+        .map(|list| {
+          list
+            .split(',')
+            .map(str::trim)
+            .filter(|uri| !uri.is_empty())
+            .map(String::from)
+            .collect()
+        })
+        .unwrap_or_default(),
+      login_token_in_query: env_var("LOGIN_TOKEN_IN_QUERY")
+        .is_ok_and(|value| value == "1"),
     })
   }
 
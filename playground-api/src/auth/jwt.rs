@@ -1,10 +1,11 @@
-use crate::GracefulExit;
+use crate::{env_var_or, GracefulExit};
 use chrono::{Duration, Utc};
 use jsonwebtoken::{
-  decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation,
+  decode, encode, errors::ErrorKind, DecodingKey, EncodingKey, Header,
+  TokenData, Validation,
 };
 use once_cell::sync::Lazy;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt::Debug;
 
 #[derive(Debug, thiserror::Error)]
@@ -37,20 +38,77 @@ static KEYS: Lazy<Keys> = Lazy::new(|| {
   Keys::new(secret.as_bytes())
 });
 
+/// How long session tokens are valid for.
+pub static EXPIRY: Lazy<Duration> = Lazy::new(|| {
+  Duration::seconds(env_var_or(
+    "JWT_EXPIRY_SECONDS",
+    Duration::weeks(2).num_seconds(),
+  ))
+});
+
+/// How long guest tokens handed out by folder shares are valid for at most.
+pub static GUEST_EXPIRY: Lazy<Duration> = Lazy::new(|| {
+  Duration::seconds(env_var_or(
+    "GUEST_TOKEN_EXPIRY_SECONDS",
+    Duration::weeks(1).num_seconds(),
+  ))
+});
+
 pub fn sign_token(sub: &str) -> JWTResult<String> {
-  encode(
-    &Header::default(),
-    &Claims {
-      sub: sub.to_string(),
-      exp: expires_in(Duration::weeks(2)).timestamp() as usize,
-    },
-    &KEYS.encoding,
-  )
-  .map_err(JWTError::Signing)
+  sign_token_with_expiry(sub, *EXPIRY)
+}
+
+pub fn sign_token_with_expiry(
+  sub: &str,
+  expiry: Duration,
+) -> JWTResult<String> {
+  sign(&Claims::new(sub, expiry))
+}
+
+/// Token letting anyone with it access `sub`'s folder `folder_id` within
+/// `scope`, until `share_id` is revoked or `expiry` passes.
+pub fn sign_guest_token(
+  sub: &str,
+  share_id: &str,
+  folder_id: &str,
+  scope: Scope,
+  expiry: Duration,
+) -> JWTResult<String> {
+  sign(&GuestClaims {
+    claims: Claims::new(sub, expiry),
+    share_id: share_id.to_string(),
+    folder_id: folder_id.to_string(),
+    scope,
+  })
+}
+
+fn sign(claims: &impl Serialize) -> JWTResult<String> {
+  encode(&Header::default(), claims, &KEYS.encoding).map_err(JWTError::Signing)
 }
 
 pub fn verify_token(token: &str) -> JWTResult<TokenData<Claims>> {
-  decode(token, &KEYS.decoding, &Validation::default()).map_err(JWTError::from)
+  verify(token)
+}
+
+/// Only guest tokens pass, user tokens lack the guest claims.
+pub fn verify_guest_token(token: &str) -> JWTResult<TokenData<GuestClaims>> {
+  verify(token)
+}
+
+fn verify<C: DeserializeOwned + AsRef<Claims>>(
+  token: &str,
+) -> JWTResult<TokenData<C>> {
+  let mut validation = Validation::default();
+  // Tokens are signed and verified by this server so there's no clock skew
+  validation.leeway = 0;
+  validation.validate_nbf = true;
+  let token_data = decode::<C>(token, &KEYS.decoding, &validation)?;
+  if token_data.claims.as_ref().iat > Utc::now().timestamp() as usize {
+    return Err(JWTError::from(jsonwebtoken::errors::Error::from(
+      ErrorKind::ImmatureSignature,
+    )));
+  }
+  Ok(token_data)
 }
 
 fn expires_in(duration: Duration) -> chrono::DateTime<Utc> {
@@ -61,4 +119,52 @@ fn expires_in(duration: Duration) -> chrono::DateTime<Utc> {
 pub struct Claims {
   pub sub: String,
   exp: usize,
+  // Tokens issued before these claims existed default to the epoch
+  #[serde(default)]
+  iat: usize,
+  #[serde(default)]
+  nbf: usize,
+}
+
+impl Claims {
+  fn new(sub: &str, expiry: Duration) -> Self {
+    let now = Utc::now();
+    Self {
+      sub: sub.to_string(),
+      exp: expires_in(expiry).timestamp() as usize,
+      iat: now.timestamp() as usize,
+      nbf: now.timestamp() as usize,
+    }
+  }
+}
+
+impl AsRef<Claims> for Claims {
+  fn as_ref(&self) -> &Claims {
+    self
+  }
+}
+
+/// What a guest token lets its holder do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+  Read,
+}
+
+/// Claims of a guest token, `sub` is the user who shared the folder.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestClaims {
+  #[serde(flatten)]
+  pub claims: Claims,
+  /// Revoking the share invalidates the token.
+  pub share_id: String,
+  pub folder_id: String,
+  pub scope: Scope,
+}
+
+impl AsRef<Claims> for GuestClaims {
+  fn as_ref(&self) -> &Claims {
+    &self.claims
+  }
 }
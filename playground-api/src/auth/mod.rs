@@ -3,15 +3,39 @@ pub mod jwt;
 pub mod oauth;
 pub mod session;
 
-use crate::{AppResult, AppState};
-use axum::Router;
-use serde::Deserialize;
+use crate::{api::APIResult, metrics, AppResult, AppState};
+use axum::{
+  headers::{authorization::Bearer, Authorization},
+  routing::post,
+  Json, Router, TypedHeader,
+};
+use serde::{Deserialize, Serialize};
+use session::Session;
 
 #[derive(Debug, Deserialize)]
 struct AuthorizedQuery {
   code: String,
+  /// CSRF token sent to Google when the login started.
+  state: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct APITokenResponse {
+  token: String,
 }
 
 pub fn api() -> AppResult<Router<AppState>> {
-  Ok(Router::new().nest("/google", google::api()?))
+  Ok(
+    metrics::tracked(Router::new().route("/refresh", post(refresh)))
+      .nest("/google", metrics::tracked(google::api()?)),
+  )
+}
+
+/// Exchange a valid session token for a new one.
+async fn refresh(
+  TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
+) -> APIResult<Json<APITokenResponse>> {
+  Ok(Json(APITokenResponse {
+    token: Session::refresh(bearer.token()).await?,
+  }))
 }
@@ -1,12 +1,23 @@
+use crate::env_var_or;
+use chrono::Utc;
 use oauth2::{
   basic::{BasicClient, BasicErrorResponseType, BasicTokenType},
   reqwest::async_http_client,
-  AuthorizationCode, EmptyExtraTokenFields, RequestTokenError, StandardErrorResponse,
-  StandardTokenResponse, TokenResponse,
+  AuthorizationCode, EmptyExtraTokenFields, PkceCodeVerifier, RefreshToken,
+  RequestTokenError, StandardErrorResponse, StandardTokenResponse,
+  TokenResponse,
 };
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use thiserror::Error;
 
+/// Tokens expiring within this window are refreshed before being used so
+/// requests don't fail halfway through.
+pub static TOKEN_REFRESH_BUFFER: Lazy<Duration> = Lazy::new(|| {
+  Duration::from_secs(env_var_or("TOKEN_REFRESH_BUFFER_SECS", 300))
+});
+
 type AsyncRequestError = RequestTokenError<
   oauth2::reqwest::Error<reqwest::Error>,
   StandardErrorResponse<BasicErrorResponseType>,
@@ -18,13 +29,26 @@ pub struct Token {
   pub access_token: String,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub refresh_token: Option<String>,
+  /// Lifetime the access token was issued with, relative to an issue time
+  /// that isn't stored so only kept for compatibility, use `expires_at`.
   pub expires_seconds: u32,
+  /// Unix timestamp in seconds the access token expires at. Tokens saved
+  /// before it existed get 0 so they count as expired and are refreshed.
+  #[serde(default)]
+  pub expires_at: i64,
 }
 
-impl From<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> for Token {
-  fn from(token: StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>) -> Self {
+impl From<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>>
+  for Token
+{
+  fn from(
+    token: StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>,
+  ) -> Self {
+    let expires_seconds =
+      token.expires_in().unwrap_or_default().as_secs() as u32;
     Self {
-      expires_seconds: token.expires_in().unwrap_or_default().as_secs() as u32,
+      expires_seconds,
+      expires_at: Utc::now().timestamp() + i64::from(expires_seconds),
       access_token: token.access_token().secret().clone(),
       refresh_token: token
         .refresh_token()
@@ -34,13 +58,50 @@ impl From<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> for Toke
 }
 
 impl Token {
-  pub async fn exchange(client: &BasicClient, code: String) -> OAuthResult<Self> {
+  /// Exchange the authorization `code` for a token proving it's the client
+  /// that started the login with `pkce_verifier`.
+  pub async fn exchange(
+    client: &BasicClient,
+    code: String,
+    pkce_verifier: PkceCodeVerifier,
+  ) -> OAuthResult<Self> {
     let token = client
       .exchange_code(AuthorizationCode::new(code))
+      .set_pkce_verifier(pkce_verifier)
       .request_async(async_http_client)
       .await?;
     Ok(token.into())
   }
+
+  /// Whether the access token expires within `buffer`, tokens whose expiry is
+  /// unknown count as expired.
+  pub fn expires_within(&self, buffer: Duration) -> bool {
+    Utc::now().timestamp() + buffer.as_secs() as i64 >= self.expires_at
+  }
+
+  /// New token if this one expires within `buffer` and can be refreshed.
+  /// Google may leave the refresh token out of the response so it's kept.
+  pub async fn refresh_if_expiring(
+    &self,
+    client: &BasicClient,
+    buffer: Duration,
+  ) -> OAuthResult<Option<Self>> {
+    let Some(refresh_token) = &self.refresh_token else {
+      return Ok(None);
+    };
+    if !self.expires_within(buffer) {
+      return Ok(None);
+    }
+    let token: Self = client
+      .exchange_refresh_token(&RefreshToken::new(refresh_token.clone()))
+      .request_async(async_http_client)
+      .await?
+      .into();
+    Ok(Some(Self {
+      refresh_token: token.refresh_token.or(Some(refresh_token.clone())),
+      ..token
+    }))
+  }
 }
 
 #[derive(Error, Debug)]
@@ -1,28 +1,89 @@
-use super::jwt;
+use super::jwt::{self, Scope};
 use crate::{
   api::{APIError, APIResult},
   db::{
     files::{File, FileMetadata, PartialFile},
+    shares::find_share,
     users::User,
     Database,
   },
   string::NonEmptyString,
-  GracefulExit,
 };
 use axum::{
   async_trait,
-  extract::{FromRequestParts, Path, Query, TypedHeader},
-  headers::{authorization::Bearer, Authorization},
+  extract::{
+    rejection::TypedHeaderRejectionReason, FromRef, FromRequestParts, Path,
+    Query, TypedHeader,
+  },
+  headers::{authorization::Bearer, Authorization, Cookie},
   http::request::Parts,
   RequestPartsExt,
 };
+use format as f;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
+
+/// Cookie logins store the session token in.
+pub const SESSION_COOKIE: &str = "access_token";
+
+/// Keeps the session cookie away from scripts and cross-site requests.
+const SESSION_COOKIE_ATTRIBUTES: &str =
+  "Path=/; HttpOnly; Secure; SameSite=Lax";
+
+/// Active session tokens mapped to the user id they were signed for.
+pub static SESSIONS_CACHE: Lazy<Mutex<HashMap<String, String>>> =
+  Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Session tokens changed since they were last persisted.
+pub static SESSION_CHANGES: Lazy<Mutex<SessionChanges>> =
+  Lazy::new(|| Mutex::new(SessionChanges::default()));
 
-pub static SESSIONS_CACHE: Lazy<Mutex<HashSet<String>>> =
-  Lazy::new(|| Mutex::new(HashSet::new()));
+/// Notified every time a change is added to `SESSION_CHANGES`.
+pub static SESSIONS_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Debug, Default)]
+pub struct SessionChanges {
+  pub saved: HashSet<String>,
+  pub invalidated: HashSet<String>,
+}
+
+impl SessionChanges {
+  pub fn is_empty(&self) -> bool {
+    self.saved.is_empty() && self.invalidated.is_empty()
+  }
+
+  fn save(&mut self, token: &str) {
+    self.invalidated.remove(token);
+    self.saved.insert(token.to_string());
+  }
+
+  fn invalidate(&mut self, token: &str) {
+    self.saved.remove(token);
+    self.invalidated.insert(token.to_string());
+  }
+
+  /// Put back `unsaved` changes that failed to persist, unless a token was
+  /// changed again since then.
+  pub fn restore(&mut self, unsaved: SessionChanges) {
+    for token in unsaved.saved {
+      if !self.invalidated.contains(&token) {
+        self.saved.insert(token);
+      }
+    }
+    for token in unsaved.invalidated {
+      if !self.saved.contains(&token) {
+        self.invalidated.insert(token);
+      }
+    }
+  }
+}
+
+async fn record_changes(record: impl FnOnce(&mut SessionChanges)) {
+  record(&mut *SESSION_CHANGES.lock().await);
+  SESSIONS_CHANGED.notify_one();
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SessionCache {
@@ -43,27 +104,85 @@ impl Session {
       .ok_or(APIError::Unauthorized)
   }
 
-  pub async fn save(token: &str) {
-    SESSIONS_CACHE.lock().await.insert(token.to_string());
+  pub async fn save(token: &str, user_id: &str) {
+    SESSIONS_CACHE
+      .lock()
+      .await
+      .insert(token.to_string(), user_id.to_string());
+    record_changes(|changes| changes.save(token)).await;
   }
 
   pub async fn invalidate(token: &str) {
     SESSIONS_CACHE.lock().await.remove(token);
+    record_changes(|changes| changes.invalidate(token)).await;
+  }
+
+  /// Invalidate every session of `user_id`, returns the amount removed.
+  pub async fn invalidate_all(user_id: &str) -> usize {
+    let mut removed = Vec::new();
+    SESSIONS_CACHE.lock().await.retain(|token, sub| {
+      let keep = sub != user_id;
+      if !keep {
+        removed.push(token.clone());
+      }
+      keep
+    });
+    record_changes(|changes| {
+      for token in &removed {
+        changes.invalidate(token);
+      }
+    })
+    .await;
+    removed.len()
+  }
+
+  /// Replace a valid session token with a freshly signed one. The token is
+  /// checked and consumed under a single lock, so only one of concurrent
+  /// refreshes with it succeeds.
+  pub async fn refresh(token: &str) -> APIResult<String> {
+    let mut cache = SESSIONS_CACHE.lock().await;
+    if cache.remove(token).is_none() {
+      return Err(APIError::UnauthorizedMessage("Invalid session".to_string()));
+    }
+    let user_id = match jwt::verify_token(token) {
+      Ok(token_data) => token_data.claims.sub,
+      Err(err) => {
+        record_changes(|changes| changes.invalidate(token)).await;
+        return Err(APIError::from(err));
+      }
+    };
+    let new_token = match jwt::sign_token(&user_id) {
+      Ok(new_token) => new_token,
+      Err(err) => {
+        // The old token is still valid, so it's kept
+        cache.insert(token.to_string(), user_id);
+        return Err(APIError::from(err));
+      }
+    };
+    cache.insert(new_token.clone(), user_id);
+    record_changes(|changes| {
+      changes.invalidate(token);
+      changes.save(&new_token);
+    })
+    .await;
+    Ok(new_token)
   }
 
   pub async fn from_token(token: &str) -> APIResult<Self> {
     let mut cache = SESSIONS_CACHE.lock().await;
-    let user_id = cache
-      .contains(token)
-      .then(|| jwt::verify_token(token).map(|token| token.claims.sub))
-      .ok_or_else(|| {
-        APIError::UnauthorizedMessage("Invalid session".to_string())
-      })?
-      .map_err(|err| {
+    if !cache.contains_key(token) {
+      return Err(APIError::UnauthorizedMessage("Invalid session".to_string()));
+    }
+    match jwt::verify_token(token) {
+      Ok(token_data) => Ok(Self {
+        user_id: token_data.claims.sub,
+      }),
+      Err(err) => {
         cache.remove(token);
-        APIError::from(err)
-      })?;
-    Ok(Self { user_id })
+        record_changes(|changes| changes.invalidate(token)).await;
+        Err(APIError::from(err))
+      }
+    }
   }
 }
 
@@ -78,24 +197,157 @@ where
     parts: &mut Parts,
     _: &S,
   ) -> Result<Self, Self::Rejection> {
-    let bearer: Option<TypedHeader<Authorization<Bearer>>> = parts
-      .extract()
-      .await
-      .unwrap_or_exit("Could not extract Authorization header");
+    let SessionToken(token) = parts.extract::<SessionToken>().await?;
+    let session = Self::from_token(&token).await;
+    if session.is_err() && jwt::verify_guest_token(&token).is_ok() {
+      return Err(APIError::Forbidden(
+        "Guests can only read the folder shared with them".to_string(),
+      ));
+    }
+    session
+  }
+}
+
+/// Session of a user, or of a guest limited to reading the folder shared with
+/// them. Handlers using it must check `can_read` for guests.
+#[derive(Debug)]
+pub struct ReadSession {
+  /// Owner of the files being read.
+  pub user_id: String,
+  /// Folder a guest token was shared for, `None` for the user themselves.
+  pub shared_folder_id: Option<String>,
+}
+
+impl ReadSession {
+  /// Whether the session can read a file within `lineage`, the ids from the
+  /// root folder down to the file.
+  pub fn can_read<'a>(
+    &self,
+    lineage: impl IntoIterator<Item = &'a str>,
+  ) -> bool {
+    let Some(shared_folder_id) = &self.shared_folder_id else {
+      return true;
+    };
+    lineage.into_iter().any(|id| id == shared_folder_id)
+  }
+}
 
-    let token =
-      bearer
-        .map(|bearer| bearer.token().to_string())
+#[async_trait]
+impl<S> FromRequestParts<S> for ReadSession
+where
+  Database: FromRef<S>,
+  S: Send + Sync,
+{
+  type Rejection = APIError;
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    // Share links carry their share id in a `share` query parameter, it's
+    // resolved first since browsers opening them may send their own session
+    if let Ok(Query(query)) = parts.extract::<Query<ShareQuery>>().await {
+      let share = find_share(&query.share, &Database::from_ref(state))
+        .await?
         .ok_or_else(|| {
-          APIError::UnauthorizedMessage(
-            "Missing/Invalid Authorization header".to_string(),
-          )
+          APIError::UnauthorizedMessage("Share was revoked".to_string())
         })?;
+      return Ok(Self {
+        user_id: share.user_id,
+        shared_folder_id: Some(share.folder_id),
+      });
+    }
+    let SessionToken(token) = parts.extract::<SessionToken>().await?;
+    let error = match Session::from_token(&token).await {
+      Ok(session) => {
+        return Ok(Self {
+          user_id: session.user_id,
+          shared_folder_id: None,
+        })
+      }
+      Err(error) => error,
+    };
+    let Ok(token_data) = jwt::verify_guest_token(&token) else {
+      return Err(error);
+    };
+    let claims = token_data.claims;
+    let share =
+      find_share(&claims.share_id, &Database::from_ref(state)).await?;
+    let shared = share.is_some_and(|share| {
+      share.user_id == claims.claims.sub && share.folder_id == claims.folder_id
+    });
+    if claims.scope != Scope::Read || !shared {
+      return Err(APIError::UnauthorizedMessage(
+        "Share was revoked".to_string(),
+      ));
+    }
+    Ok(Self {
+      user_id: claims.claims.sub,
+      shared_folder_id: Some(claims.folder_id),
+    })
+  }
+}
 
-    Ok(Self::from_token(&token).await?)
+/// Session token from the `Authorization` header, falling back to the
+/// session cookie. It isn't checked against active sessions.
+pub struct SessionToken(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for SessionToken
+where
+  S: Send + Sync,
+{
+  type Rejection = APIError;
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    _: &S,
+  ) -> Result<Self, Self::Rejection> {
+    request_token(parts).await.map(Self)
   }
 }
 
+/// Session token from the `Authorization` header, or the session cookie if
+/// there's no such header. A malformed header is rejected instead of falling
+/// back to the cookie.
+async fn request_token(parts: &mut Parts) -> APIResult<String> {
+  match parts.extract::<TypedHeader<Authorization<Bearer>>>().await {
+    Ok(TypedHeader(bearer)) => return Ok(bearer.token().to_string()),
+    Err(rejection)
+      if !matches!(rejection.reason(), TypedHeaderRejectionReason::Missing) =>
+    {
+      return Err(APIError::Unauthorized)
+    }
+    Err(_) => {}
+  }
+  let cookie = parts.extract::<TypedHeader<Cookie>>().await.ok();
+  cookie
+    .and_then(|TypedHeader(cookie)| {
+      cookie.get(SESSION_COOKIE).map(String::from)
+    })
+    .ok_or_else(|| {
+      APIError::UnauthorizedMessage(
+        "Missing/Invalid Authorization header or session cookie".to_string(),
+      )
+    })
+}
+
+/// `Set-Cookie` value storing `token` out of reach of scripts, expiring along
+/// with it.
+pub fn session_cookie(token: &str) -> String {
+  f!(
+    "{SESSION_COOKIE}={token}; Max-Age={}; {SESSION_COOKIE_ATTRIBUTES}",
+    jwt::EXPIRY.num_seconds()
+  )
+}
+
+/// `Set-Cookie` value removing the session cookie from the browser.
+pub fn cleared_session_cookie() -> String {
+  f!("{SESSION_COOKIE}=; Max-Age=0; {SESSION_COOKIE_ATTRIBUTES}")
+}
+
+/// Session from the `Authorization` header, falling back to a `token` query
+/// parameter for clients that can't set headers (e.g. browser websockets).
 pub struct SessionQuery(pub Session);
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -103,6 +355,12 @@ pub struct TokenQuery {
   pub token: String,
 }
 
+/// Id of the share a share link was made for.
+#[derive(Debug, Deserialize)]
+struct ShareQuery {
+  share: String,
+}
+
 #[async_trait]
 impl<S> FromRequestParts<S> for SessionQuery
 where
@@ -114,7 +372,15 @@ where
     parts: &mut Parts,
     _: &S,
   ) -> Result<Self, Self::Rejection> {
-    let Query(query) = parts.extract::<Query<TokenQuery>>().await?;
+    if let Ok(session) = parts.extract::<Session>().await {
+      return Ok(Self(session));
+    }
+    let Query(query) =
+      parts.extract::<Query<TokenQuery>>().await.map_err(|_| {
+        APIError::UnauthorizedMessage(
+          "Missing/Invalid Authorization header or token query".to_string(),
+        )
+      })?;
     Ok(Self(Session::from_token(&query.token).await?))
   }
 }
@@ -146,11 +412,13 @@ where
         .transpose()?,
       metadata: query.get("type").and_then(|t| {
         if t == "folder" {
-          Some(FileMetadata::Folder)
+          Some(FileMetadata::folder())
         } else {
           None
         }
       }),
+      starred: query.get("starred").map(|starred| starred == "true"),
+      version: None,
     })
   }
 }
@@ -0,0 +1,201 @@
+use futures::{
+  future::{BoxFuture, Shared},
+  FutureExt,
+};
+use std::{
+  collections::HashMap,
+  future::Future,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::time::Instant;
+
+/// Fetch shared by every caller that missed the cache for the same key.
+type InFlight<V, E> = Shared<BoxFuture<'static, Result<V, Arc<E>>>>;
+
+/// Fetches in flight by key, tagged with an id so a fetch only ever clears
+/// its own entry.
+type Flights<V, E> = Mutex<HashMap<String, (u64, InFlight<V, E>)>>;
+
+/// Key-value cache whose entries expire `ttl` after being inserted. Expired
+/// entries are swept on insert at most once per `ttl`, so they don't pile up
+/// under keys that are never read again.
+pub struct TtlCache<V: Clone, E> {
+  entries: Arc<Mutex<Entries<V>>>,
+  in_flight: Arc<Flights<V, E>>,
+  next_flight: AtomicU64,
+}
+
+struct Entries<V> {
+  ttl: Duration,
+  values: HashMap<String, (V, Instant)>,
+  swept_at: Instant,
+}
+
+impl<V: Clone> Entries<V> {
+  fn get(&mut self, key: &str) -> Option<V> {
+    match self.values.get(key) {
+      Some((value, cached_at)) if cached_at.elapsed() < self.ttl => {
+        Some(value.clone())
+      }
+      Some(_) => {
+        self.values.remove(key);
+        None
+      }
+      None => None,
+    }
+  }
+
+  fn insert(&mut self, key: &str, value: V) {
+    if self.swept_at.elapsed() >= self.ttl {
+      let ttl = self.ttl;
+      self
+        .values
+        .retain(|_, (_, cached_at)| cached_at.elapsed() < ttl);
+      self.swept_at = Instant::now();
+    }
+    self.values.insert(key.to_string(), (value, Instant::now()));
+  }
+}
+
+/// Owned by a fetch, clears its `in_flight` entry however the fetch ends,
+/// even if every caller waiting on it gave up.
+struct FlightGuard<V: Clone, E> {
+  entries: Arc<Mutex<Entries<V>>>,
+  in_flight: Arc<Flights<V, E>>,
+  key: String,
+  id: u64,
+}
+
+impl<V: Clone, E> FlightGuard<V, E> {
+  /// Remove the flight's entry and cache `value`, unless the entry was
+  /// removed or replaced meanwhile. The entry is returned rather than dropped
+  /// since it may be the last handle on a flight whose guard locks it again.
+  fn land(&self, value: Option<&V>) -> Option<InFlight<V, E>> {
+    let mut in_flight = self.in_flight.lock().unwrap();
+    if in_flight.get(&self.key).map(|(id, _)| *id) != Some(self.id) {
+      return None;
+    }
+    if let Some(value) = value {
+      self
+        .entries
+        .lock()
+        .unwrap()
+        .insert(&self.key, value.clone());
+    }
+    in_flight.remove(&self.key).map(|(_, flight)| flight)
+  }
+}
+
+impl<V: Clone, E> Drop for FlightGuard<V, E> {
+  fn drop(&mut self) {
+    let _finished = self.land(None);
+  }
+}
+
+impl<V, E> TtlCache<V, E>
+where
+  V: Clone + Send + Sync + 'static,
+  E: Send + Sync + 'static,
+{
+  pub fn new(ttl: Duration) -> Self {
+    Self {
+      entries: Arc::new(Mutex::new(Entries {
+        ttl,
+        values: HashMap::new(),
+        swept_at: Instant::now(),
+      })),
+      in_flight: Arc::new(Mutex::new(HashMap::new())),
+      next_flight: AtomicU64::new(0),
+    }
+  }
+
+  /// Cached value for `key` unless it's missing or expired.
+  pub async fn get(&self, key: &str) -> Option<V> {
+    self.entries.lock().unwrap().get(key)
+  }
+
+  #[cfg(test)]
+  pub async fn insert(&self, key: &str, value: V) {
+    self.entries.lock().unwrap().insert(key, value);
+  }
+
+  /// Drop every entry whose key starts with `prefix`. Fetches for those keys
+  /// still in flight aren't cached once they land.
+  pub async fn remove_prefix(&self, prefix: &str) {
+    let _evicted = {
+      let mut in_flight = self.in_flight.lock().unwrap();
+      let (evicted, kept) = std::mem::take(&mut *in_flight)
+        .into_iter()
+        .partition::<HashMap<_, _>, _>(|(key, _)| key.starts_with(prefix));
+      *in_flight = kept;
+      evicted
+    };
+    let mut entries = self.entries.lock().unwrap();
+    entries.values.retain(|key, _| !key.starts_with(prefix));
+  }
+
+  /// Number of entries held, including expired ones not swept yet.
+  #[cfg(test)]
+  pub async fn len(&self) -> usize {
+    self.entries.lock().unwrap().values.len()
+  }
+
+  /// Cached value for `key` or the result of `fetch`, which gets cached if
+  /// successful. Concurrent misses for the same key wait on a single `fetch`
+  /// and share its result.
+  pub async fn get_or_fetch<F, Fut>(
+    &self,
+    key: &str,
+    fetch: F,
+  ) -> Result<V, Arc<E>>
+  where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<V, E>> + Send + 'static,
+  {
+    if let Some(value) = self.get(key).await {
+      return Ok(value);
+    }
+    let flight = {
+      let mut in_flight = self.in_flight.lock().unwrap();
+      // The previous flight may have landed while we waited for the lock.
+      if let Some(value) = self.entries.lock().unwrap().get(key) {
+        return Ok(value);
+      }
+      in_flight
+        .entry(key.to_string())
+        .or_insert_with(|| {
+          let id = self.next_flight.fetch_add(1, Ordering::Relaxed);
+          (id, self.flight(key, id, fetch()))
+        })
+        .1
+        .clone()
+    };
+    flight.await
+  }
+
+  /// `fetch` caching its value under `key`, unless its `in_flight` entry was
+  /// removed meanwhile. The fetch owns the removal of its entry so it's gone
+  /// once it lands, whether or not anyone is still waiting on it.
+  fn flight<Fut>(&self, key: &str, id: u64, fetch: Fut) -> InFlight<V, E>
+  where
+    Fut: Future<Output = Result<V, E>> + Send + 'static,
+  {
+    let guard = FlightGuard {
+      entries: self.entries.clone(),
+      in_flight: self.in_flight.clone(),
+      key: key.to_string(),
+      id,
+    };
+    async move {
+      let result = fetch.await.map_err(Arc::new);
+      let _finished = guard.land(result.as_ref().ok());
+      result
+    }
+    .boxed()
+    .shared()
+  }
+}
@@ -1,3 +1,6 @@
+use crate::env_var;
+use chrono::{DateTime, SecondsFormat, Utc};
+use once_cell::sync::Lazy;
 use std::fmt::Display;
 
 pub const RESET: &str = "\x1b[0m";
@@ -11,12 +14,6 @@ pub trait Colorize {
   {
     format!("\x1b[38;2;{r};{g};{b}m{self}{RESET}")
   }
-  fn on_rgb(&self, r: u8, g: u8, b: u8) -> String
-  where
-    Self: Display,
-  {
-    format!("\x1b[48;2;{r};{g};{b}m{self}{RESET}")
-  }
   fn bold(&self) -> String
   where
     Self: Display,
@@ -56,18 +53,103 @@ pub trait Colorize {
 }
 
 impl Colorize for String {}
-impl<'a> Colorize for &'a str {}
+impl Colorize for &str {}
+
+/// Print one JSON object per line instead of colored text.
+static LOG_JSON: Lazy<bool> =
+  Lazy::new(|| env_var("LOG_JSON").is_ok_and(|value| value == "1"));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+  Log,
+  Info,
+  Err,
+  Success,
+}
+
+impl LogLevel {
+  /// Level implied by the first level style in a `log!` style chain.
+  pub fn from_styles(styles: &[&str]) -> Self {
+    styles
+      .iter()
+      .find_map(|style| match *style {
+        "info" => Some(Self::Info),
+        "err" => Some(Self::Err),
+        "success" => Some(Self::Success),
+        _ => None,
+      })
+      .unwrap_or(Self::Log)
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Log => "LOG",
+      Self::Info => "INFO",
+      Self::Err => "ERR",
+      Self::Success => "SUCCESS",
+    }
+  }
+}
+
+impl Display for LogLevel {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+/// Timestamped log line, `colored` is used for console output and the plain
+/// `message` for JSON output.
+pub fn format_line(
+  level: LogLevel,
+  target: &str,
+  message: &str,
+  colored: &str,
+  timestamp: DateTime<Utc>,
+  json: bool,
+) -> String {
+  let timestamp = timestamp.to_rfc3339_opts(SecondsFormat::Millis, true);
+  if json {
+    serde_json::json!({
+      "timestamp": timestamp,
+      "level": level.name(),
+      "target": target,
+      "message": message,
+    })
+    .to_string()
+  } else {
+    format!("{timestamp} {:<7} {colored}", level.name())
+  }
+}
+
+pub fn print_line(level: LogLevel, target: &str, message: &str, colored: &str) {
+  println!(
+    "{}",
+    format_line(level, target, message, colored, Utc::now(), *LOG_JSON)
+  );
+}
 
 #[macro_export]
 macro_rules! log {
   ( $($fn: ident).* @ $( $x: expr ),* ) => {
     {
-      println!("{}", format!($($x),*).$($fn()).*);
+      let message = format!($($x),*);
+      $crate::console::print_line(
+        $crate::console::LogLevel::from_styles(&[$(stringify!($fn)),*]),
+        module_path!(),
+        &message,
+        &message.$($fn()).*,
+      );
     }
   };
   ( $( $x: expr ),* ) => {
     {
-      println!("{}", format!($($x),*).log());
+      let message = format!($($x),*);
+      $crate::console::print_line(
+        $crate::console::LogLevel::Log,
+        module_path!(),
+        &message,
+        &message.log(),
+      );
     }
   };
 }
@@ -0,0 +1,69 @@
+use crate::{env_var, AppError, AppResult};
+use axum::http::{header::HeaderName, HeaderValue, Method};
+use format as f;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+pub const ALLOWED_METHODS: [Method; 5] = [
+  Method::GET,
+  Method::POST,
+  Method::PUT,
+  Method::PATCH,
+  Method::DELETE,
+];
+
+pub const DEFAULT_ALLOWED_HEADERS: &str =
+  "authorization,content-type,range,if-range,idempotency-key";
+
+/// CORS layer configured from `ALLOWED_ORIGINS`, `ALLOWED_HEADERS` and
+/// `CORS_ALLOW_CREDENTIALS`.
+pub fn layer() -> AppResult<CorsLayer> {
+  let origins = env_var("ALLOWED_ORIGINS").ok();
+  let headers = env_var("ALLOWED_HEADERS").ok();
+  let credentials =
+    env_var("CORS_ALLOW_CREDENTIALS").is_ok_and(|value| value == "1");
+  build(origins.as_deref(), headers.as_deref(), credentials)
+}
+
+/// `origins` and `headers` are comma separated lists, a missing or `*`
+/// origin list allows any origin which can't be combined with `credentials`.
+pub fn build(
+  origins: Option<&str>,
+  headers: Option<&str>,
+  credentials: bool,
+) -> AppResult<CorsLayer> {
+  let origins = origins.map(str::trim).filter(|origins| *origins != "*");
+  let allow_origin = match origins {
+    Some(origins) => AllowOrigin::list(parse_list::<HeaderValue>(origins)?),
+    None if credentials => {
+      return Err(AppError::Cors(
+        "Credentials cannot be allowed for any origin, set ALLOWED_ORIGINS \
+         to an explicit list"
+          .into(),
+      ))
+    }
+    None => AllowOrigin::any(),
+  };
+  let headers =
+    parse_list::<HeaderName>(headers.unwrap_or(DEFAULT_ALLOWED_HEADERS))?;
+
+  Ok(
+    CorsLayer::new()
+      .allow_origin(allow_origin)
+      .allow_methods(ALLOWED_METHODS.to_vec())
+      .allow_headers(headers)
+      .allow_credentials(credentials),
+  )
+}
+
+fn parse_list<T: std::str::FromStr>(list: &str) -> AppResult<Vec<T>> {
+  list
+    .split(',')
+    .map(str::trim)
+    .filter(|item| !item.is_empty())
+    .map(|item| {
+      item
+        .parse::<T>()
+        .map_err(|_| AppError::Cors(f!("Could not parse {item:?}")))
+    })
+    .collect()
+}
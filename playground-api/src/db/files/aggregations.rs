@@ -1,23 +1,32 @@
 use super::{
   queries::{
-    query_ancestors, query_by_id, query_children, query_lineage,
-    query_many_by_id,
+    query_ancestors, query_by_id, query_children, query_children_with_counts,
+    query_empty_folders, query_folder_style, query_lineage,
+    query_lineage_within, query_many_by_id,
   },
   system::FileSystem,
-  BasicFileInfo, DBResult, File,
+  BasicFileInfo, DBResult, File, ROOT_FOLDER_ALIAS,
 };
+use crate::db::Transaction;
 use format as f;
 use futures::TryStreamExt;
 use mongodb::bson::{doc, to_bson, Document};
 use partial_struct::{omit_and_create, CamelFields};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{collections::HashSet, ops::Deref};
+use std::{
+  collections::{HashMap, HashSet},
+  ops::Deref,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone, CamelFields)]
 #[serde(rename_all = "camelCase")]
 pub struct FolderChildren {
   #[serde(flatten)]
   file: BasicFileInfo,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub color: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub icon: Option<String>,
   pub children: Vec<File>,
 }
 
@@ -33,8 +42,12 @@ impl Deref for FolderChildren {
 pub struct FolderChildrenAndAncestors {
   #[serde(flatten)]
   file: BasicFileInfo,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub color: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub icon: Option<String>,
   pub ancestors: Vec<BasicFileInfo>,
-  pub children: Vec<File>,
+  pub children: Vec<FolderMember>,
 }
 
 impl Deref for FolderChildrenAndAncestors {
@@ -44,6 +57,83 @@ impl Deref for FolderChildrenAndAncestors {
   }
 }
 
+/// Child in a folder listing, folders come with how many files they have.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderMember {
+  #[serde(flatten)]
+  file: File,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub child_count: Option<u64>,
+}
+
+impl Deref for FolderMember {
+  type Target = File;
+  fn deref(&self) -> &Self::Target {
+    &self.file
+  }
+}
+
+/// File with everything nested in it, children are sorted by name like
+/// folder children.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileTree {
+  #[serde(flatten)]
+  file: File,
+  pub children: Vec<FileTree>,
+}
+
+impl Deref for FileTree {
+  type Target = File;
+  fn deref(&self) -> &Self::Target {
+    &self.file
+  }
+}
+
+impl FileTree {
+  /// Tree rooted at `root` out of its `descendants`.
+  pub fn assemble(root: File, descendants: Vec<File>) -> Self {
+    let mut by_folder = HashMap::<String, Vec<File>>::new();
+    for file in descendants {
+      by_folder
+        .entry(file.folder_id.clone())
+        .or_default()
+        .push(file);
+    }
+    Self::assemble_from(root, &mut by_folder)
+  }
+
+  fn assemble_from(
+    file: File,
+    by_folder: &mut HashMap<String, Vec<File>>,
+  ) -> Self {
+    let mut children = by_folder
+      .remove(&file.id)
+      .unwrap_or_default()
+      .into_iter()
+      .map(|child| Self::assemble_from(child, by_folder))
+      .collect::<Vec<_>>();
+    children.sort_by_cached_key(|child| child.name.as_ref().to_lowercase());
+    Self { file, children }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct FileLineage {
+  #[serde(flatten)]
+  file: File,
+  lineage: Vec<File>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FolderAncestors {
+  #[serde(flatten)]
+  file: BasicFileInfo,
+  ancestors: Vec<BasicFileInfo>,
+}
+
 #[omit_and_create(Lineage)]
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -53,6 +143,15 @@ pub struct LineageAndParents {
   pub parents: HashSet<String>,
 }
 
+/// Folder and every file nested in it, at any depth.
+#[derive(Debug, Deserialize)]
+pub struct FolderDescendants {
+  #[serde(flatten)]
+  pub folder: BasicFileInfo,
+  #[serde(rename = "lineage")]
+  pub descendants: Vec<File>,
+}
+
 impl FileSystem {
   /// Returns all children for the given `ids` and the direct parents of those children
   pub async fn find_lineage_with_parents(
@@ -69,7 +168,7 @@ impl FileSystem {
         ],
         File::user_id(): user_id
       } },
-      query_lineage(),
+      query_lineage(user_id),
       doc! { "$project": {
         "dupedIds": {
           "$concatArrays": [["$_id"], "$lineage._id"]
@@ -103,11 +202,50 @@ impl FileSystem {
     &self,
     query: &Document,
   ) -> DBResult<Vec<FolderChildren>> {
-    let pipeline = vec![doc! { "$match": query }, query_children()];
+    let pipeline = vec![
+      doc! { "$match": query },
+      query_folder_style(),
+      query_children(),
+    ];
 
     self.aggregate::<FolderChildren>(pipeline).await
   }
 
+  pub async fn find_folder_with_children_in(
+    &self,
+    transaction: &mut Transaction,
+    query: &Document,
+  ) -> DBResult<Vec<FolderChildren>> {
+    let pipeline = vec![
+      doc! { "$match": query },
+      query_folder_style(),
+      query_children(),
+    ];
+
+    self
+      .database
+      .aggregate_in::<File, FolderChildren>(transaction, pipeline)
+      .await
+  }
+
+  /// Folders with their children for the folder ids of each user id in
+  /// `folders`, found in a single aggregation.
+  pub async fn find_folders_with_children(
+    &self,
+    folders: &HashMap<String, HashSet<String>>,
+  ) -> DBResult<Vec<FolderChildren>> {
+    if folders.is_empty() {
+      return Ok(Vec::new());
+    }
+    let queries = folders
+      .iter()
+      .map(|(user_id, ids)| query_many_by_id(user_id, ids))
+      .collect::<DBResult<Vec<_>>>()?;
+    self
+      .find_folder_with_children(&doc! { "$or": queries })
+      .await
+  }
+
   pub async fn find_children_and_ancestors(
     &self,
     user_id: &str,
@@ -115,8 +253,8 @@ impl FileSystem {
   ) -> DBResult<Option<FolderChildrenAndAncestors>> {
     let pipeline = [doc! { "$match": query_by_id(user_id, folder_id)? }]
       .into_iter()
-      .chain(query_ancestors())
-      .chain([query_children()])
+      .chain(query_ancestors(user_id))
+      .chain([query_folder_style(), query_children_with_counts()])
       .collect::<Vec<_>>();
 
     Ok(
@@ -127,6 +265,39 @@ impl FileSystem {
     )
   }
 
+  /// Returns the path from the root folder down to `folder_id`, both included
+  pub async fn find_ancestors(
+    &self,
+    user_id: &str,
+    folder_id: &str,
+  ) -> DBResult<Option<Vec<BasicFileInfo>>> {
+    let pipeline = [doc! { "$match": query_by_id(user_id, folder_id)? }]
+      .into_iter()
+      .chain(query_ancestors(user_id))
+      .collect::<Vec<_>>();
+
+    Ok(
+      self
+        .aggregate::<FolderAncestors>(pipeline)
+        .await?
+        .pop()
+        .map(|mut family| {
+          family.ancestors.push(family.file);
+          family.ancestors
+        }),
+    )
+  }
+
+  /// Returns the folders without any children, excluding the root folder
+  pub async fn find_empty_folders(&self, user_id: &str) -> DBResult<Vec<File>> {
+    self
+      .aggregate::<File>(query_empty_folders(doc! {
+        File::user_id(): user_id,
+        "_id": { "$ne": user_id },
+      }))
+      .await
+  }
+
   pub async fn find_lineage(
     &self,
     user_id: &str,
@@ -136,7 +307,7 @@ impl FileSystem {
       self
         .aggregate::<Lineage>(vec![
           doc! { "$match": query_by_id(user_id, folder_id)? },
-          query_lineage(),
+          query_lineage(user_id),
           doc! { "$project": { "_id": 0, "lineage": "$lineage._id", } },
         ])
         .await?
@@ -145,6 +316,43 @@ impl FileSystem {
     )
   }
 
+  pub async fn find_descendants(
+    &self,
+    user_id: &str,
+    folder_id: &str,
+  ) -> DBResult<Option<FolderDescendants>> {
+    let mut query = query_by_id(user_id, folder_id)?;
+    query.insert("metadata.type", "folder");
+    let pipeline = vec![doc! { "$match": query }, query_lineage(user_id)];
+
+    Ok(self.aggregate::<FolderDescendants>(pipeline).await?.pop())
+  }
+
+  /// `user_id`'s root folder with every file nested `levels` deep in it.
+  pub async fn find_tree(
+    &self,
+    user_id: &str,
+    levels: u32,
+  ) -> DBResult<Option<FileTree>> {
+    let lineage = if levels == 0 {
+      doc! { "$addFields": { "lineage": [] } }
+    } else {
+      query_lineage_within(user_id, levels)
+    };
+    let pipeline = vec![
+      doc! { "$match": query_by_id(user_id, ROOT_FOLDER_ALIAS)? },
+      lineage,
+    ];
+
+    Ok(
+      self
+        .aggregate::<FileLineage>(pipeline)
+        .await?
+        .pop()
+        .map(|root| FileTree::assemble(root.file, root.lineage)),
+    )
+  }
+
   pub async fn find_lineage_and_parents(
     &self,
     user_id: &str,
@@ -152,7 +360,7 @@ impl FileSystem {
   ) -> DBResult<Option<LineageAndParents>> {
     let pipeline = vec![
       doc! { "$match": query_many_by_id(user_id, files)? },
-      query_lineage(),
+      query_lineage(user_id),
       doc! { "$addFields": { "lineage": { "$cond": {
         "if": { "$eq": [ { "$size": "$lineage" }, 0 ] },
         "then": [null],
@@ -22,6 +22,13 @@ pub struct File {
   pub name: NonEmptyString,
   #[omit]
   pub metadata: FileMetadata,
+  /// Favorite files, toggled by the user.
+  #[serde(default)]
+  pub starred: bool,
+  /// Incremented on every write, updates can expect a version to avoid
+  /// overwriting changes they haven't seen.
+  #[serde(default)]
+  pub version: u64,
 }
 
 impl Collection for File {
@@ -50,6 +57,8 @@ impl File {
         .unwrap_or_else(|| video.name.clone())
         .try_into()?,
       metadata: FileMetadata::Video(video),
+      starred: false,
+      version: 0,
     })
   }
 
@@ -65,7 +74,9 @@ impl File {
         .unwrap_or_else(|| user_id.clone()),
       user_id,
       name: name.try_into()?,
-      metadata: FileMetadata::Folder,
+      metadata: FileMetadata::folder(),
+      starred: false,
+      version: 0,
     })
   }
 
@@ -75,7 +86,9 @@ impl File {
       folder_id: ROOT_FOLDER_ALIAS.to_string(),
       user_id,
       name: ROOT_FOLDER_ALIAS.try_into()?,
-      metadata: FileMetadata::Folder,
+      metadata: FileMetadata::folder(),
+      starred: false,
+      version: 0,
     })
   }
 
@@ -92,7 +105,31 @@ impl File {
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum FileMetadata {
   Video(Video),
-  Folder,
+  /// Folders can be labeled with a color and an icon, e.g. an emoji.
+  Folder {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+  },
+}
+
+impl FileMetadata {
+  /// Folder without a color or icon.
+  pub fn folder() -> Self {
+    Self::Folder {
+      color: None,
+      icon: None,
+    }
+  }
+
+  /// Value of the `type` tag.
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Self::Video(_) => "video",
+      Self::Folder { .. } => "folder",
+    }
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -100,10 +137,23 @@ pub enum FileMetadata {
 pub struct Video {
   pub name: String,
   pub play_id: String,
-  pub duration_millis: u64,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub duration_millis: Option<u64>,
   pub width: u16,
   pub height: u16,
   pub thumbnail: String,
   pub mime_type: String,
   pub size_bytes: u64,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub subtitles: Vec<SubtitleTrack>,
+}
+
+/// Subtitle sidecar, e.g. a WebVTT file, players can load along the video.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct SubtitleTrack {
+  /// Language tag, videos have at most one track per language.
+  pub lang: String,
+  pub label: String,
+  pub url: String,
 }
@@ -3,18 +3,29 @@ use format as f;
 use mongodb::bson::{doc, to_bson, to_document, Document};
 use std::collections::HashSet;
 
-pub(super) fn query_lineage() -> Document {
+/// Most levels of nested folders followed when looking up a lineage.
+pub const MAX_LINEAGE_DEPTH: u32 = 100;
+
+/// Files nested in the matched folder, only following `user_id`'s files.
+pub(super) fn query_lineage(user_id: &str) -> Document {
+  query_lineage_within(user_id, MAX_LINEAGE_DEPTH)
+}
+
+/// Like `query_lineage` but only `levels` deep, 1 being the direct children.
+pub(super) fn query_lineage_within(user_id: &str, levels: u32) -> Document {
   doc! { "$graphLookup": {
     "from": File::collection_name(),
     "startWith": "$_id",
     "connectFromField": "_id",
     "connectToField": File::folder_id(),
     "as": "lineage",
-    "maxDepth": 99,
+    "maxDepth": levels.saturating_sub(1),
+    "restrictSearchWithMatch": { File::user_id(): user_id },
   } }
 }
 
-pub(super) fn query_ancestors() -> [Document; 3] {
+/// Folders containing the matched file, only following `user_id`'s folders.
+pub(super) fn query_ancestors(user_id: &str) -> [Document; 3] {
   [
     doc! { "$graphLookup": {
       "from": File::collection_name(),
@@ -23,7 +34,10 @@ pub(super) fn query_ancestors() -> [Document; 3] {
       "connectToField": "_id",
       "as": "ancestors",
       "maxDepth": 99,
-      "restrictSearchWithMatch": { "metadata.type": "folder" },
+      "restrictSearchWithMatch": {
+        "metadata.type": "folder",
+        File::user_id(): user_id,
+      },
       "depthField": "order"
     } },
     doc! { "$facet": {
@@ -48,16 +62,92 @@ pub(super) fn query_ancestors() -> [Document; 3] {
   ]
 }
 
+/// Folders matching `query` without any children, sorted by name.
+pub(super) fn query_empty_folders(mut query: Document) -> Vec<Document> {
+  query.insert("metadata.type", "folder");
+  vec![
+    doc! { "$match": query },
+    // Like `children_lookup`, only files of the folder's user count
+    doc! { "$lookup": {
+      "from": File::collection_name(),
+      "let": { "userId": f!("${}", File::user_id()) },
+      "pipeline": [
+        { "$match": { "$expr": {
+          "$eq": [f!("${}", File::user_id()), "$$userId"],
+        } } },
+        { "$limit": 1 },
+        { "$project": { "_id": 1 } },
+      ],
+      "localField": "_id",
+      "foreignField": File::folder_id(),
+      "as": "children",
+    } },
+    doc! { "$match": { "children": { "$size": 0 } } },
+    doc! { "$project": { "children": 0 } },
+    doc! { "$sort": { File::name(): 1 } },
+  ]
+}
+
+/// Color and icon of the matched folder next to its other fields, since
+/// `BasicFileInfo` leaves its metadata out.
+pub(super) fn query_folder_style() -> Document {
+  doc! { "$addFields": {
+    "color": "$metadata.color",
+    "icon": "$metadata.icon",
+  } }
+}
+
+/// Files in the matched folder owned by the folder's user.
 pub(super) fn query_children() -> Document {
+  children_lookup(Vec::new())
+}
+
+/// Like `query_children` but child folders get a `childCount` of the files
+/// in them, which is only computed and never stored.
+pub(super) fn query_children_with_counts() -> Document {
+  children_lookup(vec![
+    doc! { "$lookup": {
+      "from": File::collection_name(),
+      "let": { "userId": f!("${}", File::user_id()) },
+      "pipeline": [
+        { "$match": { "$expr": {
+          "$eq": [f!("${}", File::user_id()), "$$userId"],
+        } } },
+        { "$project": { "_id": 1 } },
+      ],
+      "localField": "_id",
+      "foreignField": File::folder_id(),
+      "as": "grandchildren",
+    } },
+    doc! { "$addFields": {
+      "childCount": { "$cond": [
+        { "$eq": ["$metadata.type", "folder"] },
+        { "$size": "$grandchildren" },
+        "$$REMOVE",
+      ] },
+    } },
+    doc! { "$project": { "grandchildren": 0 } },
+  ])
+}
+
+/// Lookup of the children of the matched folder sorted by name, with
+/// `stages` run on each child.
+fn children_lookup(stages: Vec<Document>) -> Document {
+  let mut pipeline = vec![
+    doc! { "$match": { "$expr": {
+      "$eq": [f!("${}", File::user_id()), "$$userId"],
+    } } },
+    doc! { "$addFields": {
+      "insensitiveName": { "$toLower": f!("${}", File::name()) },
+    } },
+    doc! { "$sort": { "insensitiveName": 1 } },
+    doc! { "$project": { "insensitiveName": 0 } },
+  ];
+  pipeline.extend(stages);
   doc! { "$lookup": {
     "from": File::collection_name(),
-    "pipeline": [
-      { "$addFields": {
-        "insensitiveName": { "$toLower": f!("${}", File::name()) },
-      } },
-      { "$sort": { "insensitiveName": 1 } },
-      { "$project": { "insensitiveName": 0 } }
-    ],
+    "let": { "userId": f!("${}", File::user_id()) },
+    "pipeline": pipeline,
     "localField": "_id",
     "foreignField": File::folder_id(),
     "as": "children",
@@ -65,7 +155,21 @@ pub(super) fn query_children() -> Document {
 }
 
 pub(super) fn query_by_file(file: &PartialFile) -> DBResult<Document> {
-  Ok(to_document::<PartialFile>(file)?)
+  let mut query = to_document::<PartialFile>(file)?;
+  // Files are filtered by type, the rest of their metadata varies
+  if let Some(metadata) = &file.metadata {
+    query.remove(File::metadata());
+    query.insert("metadata.type", metadata.type_name());
+  }
+  // Files from before starring existed have no `starred` field
+  if file.starred == Some(false) {
+    query.insert(File::starred(), doc! { "$ne": true });
+  }
+  // Nor a `version` field from before versioning existed
+  if file.version == Some(0) {
+    query.insert(File::version(), doc! { "$in": [0, null] });
+  }
+  Ok(query)
 }
 
 pub(super) fn query_by_id(user_id: &str, id: &str) -> DBResult<Document> {
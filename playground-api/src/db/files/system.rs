@@ -1,53 +1,292 @@
 use super::{
   aggregations::FolderChildren,
-  queries::{query_by_file, query_many_by_id},
-  File,
+  queries::{query_by_file, query_by_id, query_many_by_id},
+  File, FileMetadata, SubtitleTrack,
 };
 use crate::{
-  db::{files::PartialFile, DBResult, Database},
+  db::{files::PartialFile, DBError, DBResult, Database},
   string::{NonEmptyString, StringError},
 };
+use format as f;
 use mongodb::{
-  bson::{doc, to_document},
-  options::ReturnDocument,
-  results::UpdateResult,
+  bson::{doc, to_bson, Document},
+  options::{Collation, CollationStrength, FindOptions, ReturnDocument},
+};
+use serde::Deserialize;
+use std::{
+  collections::{HashMap, HashSet},
+  fmt::Debug,
+  sync::Arc,
 };
-use std::collections::HashSet;
 use thiserror::Error;
 
+/// Suffixes tried by `OnConflict::Rename` before giving up.
+const MAX_RENAME_ATTEMPTS: usize = 100;
+
+/// What to do when a new file's name is taken in its folder.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum OnConflict {
+  #[default]
+  Error,
+  /// Append a numeric suffix, `name (1)`, `name (2)`...
+  Rename,
+  /// Overwrite the existing video's metadata, keeping its id. Folders are
+  /// never replaced.
+  Replace,
+}
+
+/// Field files are listed by.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SortBy {
+  /// Case-insensitive, like folder children.
+  #[default]
+  Name,
+  /// Video size, folders have none and come first when ascending.
+  Size,
+  /// Creation time, from the id of each file.
+  Created,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+  #[default]
+  Asc,
+  Desc,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileSort {
+  #[serde(default, rename = "sort")]
+  pub by: SortBy,
+  #[serde(default)]
+  pub order: SortOrder,
+}
+
+impl FileSort {
+  fn options(&self) -> FindOptions {
+    let direction = match self.order {
+      SortOrder::Asc => 1,
+      SortOrder::Desc => -1,
+    };
+    // Ties are broken by id so pages don't shuffle
+    let sort = match self.by {
+      SortBy::Name => doc! { File::name(): direction, "_id": 1 },
+      SortBy::Size => doc! { "metadata.sizeBytes": direction, "_id": 1 },
+      SortBy::Created => doc! { "_id": direction },
+    };
+    FindOptions::builder()
+      .sort(sort)
+      .collation(
+        Collation::builder()
+          .locale("en")
+          .strength(CollationStrength::Secondary)
+          .build(),
+      )
+      .build()
+  }
+}
+
+/// Receives the folder changes of every write made through a `FileSystem`.
+///
+/// Folder changes are normally broadcast by `websockets::file_watcher` from a
+/// MongoDB change stream. This is only the fallback for deployments where it
+/// can't run, e.g. standalone servers, which have no change streams.
+pub trait FolderChangeFallback: Debug + Send + Sync {
+  fn folders_changed(&self, changes: Vec<FolderChildren>);
+}
+
 #[derive(Debug, Clone)]
 pub struct FileSystem {
   pub(super) database: Database,
+  fallback: Option<Arc<dyn FolderChangeFallback>>,
 }
 
 impl From<&Database> for FileSystem {
   fn from(database: &Database) -> Self {
     Self {
       database: database.clone(),
+      fallback: None,
     }
   }
 }
 
 impl FileSystem {
+  /// File system whose writes report their folder changes to `fallback`.
+  pub fn with_fallback(
+    database: &Database,
+    fallback: Arc<dyn FolderChangeFallback>,
+  ) -> Self {
+    Self {
+      database: database.clone(),
+      fallback: Some(fallback),
+    }
+  }
+
+  /// Report `changes` made by a write to the fallback, if any.
+  fn changed(&self, changes: &[FolderChildren]) {
+    if let Some(fallback) = &self.fallback {
+      fallback.folders_changed(changes.to_vec());
+    }
+  }
+
   pub async fn find_many(
     &self,
     query: &PartialFile,
+    sort: FileSort,
   ) -> FileSystemResult<Vec<File>> {
     Ok(
       self
         .database
-        .find_many::<File>(query_by_file(query)?)
+        .find_many::<File>(query_by_file(query)?, sort.options())
         .await
         .unwrap_or_default(),
     )
   }
 
+  /// Any file matching `query`.
+  async fn find_one(
+    &self,
+    query: &PartialFile,
+  ) -> FileSystemResult<Option<File>> {
+    Ok(
+      self
+        .database
+        .find_many::<File>(query_by_file(query)?, None)
+        .await
+        .unwrap_or_default()
+        .pop(),
+    )
+  }
+
+  /// `user_id`'s file `file_id`, `None` if it doesn't exist or belongs to
+  /// another user.
+  pub async fn find_file(
+    &self,
+    user_id: &str,
+    file_id: &str,
+  ) -> FileSystemResult<Option<File>> {
+    let file_id = File::map_folder_id(user_id, file_id);
+    let file = self.database.find_by_id::<File>(file_id).await?;
+    Ok(file.filter(|file| file.user_id == user_id))
+  }
+
+  /// `user_id`'s video file playing the Drive video `play_id`.
+  pub async fn find_video(
+    &self,
+    user_id: &str,
+    play_id: &str,
+  ) -> FileSystemResult<Option<File>> {
+    let files = self
+      .database
+      .find_many::<File>(video_query(user_id, play_id), None)
+      .await?;
+    Ok(files.into_iter().next())
+  }
+
+  /// Star `file_id` unless it's starred already, then unstar it.
+  pub async fn toggle_star(
+    &self,
+    user_id: &str,
+    file_id: &str,
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    if file_id == user_id {
+      return Err(FileSystemError::ReadOnly);
+    }
+    self
+      .update_from_current(user_id, file_id, |file| {
+        Ok(doc! { File::starred(): !file.starred })
+      })
+      .await
+  }
+
+  /// Set the thumbnail of `user_id`'s video file playing `play_id`.
+  pub async fn update_thumbnail(
+    &self,
+    user_id: &str,
+    play_id: &str,
+    thumbnail: &str,
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    let file = self
+      .database
+      .update_raw::<File>(
+        versioned(doc! { "metadata.thumbnail": thumbnail }),
+        video_query(user_id, play_id),
+        None,
+      )
+      .await?
+      .ok_or(FileSystemError::NotFound)?;
+    let changes = self
+      .find_folder_with_children(&query_by_file(&PartialFile {
+        id: Some(file.folder_id.clone()),
+        ..Default::default()
+      })?)
+      .await?;
+
+    self.changed(&changes);
+    Ok((file, changes))
+  }
+
+  /// Set the color and icon of `user_id`'s folder `folder_id`. The ones that
+  /// are `None` are left as they are and those that are `Some(None)` cleared.
+  pub async fn update_folder_style(
+    &self,
+    user_id: &str,
+    folder_id: &str,
+    color: Option<Option<&str>>,
+    icon: Option<Option<&str>>,
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    let folder_id = File::map_folder_id(user_id, folder_id);
+    if folder_id == user_id {
+      return Err(FileSystemError::ReadOnly);
+    }
+    let mut style = Document::new();
+    if let Some(color) = color {
+      style.insert("metadata.color", color);
+    }
+    if let Some(icon) = icon {
+      style.insert("metadata.icon", icon);
+    }
+    let file = self
+      .database
+      .update_raw::<File>(
+        versioned(style),
+        doc! {
+          "_id": folder_id,
+          File::user_id(): user_id,
+          "metadata.type": "folder",
+        },
+        None,
+      )
+      .await?
+      .ok_or(FileSystemError::NotFound)?;
+    let changes = self
+      .find_folder_with_children(&query_by_file(&PartialFile {
+        id: Some(file.folder_id.clone()),
+        ..Default::default()
+      })?)
+      .await?;
+
+    self.changed(&changes);
+    Ok((file, changes))
+  }
+
+  /// Move `files` into `folder` returning how many were moved and the
+  /// resulting folder changes. With `dry_run` nothing is written and the
+  /// changes are the ones the move would produce. Files nested in other moved
+  /// folders stay where they are so the structure of those folders is kept.
+  /// Files in `versions` must still be at the version they're mapped to.
   pub async fn move_many(
     &self,
     user_id: &str,
     files: &HashSet<String>,
     folder: &str,
-  ) -> FileSystemResult<(UpdateResult, Option<Vec<FolderChildren>>)> {
+    versions: &HashMap<String, u64>,
+    dry_run: bool,
+  ) -> FileSystemResult<(u64, Option<Vec<FolderChildren>>)> {
     if files.contains(user_id) {
       return Err(FileSystemError::ReadOnly);
     }
@@ -55,45 +294,118 @@ impl FileSystem {
     if files.contains(folder) {
       return Err(FileSystemError::FolderLoop);
     }
-    let query_result = self.find_lineage_and_parents(user_id, files).await?;
+    let mut query_result =
+      self.find_lineage_and_parents(user_id, files).await?;
+    let top_level;
+    let files = match &query_result {
+      Some(result) if !result.lineage.is_disjoint(files) => {
+        top_level = files
+          .difference(&result.lineage)
+          .cloned()
+          .collect::<HashSet<_>>();
+        query_result =
+          self.find_lineage_and_parents(user_id, &top_level).await?;
+        &top_level
+      }
+      _ => files,
+    };
     if let Some(ref result) = query_result {
       if result.lineage.contains(folder) {
         return Err(FileSystemError::FolderLoop);
       }
     }
+    let mut folder_ids = query_result.map(|q| q.parents).unwrap_or_default();
+    folder_ids.insert(folder.to_string());
+
+    if dry_run {
+      if !versions.is_empty() {
+        let current = self
+          .database
+          .find_many::<File>(query_many_by_id(user_id, files)?, None)
+          .await?;
+        check_versions(versions, &current)?;
+      }
+      let changes = self
+        .find_folder_with_children(&query_many_by_id(user_id, &folder_ids)?)
+        .await?;
+      let (moved_count, changes) = preview_move(files, folder, changes);
+      return Ok((moved_count, (moved_count > 0).then_some(changes)));
+    }
 
-    let result = self
+    // Either every file is moved and the changes computed, or nothing is
+    let (moved_count, changes) = self
       .database
-      .update_many::<File>(
-        doc! {
-          File::folder_id(): folder,
-        },
-        query_many_by_id(user_id, files)?,
-      )
+      .with_transaction(|mut transaction| async move {
+        let result: FileSystemResult<_> = async {
+          let mut expected_count = None;
+          if !versions.is_empty() {
+            let current = self
+              .database
+              .aggregate_in::<File, File>(
+                &mut transaction,
+                [doc! { "$match": query_many_by_id(user_id, files)? }],
+              )
+              .await?;
+            check_versions(versions, &current)?;
+            expected_count = Some(current.len() as u64);
+          }
+          // Versions are matched again by the update itself since standalone
+          // servers run it without a transaction
+          let result = self
+            .database
+            .update_many_in::<File>(
+              &mut transaction,
+              versioned(doc! {
+                File::folder_id(): folder,
+              }),
+              query_many_at_versions(user_id, files, versions)?,
+            )
+            .await?;
+          let changed_meanwhile =
+            expected_count.is_some_and(|count| result.modified_count < count);
+          if changed_meanwhile {
+            // Files deleted since the check just aren't moved
+            let current = self
+              .database
+              .aggregate_in::<File, File>(
+                &mut transaction,
+                [doc! { "$match": query_many_by_id(user_id, files)? }],
+              )
+              .await?;
+            check_versions(versions, &current)?;
+          }
+          if result.modified_count == 0 {
+            return Ok((0, None));
+          }
+          let query = query_many_by_id(user_id, &folder_ids)?;
+          let changes = self
+            .find_folder_with_children_in(&mut transaction, &query)
+            .await?;
+          Ok((result.modified_count, Some(changes)))
+        }
+        .await;
+        (transaction, result)
+      })
       .await?;
-
-    if result.modified_count > 0 {
-      let mut folder_ids = query_result.map(|q| q.parents).unwrap_or_default();
-      folder_ids.insert(folder.to_string());
-      let query = query_many_by_id(user_id, &folder_ids)?;
-      let changes = self.find_folder_with_children(&query).await?;
-
-      return Ok((result, Some(changes)));
+    if let Some(changes) = &changes {
+      self.changed(changes);
     }
-    Ok((result, None))
+    Ok((moved_count, changes))
   }
 
+  /// Delete `ids` and every file nested in them, returning how many were
+  /// deleted, their ids and the resulting folder changes.
   pub async fn delete_many(
     &self,
     user_id: &str,
     ids: &HashSet<String>,
-  ) -> FileSystemResult<(u64, Vec<FolderChildren>)> {
+  ) -> FileSystemResult<(u64, HashSet<String>, Vec<FolderChildren>)> {
     if ids.contains(user_id) {
       return Err(FileSystemError::ReadOnly);
     }
     // find nested files and it's parents
     let Some(result) = self.find_lineage_with_parents(user_id, ids).await? else {
-      return Ok((0, Vec::new()))
+      return Ok((0, HashSet::new(), Vec::new()))
     };
 
     let deleted = self
@@ -105,7 +417,67 @@ impl FileSystem {
       .find_folder_with_children(&query_many_by_id(user_id, &result.parents)?)
       .await?;
 
-    Ok((deleted, changes))
+    self.changed(&changes);
+    Ok((deleted, result.lineage, changes))
+  }
+
+  /// Delete `user_id`'s folder `folder_id` unless it has files in it,
+  /// returning it and the resulting folder changes.
+  pub async fn delete_empty_folder(
+    &self,
+    user_id: &str,
+    folder_id: &str,
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    let folder_id = File::map_folder_id(user_id, folder_id);
+    if folder_id == user_id {
+      return Err(FileSystemError::ReadOnly);
+    }
+    let mut query = query_by_id(user_id, folder_id)?;
+    query.insert("metadata.type", "folder");
+    // Checked and deleted in one transaction, standalone servers run them
+    // back to back. Files only point at their folder, so one saved in it
+    // after the check doesn't conflict and is left without a folder.
+    let folder = self
+      .database
+      .with_transaction(|mut transaction| async move {
+        let result = async {
+          let children = self
+            .database
+            .aggregate_in::<File, File>(
+              &mut transaction,
+              [
+                doc! {
+                  "$match": {
+                    File::user_id(): user_id,
+                    File::folder_id(): folder_id,
+                  }
+                },
+                doc! { "$limit": 1 },
+              ],
+            )
+            .await?;
+          if !children.is_empty() {
+            return Err(FileSystemError::NotEmpty);
+          }
+          self
+            .database
+            .delete_in::<File>(&mut transaction, query)
+            .await?
+            .ok_or(FileSystemError::NotFound)
+        }
+        .await;
+        (transaction, result)
+      })
+      .await?;
+    let changes = self
+      .find_folder_with_children(&query_by_file(&PartialFile {
+        id: Some(folder.folder_id.clone()),
+        ..Default::default()
+      })?)
+      .await?;
+
+    self.changed(&changes);
+    Ok((folder, changes))
   }
 
   pub async fn update_one(
@@ -114,6 +486,7 @@ impl FileSystem {
     file_id: &str,
     folder: Option<String>,
     name: Option<String>,
+    version: Option<u64>,
   ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
     if file_id == user_id {
       return Err(FileSystemError::ReadOnly);
@@ -130,16 +503,33 @@ impl FileSystem {
     update.name = name.map(NonEmptyString::try_from).transpose()?;
     update.folder_id = folder.clone();
     let update = query_by_file(update)?;
-    let query = query_by_file(&PartialFile {
+    let file_query = PartialFile {
       id: Some(file_id.to_string()),
       user_id: Some(user_id.to_string()),
       ..Default::default()
+    };
+    let query = query_by_file(&PartialFile {
+      version,
+      ..file_query.clone()
     })?;
-    let original_file = self
+    let updated = self
       .database
-      .update::<File>(update, query, Some(ReturnDocument::Before))
-      .await?
-      .ok_or(FileSystemError::NotFound)?;
+      .update_raw::<File>(
+        versioned(update),
+        query,
+        Some(ReturnDocument::Before),
+      )
+      .await?;
+    let Some(original_file) = updated else {
+      let current = self.find_one(&file_query).await?;
+      return Err(match (current, version) {
+        (Some(current), Some(expected)) => FileSystemError::Conflict {
+          expected,
+          actual: current.version,
+        },
+        _ => FileSystemError::NotFound,
+      });
+    };
     let changes = if let Some(folder) = folder {
       let mut ids = HashSet::new();
       ids.insert(folder);
@@ -156,19 +546,121 @@ impl FileSystem {
         .await?
     };
 
+    self.changed(&changes);
     Ok((original_file, changes))
   }
 
+  /// Attach `track` to the video `file_id`, replacing its track in the same
+  /// language if any.
+  pub async fn attach_subtitle(
+    &self,
+    user_id: &str,
+    file_id: &str,
+    track: SubtitleTrack,
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    self
+      .update_subtitles(user_id, file_id, |subtitles| {
+        subtitles.retain(|subtitle| subtitle.lang != track.lang);
+        subtitles.push(track);
+      })
+      .await
+  }
+
+  /// Remove the `lang` track from the video `file_id`.
+  pub async fn remove_subtitle(
+    &self,
+    user_id: &str,
+    file_id: &str,
+    lang: &str,
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    self
+      .update_subtitles(user_id, file_id, |subtitles| {
+        subtitles.retain(|subtitle| subtitle.lang != lang);
+      })
+      .await
+  }
+
+  /// Apply `update` to the subtitles of the video `file_id`.
+  async fn update_subtitles(
+    &self,
+    user_id: &str,
+    file_id: &str,
+    update: impl FnOnce(&mut Vec<SubtitleTrack>),
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    self
+      .update_from_current(user_id, file_id, |file| {
+        let FileMetadata::Video(video) = &file.metadata else {
+          return Err(FileSystemError::NotVideo);
+        };
+        let mut subtitles = video.subtitles.clone();
+        update(&mut subtitles);
+        let subtitles = to_bson(&subtitles).map_err(DBError::from)?;
+        Ok(doc! { "metadata.subtitles": subtitles })
+      })
+      .await
+  }
+
+  /// Set the fields `set` computes from the current `file_id`, failing with a
+  /// conflict if the file changed in between.
+  async fn update_from_current(
+    &self,
+    user_id: &str,
+    file_id: &str,
+    set: impl FnOnce(&File) -> FileSystemResult<Document>,
+  ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
+    let file_query = PartialFile {
+      id: Some(file_id.to_string()),
+      user_id: Some(user_id.to_string()),
+      ..Default::default()
+    };
+    let file = self
+      .find_one(&file_query)
+      .await?
+      .ok_or(FileSystemError::NotFound)?;
+    let query = query_by_file(&PartialFile {
+      version: Some(file.version),
+      ..file_query.clone()
+    })?;
+    let updated = self
+      .database
+      .update_raw::<File>(versioned(set(&file)?), query, None)
+      .await?;
+    let Some(updated) = updated else {
+      let current = self.find_one(&file_query).await?;
+      return Err(match current {
+        Some(current) => FileSystemError::Conflict {
+          expected: file.version,
+          actual: current.version,
+        },
+        None => FileSystemError::NotFound,
+      });
+    };
+    let changes = self
+      .find_folder_with_children(&query_by_file(&PartialFile {
+        id: Some(updated.folder_id.clone()),
+        ..Default::default()
+      })?)
+      .await?;
+
+    self.changed(&changes);
+    Ok((updated, changes))
+  }
+
   pub async fn create_one(
     &self,
     user_file: &File,
+    on_conflict: OnConflict,
   ) -> FileSystemResult<(File, Vec<FolderChildren>)> {
-    let new_file = self.save_one(user_file).await?.ok_or_else(|| {
-      FileSystemError::NameConflict(
-        user_file.name.clone(),
-        user_file.folder_id.clone(),
-      )
-    })?;
+    let new_file =
+      self
+        .save_one(user_file, on_conflict)
+        .await?
+        .ok_or_else(|| {
+          FileSystemError::NameConflict(
+            user_file.name.clone(),
+            user_file.folder_id.clone(),
+          )
+        })?;
 
     let query = query_by_file(&PartialFile {
       id: Some(new_file.folder_id.clone()),
@@ -176,16 +668,159 @@ impl FileSystem {
     })?;
     let changes = self.find_folder_with_children(&query).await?;
 
-    Ok((new_file.clone(), changes))
+    self.changed(&changes);
+    Ok((new_file, changes))
+  }
+
+  async fn save_one(
+    &self,
+    file: &File,
+    on_conflict: OnConflict,
+  ) -> DBResult<Option<File>> {
+    if let Some(file) = self.create_unique(file).await? {
+      return Ok(Some(file));
+    }
+    match on_conflict {
+      OnConflict::Error => Ok(None),
+      OnConflict::Rename => {
+        for suffix in 1..=MAX_RENAME_ATTEMPTS {
+          let renamed = File {
+            name: f!("{} ({suffix})", file.name).try_into()?,
+            ..file.clone()
+          };
+          if let Some(file) = self.create_unique(&renamed).await? {
+            return Ok(Some(file));
+          }
+        }
+        Ok(None)
+      }
+      OnConflict::Replace => {
+        let mut query = Self::name_query(file)?;
+        query.insert("metadata.type", "video");
+        self
+          .database
+          .update_raw::<File>(
+            versioned(doc! { File::metadata(): to_bson(&file.metadata)? }),
+            query,
+            None,
+          )
+          .await
+      }
+    }
+  }
+
+  /// Insert `file` unless its name is taken in its folder.
+  async fn create_unique(&self, file: &File) -> DBResult<Option<File>> {
+    self
+      .database
+      .create(file, Some(Self::name_query(file)?))
+      .await
   }
 
-  async fn save_one(&self, file: &File) -> DBResult<Option<File>> {
-    let mut query = &mut PartialFile::default();
+  fn name_query(file: &File) -> DBResult<Document> {
+    let query = &mut PartialFile::default();
     query.user_id = Some(file.user_id.clone());
     query.folder_id = Some(file.folder_id.clone());
     query.name = Some(file.name.clone());
-    self.database.create(file, Some(to_document(query)?)).await
+    query_by_file(query)
+  }
+}
+
+/// Query for `user_id`'s video files playing the Drive video `play_id`.
+fn video_query(user_id: &str, play_id: &str) -> Document {
+  doc! {
+    File::user_id(): user_id,
+    "metadata.type": "video",
+    "metadata.playId": play_id,
+  }
+}
+
+/// Like `query_many_by_id` but files in `versions` only match at the version
+/// they're mapped to.
+fn query_many_at_versions(
+  user_id: &str,
+  ids: &HashSet<String>,
+  versions: &HashMap<String, u64>,
+) -> DBResult<Document> {
+  if versions.is_empty() {
+    return query_many_by_id(user_id, ids);
+  }
+  let mut any_version = HashSet::new();
+  let mut clauses = Vec::new();
+  for id in ids {
+    match versions.get(id) {
+      Some(&version) => clauses.push(query_by_file(&PartialFile {
+        id: Some(id.clone()),
+        version: Some(version),
+        ..Default::default()
+      })?),
+      None => {
+        any_version.insert(id.clone());
+      }
+    }
+  }
+  clauses.push(doc! { "_id": { "$in": to_bson(&any_version)? } });
+  Ok(doc! { File::user_id(): user_id, "$or": clauses })
+}
+
+/// Update setting `set` and bumping the file version.
+fn versioned(set: Document) -> Document {
+  let mut update = doc! { "$inc": { File::version(): 1 } };
+  // Mongo before 5.0 rejects an empty `$set`
+  if !set.is_empty() {
+    update.insert("$set", set);
+  }
+  update
+}
+
+/// Fail with a conflict if any of the `current` files isn't at the version
+/// `versions` expects, files missing from `versions` can be at any version.
+fn check_versions(
+  versions: &HashMap<String, u64>,
+  current: &[File],
+) -> FileSystemResult {
+  for file in current {
+    match versions.get(&file.id) {
+      Some(&expected) if expected != file.version => {
+        return Err(FileSystemError::Conflict {
+          expected,
+          actual: file.version,
+        })
+      }
+      _ => {}
+    }
+  }
+  Ok(())
+}
+
+/// Apply moving `files` into `folder` to the current `changes` of the folders
+/// involved, returning how many files would change folder.
+fn preview_move(
+  files: &HashSet<String>,
+  folder: &str,
+  mut changes: Vec<FolderChildren>,
+) -> (u64, Vec<FolderChildren>) {
+  let moved = changes
+    .iter()
+    .filter(|change| change.id != folder)
+    .flat_map(|change| change.children.iter())
+    .filter(|child| files.contains(&child.id))
+    .map(|child| File {
+      folder_id: folder.to_string(),
+      ..child.clone()
+    })
+    .collect::<Vec<_>>();
+  for change in &mut changes {
+    if change.id == folder {
+      change.children.extend(moved.iter().cloned());
+      change
+        .children
+        .sort_by_cached_key(|child| child.name.as_ref().to_lowercase());
+    } else {
+      change.children.retain(|child| !files.contains(&child.id));
+    }
   }
+  (moved.len() as u64, changes)
 }
 
 #[derive(Error, Debug)]
@@ -196,12 +831,20 @@ pub enum FileSystemError {
   ReadOnly,
   #[error("File not found")]
   NotFound,
+  #[error("File is not a video")]
+  NotVideo,
+  #[error("Folder is not empty")]
+  NotEmpty,
   #[error("Internal database error {0}")]
   Internal(#[from] super::super::DBError),
   #[error("Bad formatted string {0}")]
   BadString(#[from] StringError),
   #[error("A file with the name {0:?} already exists in folder with id {1:?}")]
   NameConflict(NonEmptyString, String),
+  #[error(
+    "File changed since version {expected}, it's now at version {actual}"
+  )]
+  Conflict { expected: u64, actual: u64 },
 }
 
 pub type FileSystemResult<T = ()> = Result<T, FileSystemError>;
@@ -1,29 +1,56 @@
 pub mod files;
+pub mod oauth_states;
+pub mod providers;
+pub mod shares;
 pub mod users;
 
 use crate::{
   auth::{
-    jwt::JWTError,
-    session::{SessionCache, SESSIONS_CACHE},
+    jwt::{self, JWTError},
+    session::{
+      SessionCache, SessionChanges, SESSIONS_CACHE, SESSIONS_CHANGED,
+      SESSION_CHANGES,
+    },
   },
   console::Colorize,
-  env_var, log,
+  db::{files::File, oauth_states::OAuthState, shares::Share},
+  env_var, env_var_or, log,
+  metrics::time_query,
   string::StringError,
   AppError, GracefulExit,
 };
+use format as f;
+use futures::TryStreamExt;
 use mongodb::{
-  bson::{self, doc, to_document, Bson, Document},
+  bson::{self, doc, to_document, Document},
+  change_stream::{event::ChangeStreamEvent, ChangeStream},
+  error::ErrorKind,
   options::{
-    Acknowledgment, ClientOptions, FindOneAndUpdateOptions, InsertManyOptions,
+    ChangeStreamOptions, ClientOptions, FindOneAndUpdateOptions, FindOptions,
+    FullDocumentBeforeChangeType, FullDocumentType, IndexOptions,
     ReplaceOptions, ResolverConfig, ReturnDocument, UpdateOptions,
-    WriteConcern,
   },
   results::UpdateResult,
-  Client, Cursor,
+  Client, ClientSession, Cursor, IndexModel,
 };
+use once_cell::sync::Lazy;
 use serde::{de::DeserializeOwned, Serialize};
-use std::{collections::HashMap, time::Duration};
+use std::{
+  future::Future,
+  time::{Duration, Instant},
+};
 use thiserror::Error;
+use tokio::{sync::OnceCell, task::JoinHandle};
+
+static SESSIONS_FLUSH_DELAY: Lazy<Duration> =
+  Lazy::new(|| Duration::from_secs(env_var_or("SESSIONS_FLUSH_SECS", 5)));
+
+/// Queries taking longer than this are logged.
+static SLOW_QUERY_THRESHOLD: Lazy<Duration> =
+  Lazy::new(|| Duration::from_millis(env_var_or("SLOW_QUERY_MS", 500)));
+
+/// Most characters of a query included in slow query logs.
+const SLOW_QUERY_SUMMARY_LEN: usize = 200;
 
 pub trait Collection:
   std::fmt::Debug
@@ -39,24 +66,190 @@ pub trait Collection:
   fn id(&self) -> &str;
 }
 
+const DEFAULT_MAX_POOL_SIZE: u32 = 10;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SERVER_SELECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection pool settings, each one set from its env var, then from
+/// `MONGODB_URI` and lastly from its default.
+#[derive(Debug, Clone, Default)]
+pub struct PoolConfig {
+  pub max_pool_size: Option<u32>,
+  pub connect_timeout: Option<Duration>,
+  pub server_selection_timeout: Option<Duration>,
+}
+
+impl PoolConfig {
+  pub fn from_env() -> Self {
+    let secs = |var_name| {
+      env_var(var_name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+    };
+    Self {
+      max_pool_size: env_var("MONGODB_MAX_POOL_SIZE")
+        .ok()
+        .and_then(|value| value.parse().ok()),
+      connect_timeout: secs("MONGODB_CONNECT_SECS"),
+      server_selection_timeout: secs("MONGODB_SELECTION_SECS"),
+    }
+  }
+
+  pub fn apply(&self, options: &mut ClientOptions) {
+    options.max_pool_size = self
+      .max_pool_size
+      .or(options.max_pool_size)
+      .or(Some(DEFAULT_MAX_POOL_SIZE));
+    options.connect_timeout = self
+      .connect_timeout
+      .or(options.connect_timeout)
+      .or(Some(DEFAULT_CONNECT_TIMEOUT));
+    options.server_selection_timeout = self
+      .server_selection_timeout
+      .or(options.server_selection_timeout)
+      .or(Some(DEFAULT_SERVER_SELECTION_TIMEOUT));
+  }
+}
+
+/// Log message for a query that took `elapsed` to run, `None` unless it took
+/// longer than `threshold`.
+pub fn slow_query_message(
+  operation: &str,
+  collection: &str,
+  query: &[Document],
+  elapsed: Duration,
+  threshold: Duration,
+) -> Option<String> {
+  if elapsed <= threshold {
+    return None;
+  }
+  let query = query
+    .iter()
+    .map(Document::to_string)
+    .collect::<Vec<_>>()
+    .join(", ");
+  let mut summary = query
+    .chars()
+    .take(SLOW_QUERY_SUMMARY_LEN)
+    .collect::<String>();
+  if summary.len() < query.len() {
+    summary.push('…');
+  }
+  Some(f!(
+    "Slow {operation} on {collection} took {}ms: {summary}",
+    elapsed.as_millis()
+  ))
+}
+
+/// Run `query` through `time_query`, logging it if it takes longer than
+/// `SLOW_QUERY_THRESHOLD`. `summary` holds the documents the query was
+/// built from.
+async fn watch_query<T>(
+  operation: &'static str,
+  collection: &'static str,
+  summary: Vec<Document>,
+  query: impl Future<Output = T>,
+) -> T {
+  let started_at = Instant::now();
+  let result = time_query(operation, collection, query).await;
+  if let Some(message) = slow_query_message(
+    operation,
+    collection,
+    &summary,
+    started_at.elapsed(),
+    *SLOW_QUERY_THRESHOLD,
+  ) {
+    log!(info@"{message}");
+  }
+  result
+}
+
 #[derive(Debug, Clone)]
-pub struct Database(mongodb::Database);
+/// Database and the client that owns it, which sessions are started from.
+pub struct Database(mongodb::Database, Client);
+
+/// Name of the index OAuth states expired through before storing `expiresAt`.
+const LEGACY_OAUTH_STATE_INDEX: &str = "createdAt_1";
+
+/// Server error codes for dropping an index or collection that isn't there.
+const INDEX_NOT_FOUND_CODE: i32 = 27;
+const NAMESPACE_NOT_FOUND_CODE: i32 = 26;
+
+fn is_missing_index(error: &mongodb::error::Error) -> bool {
+  matches!(
+    &*error.kind,
+    ErrorKind::Command(error)
+      if [INDEX_NOT_FOUND_CODE, NAMESPACE_NOT_FOUND_CODE].contains(&error.code)
+  )
+}
+
+/// Whether the server supports transactions, it's the same for every database.
+static SUPPORTS_TRANSACTIONS: Lazy<OnceCell<bool>> = Lazy::new(OnceCell::new);
+
+/// Session of an ongoing transaction, there's none when the server doesn't
+/// support transactions.
+pub struct Transaction(Option<ClientSession>);
 
 impl Database {
   pub async fn new(db_name: &str) -> DBResult<Self> {
-    let client_options = ClientOptions::parse_with_resolver_config(
+    let mut client_options = ClientOptions::parse_with_resolver_config(
       env_var("MONGODB_URI")?,
       ResolverConfig::cloudflare(),
     )
     .await?;
+    PoolConfig::from_env().apply(&mut client_options);
 
     let client = Client::with_options(client_options)?;
 
-    let db = Self(client.database(db_name));
+    let db = Self(client.database(db_name), client);
+    db.ensure_indexes().await?;
     log!(info@"Database {db_name:?} initialized");
     Ok(db)
   }
 
+  /// Create the indexes file queries match on and the ones expiring OAuth
+  /// states and shares, indexes that already exist are left as they are.
+  /// Documents store when they expire, so the expiring indexes don't change
+  /// along with `OAUTH_STATE_TTL_SECS`.
+  pub async fn ensure_indexes(&self) -> DBResult {
+    let indexes = [
+      doc! { File::user_id(): 1, File::folder_id(): 1 },
+      doc! { File::user_id(): 1, File::name(): 1 },
+    ]
+    .map(|keys| IndexModel::builder().keys(keys).build());
+    self
+      .collection::<File>()
+      .create_indexes(indexes, None)
+      .await?;
+
+    // Shares without an expiry date are kept
+    let expiry = || {
+      let options =
+        IndexOptions::builder().expire_after(Duration::ZERO).build();
+      IndexModel::builder()
+        .keys(doc! { "expiresAt": 1 })
+        .options(options)
+        .build()
+    };
+    let oauth_states = self.collection::<OAuthState>();
+    // States used to expire through a `createdAt` index with the TTL in it
+    let legacy = oauth_states
+      .drop_index(LEGACY_OAUTH_STATE_INDEX, None)
+      .await;
+    if let Err(error) = legacy {
+      if !is_missing_index(&error) {
+        return Err(error.into());
+      }
+    }
+    oauth_states.create_index(expiry(), None).await?;
+    self
+      .collection::<Share>()
+      .create_index(expiry(), None)
+      .await?;
+    Ok(())
+  }
+
   pub async fn save_sessions(&self) {
     log!(info@"Saving sessions");
     let upsert = UpdateOptions::builder().upsert(true).build();
@@ -66,11 +259,77 @@ impl Database {
       .collection::<SessionCache>("sessions")
       .update_one(
         doc! { "_id": "sessions" },
-        doc! { "$set": { "sessions": sessions.iter().collect::<Vec<_>>() } },
+        doc! { "$set": { "sessions": sessions.keys().collect::<Vec<_>>() } },
         upsert,
       )
       .await
       .unwrap_or_exit("Could not save sessions to database");
+    // Everything pending was just written
+    *SESSION_CHANGES.lock().await = SessionChanges::default();
+  }
+
+  /// Spawn a task that persists session changes shortly after they happen.
+  pub fn sync_sessions(&self) -> JoinHandle<()> {
+    let database = self.clone();
+    tokio::spawn(async move {
+      loop {
+        SESSIONS_CHANGED.notified().await;
+        // Debounce so bursts of logins are written together
+        tokio::time::sleep(*SESSIONS_FLUSH_DELAY).await;
+        if let Err(error) = database.flush_session_changes().await {
+          log!(err@"Could not persist session changes: {error}");
+        }
+      }
+    })
+  }
+
+  /// Write pending session changes without rewriting the whole cache. They're
+  /// kept pending if writing them fails, to be retried with the next flush.
+  pub async fn flush_session_changes(&self) -> DBResult {
+    let changes = std::mem::take(&mut *SESSION_CHANGES.lock().await);
+    if changes.is_empty() {
+      return Ok(());
+    }
+    if let Err(error) = self.write_session_changes(&changes).await {
+      SESSION_CHANGES.lock().await.restore(changes);
+      SESSIONS_CHANGED.notify_one();
+      return Err(error);
+    }
+    log!(info@"Persisted {} saved and {} invalidated sessions", changes.saved.len(), changes.invalidated.len());
+    Ok(())
+  }
+
+  async fn write_session_changes(&self, changes: &SessionChanges) -> DBResult {
+    let collection = self.0.collection::<SessionCache>("sessions");
+    let query = doc! { "_id": "sessions" };
+    if !changes.saved.is_empty() {
+      let saved = changes.saved.iter().collect::<Vec<_>>();
+      let upsert = UpdateOptions::builder().upsert(true).build();
+      collection
+        .update_one(
+          query.clone(),
+          doc! { "$addToSet": { "sessions": { "$each": saved } } },
+          upsert,
+        )
+        .await?;
+    }
+    if !changes.invalidated.is_empty() {
+      let invalidated = changes.invalidated.iter().collect::<Vec<_>>();
+      collection
+        .update_one(
+          query,
+          doc! { "$pull": { "sessions": { "$in": invalidated } } },
+          None,
+        )
+        .await?;
+    }
+    Ok(())
+  }
+
+  /// Cheap round trip to check the database is reachable.
+  pub async fn ping(&self) -> DBResult {
+    self.0.run_command(doc! { "ping": 1 }, None).await?;
+    Ok(())
   }
 
   pub async fn load_sessions(&self) {
@@ -83,23 +342,33 @@ impl Database {
       .ok()
       .flatten();
     if let Some(session) = session {
-      let sessions = session.sessions;
+      // Expired tokens are dropped here since they can't be decoded anymore
+      let sessions = session.sessions.into_iter().filter_map(|token| {
+        let user_id = jwt::verify_token(&token).ok()?.claims.sub;
+        Some((token, user_id))
+      });
       SESSIONS_CACHE.lock().await.extend(sessions);
     }
   }
 
+  /// Every document matching `query`, `options` can limit, skip or sort them.
   pub async fn find_many<T: Collection>(
     &self,
     query: Document,
+    options: impl Into<Option<FindOptions>>,
   ) -> DBResult<Vec<T>> {
     let collection = self.collection::<T>();
-    let mut cursor = collection.find(query, None).await?;
-    let mut documents = Vec::new();
-    while cursor.advance().await? {
-      let document = cursor.deserialize_current()?;
-      documents.push(document);
-    }
-    Ok(documents)
+    let summary = vec![query.clone()];
+    watch_query("find_many", T::collection_name(), summary, async {
+      let mut cursor = collection.find(query, options).await?;
+      let mut documents = Vec::new();
+      while cursor.advance().await? {
+        let document = cursor.deserialize_current()?;
+        documents.push(document);
+      }
+      Ok(documents)
+    })
+    .await
   }
 
   pub async fn find_by_id<T: Collection>(
@@ -107,28 +376,31 @@ impl Database {
     id: &str,
   ) -> DBResult<Option<T>> {
     let collection = self.collection::<T>();
-    Ok(collection.find_one(doc! { "_id": id }, None).await?)
+    let query = collection.find_one(doc! { "_id": id }, None);
+    Ok(time_query("find_by_id", T::collection_name(), query).await?)
   }
 
   pub async fn aggregate<T: Collection>(
     &self,
     pipeline: impl IntoIterator<Item = Document>,
   ) -> DBResult<Cursor<T>> {
-    let result = self
-      .collection::<T>()
-      .aggregate(pipeline, None)
-      .await?
-      .with_type::<T>();
+    let collection = self.collection::<T>();
+    let pipeline = pipeline.into_iter().collect::<Vec<_>>();
+    let query = collection.aggregate(pipeline.clone(), None);
+    let result =
+      watch_query("aggregate", T::collection_name(), pipeline, query)
+        .await?
+        .with_type::<T>();
     Ok(result)
   }
 
-  #[allow(dead_code)]
   pub async fn delete<T: Collection>(
     &self,
     query: Document,
   ) -> DBResult<Option<T>> {
     let collection = self.collection::<T>();
-    Ok(collection.find_one_and_delete(query, None).await?)
+    let query = collection.find_one_and_delete(query, None);
+    Ok(time_query("delete", T::collection_name(), query).await?)
   }
 
   pub async fn delete_many<T: Collection>(
@@ -136,7 +408,12 @@ impl Database {
     query: Document,
   ) -> DBResult<u64> {
     let collection = self.collection::<T>();
-    Ok(collection.delete_many(query, None).await?.deleted_count)
+    let query = collection.delete_many(query, None);
+    Ok(
+      time_query("delete_many", T::collection_name(), query)
+        .await?
+        .deleted_count,
+    )
   }
 
   pub async fn update<T: Collection>(
@@ -144,31 +421,158 @@ impl Database {
     update: Document,
     query: Document,
     return_document: Option<ReturnDocument>,
+  ) -> DBResult<Option<T>> {
+    self
+      .update_raw(doc! { "$set": update }, query, return_document)
+      .await
+  }
+
+  /// Like `update` but `update` is a full update document with operators.
+  pub async fn update_raw<T: Collection>(
+    &self,
+    update: Document,
+    query: Document,
+    return_document: Option<ReturnDocument>,
   ) -> DBResult<Option<T>> {
     let collection = self.collection::<T>();
     let options = FindOneAndUpdateOptions::builder()
       .return_document(return_document.unwrap_or(ReturnDocument::After))
       .build();
-    Ok(
-      collection
-        .find_one_and_update(query, doc! { "$set": update }, options)
-        .await?,
-    )
+    let query = collection.find_one_and_update(query, update, options);
+    Ok(time_query("update", T::collection_name(), query).await?)
   }
 
-  pub async fn update_many<T: Collection>(
+  /// Update every document matching `query` inside `transaction`, `update` is
+  /// a full update document with operators.
+  pub async fn update_many_in<T: Collection>(
     &self,
+    transaction: &mut Transaction,
     update: Document,
     query: Document,
   ) -> DBResult<UpdateResult> {
     let collection = self.collection::<T>();
-    let result = collection
-      .update_many(query, doc! { "$set": update }, None)
-      .await?;
-    Ok(result)
+    let summary = vec![query.clone(), update.clone()];
+    let query = async {
+      match &mut transaction.0 {
+        Some(session) => {
+          collection
+            .update_many_with_session(query, update, None, session)
+            .await
+        }
+        None => collection.update_many(query, update, None).await,
+      }
+    };
+    Ok(watch_query("update_many", T::collection_name(), summary, query).await?)
+  }
+
+  /// Like `delete` inside `transaction`.
+  pub async fn delete_in<T: Collection>(
+    &self,
+    transaction: &mut Transaction,
+    query: Document,
+  ) -> DBResult<Option<T>> {
+    let collection = self.collection::<T>();
+    let query = async {
+      match &mut transaction.0 {
+        Some(session) => {
+          collection
+            .find_one_and_delete_with_session(query, None, session)
+            .await
+        }
+        None => collection.find_one_and_delete(query, None).await,
+      }
+    };
+    Ok(time_query("delete", T::collection_name(), query).await?)
+  }
+
+  /// Like `aggregate` inside `transaction`, collecting every result as `D`.
+  pub async fn aggregate_in<T, D>(
+    &self,
+    transaction: &mut Transaction,
+    pipeline: impl IntoIterator<Item = Document>,
+  ) -> DBResult<Vec<D>>
+  where
+    T: Collection,
+    D: DeserializeOwned + Unpin + Send + Sync,
+  {
+    let collection = self.collection::<T>();
+    let pipeline = pipeline.into_iter().collect::<Vec<_>>();
+    let summary = pipeline.clone();
+    let query = async {
+      match &mut transaction.0 {
+        Some(session) => {
+          collection
+            .aggregate_with_session(pipeline, None, session)
+            .await?
+            .with_type::<D>()
+            .stream(session)
+            .try_collect()
+            .await
+        }
+        None => {
+          collection
+            .aggregate(pipeline, None)
+            .await?
+            .with_type::<D>()
+            .try_collect()
+            .await
+        }
+      }
+    };
+    Ok(watch_query("aggregate", T::collection_name(), summary, query).await?)
+  }
+
+  /// Run `operation` in a transaction that's committed if it succeeds and
+  /// aborted otherwise. Standalone servers don't support transactions so
+  /// there `operation` runs without one.
+  ///
+  /// `operation` hands the transaction back along with its result so it can
+  /// be finished here.
+  pub async fn with_transaction<T, E, F, Fut>(
+    &self,
+    operation: F,
+  ) -> Result<T, E>
+  where
+    E: From<DBError>,
+    F: FnOnce(Transaction) -> Fut,
+    Fut: Future<Output = (Transaction, Result<T, E>)>,
+  {
+    let session = if self.supports_transactions().await? {
+      let mut session =
+        self.1.start_session(None).await.map_err(DBError::from)?;
+      session
+        .start_transaction(None)
+        .await
+        .map_err(DBError::from)?;
+      Some(session)
+    } else {
+      None
+    };
+    let (Transaction(session), result) = operation(Transaction(session)).await;
+    if let Some(mut session) = session {
+      if result.is_ok() {
+        session.commit_transaction().await.map_err(DBError::from)?;
+      } else if let Err(error) = session.abort_transaction().await {
+        log!(err@"Failed to abort transaction: {error}");
+      }
+    }
+    result
+  }
+
+  /// Only replica set members and mongos support transactions.
+  async fn supports_transactions(&self) -> DBResult<bool> {
+    SUPPORTS_TRANSACTIONS
+      .get_or_try_init(|| async {
+        let hello = self.0.run_command(doc! { "hello": 1 }, None).await?;
+        Ok::<_, DBError>(
+          hello.contains_key("setName")
+            || hello.get_str("msg").is_ok_and(|msg| msg == "isdbgrid"),
+        )
+      })
+      .await
+      .copied()
   }
 
-  #[allow(dead_code)]
   /// Replace doc in collection or create it if it doesn't exist.
   pub async fn replace<T: Collection>(
     &self,
@@ -177,52 +581,57 @@ impl Database {
   ) -> DBResult {
     let collection = self.collection::<T>();
     let upsert = ReplaceOptions::builder().upsert(true).build();
-    collection
-      .replace_one(
-        query.unwrap_or_else(|| doc! { "_id": doc.id() }),
-        doc,
-        upsert,
-      )
-      .await?;
+    let query = collection.replace_one(
+      query.unwrap_or_else(|| doc! { "_id": doc.id() }),
+      doc,
+      upsert,
+    );
+    time_query("replace", T::collection_name(), query).await?;
     Ok(())
   }
 
   /// Insert doc only if it doesn't exist.
-  pub async fn create<'a, T: Collection>(
+  pub async fn create<T: Collection>(
     &self,
-    doc: &'a T,
+    doc: &T,
     query: Option<Document>,
   ) -> DBResult<Option<T>> {
     let collection = self.collection::<T>();
     let upsert = UpdateOptions::builder().upsert(true).build();
-    let result = collection
-      .update_one(
-        query.unwrap_or_else(|| doc! { "_id": doc.id() }),
-        doc! { "$setOnInsert": to_document(&doc)? },
-        upsert,
-      )
-      .await?;
+    let query = collection.update_one(
+      query.unwrap_or_else(|| doc! { "_id": doc.id() }),
+      doc! { "$setOnInsert": to_document(&doc)? },
+      upsert,
+    );
+    let result = time_query("create", T::collection_name(), query).await?;
     Ok(result.upserted_id.is_some().then_some(doc.clone()))
   }
 
-  #[allow(dead_code)]
-  /// Insert docs only if they don't exist.
-  pub async fn create_many<'a, T: Collection>(
+  /// Stream every change to `T`'s collection with the document as it is after
+  /// the change, and as it was before it when the server keeps pre-images.
+  /// Needs a replica set.
+  pub async fn watch<T: Collection>(
     &self,
-    docs: &[T],
-  ) -> DBResult<HashMap<usize, Bson>> {
-    let collection = self.collection::<T>();
-    let options = InsertManyOptions::builder()
-      .ordered(false)
-      .write_concern(
-        WriteConcern::builder()
-          .w(Acknowledgment::Majority)
-          .w_timeout(Duration::from_secs(5))
-          .build(),
-      )
+  ) -> DBResult<ChangeStream<ChangeStreamEvent<T>>> {
+    let options = ChangeStreamOptions::builder()
+      .full_document(Some(FullDocumentType::UpdateLookup))
+      .full_document_before_change(Some(
+        FullDocumentBeforeChangeType::WhenAvailable,
+      ))
       .build();
-    let result = collection.insert_many(docs, options).await?;
-    Ok(result.inserted_ids)
+    Ok(self.collection::<T>().watch([], options).await?)
+  }
+
+  /// Keep the document before each change to `T`'s collection, so
+  /// [`Database::watch`] can tell where deleted and moved documents were.
+  /// Needs MongoDB 6.0 or newer.
+  pub async fn keep_pre_images<T: Collection>(&self) -> DBResult {
+    let command = doc! {
+      "collMod": T::collection_name(),
+      "changeStreamPreAndPostImages": { "enabled": true },
+    };
+    self.0.run_command(command, None).await?;
+    Ok(())
   }
 
   pub fn collection<T: Collection>(&self) -> mongodb::Collection<T> {
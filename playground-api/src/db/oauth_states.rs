@@ -0,0 +1,77 @@
+use super::{Collection, DBResult, Database};
+use crate::env_var_or;
+use mongodb::bson::{doc, DateTime};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How long a login has to come back from Google before its state expires.
+pub static OAUTH_STATE_TTL: Lazy<Duration> =
+  Lazy::new(|| Duration::from_secs(env_var_or("OAUTH_STATE_TTL_SECS", 600)));
+
+/// CSRF token and PKCE verifier of a login in progress, stored in the
+/// database so the callback can land on any instance.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthState {
+  #[serde(rename = "_id")]
+  pub _id: String,
+  /// Secret the login's PKCE challenge was derived from.
+  pub pkce_verifier: String,
+  /// Where to send the user back to, `LOGIN_REDIRECT` if missing.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub login_redirect: Option<String>,
+  pub created_at: DateTime,
+  /// Mongo removes the state after this, `OAUTH_STATE_TTL` after it was
+  /// created. States saved before it was stored count as expired.
+  #[serde(default = "DateTime::now")]
+  pub expires_at: DateTime,
+}
+
+impl OAuthState {
+  pub fn new(csrf_token: &str, pkce_verifier: &str) -> Self {
+    let created_at = DateTime::now();
+    Self {
+      _id: csrf_token.to_string(),
+      pkce_verifier: pkce_verifier.to_string(),
+      login_redirect: None,
+      created_at,
+      expires_at: DateTime::from_millis(
+        created_at.timestamp_millis() + OAUTH_STATE_TTL.as_millis() as i64,
+      ),
+    }
+  }
+
+  fn is_expired(&self) -> bool {
+    self.expires_at <= DateTime::now()
+  }
+}
+
+impl Collection for OAuthState {
+  fn collection_name() -> &'static str {
+    "oauthStates"
+  }
+  fn id(&self) -> &str {
+    &self._id
+  }
+}
+
+pub async fn save_oauth_state(
+  state: &OAuthState,
+  database: &Database,
+) -> DBResult {
+  database.replace(state, None).await
+}
+
+/// Remove and return the state for `csrf_token` so it can't be used twice.
+/// Mongo only purges expired documents every minute so those are checked
+/// here too.
+pub async fn take_oauth_state(
+  csrf_token: &str,
+  database: &Database,
+) -> DBResult<Option<OAuthState>> {
+  let state = database
+    .delete::<OAuthState>(doc! { "_id": csrf_token })
+    .await?;
+  Ok(state.filter(|state| !state.is_expired()))
+}
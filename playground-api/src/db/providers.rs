@@ -0,0 +1,87 @@
+use super::{Collection, DBResult, Database};
+use crate::auth::oauth::Token;
+use mongodb::bson::{doc, to_bson};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// External account a user logged in with, e.g. `google@username`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Provider {
+  #[serde(rename = "_id")]
+  pub _id: String,
+  pub email: String,
+  pub picture: String,
+  pub token: Token,
+}
+
+impl Collection for Provider {
+  fn collection_name() -> &'static str {
+    "providers"
+  }
+  fn id(&self) -> &str {
+    &self._id
+  }
+}
+
+/// Create the provider or refresh its profile and token.
+pub async fn save_provider(
+  provider: &Provider,
+  database: &Database,
+) -> DBResult {
+  database.replace(provider, None).await
+}
+
+/// Replace the token of provider `provider_id`, e.g. after refreshing it.
+pub async fn update_provider_token(
+  provider_id: &str,
+  token: &Token,
+  database: &Database,
+) -> DBResult {
+  database
+    .update::<Provider>(
+      doc! { "token": to_bson(token)? },
+      doc! { "_id": provider_id },
+      None,
+    )
+    .await?;
+  Ok(())
+}
+
+/// Public profile of a linked `Provider`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkedAccount {
+  pub id: String,
+  pub picture: String,
+}
+
+/// Every provider in `provider_ids` fetched in one query.
+pub async fn find_providers(
+  provider_ids: &HashSet<String>,
+  database: &Database,
+) -> DBResult<Vec<Provider>> {
+  database
+    .find_many::<Provider>(
+      doc! { "_id": { "$in": provider_ids.iter().collect::<Vec<_>>() } },
+      None,
+    )
+    .await
+}
+
+/// Profiles of every provider in `provider_ids`.
+pub async fn find_linked_accounts(
+  provider_ids: &HashSet<String>,
+  database: &Database,
+) -> DBResult<Vec<LinkedAccount>> {
+  let mut accounts = find_providers(provider_ids, database)
+    .await?
+    .into_iter()
+    .map(|provider| LinkedAccount {
+      id: provider._id,
+      picture: provider.picture,
+    })
+    .collect::<Vec<_>>();
+  accounts.sort_by(|a, b| a.id.cmp(&b.id));
+  Ok(accounts)
+}
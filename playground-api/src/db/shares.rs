@@ -0,0 +1,78 @@
+use super::{Collection, DBResult, Database};
+use mongodb::bson::{doc, DateTime};
+use oauth2::CsrfToken;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Folder shared through a guest link, deleting it revokes the link.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Share {
+  /// Random so it can't be guessed, since share links are made of it.
+  #[serde(rename = "_id")]
+  pub _id: String,
+  pub user_id: String,
+  pub folder_id: String,
+  pub created_at: DateTime,
+  /// Mongo removes the share after this, shares without it last until
+  /// revoked.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub expires_at: Option<DateTime>,
+}
+
+impl Share {
+  pub fn new(user_id: &str, folder_id: &str, expiry: Option<Duration>) -> Self {
+    let created_at = DateTime::now();
+    Self {
+      _id: CsrfToken::new_random().secret().to_string(),
+      user_id: user_id.to_string(),
+      folder_id: folder_id.to_string(),
+      created_at,
+      expires_at: expiry.map(|expiry| {
+        DateTime::from_millis(
+          created_at.timestamp_millis() + expiry.as_millis() as i64,
+        )
+      }),
+    }
+  }
+
+  fn is_expired(&self) -> bool {
+    self
+      .expires_at
+      .is_some_and(|expires_at| expires_at <= DateTime::now())
+  }
+}
+
+impl Collection for Share {
+  fn collection_name() -> &'static str {
+    "shares"
+  }
+  fn id(&self) -> &str {
+    &self._id
+  }
+}
+
+pub async fn save_share(share: &Share, database: &Database) -> DBResult {
+  database.replace(share, None).await
+}
+
+/// Share `share_id` unless it was revoked. Mongo only purges expired documents
+/// every minute so those are checked here too.
+pub async fn find_share(
+  share_id: &str,
+  database: &Database,
+) -> DBResult<Option<Share>> {
+  let share = database.find_by_id::<Share>(share_id).await?;
+  Ok(share.filter(|share| !share.is_expired()))
+}
+
+/// Delete `user_id`'s share `share_id`, returning it if it existed.
+pub async fn revoke_share(
+  user_id: &str,
+  share_id: &str,
+  database: &Database,
+) -> DBResult<Option<Share>> {
+  database
+    .delete::<Share>(doc! { "_id": share_id, "userId": user_id })
+    .await
+}
@@ -1,7 +1,11 @@
-use super::{files::File, Collection, DBResult, Database};
+use super::{files::File, providers::Provider, Collection, DBResult, Database};
 use crate::auth::jwt;
-use mongodb::bson::doc;
+use mongodb::{
+  bson::doc,
+  options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -10,6 +14,10 @@ pub struct User {
   pub _id: String,
   pub name: String,
   pub picture: String,
+  /// Ids of every `Provider` linked to this user, including the one it was
+  /// created with.
+  #[serde(default)]
+  pub linked_accounts: HashSet<String>,
 }
 
 impl User {
@@ -18,6 +26,7 @@ impl User {
       _id: id.to_string(),
       name: name.to_string(),
       picture: picture.to_string(),
+      linked_accounts: HashSet::from([id.to_string()]),
     }
   }
 }
@@ -37,6 +46,99 @@ pub async fn save_user(user: &User, database: &Database) -> DBResult<String> {
     database
       .create(&File::new_root_folder(user._id.clone())?, None)
       .await?;
+  } else {
+    // Users created before providers were tracked have no linked accounts.
+    add_provider_to_user(&user._id, &user._id, database).await?;
   }
   Ok(token)
 }
+
+/// Link `provider_id` to the user, returns the updated user if it exists.
+pub async fn add_provider_to_user(
+  user_id: &str,
+  provider_id: &str,
+  database: &Database,
+) -> DBResult<Option<User>> {
+  let options = FindOneAndUpdateOptions::builder()
+    .return_document(ReturnDocument::After)
+    .build();
+  Ok(
+    database
+      .collection::<User>()
+      .find_one_and_update(
+        doc! { "_id": user_id },
+        doc! { "$addToSet": { "linkedAccounts": provider_id } },
+        options,
+      )
+      .await?,
+  )
+}
+
+/// Whether `provider_id` belongs to a user other than `user_id`, either as
+/// the account they were created with or as a linked one.
+pub async fn is_linked_to_other_user(
+  user_id: &str,
+  provider_id: &str,
+  database: &Database,
+) -> DBResult<bool> {
+  let owners = database
+    .find_many::<User>(
+      doc! {
+        "_id": { "$ne": user_id },
+        "$or": [{ "_id": provider_id }, { "linkedAccounts": provider_id }],
+      },
+      FindOptions::builder().limit(1).build(),
+    )
+    .await?;
+  Ok(!owners.is_empty())
+}
+
+/// Unlink `provider_id` from the user and delete its `Provider`, returns the
+/// updated user or `None` if the provider wasn't linked to it.
+pub async fn remove_provider_from_user(
+  user_id: &str,
+  provider_id: &str,
+  database: &Database,
+) -> DBResult<Option<User>> {
+  let options = FindOneAndUpdateOptions::builder()
+    .return_document(ReturnDocument::After)
+    .build();
+  let user = database
+    .collection::<User>()
+    .find_one_and_update(
+      doc! { "_id": user_id, "linkedAccounts": provider_id },
+      doc! { "$pull": { "linkedAccounts": provider_id } },
+      options,
+    )
+    .await?;
+  if user.is_some() {
+    database
+      .delete::<Provider>(doc! { "_id": provider_id })
+      .await?;
+  }
+  Ok(user)
+}
+
+/// Delete the user along with their files and providers.
+///
+/// The user is removed after its files and providers and the root folder
+/// last, so an interrupted deletion can be retried and logging back in
+/// before that still finds a usable root folder.
+pub async fn delete_user(user: &User, database: &Database) -> DBResult {
+  // Users created before providers were tracked have no linked accounts,
+  // their primary provider shares their id
+  let mut provider_ids = user.linked_accounts.iter().collect::<HashSet<_>>();
+  provider_ids.insert(&user._id);
+  let provider_ids = provider_ids.into_iter().collect::<Vec<_>>();
+  database
+    .delete_many::<File>(
+      doc! { "userId": &user._id, "_id": { "$ne": &user._id } },
+    )
+    .await?;
+  database
+    .delete_many::<Provider>(doc! { "_id": { "$in": provider_ids } })
+    .await?;
+  database.delete::<User>(doc! { "_id": &user._id }).await?;
+  database.delete::<File>(doc! { "_id": &user._id }).await?;
+  Ok(())
+}
@@ -1,88 +1,344 @@
 use crate::{
   api::{APIError, APIResult},
-  env_var,
+  console::Colorize,
+  env_var_or, log,
 };
 use axum::{
+  body::StreamBody,
   http::{HeaderMap, HeaderValue},
-  response::IntoResponse,
+  response::{IntoResponse, Response},
 };
 use format as f;
 use once_cell::sync::Lazy;
-use reqwest::StatusCode;
-use serde::de::DeserializeOwned;
+use reqwest::{Method, StatusCode, Url};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+  collections::hash_map::{DefaultHasher, RandomState},
+  hash::{BuildHasher, Hasher},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::{Duration, Instant},
+};
 
 fn mebibytes(var_name: &str, default: usize) -> usize {
-  env_var(var_name)
-    .map(|n| n.parse::<usize>().unwrap_or(default))
-    .unwrap_or(default)
-    * 1024
-    * 1024
+  env_var_or(var_name, default) * 1024 * 1024
 }
 
 static CONTENT_LENGTH: Lazy<usize> =
   Lazy::new(|| mebibytes("VIDEO_CONTENT_LENGTH", 10));
 static FIRST_CONTENT_LENGTH: Lazy<usize> =
   Lazy::new(|| mebibytes("VIDEO_FIRST_CONTENT_LENGTH", 16));
+/// Largest video chunk clients can ask for.
+static MAX_CONTENT_LENGTH: Lazy<usize> =
+  Lazy::new(|| mebibytes("VIDEO_MAX_CONTENT_LENGTH", 32));
 
-pub fn get_range(headers: HeaderMap) -> (usize, usize) {
-  let raw_range = match headers.get("Range") {
-    Some(header) => header
-      .to_str()
-      .unwrap_or_default()
-      .get(6..)
-      .unwrap_or_default()
-      .split('-')
-      .map(|v| v.parse::<usize>().ok())
-      .collect::<Vec<_>>(),
-    None => vec![Some(0), Some(*FIRST_CONTENT_LENGTH)],
-  };
+/// How long clients may cache proxied images.
+static IMAGE_MAX_AGE: Lazy<u64> =
+  Lazy::new(|| env_var_or("THUMBNAIL_CACHE_SECS", 86400));
+
+/// Maximum time to wait on upstream requests.
+pub static REQUEST_TIMEOUT: Lazy<Duration> =
+  Lazy::new(|| Duration::from_secs(env_var_or("UPSTREAM_TIMEOUT_SECS", 30)));
+
+/// Client whose requests, body included, time out after `REQUEST_TIMEOUT`.
+pub fn client() -> reqwest::Client {
+  reqwest::Client::builder()
+    .timeout(*REQUEST_TIMEOUT)
+    .build()
+    .unwrap_or_default()
+}
 
-  let start = raw_range
-    .get(0)
-    .copied()
+/// Client for long lived streams, only connecting is bounded here so callers
+/// time the wait for the response with `send_with_timeout`.
+fn stream_client() -> reqwest::Client {
+  reqwest::Client::builder()
+    .connect_timeout(*REQUEST_TIMEOUT)
+    .build()
     .unwrap_or_default()
-    .unwrap_or_default();
+}
+
+/// Query params holding credentials, their values are never logged.
+const SECRET_PARAMS: [&str; 4] =
+  ["key", "access_token", "refresh_token", "client_secret"];
 
-  let end = raw_range.get(1).copied().unwrap_or_default().unwrap_or(
-    start
-      + if start == 0 {
-        *FIRST_CONTENT_LENGTH
+/// `url` with the values of `SECRET_PARAMS` replaced so it can be logged.
+pub fn redact_url(url: &Url) -> String {
+  if url.query().is_none() {
+    return url.to_string();
+  }
+  let mut url = url.clone();
+  let pairs = url
+    .query_pairs()
+    .map(|(name, value)| {
+      let value = if SECRET_PARAMS.contains(&&*name) {
+        "REDACTED".to_string()
       } else {
-        *CONTENT_LENGTH
-      },
-  );
+        value.into_owned()
+      };
+      (name.into_owned(), value)
+    })
+    .collect::<Vec<_>>();
+  url.query_pairs_mut().clear().extend_pairs(pairs);
+  url.to_string()
+}
+
+/// Log line for an upstream request that ended with `outcome` after
+/// `elapsed`, with credentials redacted from the url.
+pub fn upstream_log_line(
+  method: &Method,
+  url: &Url,
+  outcome: Result<StatusCode, &reqwest::Error>,
+  elapsed: Duration,
+) -> String {
+  let redacted = redact_url(url);
+  let elapsed = elapsed.as_millis();
+  match outcome {
+    Ok(status) => f!("{method} {redacted} {status} in {elapsed}ms"),
+    Err(error) => {
+      let error = redact_error(error);
+      f!("{method} {redacted} failed in {elapsed}ms: {error}")
+    }
+  }
+}
+
+/// `error` as a string without the credentials of the url it failed on.
+pub fn redact_error(error: &reqwest::Error) -> String {
+  let message = error.to_string();
+  match error.url() {
+    // Errors mention the url they failed on, credentials included.
+    Some(url) => message.replace(url.as_str(), &redact_url(url)),
+    None => message,
+  }
+}
+
+/// Send `request` logging its method, url, status and latency.
+async fn send_logged(
+  request: reqwest::RequestBuilder,
+) -> reqwest::Result<reqwest::Response> {
+  // Requests with streamed bodies can't be cloned so they're not logged.
+  let target = request
+    .try_clone()
+    .and_then(|request| request.build().ok())
+    .map(|request| (request.method().clone(), request.url().clone()));
+  let started_at = Instant::now();
+  let result = request.send().await;
+  if let Some((method, url)) = target {
+    let outcome = result.as_ref().map(reqwest::Response::status);
+    let line = upstream_log_line(&method, &url, outcome, started_at.elapsed());
+    log!(info@"{line}");
+  }
+  result
+}
 
-  (start, end)
+/// Send `request` failing with `APIError::Timeout` if the response headers
+/// don't arrive within `timeout`. The body is not bounded.
+pub async fn send_with_timeout(
+  request: reqwest::RequestBuilder,
+  timeout: Duration,
+) -> APIResult<reqwest::Response> {
+  tokio::time::timeout(timeout, send_logged(request))
+    .await
+    .map_err(|_| APIError::Timeout(f!("No upstream response in {timeout:?}")))?
+    .map_err(APIError::from)
 }
 
-pub enum JsonResult<T: DeserializeOwned> {
-  Typed(T),
-  Untyped(serde_json::Value),
+/// How often and for how long idempotent upstream requests are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+  /// Attempts including the first one.
+  pub max_attempts: u32,
+  /// Delay before the first retry, doubled on every one after it.
+  pub base_delay: Duration,
+  /// No retry is started once this much time has passed since the first
+  /// attempt.
+  pub max_elapsed: Duration,
 }
 
-pub async fn json_response<T: serde::de::DeserializeOwned>(
+pub static RETRY_POLICY: Lazy<RetryPolicy> = Lazy::new(|| RetryPolicy {
+  max_attempts: env_var_or("UPSTREAM_RETRY_ATTEMPTS", 3).max(1),
+  base_delay: Duration::from_millis(env_var_or("UPSTREAM_RETRY_BASE_MS", 200)),
+  max_elapsed: Duration::from_secs(env_var_or("UPSTREAM_RETRY_MAX_SECS", 10)),
+});
+
+impl RetryPolicy {
+  /// Exponential delay before retry number `retry`, starting at 0, with half
+  /// of it randomized so clients failing together don't retry together.
+  fn delay(&self, retry: u32) -> Duration {
+    let delay = self.base_delay.saturating_mul(2u32.saturating_pow(retry));
+    let random = RandomState::new().build_hasher().finish();
+    delay / 2 + (delay / 2).mul_f64((random % 1000) as f64 / 1000.0)
+  }
+}
+
+/// Rate limits and server errors that may go away by themselves.
+fn is_transient(status: StatusCode) -> bool {
+  status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Send idempotent `request` retrying transient failures with exponential
+/// backoff according to `policy`. Other failures are returned right away,
+/// as is the last one when the policy runs out.
+pub async fn send_with_retry(
+  request: reqwest::RequestBuilder,
+  policy: &RetryPolicy,
+) -> APIResult<reqwest::Response> {
+  let start = Instant::now();
+  let mut retry = 0;
+  loop {
+    // Requests with streamed bodies can't be cloned so they're sent once.
+    let Some(attempt) = request.try_clone() else {
+      return Ok(send_logged(request).await?);
+    };
+    let result = send_logged(attempt).await;
+    let transient = match &result {
+      Ok(response) => is_transient(response.status()),
+      Err(error) => error.is_timeout() || error.is_connect(),
+    };
+    let delay = policy.delay(retry);
+    if !transient
+      || retry + 1 >= policy.max_attempts
+      || start.elapsed() + delay > policy.max_elapsed
+    {
+      return Ok(result?);
+    }
+    retry += 1;
+    tokio::time::sleep(delay).await;
+  }
+}
+
+/// Round-robin pool of clients so connections are reused without funneling
+/// every request through one client, Google eventually starts blocking those.
+#[derive(Debug, Clone)]
+pub struct ClientPool {
+  clients: Arc<Vec<reqwest::Client>>,
+  next: Arc<AtomicUsize>,
+}
+
+impl ClientPool {
+  pub fn new(size: usize) -> Self {
+    Self {
+      clients: Arc::new((0..size.max(1)).map(|_| stream_client()).collect()),
+      next: Arc::new(AtomicUsize::new(0)),
+    }
+  }
+
+  pub fn get(&self) -> &reqwest::Client {
+    let index = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+    &self.clients[index]
+  }
+}
+
+/// Bytes per video chunk a client asked for capped to `MAX_CONTENT_LENGTH`,
+/// `None` when it didn't ask so the defaults are used.
+pub fn chunk_size(requested: Option<usize>) -> APIResult<Option<usize>> {
+  match requested {
+    Some(0) => Err(APIError::BadRequest(
+      "Chunk size must be greater than 0".into(),
+    )),
+    requested => Ok(requested.map(|size| size.min(*MAX_CONTENT_LENGTH))),
+  }
+}
+
+/// Parse the `Range` header into an inclusive `(start, end)` byte range,
+/// defaulting to the first chunk when missing. Open ended ranges are capped to
+/// one chunk, of `chunk_size` if given, and `end` is clamped to
+/// `content_length` when it's known.
+pub fn get_range(
+  headers: &HeaderMap,
+  content_length: Option<usize>,
+  chunk_size: Option<usize>,
+) -> APIResult<(usize, usize)> {
+  let Some(header) = headers.get("Range") else {
+    let chunk = chunk_size.unwrap_or(*FIRST_CONTENT_LENGTH);
+    return clamp_range(0, chunk, content_length);
+  };
+  let invalid = || APIError::BadRequest(f!("Invalid Range header {header:?}"));
+  if header.as_bytes().contains(&b',') {
+    return Err(APIError::RangeNotSatisfiable(f!(
+      "Multiple ranges are not supported, request one range at a time instead of {header:?}"
+    )));
+  }
+  let (start, end) = header
+    .to_str()
+    .ok()
+    .and_then(|range| range.trim().strip_prefix("bytes="))
+    .and_then(|range| range.split_once('-'))
+    .ok_or_else(invalid)?;
+  let parse =
+    |value: &str| value.trim().parse::<usize>().map_err(|_| invalid());
+
+  let (start, end) = match (start.trim(), end.trim()) {
+    ("", "") => return Err(invalid()),
+    // Suffix range: the last `suffix` bytes
+    ("", suffix) => {
+      let length = content_length.ok_or_else(invalid)?;
+      (
+        length.saturating_sub(parse(suffix)?),
+        length.saturating_sub(1),
+      )
+    }
+    (start, "") => open_ended_range(parse(start)?, chunk_size),
+    (start, end) => (parse(start)?, parse(end)?),
+  };
+
+  if end < start {
+    return Err(invalid());
+  }
+  clamp_range(start, end, content_length)
+}
+
+/// Window streamed for `bytes={start}-`, the first one is bigger so playback
+/// can start without another request.
+fn open_ended_range(start: usize, chunk_size: Option<usize>) -> (usize, usize) {
+  let chunk = chunk_size.unwrap_or(if start == 0 {
+    *FIRST_CONTENT_LENGTH
+  } else {
+    *CONTENT_LENGTH
+  });
+  (start, start.saturating_add(chunk))
+}
+
+fn clamp_range(
+  start: usize,
+  end: usize,
+  content_length: Option<usize>,
+) -> APIResult<(usize, usize)> {
+  match content_length {
+    Some(length) if start >= length => Err(APIError::RangeNotSatisfiable(f!(
+      "Range start {start} is past the content length {length}"
+    ))),
+    Some(length) => Ok((start, end.min(length - 1))),
+    None => Ok((start, end)),
+  }
+}
+
+/// Deserialize the JSON body of an upstream `response`. Bodies that don't
+/// match `T` are only logged, clients get `APIError::UnexpectedResponse`.
+pub async fn json_response<T: DeserializeOwned>(
   response: reqwest::Response,
-) -> APIResult<JsonResult<T>> {
+) -> APIResult<T> {
   let status_code = response.status();
+  // The query is left out since it may carry API keys
+  let path = response.url().path().to_string();
 
   if status_code.is_client_error() || status_code.is_server_error() {
-    return Err(APIError::StatusCode(
-      status_code,
-      response.json::<serde_json::Value>().await.ok(),
-    ));
+    // Upstream error bodies are only logged, they're not meant for clients
+    let url = redact_url(response.url());
+    let body = response.text().await.unwrap_or_default();
+    log!(err@"Upstream {url} answered {status_code}: {body}");
+    return Err(APIError::StatusCode(status_code));
   }
 
   let response_text = response
     .text()
     .await
     .map_err(|_| APIError::Internal("Response has no body".into()))?;
-  let typed = serde_json::from_str::<T>(&response_text);
-  match typed {
-    Ok(file) => Ok(JsonResult::Typed(file)),
-    Err(_) => Ok(JsonResult::Untyped(serde_json::from_str::<
-      serde_json::Value,
-    >(&response_text)?)),
-  }
+  serde_json::from_str::<T>(&response_text).map_err(|error| {
+    log!(err@"Unexpected response from {path:?}, {error}: {response_text}");
+    APIError::UnexpectedResponse
+  })
 }
 
 pub fn extract_header(
@@ -97,33 +353,226 @@ pub fn extract_header(
   )
 }
 
-/// Download video and stream on demand.
+/// Stream video from upstream on demand without buffering it.
+///
+/// `If-Range` is forwarded so upstream decides in the same request. When the
+/// client's copy is stale upstream answers with the whole video, that answer
+/// is dropped and only the first window is fetched and sent with a `200`.
+/// Ranges are clamped to `content_length` when it's known.
 pub async fn stream_video(
+  request_client: &reqwest::Client,
   video_url: &str,
   headers: HeaderMap,
-) -> APIResult<impl IntoResponse> {
-  let (range_start, range_end) = get_range(headers);
-  let byte_range = f!("{range_start}-{range_end}");
+  content_length: Option<usize>,
+  chunk_size: Option<usize>,
+) -> APIResult<Response> {
+  let (range_start, range_end) =
+    get_range(&headers, content_length, chunk_size)?;
+  let mut request = request_client
+    .get(video_url)
+    .header("Range", f!("bytes={range_start}-{range_end}"));
+  if let Some(if_range) = headers.get("If-Range") {
+    request = request.header("If-Range", if_range.clone());
+  }
+  let response = send_with_timeout(request, *REQUEST_TIMEOUT).await?;
+  let response = video_status(response)?;
+  if response.status() == StatusCode::OK && headers.contains_key("If-Range") {
+    return stream_first_window(
+      request_client,
+      video_url,
+      content_length,
+      chunk_size,
+    )
+    .await;
+  }
+  video_response(response.status(), response)
+}
 
-  // Need to create a new client on each request or else google
-  // eventually starts blocking the requests
-  let response = reqwest::Client::new()
+/// Answer a stale `If-Range` with a `200` holding only the start of the video
+/// instead of relaying all of it.
+async fn stream_first_window(
+  request_client: &reqwest::Client,
+  video_url: &str,
+  content_length: Option<usize>,
+  chunk_size: Option<usize>,
+) -> APIResult<Response> {
+  let (start, end) = open_ended_range(0, chunk_size);
+  let (start, end) = clamp_range(start, end, content_length)?;
+  let request = request_client
     .get(video_url)
-    .header("Range", f!("bytes={byte_range}"))
-    .send()
-    .await?
-    .error_for_status()?;
+    .header("Range", f!("bytes={start}-{end}"));
+  let response = send_with_timeout(request, *REQUEST_TIMEOUT).await?;
+  let response = video_status(response)?;
+  video_response(StatusCode::OK, response)
+}
+
+/// Map Drive's answers for missing or private videos to API errors.
+fn video_status(response: reqwest::Response) -> APIResult<reqwest::Response> {
+  match response.status() {
+    StatusCode::NOT_FOUND => {
+      return Err(APIError::NotFound("Video not found".into()))
+    }
+    StatusCode::FORBIDDEN => {
+      return Err(APIError::UnauthorizedMessage(
+        "Video is not accessible".into(),
+      ))
+    }
+    _ => {}
+  }
+  Ok(response.error_for_status()?)
+}
+
+/// Relay the whole video at `video_url`, without ranges since it's meant to
+/// be saved rather than played.
+pub async fn download_video(
+  request_client: &reqwest::Client,
+  video_url: &str,
+) -> APIResult<Response> {
+  let response =
+    send_with_timeout(request_client.get(video_url), *REQUEST_TIMEOUT).await?;
+  let response = video_status(response)?;
+  let upstream = response.headers();
+  let mut headers = HeaderMap::new();
+  headers.insert("Content-Type", extract_header(upstream, "Content-Type")?);
+  if let Some(content_length) = upstream.get("Content-Length") {
+    headers.insert("Content-Length", content_length.clone());
+  }
+
+  let body = StreamBody::new(response.bytes_stream());
+  Ok((StatusCode::OK, headers, body).into_response())
+}
 
-  let headers = response.headers();
-  let content_range = extract_header(headers, "Content-Range")?;
-  let content_type = extract_header(headers, "Content-Type")?;
+/// `Content-Disposition` saving the response as `file_name`. Characters that
+/// can't appear in a quoted header value are replaced with `_`.
+pub fn attachment(file_name: &str) -> APIResult<HeaderValue> {
+  let file_name = file_name
+    .chars()
+    .map(|c| match c {
+      '"' | '\\' => '_',
+      c if c.is_ascii_graphic() || c == ' ' => c,
+      _ => '_',
+    })
+    .collect::<String>();
+  Ok(f!("attachment; filename=\"{file_name}\"").parse()?)
+}
+
+/// `value` as json with a weak ETag over its body, or `304 Not Modified` if
+/// the client's `If-None-Match` already has it.
+pub fn json_with_etag<T: Serialize>(
+  request_headers: &HeaderMap,
+  value: &T,
+) -> APIResult<Response> {
+  let body = serde_json::to_vec(value)?;
+  let mut hasher = DefaultHasher::new();
+  hasher.write(&body);
+  let etag: HeaderValue = f!("W/\"{:016x}\"", hasher.finish()).parse()?;
+  let mut headers = HeaderMap::new();
+  if request_headers
+    .get_all("If-None-Match")
+    .iter()
+    .any(|if_none_match| if_none_match_matches(if_none_match, &etag))
+  {
+    headers.insert("ETag", etag);
+    return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+  }
+  headers.insert("Content-Type", "application/json".parse()?);
+  headers.insert("ETag", etag);
+  Ok((StatusCode::OK, headers, body).into_response())
+}
 
-  let body = response.bytes().await?;
+/// `If-None-Match` uses a weak comparison, so `W/` prefixes are ignored.
+fn if_none_match_matches(
+  if_none_match: &HeaderValue,
+  etag: &HeaderValue,
+) -> bool {
+  let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str())
+  else {
+    return false;
+  };
+  let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+  if_none_match
+    .split(',')
+    .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
 
+/// Headers a `GET` for the whole video would answer with, without its body.
+pub fn video_head_response(
+  content_length: Option<u64>,
+  content_type: &str,
+) -> APIResult<Response> {
   let mut headers = HeaderMap::new();
   headers.insert("Accept-Ranges", "bytes".parse()?);
-  headers.insert("Content-Range", content_range);
+  headers.insert("Content-Type", content_type.parse()?);
+  if let Some(content_length) = content_length {
+    headers.insert("Content-Length", content_length.into());
+  }
+  Ok((StatusCode::OK, headers).into_response())
+}
+
+fn video_response(
+  status: StatusCode,
+  response: reqwest::Response,
+) -> APIResult<Response> {
+  let upstream = response.headers();
+  let mut headers = HeaderMap::new();
+  headers.insert("Accept-Ranges", "bytes".parse()?);
+  headers.insert("Content-Type", extract_header(upstream, "Content-Type")?);
+  if status == StatusCode::PARTIAL_CONTENT {
+    headers.insert("Content-Range", extract_header(upstream, "Content-Range")?);
+  } else if let Some(content_length) = upstream.get("Content-Length") {
+    headers.insert("Content-Length", content_length.clone());
+  }
+  for validator in ["ETag", "Last-Modified"] {
+    if let Some(value) = upstream.get(validator) {
+      headers.insert(validator, value.clone());
+    }
+  }
+
+  let body = StreamBody::new(response.bytes_stream());
+  Ok((status, headers, body).into_response())
+}
+
+/// Fetch the image at `image_url` and relay it with caching headers.
+///
+/// Drive answers missing or private thumbnails with an html page instead of an
+/// error, so anything that isn't an image is reported as not found.
+pub async fn proxy_image(
+  request_client: &reqwest::Client,
+  image_url: &str,
+) -> APIResult<Response> {
+  let response =
+    send_with_timeout(request_client.get(image_url), *REQUEST_TIMEOUT).await?;
+  match response.status() {
+    StatusCode::NOT_FOUND => {
+      return Err(APIError::NotFound("Image not found".into()))
+    }
+    StatusCode::FORBIDDEN => {
+      return Err(APIError::UnauthorizedMessage(
+        "Image is not accessible".into(),
+      ))
+    }
+    _ => {}
+  }
+  let response = response.error_for_status()?;
+  let content_type = response
+    .headers()
+    .get("Content-Type")
+    .filter(|value| value.as_bytes().starts_with(b"image/"))
+    .cloned()
+    .ok_or_else(|| APIError::NotFound("Image not found".into()))?;
+
+  let mut headers = HeaderMap::new();
   headers.insert("Content-Type", content_type);
+  headers.insert(
+    "Cache-Control",
+    f!("public, max-age={}", *IMAGE_MAX_AGE).parse()?,
+  );
+  for validator in ["ETag", "Last-Modified"] {
+    if let Some(value) = response.headers().get(validator) {
+      headers.insert(validator, value.clone());
+    }
+  }
 
-  Ok((StatusCode::PARTIAL_CONTENT, headers, body))
+  let body = StreamBody::new(response.bytes_stream());
+  Ok((StatusCode::OK, headers, body).into_response())
 }
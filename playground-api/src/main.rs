@@ -1,102 +1,171 @@
 mod api;
+mod archive;
 mod auth;
+mod cache;
 mod console;
+mod cors;
 mod db;
 mod http;
+mod metrics;
+mod rate_limit;
 mod routes;
 mod string;
 mod tests;
 mod websockets;
 
-use auth::{google::GoogleState, session::Session};
+use api::APIResult;
+use auth::{
+  google::GoogleState,
+  session::{cleared_session_cookie, Session, SessionToken},
+};
 use axum::{
-  extract::FromRef,
-  headers::{authorization::Bearer, Authorization},
-  http::HeaderValue,
+  extract::{FromRef, State},
+  http::{header, HeaderValue},
+  response::{IntoResponse, Response},
   routing::{delete, get},
-  Router, TypedHeader,
+  Json, Router,
 };
 use console::Colorize;
 use db::{files::system::FileSystem, Database};
-use format as f;
 use reqwest::StatusCode;
 use routes::files::FilesRouterState;
-use std::net::SocketAddr;
+use serde::Serialize;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use thiserror::Error;
 use tokio::signal;
-use tower_http::cors::CorsLayer;
-use websockets::WebSocketState;
+use websockets::{shutdown::DRAIN_TIMEOUT, WebSocketState};
 
 #[tokio::main]
 async fn main() {
+  AppState::validate_env().unwrap_or_exit("Could not start server");
   let database = Database::new("playground")
     .await
     .unwrap_or_exit("Could not initialize database");
   database.load_sessions().await;
+  database.sync_sessions();
   let state =
     AppState::new(&database).unwrap_or_exit("Could not initialize app state");
+  let websockets_state = state.websockets.clone();
+  websockets::file_watcher::spawn(&database, websockets_state.clone()).await;
   let auth_routes =
     auth::api().unwrap_or_exit("Could not initialize auth routes.");
   let files_api =
     routes::files::api().unwrap_or_exit("Could not initialize files API.");
   let websockets_api = websockets::api();
 
-  let cors = CorsLayer::new()
-    .allow_methods(tower_http::cors::Any)
-    .allow_headers(tower_http::cors::Any);
-  let cors = if let Ok(allowed_origins) = env_var("ALLOWED_ORIGINS") {
-    let origins = allowed_origins
-      .split(',')
-      .map(|origin| origin.parse::<HeaderValue>())
-      .collect::<Result<Vec<_>, _>>()
-      .unwrap_or_exit(f!(
-        "Could not parse allowed origins {allowed_origins:?}"
-      ));
-    cors.allow_origin(origins)
-  } else {
-    cors
-  };
+  let cors = cors::layer().unwrap_or_exit("Could not initialize CORS");
 
-  let app = Router::new()
-    .route("/logout", delete(logout))
-    .route("/ping", get(ping))
-    .nest("/auth", auth_routes)
-    .nest("/api/users", routes::users::api())
-    .nest("/api/files", files_api)
-    .nest("/ws", websockets_api)
-    .with_state(state)
-    .layer(cors);
+  let app = metrics::tracked(
+    Router::new()
+      .route("/logout", delete(logout))
+      .route("/logout/all", delete(logout_all))
+      .route("/ping", get(ping))
+      .route("/health", get(health))
+      .route("/metrics", get(metrics::metrics)),
+  )
+  .nest("/auth", auth_routes)
+  .merge(routes::versioned(
+    Router::new()
+      .nest("/users", metrics::tracked(routes::users::api()))
+      .nest("/files", metrics::tracked(files_api)),
+  ))
+  .nest("/ws", metrics::tracked(websockets_api))
+  .with_state(state)
+  .layer(cors);
+  let app = routes::with_prefix(app, &routes::API_PREFIX);
 
   let socket_address: SocketAddr = env_var("SOCKET_ADDRESS")
     .unwrap_or_exit("Socket address is missing")
     .parse()
     .unwrap_or_exit("Failed to parse socket address");
 
-  log!(success@"listening on {socket_address}");
+  log!(success@"listening on {socket_address}{}", *routes::API_PREFIX);
 
   axum::Server::bind(&socket_address)
     .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-    .with_graceful_shutdown(shutdown_signal(&database))
+    .with_graceful_shutdown(shutdown_signal(&database, &websockets_state))
     .await
     .unwrap_or_exit("Failed to start server");
 }
 
-async fn logout(
-  TypedHeader(bearer): TypedHeader<Authorization<Bearer>>,
-) -> StatusCode {
-  Session::invalidate(bearer.token()).await;
-  StatusCode::NO_CONTENT
+async fn logout(SessionToken(token): SessionToken) -> APIResult<Response> {
+  Session::invalidate(&token).await;
+  logged_out()
+}
+
+async fn logout_all(session: Session) -> APIResult<Response> {
+  Session::invalidate_all(&session.user_id).await;
+  logged_out()
+}
+
+/// Empty response clearing the session cookie, if the client had one.
+fn logged_out() -> APIResult<Response> {
+  let cookie = HeaderValue::from_str(&cleared_session_cookie())?;
+  Ok((StatusCode::NO_CONTENT, [(header::SET_COOKIE, cookie)]).into_response())
 }
 
 async fn ping<'a>() -> &'a str {
   "PONG"
 }
 
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+  status: &'static str,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  failing: Vec<&'static str>,
+}
+
+async fn health(
+  State(database): State<Database>,
+) -> (StatusCode, Json<HealthReport>) {
+  let mut failing = Vec::new();
+  match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, database.ping()).await {
+    Ok(Ok(())) => {}
+    Ok(Err(error)) => {
+      log!(err@"Health check failed, database unreachable: {error}");
+      failing.push("database");
+    }
+    Err(_) => {
+      log!(err@"Health check failed, database ping timed out");
+      failing.push("database");
+    }
+  }
+
+  if failing.is_empty() {
+    (
+      StatusCode::OK,
+      Json(HealthReport {
+        status: "ok",
+        failing,
+      }),
+    )
+  } else {
+    (
+      StatusCode::SERVICE_UNAVAILABLE,
+      Json(HealthReport {
+        status: "unavailable",
+        failing,
+      }),
+    )
+  }
+}
+
 pub fn env_var(var_name: &str) -> AppResult<String> {
   std::env::var(var_name).map_err(|_| AppError::Env(var_name.to_string()))
 }
 
-async fn shutdown_signal(database: &Database) {
+/// Parse env var or fall back to `default` if it's missing or invalid.
+pub fn env_var_or<T: std::str::FromStr>(var_name: &str, default: T) -> T {
+  env_var(var_name)
+    .ok()
+    .and_then(|value| value.parse::<T>().ok())
+    .unwrap_or(default)
+}
+
+async fn shutdown_signal(database: &Database, sockets: &WebSocketState) {
   let ctrl_c = async {
     signal::ctrl_c()
       .await
@@ -120,26 +189,41 @@ async fn shutdown_signal(database: &Database) {
   }
 
   log!(info@"Signal received, starting graceful shutdown");
+  let connections = sockets.connection_count();
+  if !sockets.shutdown(*DRAIN_TIMEOUT).await {
+    let remaining = sockets.connection_count();
+    log!(err@"Timed out closing websockets, {remaining} of {connections} still open");
+  }
   database.save_sessions().await;
   log!(success@"Graceful shutdown done!");
 }
 
+/// Process exit code for errors the app can't recover from.
+pub const FAILURE_EXIT_CODE: i32 = 1;
+
 trait GracefulExit<T> {
-  fn unwrap_or_exit(self, msg: impl std::fmt::Display) -> T;
+  /// Log `msg` along with the error and return the exit code to use.
+  fn unwrap_or_exit_code(self, msg: impl std::fmt::Display) -> Result<T, i32>;
+
+  fn unwrap_or_exit(self, msg: impl std::fmt::Display) -> T
+  where
+    Self: Sized,
+  {
+    self
+      .unwrap_or_exit_code(msg)
+      .unwrap_or_else(|code| std::process::exit(code))
+  }
 }
 
 impl<T, E> GracefulExit<T> for Result<T, E>
 where
   E: std::fmt::Display,
 {
-  fn unwrap_or_exit(self, msg: impl std::fmt::Display) -> T {
-    match self {
-      Ok(t) => t,
-      Err(e) => {
-        log!("{msg}: {e}");
-        std::process::exit(0)
-      }
-    }
+  fn unwrap_or_exit_code(self, msg: impl std::fmt::Display) -> Result<T, i32> {
+    self.map_err(|e| {
+      log!(err@"{msg}: {e}");
+      FAILURE_EXIT_CODE
+    })
   }
 }
 
@@ -152,14 +236,36 @@ pub struct AppState {
   file_system: FileSystem,
 }
 
+/// Env vars the server can't run without, some are only read on first use.
+pub const REQUIRED_ENV_VARS: [&str; 8] = [
+  "SOCKET_ADDRESS",
+  "LOGIN_REDIRECT",
+  "JWT_SECRET",
+  "MONGODB_URI",
+  "GOOGLE_API_KEY",
+  "GOOGLE_CLIENT_ID",
+  "GOOGLE_CLIENT_SECRET",
+  "GOOGLE_REDIRECT_URL",
+];
+
 impl AppState {
+  /// Check every required env var up front so all missing ones are reported
+  /// at boot instead of on the first request that needs them.
+  pub fn validate_env() -> AppResult {
+    check_env_vars(&REQUIRED_ENV_VARS)
+  }
+
   fn new(database: &Database) -> AppResult<Self> {
+    let websockets = WebSocketState::new();
     Ok(Self {
       database: database.clone(),
       google: GoogleState::new()?,
-      websockets: WebSocketState::new(),
+      file_system: FileSystem::with_fallback(
+        database,
+        Arc::new(websockets.clone()),
+      ),
+      websockets,
       files_router: FilesRouterState::new(),
-      file_system: FileSystem::from(database),
     })
   }
 }
@@ -194,12 +300,29 @@ impl FromRef<AppState> for FileSystem {
   }
 }
 
+fn check_env_vars(var_names: &[&str]) -> AppResult {
+  let missing = var_names
+    .iter()
+    .filter(|name| env_var(name).map_or(true, |value| value.is_empty()))
+    .map(|name| name.to_string())
+    .collect::<Vec<_>>();
+  if missing.is_empty() {
+    Ok(())
+  } else {
+    Err(AppError::MissingEnv(missing))
+  }
+}
+
 #[derive(Error, Debug)]
 pub enum AppError {
   #[error("Missing env var: {}", .0.err())]
   Env(String),
+  #[error("Missing env vars: {}", .0.join(", ").err())]
+  MissingEnv(Vec<String>),
   #[error("{}", .0.to_string().err())]
   UrlParsing(#[from] oauth2::url::ParseError),
+  #[error("Invalid CORS configuration: {}", .0.err())]
+  Cors(String),
 }
 
 type AppResult<T = ()> = Result<T, AppError>;
@@ -0,0 +1,179 @@
+use crate::websockets::WebSocketState;
+use axum::{
+  extract::{MatchedPath, State},
+  http::{header, HeaderValue, Request},
+  middleware::{self, Next},
+  response::{IntoResponse, Response},
+  Router,
+};
+use format as f;
+use once_cell::sync::Lazy;
+use std::{
+  collections::BTreeMap,
+  fmt::Write,
+  future::Future,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+/// Metrics of the whole app, rendered at `GET /metrics`.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+/// Upper bounds in seconds of the database latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 10] =
+  [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+  /// Handled requests by method, route and status.
+  requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+  /// Query latencies by operation and collection.
+  queries: Mutex<BTreeMap<(&'static str, &'static str), Histogram>>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+  /// Observations per bucket of `LATENCY_BUCKETS`, not cumulative.
+  buckets: [u64; LATENCY_BUCKETS.len()],
+  count: u64,
+  sum: f64,
+}
+
+impl Histogram {
+  fn observe(&mut self, seconds: f64) {
+    if let Some(bucket) =
+      LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound)
+    {
+      self.buckets[bucket] += 1;
+    }
+    self.count += 1;
+    self.sum += seconds;
+  }
+}
+
+impl Metrics {
+  pub fn count_request(&self, method: &str, route: &str, status: u16) {
+    *self
+      .requests
+      .lock()
+      .unwrap()
+      .entry((method.to_string(), route.to_string(), status))
+      .or_default() += 1;
+  }
+
+  pub fn observe_query(
+    &self,
+    operation: &'static str,
+    collection: &'static str,
+    duration: Duration,
+  ) {
+    self
+      .queries
+      .lock()
+      .unwrap()
+      .entry((operation, collection))
+      .or_default()
+      .observe(duration.as_secs_f64());
+  }
+
+  /// Every metric in Prometheus text format.
+  pub fn render(&self, websocket_connections: usize) -> String {
+    let mut text = String::new();
+    text.push_str("# HELP http_requests_total Handled HTTP requests.\n");
+    text.push_str("# TYPE http_requests_total counter\n");
+    for ((method, route, status), count) in self.requests.lock().unwrap().iter()
+    {
+      let _ = writeln!(
+        text,
+        "http_requests_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {count}",
+        escape_label(route)
+      );
+    }
+
+    text.push_str("# HELP websocket_connections Open websocket connections.\n");
+    text.push_str("# TYPE websocket_connections gauge\n");
+    let _ = writeln!(text, "websocket_connections {websocket_connections}");
+
+    text.push_str(
+      "# HELP db_query_duration_seconds Database query latency in seconds.\n",
+    );
+    text.push_str("# TYPE db_query_duration_seconds histogram\n");
+    for ((operation, collection), histogram) in
+      self.queries.lock().unwrap().iter()
+    {
+      let labels = f!("operation=\"{operation}\",collection=\"{collection}\"");
+      let mut cumulative = 0;
+      for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+        cumulative += count;
+        let _ = writeln!(
+          text,
+          "db_query_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+        );
+      }
+      let Histogram { count, sum, .. } = histogram;
+      let _ = writeln!(
+        text,
+        "db_query_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {count}"
+      );
+      let _ = writeln!(text, "db_query_duration_seconds_sum{{{labels}}} {sum}");
+      let _ =
+        writeln!(text, "db_query_duration_seconds_count{{{labels}}} {count}");
+    }
+    text
+  }
+}
+
+fn escape_label(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+/// Count requests by their route pattern so ids don't end up in labels.
+/// Requests routed to a nested router are left to that router's layer since
+/// their route isn't known yet.
+pub async fn track_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+  let Some(route) = request.extensions().get::<MatchedPath>().cloned() else {
+    return next.run(request).await;
+  };
+  let method = request.method().clone();
+  let response = next.run(request).await;
+  METRICS.count_request(
+    method.as_str(),
+    route.as_str(),
+    response.status().as_u16(),
+  );
+  response
+}
+
+/// `router` with the requests to its own routes counted, nested routers need
+/// to be tracked separately.
+pub fn tracked<S>(router: Router<S>) -> Router<S>
+where
+  S: Clone + Send + Sync + 'static,
+{
+  router.route_layer(middleware::from_fn(track_requests))
+}
+
+/// Run `query` recording how long it took.
+pub async fn time_query<T>(
+  operation: &'static str,
+  collection: &'static str,
+  query: impl Future<Output = T>,
+) -> T {
+  let started_at = Instant::now();
+  let result = query.await;
+  METRICS.observe_query(operation, collection, started_at.elapsed());
+  result
+}
+
+pub async fn metrics(State(sockets): State<WebSocketState>) -> Response {
+  (
+    [(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE))],
+    METRICS.render(sockets.connection_count()),
+  )
+    .into_response()
+}
@@ -0,0 +1,131 @@
+use crate::{
+  api::{APIError, APIResult},
+  auth::session::Session,
+  env_var,
+};
+use axum::{
+  async_trait,
+  extract::{ConnectInfo, FromRequestParts},
+  http::{request::Parts, HeaderMap},
+  RequestPartsExt,
+};
+use format as f;
+use once_cell::sync::Lazy;
+use std::{
+  collections::HashMap,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+  time::Duration,
+};
+use tokio::{sync::Mutex, time::Instant};
+
+/// Header a trusted reverse proxy appends the client's address to, like
+/// `X-Forwarded-For`. Unset, clients are told apart by the connecting address.
+static TRUSTED_IP_HEADER: Lazy<Option<String>> = Lazy::new(|| {
+  env_var("TRUSTED_IP_HEADER")
+    .ok()
+    .filter(|header| !header.trim().is_empty())
+});
+
+/// Buckets are pruned once there are more than this many keys.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+#[derive(Debug)]
+struct TokenBucket {
+  tokens: f64,
+  updated_at: Instant,
+}
+
+/// Token bucket limiter allowing `capacity` requests per `window` for each key.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+  capacity: f64,
+  window: Duration,
+  buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+}
+
+impl RateLimiter {
+  pub fn new(capacity: u32, window: Duration) -> Self {
+    Self {
+      capacity: capacity.max(1) as f64,
+      window,
+      buckets: Arc::new(Mutex::new(HashMap::new())),
+    }
+  }
+
+  /// Take a token from `key`'s bucket or fail with `TooManyRequests`.
+  pub async fn check(&self, key: &str) -> APIResult {
+    let now = Instant::now();
+    let mut buckets = self.buckets.lock().await;
+    if buckets.len() > MAX_IDLE_BUCKETS {
+      buckets.retain(|_, bucket| now - bucket.updated_at < self.window);
+    }
+    let bucket =
+      buckets
+        .entry(key.to_string())
+        .or_insert_with(|| TokenBucket {
+          tokens: self.capacity,
+          updated_at: now,
+        });
+
+    let refill = (now - bucket.updated_at).as_secs_f64()
+      / self.window.as_secs_f64()
+      * self.capacity;
+    bucket.tokens = (bucket.tokens + refill).min(self.capacity);
+    bucket.updated_at = now;
+
+    if bucket.tokens < 1.0 {
+      return Err(APIError::TooManyRequests(f!(
+        "Rate limit of {} requests every {:?} exceeded",
+        self.capacity,
+        self.window
+      )));
+    }
+    bucket.tokens -= 1.0;
+    Ok(())
+  }
+}
+
+/// Key a request is rate limited by: the user of its session, or the client's
+/// address for anonymous requests.
+#[derive(Debug)]
+pub struct ClientKey(pub String);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientKey
+where
+  S: Send + Sync,
+{
+  type Rejection = APIError;
+
+  async fn from_request_parts(
+    parts: &mut Parts,
+    state: &S,
+  ) -> Result<Self, Self::Rejection> {
+    if let Ok(session) = Session::from_request_parts(parts, state).await {
+      return Ok(Self(session.user_id));
+    }
+    let ConnectInfo(address) = parts
+      .extract::<ConnectInfo<SocketAddr>>()
+      .await
+      .map_err(|error| APIError::Internal(error.to_string()))?;
+    let ip = client_ip(&parts.headers, TRUSTED_IP_HEADER.as_deref(), address);
+    Ok(Self(ip.to_string()))
+  }
+}
+
+/// Address of the client behind `address`. Proxies append the address they
+/// got the request from to `trusted_header`, so only its last entry can be
+/// trusted, earlier ones come from the client.
+pub fn client_ip(
+  headers: &HeaderMap,
+  trusted_header: Option<&str>,
+  address: SocketAddr,
+) -> IpAddr {
+  trusted_header
+    .and_then(|header| headers.get(header))
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.rsplit(',').next())
+    .and_then(|ip| ip.trim().parse().ok())
+    .unwrap_or_else(|| address.ip())
+}
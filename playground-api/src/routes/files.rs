@@ -1,39 +1,113 @@
 use crate::{
-  api::{self, APIError, APIResult},
-  auth::session::{FileId, FileIdVecQuery, Session},
+  api::{
+    self,
+    google::{
+      DriveAccount, DriveClient, DriveFilePage, DrivePageQuery,
+      ReqwestDriveClient, DRIVE_API,
+    },
+    json::ValidJson,
+    APIError, APIResult, FieldError,
+  },
+  archive::{check_archive_limits, zip_response, ArchiveEntry},
+  auth::{
+    google::GoogleState,
+    jwt::{self, Scope},
+    session::{FileId, FileIdVecQuery, ReadSession, Session},
+  },
+  cache::TtlCache,
   console::Colorize,
-  db::files::{
-    aggregations::{FolderChildren, FolderChildrenAndAncestors},
-    system::FileSystem,
-    File, PartialFile, Video,
+  db::{
+    files::{
+      aggregations::{FileTree, FolderChildren},
+      queries::MAX_LINEAGE_DEPTH,
+      system::{FileSort, FileSystem, OnConflict},
+      BasicFileInfo, File, FileMetadata, PartialFile, SubtitleTrack, Video,
+    },
+    providers::find_providers,
+    shares::{revoke_share, save_share, Share},
+    Database,
   },
-  http::stream_video,
+  env_var, env_var_or,
+  http::{self, proxy_image, video_head_response, ClientPool},
   log,
+  rate_limit::{ClientKey, RateLimiter},
+  routes::api_path,
+  string::NonEmptyString,
   websockets::{
-    channel::{EventMessage, EventSender},
+    channel::{EventMessage, EventSender, FileDelete},
     WebSocketState,
   },
   AppResult, AppState,
 };
 use axum::{
   extract::{Path, Query, State},
-  http::HeaderMap,
-  response::IntoResponse,
+  http::{HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
   routing, Json, Router,
 };
 use format as f;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use futures::TryFutureExt;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+  collections::{hash_map::DefaultHasher, HashMap, HashSet},
+  future::Future,
+  hash::{Hash, Hasher},
+  sync::Arc,
+  time::Duration,
+};
+
+/// Mime types accepted as videos besides `video/*`, from the comma separated
+/// `EXTRA_VIDEO_MIME_TYPES`.
+static EXTRA_VIDEO_MIME_TYPES: Lazy<HashSet<String>> = Lazy::new(|| {
+  env_var("EXTRA_VIDEO_MIME_TYPES")
+    .unwrap_or_default()
+    .split(',')
+    .map(|mime_type| mime_type.trim().to_ascii_lowercase())
+    .filter(|mime_type| !mime_type.is_empty())
+    .collect()
+});
+
+/// Files created by requests sent with an `Idempotency-Key` and the hash of
+/// their body, by user and key, so retries get the file back instead of
+/// creating a duplicate.
+static CREATED_FILES: Lazy<TtlCache<(u64, File), APIError>> = Lazy::new(|| {
+  TtlCache::new(Duration::from_secs(env_var_or("IDEMPOTENCY_TTL_SECS", 600)))
+});
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
 
 #[derive(Debug, Clone)]
 pub struct FilesRouterState {
   request_client: reqwest::Client,
+  drive: Arc<dyn DriveClient>,
+  /// Throttles requests proxied to Google Drive, keyed by user id or client IP
+  /// for endpoints that don't require a session.
+  drive_limiter: RateLimiter,
 }
 
 impl FilesRouterState {
   pub fn new() -> Self {
+    let request_client = http::client();
+    let drive = ReqwestDriveClient {
+      request_client: request_client.clone(),
+      video_clients: ClientPool::new(env_var_or("VIDEO_CLIENT_POOL_SIZE", 4)),
+    };
+    Self::with_drive(request_client, Arc::new(drive))
+  }
+
+  /// State whose file lookups and streams go through `drive`.
+  pub fn with_drive(
+    request_client: reqwest::Client,
+    drive: Arc<dyn DriveClient>,
+  ) -> Self {
     Self {
-      request_client: reqwest::Client::new(),
+      request_client,
+      drive,
+      drive_limiter: RateLimiter::new(
+        env_var_or("DRIVE_RATE_LIMIT", 30),
+        Duration::from_secs(env_var_or("DRIVE_RATE_LIMIT_SECS", 10)),
+      ),
     }
   }
 }
@@ -43,44 +117,519 @@ pub fn api() -> AppResult<Router<AppState>> {
     Router::new()
       .route("/", routing::get(get_files))
       .route("/", routing::delete(delete_files))
+      .route("/:file_id", routing::get(get_file))
       .route("/:file_id", routing::patch(update_file))
+      .route("/:file_id/star", routing::patch(toggle_star))
+      .route("/:file_id/subtitles", routing::put(attach_subtitle))
+      .route(
+        "/:file_id/subtitles/:lang",
+        routing::delete(remove_subtitle),
+      )
+      .route("/empty-folders", routing::get(get_empty_folders))
+      .route("/tree", routing::get(get_tree))
       .route("/folder", routing::post(create_folder))
-      .route("/folder/:folder_id", routing::get(get_folder_family))
+      .route(
+        "/folder/:folder_id",
+        routing::get(get_folder_family).patch(update_folder),
+      )
+      .route(
+        "/folder/:folder_id/breadcrumbs",
+        routing::get(get_breadcrumbs),
+      )
+      .route("/folder/:folder_id/zip", routing::post(zip_folder))
+      .route("/folder/:folder_id/share", routing::post(share_folder))
+      .route("/share/:share_id", routing::delete(revoke_share_link))
+      .route(
+        "/folder/:folder_id/empty",
+        routing::delete(delete_empty_folder),
+      )
       .route("/folder/move", routing::put(move_files))
       .route("/video/metadata", routing::get(get_video_metadata))
-      .route("/video/:video_id", routing::get(stream))
-      .route("/video/:video_id", routing::post(create_video)),
+      .route("/drive", routing::get(get_drive_files))
+      .route("/thumbnail/:video_id", routing::get(thumbnail))
+      .route("/video/:video_id", routing::get(stream).head(stream_head))
+      .route("/video/:video_id", routing::post(create_video))
+      .route("/video/:video_id/download", routing::get(download))
+      .route(
+        "/video/:video_id/thumbnail",
+        routing::patch(update_thumbnail),
+      ),
   )
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamQuery {
+  /// Bytes per chunk instead of `VIDEO_CONTENT_LENGTH`.
+  chunk_size: Option<usize>,
+}
+
 pub async fn stream(
+  ClientKey(client): ClientKey,
+  State(FilesRouterState {
+    drive,
+    drive_limiter,
+    ..
+  }): State<FilesRouterState>,
   Path(video_id): Path<String>,
+  Query(StreamQuery { chunk_size }): Query<StreamQuery>,
   headers: HeaderMap,
 ) -> APIResult<impl IntoResponse> {
-  stream_video(
-    &f!(
-      "https://drive.google.com/uc?export=download&confirm=yTib&id={video_id}"
-    ),
-    headers,
-  )
-  .await
+  drive_limiter.check(&client).await?;
+  let chunk_size = http::chunk_size(chunk_size)?;
+  // Cached, so ranges can be clamped without asking Drive every chunk
+  let file = drive.get_file(&video_id, DriveAccount::Public).await?;
+  let content_length = file.size_bytes.map(|size| size as usize);
+  drive
+    .stream_range(&video_id, headers, content_length, chunk_size)
+    .await
+}
+
+/// Download a video saved by the user named after its file.
+pub async fn download(
+  session: Session,
+  State(FilesRouterState {
+    drive,
+    drive_limiter,
+    ..
+  }): State<FilesRouterState>,
+  State(file_system): State<FileSystem>,
+  Path(video_id): Path<String>,
+) -> APIResult<Response> {
+  drive_limiter.check(&session.user_id).await?;
+  let file = file_system
+    .find_video(&session.user_id, &video_id)
+    .await?
+    .ok_or_else(|| APIError::NotFound(f!("Video {video_id:?} not found")))?;
+  let FileMetadata::Video(video) = &file.metadata else {
+    return Err(APIError::NotFound(f!("Video {video_id:?} not found")));
+  };
+  let file_name = download_name(&file.name, &video.mime_type);
+  download_attachment(&*drive, &video_id, &file_name).await
+}
+
+/// Relay the Drive video `video_id` as an attachment saved as `file_name`.
+pub async fn download_attachment(
+  drive: &dyn DriveClient,
+  video_id: &str,
+  file_name: &str,
+) -> APIResult<Response> {
+  let mut response = drive.download(video_id).await?;
+  response
+    .headers_mut()
+    .insert("Content-Disposition", http::attachment(file_name)?);
+  Ok(response)
+}
+
+/// `name` with the extension of `mime_type` unless it already ends with it.
+/// Names are left alone for unknown mime types.
+pub fn download_name(name: &str, mime_type: &str) -> String {
+  let Some(extension) = video_extension(mime_type) else {
+    return name.to_string();
+  };
+  if name.to_lowercase().ends_with(&f!(".{extension}")) {
+    name.to_string()
+  } else {
+    f!("{name}.{extension}")
+  }
+}
+
+/// File extension of the video `mime_type`s Drive serves.
+fn video_extension(mime_type: &str) -> Option<&'static str> {
+  Some(match mime_type {
+    "video/mp4" => "mp4",
+    "video/quicktime" => "mov",
+    "video/x-matroska" => "mkv",
+    "video/webm" => "webm",
+    "video/x-msvideo" => "avi",
+    "video/x-ms-wmv" => "wmv",
+    "video/x-flv" => "flv",
+    "video/mpeg" => "mpeg",
+    "video/3gpp" => "3gp",
+    "video/ogg" => "ogv",
+    _ => return None,
+  })
+}
+
+/// Download the videos in a folder and its subfolders as a zip archive.
+pub async fn zip_folder(
+  session: Session,
+  State(FilesRouterState {
+    drive,
+    drive_limiter,
+    ..
+  }): State<FilesRouterState>,
+  State(file_system): State<FileSystem>,
+  Path(folder_id): Path<String>,
+) -> APIResult<Response> {
+  drive_limiter.check(&session.user_id).await?;
+  let family = file_system
+    .find_descendants(&session.user_id, &folder_id)
+    .await?
+    .ok_or_else(|| {
+      APIError::NotFound(f!("Folder with id {folder_id:?} not found"))
+    })?;
+  let entries = archive_entries(&family.folder.id, &family.descendants);
+  check_archive_limits(&entries)?;
+  zip_response(drive, entries, &f!("{}.zip", family.folder.name))
+}
+
+/// `name` as a single zip path component, so extracting it can't write
+/// outside the archive's folder.
+fn archive_name(name: &str) -> String {
+  let name = name.replace(['/', '\\'], "_");
+  match name.trim() {
+    "" | "." | ".." => "_".into(),
+    _ => name,
+  }
+}
+
+/// `path` with a ` (n)` suffix before its extension, picking the first `n`
+/// not in `taken`.
+fn unique_path(path: &str, taken: &HashSet<String>) -> String {
+  let (folder, name) = match path.rsplit_once('/') {
+    Some((folder, name)) => (f!("{folder}/"), name),
+    None => (String::new(), path),
+  };
+  let (stem, extension) = match name.rsplit_once('.') {
+    Some((stem, extension)) if !stem.is_empty() => (stem, f!(".{extension}")),
+    _ => (name, String::new()),
+  };
+  (1..)
+    .map(|n| f!("{folder}{stem} ({n}){extension}"))
+    .find(|path| !taken.contains(path))
+    .unwrap_or_default()
+}
+
+/// Entries for the videos in `files`, nested in `folder_id`, at their path
+/// relative to it. Names are made safe and unique within the archive.
+pub fn archive_entries(folder_id: &str, files: &[File]) -> Vec<ArchiveEntry> {
+  let folders = files
+    .iter()
+    .filter(|file| matches!(file.metadata, FileMetadata::Folder { .. }))
+    .map(|folder| (folder.id.as_str(), folder))
+    .collect::<HashMap<_, _>>();
+  let mut entries = files
+    .iter()
+    .filter_map(|file| {
+      let FileMetadata::Video(video) = &file.metadata else {
+        return None;
+      };
+      let name = download_name(&file.name, &video.mime_type);
+      let mut path = vec![archive_name(&name)];
+      let mut parent = file.folder_id.as_str();
+      while parent != folder_id {
+        let folder = folders.get(parent)?;
+        path.push(archive_name(&folder.name));
+        parent = &folder.folder_id;
+      }
+      path.reverse();
+      Some(ArchiveEntry {
+        path: path.join("/"),
+        video_id: video.play_id.clone(),
+        size_bytes: video.size_bytes,
+      })
+    })
+    .collect::<Vec<_>>();
+  entries.sort_by(|a, b| (&a.path, &a.video_id).cmp(&(&b.path, &b.video_id)));
+  let mut taken = HashSet::new();
+  for entry in &mut entries {
+    if taken.contains(&entry.path) {
+      entry.path = unique_path(&entry.path, &taken);
+    }
+    taken.insert(entry.path.clone());
+  }
+  entries.sort_by(|a, b| a.path.cmp(&b.path));
+  entries
+}
+
+/// Answer `HEAD` from the cached Drive metadata instead of the video itself,
+/// so it isn't throttled like a `GET` that streams the content.
+pub async fn stream_head(
+  State(FilesRouterState { drive, .. }): State<FilesRouterState>,
+  Path(video_id): Path<String>,
+) -> APIResult<impl IntoResponse> {
+  let file = drive.get_file(&video_id, DriveAccount::Public).await?;
+  video_head_response(file.size_bytes, &file.mime_type)
+}
+
+/// Relay a video's Drive thumbnail so clients don't need access to Drive.
+pub async fn thumbnail(
+  ClientKey(client): ClientKey,
+  State(FilesRouterState {
+    request_client,
+    drive_limiter,
+    ..
+  }): State<FilesRouterState>,
+  Path(video_id): Path<String>,
+) -> APIResult<impl IntoResponse> {
+  drive_limiter.check(&client).await?;
+  proxy_image(&request_client, &api::google::thumbnail_url(&video_id)).await
 }
 
 pub async fn get_files(
   State(file_system): State<FileSystem>,
+  headers: HeaderMap,
   query: PartialFile,
+  Query(sort): Query<FileSort>,
+) -> APIResult<Response> {
+  http::json_with_etag(&headers, &file_system.find_many(&query, sort).await?)
+}
+
+pub async fn get_empty_folders(
+  session: Session,
+  State(file_system): State<FileSystem>,
 ) -> APIResult<Json<Vec<File>>> {
-  Ok(Json(file_system.find_many(&query).await?))
+  Ok(Json(
+    file_system.find_empty_folders(&session.user_id).await?,
+  ))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeQuery {
+  #[serde(alias = "max_depth")]
+  max_depth: Option<u32>,
+}
+
+pub async fn get_tree(
+  session: Session,
+  State(file_system): State<FileSystem>,
+  Query(query): Query<TreeQuery>,
+) -> APIResult<Json<FileTree>> {
+  let levels = query
+    .max_depth
+    .map_or(MAX_LINEAGE_DEPTH, |depth| depth.min(MAX_LINEAGE_DEPTH));
+  Ok(Json(
+    file_system
+      .find_tree(&session.user_id, levels)
+      .await?
+      .ok_or_else(|| APIError::NotFound("Root folder not found".into()))?,
+  ))
+}
+
+/// 404 for other users' files too, so their ids can't be probed.
+pub async fn get_file(
+  session: ReadSession,
+  State(file_system): State<FileSystem>,
+  FileId(file_id): FileId,
+) -> APIResult<Json<File>> {
+  let not_found =
+    || APIError::NotFound(f!("File with id {file_id:?} not found"));
+  let file = file_system
+    .find_file(&session.user_id, &file_id)
+    .await?
+    .ok_or_else(not_found)?;
+  if session.shared_folder_id.is_some() {
+    let ancestors = file_system
+      .find_ancestors(&session.user_id, &file.folder_id)
+      .await?
+      .unwrap_or_default();
+    let lineage = ancestors.iter().map(|ancestor| ancestor.id.as_str());
+    if !session.can_read(lineage.chain([file.id.as_str()])) {
+      return Err(not_found());
+    }
+  }
+  Ok(Json(file))
 }
 
 pub async fn get_folder_family(
+  session: ReadSession,
+  State(file_system): State<FileSystem>,
+  headers: HeaderMap,
+  Path(folder_id): Path<String>,
+) -> APIResult<Response> {
+  let not_found =
+    || APIError::NotFound(f!("Folder with id {folder_id:?} not found"));
+  let mut family = file_system
+    .find_children_and_ancestors(&session.user_id, &folder_id)
+    .await?
+    .ok_or_else(not_found)?;
+  if let Some(shared_folder_id) = &session.shared_folder_id {
+    let lineage = family.ancestors.iter().map(|ancestor| ancestor.id.as_str());
+    if !session.can_read(lineage.chain([family.id.as_str()])) {
+      return Err(not_found());
+    }
+    // Guests don't get to see the folders above the shared one
+    let shared_at = family
+      .ancestors
+      .iter()
+      .position(|ancestor| &ancestor.id == shared_folder_id)
+      .unwrap_or(family.ancestors.len());
+    family.ancestors.drain(..shared_at);
+  }
+  http::json_with_etag(&headers, &family)
+}
+
+/// Longest a share link can be time limited to, about ten years.
+const MAX_SHARE_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareFolderQuery {
+  /// Seconds the link lasts, it lasts until revoked if missing.
+  #[serde(alias = "expires_in")]
+  expires_in: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareFolderResponse {
+  /// Revokes the link when deleted.
+  share_id: String,
+  token: String,
+  /// Path to the shared folder with the share id in it.
+  link: String,
+}
+
+/// Link letting anyone read `folder_id` and everything in it without logging
+/// in, until it's revoked or expires.
+pub async fn share_folder(
+  session: Session,
+  State(database): State<Database>,
+  State(file_system): State<FileSystem>,
+  Path(folder_id): Path<String>,
+  Query(query): Query<ShareFolderQuery>,
+) -> APIResult<Json<ShareFolderResponse>> {
+  if let Some(expires_in) = query.expires_in {
+    if !(1..=MAX_SHARE_SECS).contains(&expires_in) {
+      return Err(APIError::invalid_field(
+        "expiresIn",
+        f!("Must be between 1 and {MAX_SHARE_SECS} seconds"),
+      ));
+    }
+  }
+  let folder = file_system
+    .find_file(&session.user_id, &folder_id)
+    .await?
+    .filter(|file| matches!(file.metadata, FileMetadata::Folder { .. }))
+    .ok_or_else(|| {
+      APIError::NotFound(f!("Folder with id {folder_id:?} not found"))
+    })?;
+
+  let expiry = query.expires_in.map(Duration::from_secs);
+  let share = Share::new(&session.user_id, &folder.id, expiry);
+  let token = jwt::sign_guest_token(
+    &session.user_id,
+    &share._id,
+    &folder.id,
+    Scope::Read,
+    query
+      .expires_in
+      .map(|secs| chrono::Duration::seconds(secs as i64))
+      .map_or(*jwt::GUEST_EXPIRY, |expiry| expiry.min(*jwt::GUEST_EXPIRY)),
+  )?;
+  save_share(&share, &database).await?;
+
+  Ok(Json(ShareFolderResponse {
+    link: api_path(&f!("/files/folder/{}?share={}", folder.id, share._id)),
+    share_id: share._id,
+    token,
+  }))
+}
+
+/// Revoke a share link, guests using it lose access right away.
+pub async fn revoke_share_link(
+  session: Session,
+  State(database): State<Database>,
+  Path(share_id): Path<String>,
+) -> APIResult<StatusCode> {
+  revoke_share(&session.user_id, &share_id, &database)
+    .await?
+    .ok_or_else(|| {
+      APIError::NotFound(f!("Share with id {share_id:?} not found"))
+    })?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+/// Longest folder icon, long enough for emoji made of several code points.
+const MAX_ICON_LENGTH: usize = 16;
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateFolderBody {
+  /// Hex color like `#ff8800`, cleared if `null` and kept if missing.
+  #[serde(default, deserialize_with = "deserialize_present")]
+  color: Option<Option<String>>,
+  /// Short label like an emoji, cleared if `null` and kept if missing.
+  #[serde(default, deserialize_with = "deserialize_present")]
+  icon: Option<Option<String>>,
+}
+
+/// `Some` for fields present in the body even if `null`, missing ones are
+/// left `None` by `#[serde(default)]`.
+fn deserialize_present<'de, T, D>(
+  deserializer: D,
+) -> Result<Option<T>, D::Error>
+where
+  T: Deserialize<'de>,
+  D: Deserializer<'de>,
+{
+  T::deserialize(deserializer).map(Some)
+}
+
+impl UpdateFolderBody {
+  pub fn validate(&self) -> APIResult {
+    let mut fields = Vec::new();
+    if let Some(Some(color)) = &self.color {
+      let hex = color.strip_prefix('#').unwrap_or_default();
+      if ![3, 6, 8].contains(&hex.len())
+        || !hex.chars().all(|c| c.is_ascii_hexdigit())
+      {
+        fields.push(FieldError {
+          field: "color".into(),
+          message: f!("{color:?} is not a hex color like #ff8800"),
+        });
+      }
+    }
+    if let Some(Some(icon)) = &self.icon {
+      let length = icon.chars().count();
+      if length == 0
+        || length > MAX_ICON_LENGTH
+        || icon.chars().any(char::is_control)
+      {
+        fields.push(FieldError {
+          field: "icon".into(),
+          message: f!(
+            "Icon must have between 1 and {MAX_ICON_LENGTH} printable characters"
+          ),
+        });
+      }
+    }
+    if fields.is_empty() {
+      Ok(())
+    } else {
+      Err(APIError::Validation(fields))
+    }
+  }
+}
+
+/// Set the color and icon of a folder, only those in the body are changed.
+pub async fn update_folder(
+  session: Session,
+  State(file_system): State<FileSystem>,
+  Path(folder_id): Path<String>,
+  ValidJson(body): ValidJson<UpdateFolderBody>,
+) -> APIResult<Json<File>> {
+  body.validate()?;
+  let (file, _) = file_system
+    .update_folder_style(
+      &session.user_id,
+      &folder_id,
+      body.color.as_ref().map(Option::as_deref),
+      body.icon.as_ref().map(Option::as_deref),
+    )
+    .await?;
+  Ok(Json(file))
+}
+
+pub async fn get_breadcrumbs(
   session: Session,
   State(file_system): State<FileSystem>,
   Path(folder_id): Path<String>,
-) -> APIResult<Json<FolderChildrenAndAncestors>> {
+) -> APIResult<Json<Vec<BasicFileInfo>>> {
   Ok(Json(
     file_system
-      .find_children_and_ancestors(&session.user_id, &folder_id)
+      .find_ancestors(&session.user_id, &folder_id)
       .await?
       .ok_or_else(|| {
         APIError::NotFound(f!("Folder with id {folder_id:?} not found"))
@@ -88,86 +637,223 @@ pub async fn get_folder_family(
   ))
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Hash)]
+#[serde(rename_all = "camelCase")]
 pub struct CreateVideoBody {
+  /// Linked account to fetch the video as, for videos that aren't public.
+  account: Option<String>,
   folder: Option<String>,
   name: Option<String>,
   thumbnail: Option<String>,
+  #[serde(default)]
+  on_conflict: OnConflict,
+}
+
+impl CreateVideoBody {
+  pub fn validate(&self) -> APIResult {
+    self.name.as_deref().map(validate_name).transpose()?;
+    Ok(())
+  }
 }
 
+// Every argument is an extractor, like the other handlers
+#[allow(clippy::too_many_arguments)]
 pub async fn create_video(
   session: Session,
   Path(video_id): Path<String>,
-  State(FilesRouterState { request_client }): State<FilesRouterState>,
-  State(WebSocketState { event_sender }): State<WebSocketState>,
+  State(database): State<Database>,
+  State(google): State<GoogleState>,
+  State(FilesRouterState {
+    drive,
+    drive_limiter,
+    ..
+  }): State<FilesRouterState>,
   State(file_system): State<FileSystem>,
-  Json(body): Json<CreateVideoBody>,
+  headers: HeaderMap,
+  ValidJson(body): ValidJson<CreateVideoBody>,
 ) -> APIResult<Json<File>> {
-  let mut metadata = fetch_video_metadata(&request_client, &video_id).await?;
+  body.validate()?;
+  drive_limiter.check(&session.user_id).await?;
+  let access_token = match &body.account {
+    Some(account) => {
+      Some(linked_access_token(&session, account, &database, &google).await?)
+    }
+    None => None,
+  };
+  let user_id = session.user_id.clone();
+  let create = |body: CreateVideoBody| async move {
+    let account = match (&body.account, &access_token) {
+      (Some(id), Some(access_token)) => {
+        DriveAccount::Linked { id, access_token }
+      }
+      _ => DriveAccount::Public,
+    };
+    let mut metadata =
+      fetch_video_metadata(&*drive, &video_id, account).await?;
 
-  if let Some(thumbnail) = body.thumbnail {
-    metadata.thumbnail = thumbnail;
-  }
+    if let Some(thumbnail) = body.thumbnail {
+      metadata.thumbnail = thumbnail;
+    }
 
-  let (new_file, changes) = file_system
-    .create_one(&File::from_video(
-      metadata,
-      session.user_id,
-      body.folder,
-      body.name,
-    )?)
-    .await?;
-  send_folder_changes(&event_sender, changes)?;
-  Ok(Json(new_file))
+    let (new_file, _) = file_system
+      .create_one(
+        &File::from_video(metadata, user_id, body.folder, body.name)?,
+        body.on_conflict,
+      )
+      .await?;
+    Ok(new_file)
+  };
+  Ok(Json(
+    create_idempotent(&session.user_id, &headers, body, create).await?,
+  ))
 }
 
 #[derive(Debug, Deserialize)]
+pub struct UpdateThumbnailBody {
+  /// Defaults to the Drive thumbnail relayed by this API.
+  thumbnail: Option<String>,
+}
+
+pub async fn update_thumbnail(
+  session: Session,
+  Path(video_id): Path<String>,
+  State(file_system): State<FileSystem>,
+  ValidJson(body): ValidJson<UpdateThumbnailBody>,
+) -> APIResult<Json<File>> {
+  let thumbnail = body
+    .thumbnail
+    .unwrap_or_else(|| default_thumbnail(&video_id));
+  let (file, _) = file_system
+    .update_thumbnail(&session.user_id, &video_id, &thumbnail)
+    .await?;
+  Ok(Json(file))
+}
+
+#[derive(Debug, Deserialize, Hash)]
 pub struct CreateFolderBody {
   folder: Option<String>,
   name: String,
 }
 
+impl CreateFolderBody {
+  pub fn validate(&self) -> APIResult {
+    validate_name(&self.name)
+  }
+}
+
 pub async fn create_folder(
   session: Session,
-  State(WebSocketState { event_sender }): State<WebSocketState>,
   State(file_system): State<FileSystem>,
-  Json(body): Json<CreateFolderBody>,
+  headers: HeaderMap,
+  ValidJson(body): ValidJson<CreateFolderBody>,
 ) -> APIResult<Json<File>> {
-  let (new_file, changes) = file_system
-    .create_one(&File::new_folder(session.user_id, body.name, body.folder)?)
-    .await?;
-  send_folder_changes(&event_sender, changes)?;
-  Ok(Json(new_file))
+  body.validate()?;
+  let user_id = session.user_id.clone();
+  let create = |body: CreateFolderBody| async move {
+    let (new_file, _) = file_system
+      .create_one(
+        &File::new_folder(user_id, body.name, body.folder)?,
+        OnConflict::Error,
+      )
+      .await?;
+    Ok(new_file)
+  };
+  Ok(Json(
+    create_idempotent(&session.user_id, &headers, body, create).await?,
+  ))
+}
+
+/// Fail with a `name` field error unless `name` is a valid file name.
+fn validate_name(name: &str) -> APIResult {
+  NonEmptyString::try_from(name)
+    .map_err(|error| APIError::invalid_field("name", error))?;
+  Ok(())
+}
+
+/// File made by `create` from `body`, unless a request of `user_id` with the
+/// same `Idempotency-Key` header already made one in the last
+/// `IDEMPOTENCY_TTL_SECS`, then that file is returned instead. Reusing a key
+/// with a different body fails with a 422. Failed attempts aren't remembered
+/// so they can be retried.
+pub async fn create_idempotent<B, Fut>(
+  user_id: &str,
+  headers: &HeaderMap,
+  body: B,
+  create: impl FnOnce(B) -> Fut,
+) -> APIResult<File>
+where
+  B: Hash,
+  Fut: Future<Output = APIResult<File>> + Send + 'static,
+{
+  let Some(key) = headers.get(IDEMPOTENCY_KEY) else {
+    return create(body).await;
+  };
+  let key = key.to_str().map_err(|_| {
+    APIError::BadRequest("Idempotency-Key must be visible ASCII".into())
+  })?;
+  let mut hasher = DefaultHasher::new();
+  body.hash(&mut hasher);
+  let body_hash = hasher.finish();
+  let (created_hash, file) = CREATED_FILES
+    .get_or_fetch(&f!("{user_id}:{key}"), || {
+      create(body).map_ok(move |file| (body_hash, file))
+    })
+    .await
+    .map_err(|error| Arc::try_unwrap(error).unwrap_or_else(APIError::Shared))?;
+  if created_hash != body_hash {
+    return Err(APIError::invalid_field(
+      IDEMPOTENCY_KEY,
+      "Was already used with a different body",
+    ));
+  }
+  Ok(file)
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct MoveFilesBody {
   files: HashSet<String>,
   folder: String,
+  /// Fail with a 409 unless each of these files is still at its version.
+  #[serde(default)]
+  versions: HashMap<String, u64>,
+  #[serde(default)]
+  dry_run: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MoveFilesResponse {
   moved_count: u64,
+  /// Folder changes the move would produce, only set for dry runs.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  changes: Option<Vec<FolderChildren>>,
 }
 
 pub async fn move_files(
   session: Session,
-  State(WebSocketState { event_sender }): State<WebSocketState>,
   State(file_system): State<FileSystem>,
-  Json(body): Json<MoveFilesBody>,
+  ValidJson(body): ValidJson<MoveFilesBody>,
 ) -> APIResult<Json<MoveFilesResponse>> {
-  let (result, changes) = file_system
-    .move_many(&session.user_id, &body.files, &body.folder)
+  let (moved_count, changes) = file_system
+    .move_many(
+      &session.user_id,
+      &body.files,
+      &body.folder,
+      &body.versions,
+      body.dry_run,
+    )
     .await?;
 
-  if let Some(changes) = changes {
-    send_folder_changes(&event_sender, changes)?;
+  if body.dry_run {
+    return Ok(Json(MoveFilesResponse {
+      moved_count,
+      changes: Some(changes.unwrap_or_default()),
+    }));
   }
-
   Ok(Json(MoveFilesResponse {
-    moved_count: result.modified_count,
+    moved_count,
+    changes: None,
   }))
 }
 
@@ -175,22 +861,66 @@ pub async fn move_files(
 pub struct UpdateFileBody {
   name: Option<String>,
   folder: Option<String>,
+  /// Fail with a 409 unless the file is still at this version.
+  version: Option<u64>,
+}
+
+impl UpdateFileBody {
+  pub fn validate(&self) -> APIResult {
+    self.name.as_deref().map(validate_name).transpose()?;
+    Ok(())
+  }
 }
 
 pub async fn update_file(
   session: Session,
-  State(WebSocketState { event_sender }): State<WebSocketState>,
   State(file_system): State<FileSystem>,
   FileId(file_id): FileId,
-  Json(body): Json<UpdateFileBody>,
+  ValidJson(body): ValidJson<UpdateFileBody>,
 ) -> APIResult<Json<File>> {
-  let (file, changes) = file_system
-    .update_one(&session.user_id, &file_id, body.folder, body.name)
+  body.validate()?;
+  let (file, _) = file_system
+    .update_one(
+      &session.user_id,
+      &file_id,
+      body.folder,
+      body.name,
+      body.version,
+    )
     .await?;
 
-  log!("CHANGES => {changes:#?}");
-  send_folder_changes(&event_sender, changes)?;
+  Ok(Json(file))
+}
 
+pub async fn toggle_star(
+  session: Session,
+  State(file_system): State<FileSystem>,
+  FileId(file_id): FileId,
+) -> APIResult<Json<File>> {
+  let (file, _) = file_system.toggle_star(&session.user_id, &file_id).await?;
+  Ok(Json(file))
+}
+
+pub async fn attach_subtitle(
+  session: Session,
+  State(file_system): State<FileSystem>,
+  FileId(file_id): FileId,
+  ValidJson(track): ValidJson<SubtitleTrack>,
+) -> APIResult<Json<File>> {
+  let (file, _) = file_system
+    .attach_subtitle(&session.user_id, &file_id, track)
+    .await?;
+  Ok(Json(file))
+}
+
+pub async fn remove_subtitle(
+  session: Session,
+  State(file_system): State<FileSystem>,
+  Path((file_id, lang)): Path<(String, String)>,
+) -> APIResult<Json<File>> {
+  let (file, _) = file_system
+    .remove_subtitle(&session.user_id, &file_id, &lang)
+    .await?;
   Ok(Json(file))
 }
 
@@ -198,20 +928,59 @@ pub async fn update_file(
 #[serde(rename_all = "camelCase")]
 pub struct DeleteFilesResponse {
   deleted: u64,
+  /// Every deleted file, including the ones nested in deleted folders.
+  deleted_ids: HashSet<String>,
 }
 
 pub async fn delete_files(
   session: Session,
-  State(WebSocketState { event_sender }): State<WebSocketState>,
+  State(sockets): State<WebSocketState>,
   State(file_system): State<FileSystem>,
   FileIdVecQuery(query): FileIdVecQuery,
 ) -> APIResult<Json<DeleteFilesResponse>> {
-  let (deleted, changes) =
+  let (deleted, deleted_ids, _) =
     file_system.delete_many(&session.user_id, &query).await?;
 
-  send_folder_changes(&event_sender, changes)?;
+  if deleted > 0 {
+    send_event(
+      &sockets.event_sender,
+      EventMessage::FileDelete(FileDelete {
+        user_id: session.user_id,
+        deleted_ids: deleted_ids.clone(),
+      }),
+    )?;
+  }
 
-  Ok(Json(DeleteFilesResponse { deleted }))
+  Ok(Json(DeleteFilesResponse {
+    deleted,
+    deleted_ids,
+  }))
+}
+
+/// Delete a folder only if it has no files in it.
+pub async fn delete_empty_folder(
+  session: Session,
+  State(sockets): State<WebSocketState>,
+  State(file_system): State<FileSystem>,
+  Path(folder_id): Path<String>,
+) -> APIResult<Json<DeleteFilesResponse>> {
+  let (folder, _) = file_system
+    .delete_empty_folder(&session.user_id, &folder_id)
+    .await?;
+  let deleted_ids = HashSet::from([folder.id]);
+
+  send_event(
+    &sockets.event_sender,
+    EventMessage::FileDelete(FileDelete {
+      user_id: session.user_id,
+      deleted_ids: deleted_ids.clone(),
+    }),
+  )?;
+
+  Ok(Json(DeleteFilesResponse {
+    deleted: 1,
+    deleted_ids,
+  }))
 }
 
 #[derive(Debug, Deserialize)]
@@ -221,17 +990,161 @@ pub struct GetFileMetadataQuery {
 }
 
 pub async fn get_video_metadata(
-  State(FilesRouterState { request_client }): State<FilesRouterState>,
+  ClientKey(client): ClientKey,
+  State(FilesRouterState {
+    drive,
+    drive_limiter,
+    ..
+  }): State<FilesRouterState>,
   Query(GetFileMetadataQuery { video_id }): Query<GetFileMetadataQuery>,
 ) -> APIResult<Json<Video>> {
+  drive_limiter.check(&client).await?;
   Ok(Json(
-    fetch_video_metadata(&request_client, &video_id).await?,
+    fetch_video_metadata(&*drive, &video_id, DriveAccount::Public).await?,
   ))
 }
 
-async fn fetch_video_metadata(
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveFilesQuery {
+  account: Option<String>,
+  page_token: Option<String>,
+  page_size: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveAccountFiles {
+  account_id: String,
+  #[serde(flatten)]
+  page: DriveFilePage,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveFilesResponse {
+  accounts: Vec<DriveAccountFiles>,
+  /// Error message of every account whose videos couldn't be listed, keyed
+  /// by account id.
+  #[serde(skip_serializing_if = "HashMap::is_empty")]
+  errors: HashMap<String, String>,
+}
+
+/// List videos in the Drive of every linked account, or only `account`'s
+/// when paging since page tokens belong to a single account.
+pub async fn get_drive_files(
+  session: Session,
+  State(database): State<Database>,
+  State(google): State<GoogleState>,
+  State(FilesRouterState {
+    request_client,
+    drive_limiter,
+    ..
+  }): State<FilesRouterState>,
+  Query(query): Query<DriveFilesQuery>,
+) -> APIResult<Json<DriveFilesResponse>> {
+  drive_limiter.check(&session.user_id).await?;
+  let user = session.get_user(&database).await?;
+  let account_ids = match query.account {
+    Some(account) if user.linked_accounts.contains(&account) => {
+      HashSet::from([account])
+    }
+    Some(account) => {
+      return Err(APIError::NotFound(f!(
+        "Account {account:?} is not linked to this user"
+      )))
+    }
+    None if query.page_token.is_some() => {
+      return Err(APIError::BadRequest(
+        "pageToken requires the account it belongs to".into(),
+      ))
+    }
+    None => user.linked_accounts,
+  };
+
+  let page = DrivePageQuery {
+    page_token: query.page_token,
+    page_size: query.page_size,
+  };
+  let mut accounts = Vec::new();
+  let mut errors = HashMap::new();
+  for provider in find_providers(&account_ids, &database).await? {
+    match google.fresh_access_token(&provider, &database).await {
+      Ok(access_token) => accounts.push((provider._id, access_token)),
+      Err(error) => {
+        log!(err@"Failed to refresh token of {:?}: {error}", provider._id);
+        errors.insert(provider._id, error.to_string());
+      }
+    }
+  }
+  let mut response =
+    list_accounts_videos(DRIVE_API, accounts, &page, &request_client).await;
+  response.errors.extend(errors);
+  Ok(Json(response))
+}
+
+/// List a page of videos for every `(account id, access token)` pair from the
+/// Drive API at `api_url`. Accounts that fail, e.g. because access was
+/// revoked, are reported in `errors` without failing the others.
+pub async fn list_accounts_videos(
+  api_url: &str,
+  accounts: impl IntoIterator<Item = (String, String)>,
+  page: &DrivePageQuery,
   request_client: &reqwest::Client,
+) -> DriveFilesResponse {
+  let list = |(account_id, access_token): (String, String)| async move {
+    let page =
+      api::google::list_videos(api_url, &access_token, page, request_client)
+        .await;
+    (account_id, page)
+  };
+  let pages = futures::future::join_all(accounts.into_iter().map(list)).await;
+
+  let mut response = DriveFilesResponse {
+    accounts: Vec::new(),
+    errors: HashMap::new(),
+  };
+  for (account_id, page) in pages {
+    match page {
+      Ok(page) => response
+        .accounts
+        .push(DriveAccountFiles { account_id, page }),
+      Err(error) => {
+        log!(err@"Failed to list Drive videos of {account_id:?}: {error}");
+        response.errors.insert(account_id, error.to_string());
+      }
+    }
+  }
+  response
+}
+
+/// Access token of `account` if it's linked to the user of `session`.
+async fn linked_access_token(
+  session: &Session,
+  account: &str,
+  database: &Database,
+  google: &GoogleState,
+) -> APIResult<String> {
+  let user = session.get_user(database).await?;
+  let not_linked =
+    || APIError::NotFound(f!("Account {account:?} is not linked to this user"));
+  if !user.linked_accounts.contains(account) {
+    return Err(not_linked());
+  }
+  let provider =
+    find_providers(&HashSet::from([account.to_string()]), database)
+      .await?
+      .pop()
+      .ok_or_else(not_linked)?;
+  google.fresh_access_token(&provider, database).await
+}
+
+/// Video for the Drive file `file_url`, which is a share link or file id,
+/// fetched as `account`.
+pub async fn fetch_video_metadata(
+  drive: &dyn DriveClient,
   file_url: &str,
+  account: DriveAccount<'_>,
 ) -> APIResult<Video> {
   let video_id = if file_url.contains('/') {
     extract_drive_file_id(file_url).ok_or(APIError::BadRequest(f!(
@@ -240,13 +1153,21 @@ async fn fetch_video_metadata(
   } else {
     file_url.to_string()
   };
-  let file_data = api::google::get_file(&video_id, request_client).await?;
+  let file_data = drive.get_file(&video_id, account).await?;
   let video_metadata = file_data.video_metadata.ok_or_else(|| {
     APIError::BadRequest(f!(
       "Found file for file id {video_id:?} with name {:?} but is not a video",
       file_data.name
     ))
   })?;
+  if !is_video_mime_type(&file_data.mime_type) {
+    return Err(APIError::BadRequest(f!(
+      "Found file for file id {video_id:?} with name {:?} but its type {:?} \
+       is not a video",
+      file_data.name,
+      file_data.mime_type
+    )));
+  }
   Ok(Video {
     play_id: video_id.clone(),
     name: file_data.name,
@@ -255,10 +1176,21 @@ async fn fetch_video_metadata(
     duration_millis: video_metadata.duration_millis,
     mime_type: file_data.mime_type,
     size_bytes: file_data.size_bytes.unwrap_or_default(),
-    thumbnail: api::google::thumbnail_url(&video_id),
+    subtitles: Vec::new(),
+    thumbnail: default_thumbnail(&video_id),
   })
 }
 
+/// Drive thumbnail of `video_id` relayed by the `thumbnail` route.
+fn default_thumbnail(video_id: &str) -> String {
+  api_path(&f!("/files/thumbnail/{video_id}"))
+}
+
+fn is_video_mime_type(mime_type: &str) -> bool {
+  let mime_type = mime_type.to_ascii_lowercase();
+  mime_type.starts_with("video/") || EXTRA_VIDEO_MIME_TYPES.contains(&mime_type)
+}
+
 fn extract_drive_file_id(share_link: &str) -> Option<String> {
   share_link.find("file/d/").and_then(|start| {
     let slice = &share_link[(start + 7)..];
@@ -266,17 +1198,11 @@ fn extract_drive_file_id(share_link: &str) -> Option<String> {
   })
 }
 
-fn send_folder_changes(
-  event_sender: &EventSender,
-  changes: Vec<FolderChildren>,
-) -> APIResult {
+fn send_event(event_sender: &EventSender, event: EventMessage) -> APIResult {
   if event_sender.receiver_count() == 0 {
-    log!(info@"There's {} folder changes but no one's listening. Message will not be sent", changes.len());
+    log!(info@"There's a {event:?} event but no one's listening. Message will not be sent");
   } else {
-    log!(info@"Sending message to {} listeners", event_sender.receiver_count());
-    for change in changes.into_iter() {
-      event_sender.send(EventMessage::FolderChange(change))?;
-    }
+    event_sender.send(event)?;
   }
   Ok(())
 }
@@ -1,2 +1,64 @@
 pub mod files;
 pub mod users;
+
+use crate::env_var;
+use axum::{
+  http::{HeaderValue, Request},
+  middleware::{self, Next},
+  response::Response,
+  Router,
+};
+use format as f;
+use once_cell::sync::Lazy;
+
+/// Base path every route is served under, empty to serve them from the root.
+pub static API_PREFIX: Lazy<String> =
+  Lazy::new(|| normalize_prefix(&env_var("API_PREFIX").unwrap_or_default()));
+
+/// `prefix` with a single leading slash and no trailing one.
+pub fn normalize_prefix(prefix: &str) -> String {
+  let prefix = prefix.trim().trim_matches('/');
+  if prefix.is_empty() {
+    String::new()
+  } else {
+    f!("/{prefix}")
+  }
+}
+
+/// Nest `app` under `prefix`, an empty prefix leaves it as is since axum
+/// can't nest at the root.
+pub fn with_prefix(app: Router, prefix: &str) -> Router {
+  if prefix.is_empty() {
+    app
+  } else {
+    Router::new().nest(prefix, app)
+  }
+}
+
+/// Path the current version of the api is mounted at by `versioned`.
+const VERSIONED_PATH: &str = "/api/v1";
+
+/// Absolute path of `path` in the current version of the api, for links
+/// handed to clients.
+pub fn api_path(path: &str) -> String {
+  f!("{}{VERSIONED_PATH}{path}", *API_PREFIX)
+}
+
+/// Mount `api` at `VERSIONED_PATH` and, for clients that predate versioning,
+/// at `/api` with responses marked as deprecated.
+pub fn versioned<S>(api: Router<S>) -> Router<S>
+where
+  S: Clone + Send + Sync + 'static,
+{
+  Router::new()
+    .nest(VERSIONED_PATH, api.clone())
+    .nest("/api", api.layer(middleware::from_fn(deprecated)))
+}
+
+async fn deprecated<B>(request: Request<B>, next: Next<B>) -> Response {
+  let mut response = next.run(request).await;
+  response
+    .headers_mut()
+    .insert("Deprecation", HeaderValue::from_static("true"));
+  response
+}
@@ -1,12 +1,25 @@
+use crate::api::google::evict_account_files;
 use crate::auth::session::Session;
-use crate::db::users::User;
+use crate::db::providers::{find_linked_accounts, LinkedAccount};
+use crate::db::users::{delete_user, remove_provider_from_user, User};
 use crate::db::Database;
-use crate::{api::APIResult, AppState};
-use axum::extract::State;
-use axum::{routing::get, Json, Router};
+use crate::{
+  api::{APIError, APIResult},
+  AppState,
+};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::{
+  routing::{delete, get},
+  Json, Router,
+};
+use format as f;
 
 pub fn api() -> Router<AppState> {
-  Router::new().route("/me", get(current_user))
+  Router::new()
+    .route("/me", get(current_user).delete(delete_current_user))
+    .route("/me/accounts", get(linked_accounts))
+    .route("/providers/:provider_id", delete(unlink_provider))
 }
 
 async fn current_user(
@@ -15,3 +28,58 @@ async fn current_user(
 ) -> APIResult<Json<User>> {
   Ok(Json(session.get_user(&database).await?))
 }
+
+/// Delete the current user with everything they own, sessions are revoked
+/// first so no new changes come in while deleting.
+pub async fn delete_current_user(
+  session: Session,
+  State(database): State<Database>,
+) -> APIResult<StatusCode> {
+  let user = session.get_user(&database).await?;
+  Session::invalidate_all(&user._id).await;
+  delete_user(&user, &database).await?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+/// Profiles of every provider account linked to the current user.
+pub async fn linked_accounts(
+  session: Session,
+  State(database): State<Database>,
+) -> APIResult<Json<Vec<LinkedAccount>>> {
+  let user = session.get_user(&database).await?;
+  Ok(Json(
+    find_linked_accounts(&user.linked_accounts, &database).await?,
+  ))
+}
+
+/// Unlink a secondary provider account, the one the user was created with
+/// can't be removed.
+pub async fn unlink_provider(
+  session: Session,
+  State(database): State<Database>,
+  Path(provider_id): Path<String>,
+) -> APIResult<StatusCode> {
+  let user = session.get_user(&database).await?;
+  if !user.linked_accounts.contains(&provider_id) {
+    return Err(APIError::NotFound(f!(
+      "Provider {provider_id:?} is not linked to this account"
+    )));
+  }
+  if provider_id == user._id {
+    return Err(APIError::BadRequest(f!(
+      "Provider {provider_id:?} is the primary account and can't be unlinked"
+    )));
+  }
+  if user.linked_accounts.len() <= 1 {
+    return Err(APIError::BadRequest(f!(
+      "Provider {provider_id:?} is the only linked account"
+    )));
+  }
+  remove_provider_from_user(&user._id, &provider_id, &database)
+    .await?
+    .ok_or_else(|| {
+      APIError::NotFound(f!("Provider {provider_id:?} was already unlinked"))
+    })?;
+  evict_account_files(&provider_id).await;
+  Ok(StatusCode::NO_CONTENT)
+}
@@ -1,26 +1,42 @@
+use crate::env_var_or;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::ops::Deref;
+use std::{fmt::Display, ops::Deref};
 use thiserror::Error;
 
+/// Maximum amount of characters allowed in a `NonEmptyString`.
+pub static MAX_LENGTH: Lazy<usize> =
+  Lazy::new(|| env_var_or("STRING_MAX_LENGTH", 255));
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct NonEmptyString(String);
 
-impl Default for NonEmptyString {
-  fn default() -> Self {
-    Self("Empty String".into())
-  }
-}
-
 impl NonEmptyString {
+  /// Trims `s` and validates it's not empty, not too long and free of path
+  /// separators or control characters.
   fn try_from_str(s: &str) -> StringResult<Self> {
+    let s = s.trim();
     if s.is_empty() {
-      Err(StringError::Empty)
-    } else {
-      Ok(NonEmptyString(s.to_string()))
+      return Err(StringError::Empty);
+    }
+    let length = s.chars().count();
+    if length > *MAX_LENGTH {
+      return Err(StringError::TooLong {
+        max: *MAX_LENGTH,
+        actual: length,
+      });
+    }
+    if let Some(c) = s.chars().find(|&c| is_invalid_char(c)) {
+      return Err(StringError::InvalidChar(c));
     }
+    Ok(NonEmptyString(s.to_string()))
   }
 }
 
+fn is_invalid_char(c: char) -> bool {
+  c == '/' || c == '\\' || c.is_control()
+}
+
 impl Deref for NonEmptyString {
   type Target = String;
   fn deref(&self) -> &Self::Target {
@@ -28,6 +44,30 @@ impl Deref for NonEmptyString {
   }
 }
 
+impl Display for NonEmptyString {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl AsRef<str> for NonEmptyString {
+  fn as_ref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl PartialEq<str> for NonEmptyString {
+  fn eq(&self, other: &str) -> bool {
+    self.0 == other
+  }
+}
+
+impl PartialEq<&str> for NonEmptyString {
+  fn eq(&self, other: &&str) -> bool {
+    self.0 == *other
+  }
+}
+
 impl TryFrom<String> for NonEmptyString {
   type Error = StringError;
 
@@ -56,6 +96,10 @@ impl TryFrom<&str> for NonEmptyString {
 pub enum StringError {
   #[error("String cannot be empty")]
   Empty,
+  #[error("String cannot be longer than {max} characters, got {actual}")]
+  TooLong { max: usize, actual: usize },
+  #[error("String cannot contain {0:?}")]
+  InvalidChar(char),
 }
 
 pub type StringResult<T = ()> = Result<T, StringError>;
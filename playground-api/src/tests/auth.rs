@@ -0,0 +1,466 @@
+#![cfg(test)]
+use super::{
+  cleanup_shares_collection, get_database, serve, set_test_env, USER_ID1,
+};
+use crate::{
+  api::APIError,
+  auth::{
+    google::{check_oauth_state_cookie, login_response},
+    jwt::{self, Scope},
+    session::{
+      ReadSession, Session, SessionQuery, SessionToken, SESSIONS_CACHE,
+    },
+  },
+  db::shares::{save_share, Share},
+  logout,
+};
+use axum::{
+  extract::FromRequestParts,
+  http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+  routing::{delete, get},
+  Router,
+};
+use chrono::Duration;
+use format as f;
+
+async fn extract_session_query(
+  request: Request<()>,
+) -> Result<SessionQuery, APIError> {
+  let (mut parts, _) = request.into_parts();
+  SessionQuery::from_request_parts(&mut parts, &()).await
+}
+
+#[tokio::test]
+async fn it_authenticates_websockets_with_header_token() {
+  set_test_env();
+  let token = jwt::sign_token(USER_ID1).expect("Failed to sign token");
+  Session::save(&token, USER_ID1).await;
+
+  let request = Request::builder()
+    .uri("/ws")
+    .header("Authorization", f!("Bearer {token}"))
+    .body(())
+    .expect("Failed to build request");
+  let result = extract_session_query(request).await;
+  Session::invalidate(&token).await;
+
+  let SessionQuery(session) =
+    result.expect("Expected header token to be accepted");
+  assert!(
+    session.user_id == USER_ID1,
+    "Expected session for {USER_ID1:?}, instead got {:?}",
+    session.user_id
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_websockets_without_token() {
+  set_test_env();
+  let request = Request::builder()
+    .uri("/ws")
+    .body(())
+    .expect("Failed to build request");
+  let result = extract_session_query(request).await;
+  assert!(
+    matches!(result, Err(APIError::UnauthorizedMessage(_))),
+    "Expected upgrade without token to be unauthorized"
+  );
+}
+
+#[tokio::test]
+async fn it_refreshes_valid_tokens() {
+  set_test_env();
+  let token = jwt::sign_token_with_expiry(USER_ID1, Duration::hours(1))
+    .expect("Failed to sign token");
+  Session::save(&token, USER_ID1).await;
+
+  let new_token = Session::refresh(&token)
+    .await
+    .expect("Expected valid token to be refreshed");
+  let cache = SESSIONS_CACHE.lock().await;
+  let (has_old, has_new) =
+    (cache.contains_key(&token), cache.contains_key(&new_token));
+  drop(cache);
+  Session::invalidate(&new_token).await;
+
+  assert!(!has_old, "Expected old token to be removed from sessions");
+  assert!(has_new, "Expected refreshed token to be saved in sessions");
+  let claims = jwt::verify_token(&new_token)
+    .expect("Expected refreshed token to be valid")
+    .claims;
+  assert!(
+    claims.sub == USER_ID1,
+    "Expected refreshed token for {USER_ID1:?}, instead got {:?}",
+    claims.sub
+  );
+}
+
+#[tokio::test]
+async fn it_refreshes_a_token_only_once() {
+  set_test_env();
+  let token = jwt::sign_token_with_expiry(USER_ID1, Duration::hours(1))
+    .expect("Failed to sign token");
+  Session::save(&token, USER_ID1).await;
+
+  let refreshes = (0..8).map(|_| {
+    let token = token.clone();
+    tokio::spawn(async move { Session::refresh(&token).await })
+  });
+  let results = futures::future::join_all(refreshes).await;
+  let new_tokens = results
+    .into_iter()
+    .filter_map(|result| result.ok()?.ok())
+    .collect::<Vec<_>>();
+  for new_token in &new_tokens {
+    Session::invalidate(new_token).await;
+  }
+
+  assert!(
+    new_tokens.len() == 1,
+    "Expected a single refresh to succeed, instead got {}",
+    new_tokens.len()
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_refreshing_expired_tokens() {
+  set_test_env();
+  let token = jwt::sign_token_with_expiry(USER_ID1, Duration::minutes(-5))
+    .expect("Failed to sign token");
+  Session::save(&token, USER_ID1).await;
+
+  let result = Session::refresh(&token).await;
+  let still_cached = SESSIONS_CACHE.lock().await.contains_key(&token);
+  Session::invalidate(&token).await;
+
+  assert!(
+    matches!(result, Err(APIError::Jwt(_))),
+    "Expected expired token refresh to fail, instead got {result:?}"
+  );
+  assert!(
+    !still_cached,
+    "Expected expired token to be removed from sessions"
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_tokens_after_expiry() {
+  set_test_env();
+  let token = jwt::sign_token_with_expiry(USER_ID1, Duration::seconds(1))
+    .expect("Failed to sign token");
+  assert!(
+    jwt::verify_token(&token).is_ok(),
+    "Expected token to be valid before expiring"
+  );
+
+  tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+  let result = jwt::verify_token(&token);
+  assert!(
+    result.is_err(),
+    "Expected token to be invalid after expiring, instead got {result:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_revokes_every_session_of_a_user() {
+  set_test_env();
+  // Other tests share the sessions cache so these users must be unique
+  let (revoked_user, kept_user) = ("google@revoke-all", "google@revoke-keep");
+  let tokens = [
+    (revoked_user, Duration::hours(1)),
+    (revoked_user, Duration::hours(2)),
+    (kept_user, Duration::hours(1)),
+  ]
+  .map(|(user_id, expiry)| {
+    let token = jwt::sign_token_with_expiry(user_id, expiry)
+      .expect("Failed to sign token");
+    (token, user_id)
+  });
+  for (token, user_id) in &tokens {
+    Session::save(token, user_id).await;
+  }
+
+  let revoked = Session::invalidate_all(revoked_user).await;
+  let cache = SESSIONS_CACHE.lock().await;
+  let remaining = tokens
+    .iter()
+    .map(|(token, _)| cache.contains_key(token))
+    .collect::<Vec<_>>();
+  drop(cache);
+  Session::invalidate(&tokens[2].0).await;
+
+  assert!(
+    revoked == 2,
+    "Expected 2 revoked sessions, instead got {revoked}"
+  );
+  assert!(
+    remaining == [false, false, true],
+    "Expected only {kept_user:?} session to remain, instead got {remaining:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_logs_in_with_a_secure_session_cookie() {
+  set_test_env();
+  let login_redirect = "http://localhost/login";
+  let token = jwt::sign_token(USER_ID1).expect("Failed to sign token");
+  Session::save(&token, USER_ID1).await;
+
+  let response = login_response(login_redirect, &token, false)
+    .expect("Failed to build login response");
+  let headers = response.headers();
+  let location = headers.get(header::LOCATION);
+  let cookie = headers
+    .get(header::SET_COOKIE)
+    .and_then(|cookie| cookie.to_str().ok())
+    .unwrap_or_default()
+    .to_string();
+  assert!(
+    location.is_some_and(|location| location == login_redirect),
+    "Expected redirect to {login_redirect:?} without the token, instead got {location:?}"
+  );
+  assert!(
+    cookie.starts_with(&f!("access_token={token};"))
+      && ["HttpOnly", "Secure", "SameSite=Lax"]
+        .iter()
+        .all(|attribute| cookie.contains(attribute)),
+    "Expected HttpOnly, Secure and SameSite session cookie, instead got {cookie:?}"
+  );
+
+  let cookie_pair = cookie.split(';').next().unwrap_or_default();
+  let request = Request::builder()
+    .uri("/files")
+    .header(header::COOKIE, f!("theme=dark; {cookie_pair}"))
+    .body(())
+    .expect("Failed to build request");
+  let (mut parts, _) = request.into_parts();
+  let result = Session::from_request_parts(&mut parts, &()).await;
+  Session::invalidate(&token).await;
+  assert!(
+    result
+      .as_ref()
+      .is_ok_and(|session| session.user_id == USER_ID1),
+    "Expected session cookie to be accepted, instead got {:?}",
+    result.map(|session| session.user_id)
+  );
+}
+
+#[tokio::test]
+async fn it_finishes_logins_only_in_the_browser_that_started_them() {
+  let with_cookie = |cookie: &'static str| {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::COOKIE, HeaderValue::from_static(cookie));
+    headers
+  };
+  let results = [
+    with_cookie("theme=dark; oauth_state=started"),
+    with_cookie("oauth_state=forged"),
+    HeaderMap::new(),
+  ]
+  .map(|headers| check_oauth_state_cookie(&headers, "started").is_ok());
+
+  assert!(
+    results == [true, false, false],
+    "Expected only the matching state cookie to pass, instead got {results:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_keeps_the_token_in_the_url_in_query_mode() {
+  let response = login_response("http://localhost/login", "token", true)
+    .expect("Failed to build login response");
+  let headers = response.headers();
+  let location = headers.get(header::LOCATION);
+  assert!(
+    location.is_some_and(|location| {
+      location == "http://localhost/login?access_token=token"
+    }) && headers.get(header::SET_COOKIE).is_none(),
+    "Expected token in the redirect url and no cookie, instead got {headers:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_clears_the_session_cookie_on_logout() {
+  set_test_env();
+  let token = jwt::sign_token(USER_ID1).expect("Failed to sign token");
+  Session::save(&token, USER_ID1).await;
+
+  let request = Request::builder()
+    .uri("/logout")
+    .header(header::COOKIE, f!("access_token={token}"))
+    .body(())
+    .expect("Failed to build request");
+  let (mut parts, _) = request.into_parts();
+  let token_from_cookie = SessionToken::from_request_parts(&mut parts, &())
+    .await
+    .expect("Expected token from the session cookie");
+  let response = logout(token_from_cookie).await.expect("Failed to log out");
+
+  let cookie = response
+    .headers()
+    .get(header::SET_COOKIE)
+    .and_then(|cookie| cookie.to_str().ok())
+    .unwrap_or_default();
+  assert!(
+    response.status() == StatusCode::NO_CONTENT
+      && cookie.starts_with("access_token=;")
+      && cookie.contains("Max-Age=0"),
+    "Expected logout to clear the session cookie, instead got {} with {cookie:?}",
+    response.status()
+  );
+  let has_session = SESSIONS_CACHE.lock().await.contains_key(&token);
+  assert!(!has_session, "Expected logout to invalidate the session");
+}
+
+#[tokio::test]
+async fn it_rejects_malformed_authorization_headers() {
+  set_test_env();
+  let app = Router::new().route(
+    "/session",
+    get(|session: Session| async { session.user_id }),
+  );
+  let url = f!("{}/session", serve(app));
+  let client = reqwest::Client::new();
+  for authorization in [
+    HeaderValue::from_bytes(b"Bearer \xff\xfe").expect("Valid header bytes"),
+    HeaderValue::from_static("Basic dXNlcjpwYXNz"),
+    HeaderValue::from_static("Bearer"),
+  ] {
+    // A failed request would mean the server went down
+    let status = client
+      .get(&url)
+      .header(header::AUTHORIZATION, authorization.clone())
+      .send()
+      .await
+      .expect("Request with malformed header failed")
+      .status();
+    assert!(
+      status == StatusCode::UNAUTHORIZED,
+      "Expected {authorization:?} to be unauthorized, instead got {status}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_lets_guests_read_but_not_delete() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let share = Share::new(USER_ID1, "shared-folder", None);
+  save_share(&share, &database)
+    .await
+    .expect("Failed to save share");
+  let app = Router::new()
+    .route(
+      "/files",
+      get(|session: ReadSession| async move {
+        session.shared_folder_id.unwrap_or_default()
+      })
+      .merge(delete(|session: Session| async move { session.user_id })),
+    )
+    .with_state(database.clone());
+  let url = f!("{}/files", serve(app));
+  let token = jwt::sign_guest_token(
+    USER_ID1,
+    &share._id,
+    "shared-folder",
+    Scope::Read,
+    Duration::minutes(5),
+  )
+  .expect("Failed to sign guest token");
+  let client = reqwest::Client::new();
+
+  let response = client
+    .get(&url)
+    .bearer_auth(&token)
+    .send()
+    .await
+    .expect("Failed to send guest read");
+  let read_status = response.status();
+  let body = response.text().await.unwrap_or_default();
+  let delete_status = client
+    .delete(&url)
+    .bearer_auth(&token)
+    .send()
+    .await
+    .expect("Failed to send guest delete")
+    .status();
+  cleanup_shares_collection(&database).await;
+
+  assert!(
+    read_status == StatusCode::OK && body == "shared-folder",
+    "Expected guest to read the shared folder, instead got {read_status} with {body:?}"
+  );
+  assert!(
+    delete_status == StatusCode::FORBIDDEN,
+    "Expected guest delete to be forbidden, instead got {delete_status}"
+  );
+}
+
+#[test]
+fn it_tells_guest_tokens_apart_from_user_tokens() {
+  set_test_env();
+  let user_token = jwt::sign_token(USER_ID1).expect("Failed to sign token");
+  let guest = jwt::verify_guest_token(&user_token);
+  assert!(
+    guest.is_err(),
+    "Expected user token to not be a guest token"
+  );
+
+  let token = jwt::sign_guest_token(
+    USER_ID1,
+    "share-id",
+    "shared-folder",
+    Scope::Read,
+    Duration::minutes(5),
+  )
+  .expect("Failed to sign guest token");
+  let claims = jwt::verify_guest_token(&token).map(|data| data.claims);
+  let matches = claims.as_ref().is_ok_and(|claims| {
+    claims.share_id == "share-id"
+      && claims.folder_id == "shared-folder"
+      && claims.scope == Scope::Read
+  });
+  assert!(matches, "Expected guest claims, instead got {claims:?}");
+
+  let expired = jwt::sign_guest_token(
+    USER_ID1,
+    "share-id",
+    "shared-folder",
+    Scope::Read,
+    Duration::seconds(-60),
+  )
+  .expect("Failed to sign guest token");
+  let claims = jwt::verify_guest_token(&expired);
+  assert!(
+    claims.is_err(),
+    "Expected expired guest token to fail, instead got {claims:?}"
+  );
+}
+
+#[test]
+fn it_limits_guests_to_the_shared_folder() {
+  let guest = ReadSession {
+    user_id: USER_ID1.to_string(),
+    shared_folder_id: Some("shared".to_string()),
+  };
+  let owner = ReadSession {
+    user_id: USER_ID1.to_string(),
+    shared_folder_id: None,
+  };
+  for (lineage, expected) in [
+    (vec![USER_ID1, "shared"], true),
+    (vec![USER_ID1, "shared", "nested", "video"], true),
+    (vec![USER_ID1, "sibling", "video"], false),
+    (vec![USER_ID1], false),
+  ] {
+    let can_read = guest.can_read(lineage.iter().copied());
+    assert!(
+      can_read == expected,
+      "Expected guest reading {lineage:?} to be {expected}, instead got {can_read}"
+    );
+    assert!(
+      owner.can_read(lineage.iter().copied()),
+      "Expected owner to read {lineage:?}"
+    );
+  }
+}
@@ -0,0 +1,99 @@
+#![cfg(test)]
+use crate::cache::TtlCache;
+use format as f;
+use std::{
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+async fn fetch_count(
+  cache: &TtlCache<usize, ()>,
+  fetches: &Arc<AtomicUsize>,
+) -> Result<usize, Arc<()>> {
+  let fetches = fetches.clone();
+  cache
+    .get_or_fetch("video", || async move {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+      Ok(fetches.fetch_add(1, Ordering::SeqCst) + 1)
+    })
+    .await
+}
+
+#[tokio::test]
+async fn it_refetches_entries_past_their_ttl() {
+  let cache = TtlCache::new(Duration::from_millis(50));
+  let fetches = Arc::new(AtomicUsize::new(0));
+
+  let first = fetch_count(&cache, &fetches).await;
+  let cached = fetch_count(&cache, &fetches).await;
+  assert!(
+    first == Ok(1) && cached == Ok(1),
+    "Expected second read to hit the cache, instead got {first:?} and {cached:?}"
+  );
+
+  tokio::time::sleep(Duration::from_millis(60)).await;
+  let refetched = fetch_count(&cache, &fetches).await;
+  assert!(
+    refetched == Ok(2),
+    "Expected expired entry to be refetched, instead got {refetched:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_coalesces_concurrent_fetches_for_the_same_key() {
+  let cache = Arc::new(TtlCache::new(Duration::from_secs(60)));
+  let fetches = Arc::new(AtomicUsize::new(0));
+
+  let requests = (0..16).map(|_| {
+    let (cache, fetches) = (cache.clone(), fetches.clone());
+    tokio::spawn(async move { fetch_count(&cache, &fetches).await })
+  });
+  let results = futures::future::join_all(requests).await;
+
+  let upstream_calls = fetches.load(Ordering::SeqCst);
+  assert!(
+    upstream_calls == 1,
+    "Expected a single upstream call, instead got {upstream_calls}"
+  );
+  assert!(
+    results.iter().all(|result| matches!(result, Ok(Ok(1)))),
+    "Expected every request to share the fetched value, instead got {results:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_sweeps_expired_entries_of_other_keys() {
+  let cache = TtlCache::<usize, ()>::new(Duration::from_millis(50));
+  for key in 0..8 {
+    cache.insert(&f!("file-{key}"), key).await;
+  }
+
+  tokio::time::sleep(Duration::from_millis(60)).await;
+  cache.insert("fresh", 8).await;
+  let len = cache.len().await;
+  assert!(
+    len == 1,
+    "Expected only the fresh entry left, instead got {len}"
+  );
+}
+
+#[tokio::test]
+async fn it_removes_entries_by_key_prefix() {
+  let cache = TtlCache::<usize, ()>::new(Duration::from_secs(60));
+  cache.insert("account/video", 1).await;
+  cache.insert("account/thumbnail", 2).await;
+  cache.insert("other/video", 3).await;
+
+  cache.remove_prefix("account/").await;
+  let (removed, kept) = (
+    cache.get("account/video").await,
+    cache.get("other/video").await,
+  );
+  assert!(
+    removed.is_none() && kept == Some(3),
+    "Expected only the prefixed entries removed, instead got {removed:?} and {kept:?}"
+  );
+}
@@ -0,0 +1,69 @@
+#![cfg(test)]
+use crate::console::{format_line, Colorize, LogLevel};
+use chrono::{TimeZone, Utc};
+
+fn timestamp() -> chrono::DateTime<Utc> {
+  Utc.with_ymd_and_hms(2023, 1, 2, 3, 4, 5).unwrap()
+}
+
+#[test]
+fn it_detects_level_from_styles() {
+  let levels = [
+    LogLevel::from_styles(&["info"]),
+    LogLevel::from_styles(&["bold", "err"]),
+    LogLevel::from_styles(&["success"]),
+    LogLevel::from_styles(&["bold"]),
+  ];
+  let expected = [
+    LogLevel::Info,
+    LogLevel::Err,
+    LogLevel::Success,
+    LogLevel::Log,
+  ];
+  assert!(
+    levels == expected,
+    "Expected {expected:?}, instead got {levels:?}"
+  );
+}
+
+#[test]
+fn it_prefixes_console_lines_with_timestamp_and_level() {
+  let colored = "Server started".success();
+  let line = format_line(
+    LogLevel::Success,
+    "playground_api",
+    "Server started",
+    &colored,
+    timestamp(),
+    false,
+  );
+  let expected = format!("2023-01-02T03:04:05.000Z SUCCESS {colored}");
+  assert!(
+    line == expected,
+    "Expected {expected:?}, instead got {line:?}"
+  );
+}
+
+#[test]
+fn it_formats_json_lines() {
+  let line = format_line(
+    LogLevel::Err,
+    "playground_api::db",
+    "Connection \"lost\"",
+    &"Connection \"lost\"".err(),
+    timestamp(),
+    true,
+  );
+  let json = serde_json::from_str::<serde_json::Value>(&line);
+  let expected = serde_json::json!({
+    "timestamp": "2023-01-02T03:04:05.000Z",
+    "level": "ERR",
+    "target": "playground_api::db",
+    "message": "Connection \"lost\"",
+  });
+  assert!(
+    json.as_ref().is_ok_and(|json| *json == expected),
+    "Expected {expected}, instead got {line:?}"
+  );
+  assert!(!line.contains('\n'), "Expected a single line, got {line:?}");
+}
@@ -0,0 +1,70 @@
+#![cfg(test)]
+use super::serve;
+use crate::{cors, AppError};
+use axum::{http::HeaderMap, routing::get, Router};
+
+async fn preflight(layer: tower_http::cors::CorsLayer) -> HeaderMap {
+  let app = Router::new()
+    .route("/", get(|| async { "OK" }))
+    .layer(layer);
+  let url = serve(app);
+  let response = reqwest::Client::new()
+    .request(reqwest::Method::OPTIONS, url)
+    .header("Origin", "https://app.example.com")
+    .header("Access-Control-Request-Method", "PATCH")
+    .header("Access-Control-Request-Headers", "authorization")
+    .send()
+    .await
+    .expect("Preflight request failed");
+  response.headers().clone()
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+  headers
+    .get(name)
+    .and_then(|value| value.to_str().ok())
+    .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn it_answers_preflight_with_explicit_methods_and_credentials() {
+  let layer = cors::build(
+    Some("https://app.example.com,https://other.example.com"),
+    None,
+    true,
+  )
+  .expect("Valid CORS config");
+  let headers = preflight(layer).await;
+
+  let origin = header(&headers, "access-control-allow-origin");
+  assert!(
+    origin == "https://app.example.com",
+    "Expected requesting origin to be allowed, instead got {origin:?}"
+  );
+  let credentials = header(&headers, "access-control-allow-credentials");
+  assert!(
+    credentials == "true",
+    "Expected credentials to be allowed, instead got {credentials:?}"
+  );
+  let methods = header(&headers, "access-control-allow-methods");
+  assert!(
+    methods == "GET,POST,PUT,PATCH,DELETE",
+    "Expected explicit methods, instead got {methods:?}"
+  );
+  let allowed_headers = header(&headers, "access-control-allow-headers");
+  assert!(
+    allowed_headers.contains("authorization"),
+    "Expected authorization header to be allowed, instead got {allowed_headers:?}"
+  );
+}
+
+#[test]
+fn it_rejects_credentials_for_any_origin() {
+  for origins in [None, Some("*")] {
+    let result = cors::build(origins, None, true);
+    assert!(
+      matches!(result, Err(AppError::Cors(_))),
+      "Expected wildcard origin with credentials to be rejected for {origins:?}"
+    );
+  }
+}
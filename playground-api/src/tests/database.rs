@@ -0,0 +1,89 @@
+#![cfg(test)]
+use crate::db::{slow_query_message, PoolConfig};
+use mongodb::{bson::doc, options::ClientOptions};
+use std::time::Duration;
+
+async fn parse(uri: &str) -> ClientOptions {
+  ClientOptions::parse(uri)
+    .await
+    .expect("Failed to parse client options")
+}
+
+#[tokio::test]
+async fn it_applies_pool_config_over_uri_options() {
+  let mut options =
+    parse("mongodb://localhost/?maxPoolSize=50&serverSelectionTimeoutMS=2000")
+      .await;
+  PoolConfig {
+    max_pool_size: Some(5),
+    connect_timeout: Some(Duration::from_secs(3)),
+    server_selection_timeout: None,
+  }
+  .apply(&mut options);
+
+  assert!(
+    options.max_pool_size == Some(5),
+    "Expected max pool size from config, instead got {:?}",
+    options.max_pool_size
+  );
+  assert!(
+    options.connect_timeout == Some(Duration::from_secs(3)),
+    "Expected connect timeout from config, instead got {:?}",
+    options.connect_timeout
+  );
+  assert!(
+    options.server_selection_timeout == Some(Duration::from_secs(2)),
+    "Expected server selection timeout from uri, instead got {:?}",
+    options.server_selection_timeout
+  );
+}
+
+#[tokio::test]
+async fn it_defaults_unset_pool_options() {
+  let mut options = parse("mongodb://localhost").await;
+  PoolConfig::default().apply(&mut options);
+
+  assert!(
+    options.max_pool_size.is_some()
+      && options.connect_timeout.is_some()
+      && options.server_selection_timeout.is_some(),
+    "Expected every pool option to be set, instead got {:?} {:?} {:?}",
+    options.max_pool_size,
+    options.connect_timeout,
+    options.server_selection_timeout
+  );
+}
+
+#[test]
+fn it_logs_queries_slower_than_the_threshold() {
+  let query = [doc! { "userId": "user", "title": "a".repeat(300) }];
+  let message = slow_query_message(
+    "find_many",
+    "files",
+    &query,
+    Duration::from_millis(5),
+    Duration::from_millis(1),
+  )
+  .expect("Expected a slow query message");
+
+  assert!(
+    message.starts_with("Slow find_many on files took 5ms: { \"userId\""),
+    "Expected operation, collection and query in message, instead got {message:?}"
+  );
+  assert!(
+    message.ends_with("aaa…"),
+    "Expected a truncated query in message, instead got {message:?}"
+  );
+
+  let message = slow_query_message(
+    "find_many",
+    "files",
+    &query,
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+  );
+  assert!(
+    message.is_none(),
+    "Expected no message for a fast query, instead got {message:?}"
+  );
+}
@@ -0,0 +1,535 @@
+#![cfg(test)]
+use super::{
+  cleanup_files_collection, get_database, insert_many, read_body, serve,
+  USER_ID1, USER_ID2,
+};
+use crate::{
+  api::{
+    google::{
+      get_file, list_videos, DriveAccount, DriveClient, DriveFile,
+      DrivePageQuery,
+    },
+    APIError, APIResult,
+  },
+  archive::zip_response,
+  db::files::{File, FileMetadata, Video},
+  routes::files::{
+    archive_entries, download_attachment, fetch_video_metadata,
+    list_accounts_videos,
+  },
+  GracefulExit,
+};
+use async_zip::tokio::read::seek::ZipFileReader;
+use axum::{
+  async_trait,
+  body::HttpBody,
+  extract::{Query, State},
+  http::{HeaderMap, StatusCode},
+  response::{IntoResponse, Response},
+  routing::get,
+  Json, Router,
+};
+use format as f;
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
+use tokio::sync::Mutex;
+
+type Received = Arc<Mutex<Vec<(HashMap<String, String>, String)>>>;
+
+/// Drive `files.list` stub returning one video and the next page token.
+async fn drive_files(
+  State(received): State<Received>,
+  Query(query): Query<HashMap<String, String>>,
+  headers: HeaderMap,
+) -> Json<serde_json::Value> {
+  let authorization = headers
+    .get("Authorization")
+    .and_then(|value| value.to_str().ok())
+    .unwrap_or_default()
+    .to_string();
+  received.lock().await.push((query, authorization));
+  Json(serde_json::json!({
+    "nextPageToken": "page-3",
+    "files": [{
+      "id": "video-id",
+      "name": "Video.mp4",
+      "mimeType": "video/mp4",
+      "size": "1024",
+      "videoMediaMetadata": {
+        "width": 1920,
+        "height": 1080,
+        "durationMillis": "60000"
+      }
+    }]
+  }))
+}
+
+/// Drive stub that rejects the "revoked" access token.
+async fn drive_files_unless_revoked(
+  state: State<Received>,
+  query: Query<HashMap<String, String>>,
+  headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+  if headers
+    .get("Authorization")
+    .is_some_and(|value| value == "Bearer revoked")
+  {
+    return Err(StatusCode::UNAUTHORIZED);
+  }
+  Ok(drive_files(state, query, headers).await)
+}
+
+/// Drive `files.get` stub naming the file after the token it was fetched
+/// with.
+async fn drive_file(
+  State(hits): State<Arc<AtomicUsize>>,
+  headers: HeaderMap,
+) -> Json<serde_json::Value> {
+  hits.fetch_add(1, Ordering::SeqCst);
+  let authorization = headers
+    .get("Authorization")
+    .and_then(|value| value.to_str().ok())
+    .unwrap_or_default();
+  Json(serde_json::json!({
+    "name": f!("Seen with {authorization}"),
+    "mimeType": "video/mp4",
+  }))
+}
+
+#[tokio::test]
+async fn it_keeps_drive_files_of_accounts_apart() {
+  let hits = Arc::new(AtomicUsize::new(0));
+  let app = Router::new()
+    .route("/files/:file_id", get(drive_file))
+    .with_state(hits.clone());
+  let api_url = serve(app);
+
+  let client = reqwest::Client::new();
+  let mut names = Vec::new();
+  for (id, access_token) in [
+    ("account-1", "token-1"),
+    ("account-2", "token-2"),
+    ("account-1", ""),
+  ] {
+    let account = DriveAccount::Linked { id, access_token };
+    let file = get_file(&api_url, "cached-video-id", account, &client)
+      .await
+      .expect("Failed to get file");
+    names.push(file.name);
+  }
+
+  let expected = ["token-1", "token-2", "token-1"]
+    .map(|token| f!("Seen with Bearer {token}"));
+  assert!(
+    names == expected,
+    "Expected each account to get its own file, instead got {names:?}"
+  );
+  let hits = hits.load(Ordering::SeqCst);
+  assert!(
+    hits == 2,
+    "Expected the repeated account to be served from the cache, instead got {hits} requests"
+  );
+}
+
+#[tokio::test]
+async fn it_forwards_paging_params_and_returns_next_page_token() {
+  let received = Received::default();
+  let app = Router::new()
+    .route("/files", get(drive_files))
+    .with_state(received.clone());
+  let api_url = serve(app);
+
+  let page = list_videos(
+    &api_url,
+    "access-token",
+    &DrivePageQuery {
+      page_token: Some("page-2".into()),
+      page_size: Some(25),
+    },
+    &reqwest::Client::new(),
+  )
+  .await
+  .expect("Failed to list videos");
+
+  let received = received.lock().await;
+  let (query, authorization) = &received[0];
+  assert!(
+    query
+      .get("pageToken")
+      .is_some_and(|token| token == "page-2")
+      && query.get("pageSize").is_some_and(|size| size == "25"),
+    "Expected paging params to be forwarded, instead got {query:?}"
+  );
+  assert!(
+    authorization == "Bearer access-token",
+    "Expected provider access token, instead got {authorization:?}"
+  );
+  assert!(
+    page.next_page_token.as_deref() == Some("page-3"),
+    "Expected next page token, instead got {:?}",
+    page.next_page_token
+  );
+  assert!(
+    page.files.len() == 1 && page.files[0].id == "video-id",
+    "Expected listed video, instead got {:#?}",
+    page.files
+  );
+}
+
+#[tokio::test]
+async fn it_lists_other_accounts_when_one_fails() {
+  let app = Router::new()
+    .route("/files", get(drive_files_unless_revoked))
+    .with_state(Received::default());
+  let api_url = serve(app);
+
+  let accounts = [("ok", "access-token"), ("bad", "revoked")]
+    .map(|(id, token)| (id.to_string(), token.to_string()));
+  let response = list_accounts_videos(
+    &api_url,
+    accounts,
+    &DrivePageQuery {
+      page_token: None,
+      page_size: None,
+    },
+    &reqwest::Client::new(),
+  )
+  .await;
+
+  let json = serde_json::to_value(&response).expect("Failed to serialize");
+  let accounts = json["accounts"].as_array();
+  assert!(
+    accounts.is_some_and(|accounts| accounts.len() == 1
+      && accounts[0]["accountId"] == "ok"
+      && accounts[0]["files"][0]["id"] == "video-id"),
+    "Expected the working account's videos, instead got {json}"
+  );
+  let errors = json["errors"].as_object();
+  assert!(
+    errors
+      .is_some_and(|errors| errors.len() == 1 && errors.contains_key("bad")),
+    "Expected an error for the revoked account, instead got {json}"
+  );
+}
+
+fn parse_duration(metadata: serde_json::Value) -> Option<u64> {
+  let file: DriveFile = serde_json::from_value(serde_json::json!({
+    "name": "Video.mp4",
+    "mimeType": "video/mp4",
+    "videoMediaMetadata": metadata,
+  }))
+  .expect("Failed to deserialize drive file");
+  file
+    .video_metadata
+    .and_then(|metadata| metadata.duration_millis)
+}
+
+#[test]
+fn it_parses_string_and_numeric_durations() {
+  for metadata in [
+    serde_json::json!({ "width": 1, "height": 1, "durationMillis": "60000" }),
+    serde_json::json!({ "width": 1, "height": 1, "durationMillis": 60000 }),
+  ] {
+    let duration = parse_duration(metadata);
+    assert!(
+      duration == Some(60000),
+      "Expected 60000ms duration, instead got {duration:?}"
+    );
+  }
+}
+
+#[test]
+fn it_treats_missing_and_empty_durations_as_none() {
+  for metadata in [
+    serde_json::json!({ "width": 1, "height": 1 }),
+    serde_json::json!({ "width": 1, "height": 1, "durationMillis": "" }),
+    serde_json::json!({ "width": 1, "height": 1, "durationMillis": null }),
+  ] {
+    let duration = parse_duration(metadata);
+    assert!(
+      duration.is_none(),
+      "Expected no duration, instead got {duration:?}"
+    );
+  }
+}
+
+#[test]
+fn it_omits_missing_duration_from_responses() {
+  let metadata = serde_json::json!({ "width": 1, "height": 1 });
+  let file: DriveFile = serde_json::from_value(serde_json::json!({
+    "name": "Video.mp4",
+    "mimeType": "video/mp4",
+    "videoMediaMetadata": metadata,
+  }))
+  .expect("Failed to deserialize drive file");
+  let json = serde_json::to_value(&file).expect("Failed to serialize");
+  assert!(
+    json["videoMetadata"].get("durationMillis").is_none(),
+    "Expected durationMillis to be omitted, instead got {json}"
+  );
+}
+
+/// Drive client that knows a single file, which can only be downloaded.
+#[derive(Debug)]
+struct MockDrive(DriveFile);
+
+#[async_trait]
+impl DriveClient for MockDrive {
+  async fn get_file(
+    &self,
+    file_id: &str,
+    _: DriveAccount<'_>,
+  ) -> APIResult<DriveFile> {
+    if file_id == self.0.id {
+      Ok(self.0.clone())
+    } else {
+      Err(APIError::NotFound(f!("File {file_id:?} not found")))
+    }
+  }
+
+  async fn stream_range(
+    &self,
+    _: &str,
+    _: HeaderMap,
+    _: Option<usize>,
+    _: Option<usize>,
+  ) -> APIResult<Response> {
+    Err(APIError::Internal("Streaming is not mocked".into()))
+  }
+
+  async fn download(&self, file_id: &str) -> APIResult<Response> {
+    self.get_file(file_id, DriveAccount::Public).await?;
+    Ok(([("Content-Type", "video/mp4")], "video").into_response())
+  }
+}
+
+fn mock_file(metadata: Option<serde_json::Value>) -> MockDrive {
+  let mut file = serde_json::json!({
+    "id": "video-id",
+    "name": "Video.mp4",
+    "mimeType": "video/mp4",
+    "size": "1024",
+  });
+  if let Some(metadata) = metadata {
+    file["videoMediaMetadata"] = metadata;
+  }
+  MockDrive(
+    serde_json::from_value(file).expect("Failed to deserialize drive file"),
+  )
+}
+
+#[tokio::test]
+async fn it_maps_drive_files_to_videos() {
+  let drive = mock_file(Some(serde_json::json!({
+    "width": 1920,
+    "height": 1080,
+    "durationMillis": "60000",
+  })));
+  let video = fetch_video_metadata(
+    &drive,
+    "https://drive.google.com/file/d/video-id/view?usp=sharing",
+    DriveAccount::Public,
+  )
+  .await
+  .expect("Failed to fetch video metadata");
+
+  assert!(
+    video.play_id == "video-id"
+      && video.name == "Video.mp4"
+      && video.mime_type == "video/mp4"
+      && video.size_bytes == 1024
+      && (video.width, video.height) == (1920, 1080)
+      && video.duration_millis == Some(60000)
+      && video.thumbnail.ends_with("/files/thumbnail/video-id"),
+    "Expected the drive file's fields, instead got {video:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_drive_videos_with_other_mime_types() {
+  let mut drive = mock_file(Some(serde_json::json!({
+    "width": 1920,
+    "height": 1080,
+  })));
+  drive.0.mime_type = "image/gif".into();
+  let result =
+    fetch_video_metadata(&drive, "video-id", DriveAccount::Public).await;
+  assert!(
+    matches!(&result, Err(APIError::BadRequest(message)) if message.contains("\"image/gif\"")),
+    "Expected a bad request naming the mime type, instead got {result:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_drive_files_that_are_not_videos() {
+  let result =
+    fetch_video_metadata(&mock_file(None), "video-id", DriveAccount::Public)
+      .await;
+  assert!(
+    matches!(result, Err(APIError::BadRequest(_))),
+    "Expected a bad request for a file without video metadata, instead got {result:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_downloads_videos_as_attachments() {
+  let response =
+    download_attachment(&mock_file(None), "video-id", "My \"clip\"\n.mp4")
+      .await
+      .expect("Failed to download video");
+
+  let status = response.status();
+  let disposition = response.headers().get("Content-Disposition").cloned();
+  assert!(
+    status == StatusCode::OK
+      && disposition
+        .as_ref()
+        .is_some_and(|value| value == "attachment; filename=\"My _clip__.mp4\""),
+    "Expected a sanitized attachment, instead got {status} {disposition:?}"
+  );
+}
+
+fn seeded_video(name: &str, folder_id: &str) -> File {
+  seeded_video_of(USER_ID1, name, folder_id)
+}
+
+fn seeded_video_of(user_id: &str, name: &str, folder_id: &str) -> File {
+  let video = Video {
+    name: name.into(),
+    play_id: "video-id".into(),
+    mime_type: "video/mp4".into(),
+    ..Default::default()
+  };
+  File::from_video(video, user_id.into(), Some(folder_id.into()), None)
+    .expect("Failed to create video file")
+}
+
+#[tokio::test]
+async fn it_zips_folders_keeping_their_structure() {
+  let trip = File::new_folder(USER_ID1.into(), "Trip".into(), None)
+    .expect("Failed to create folder");
+  let day =
+    File::new_folder(USER_ID1.into(), "Day 1".into(), Some(trip.id.clone()))
+      .expect("Failed to create folder");
+  let files = [
+    seeded_video("Intro", &trip.id),
+    seeded_video("Beach.mp4", &day.id),
+    day.clone(),
+  ];
+  let entries = archive_entries(&trip.id, &files);
+  let response = zip_response(Arc::new(mock_file(None)), entries, "Trip.zip")
+    .expect("Failed to zip folder");
+  let archive =
+    ZipFileReader::with_tokio(std::io::Cursor::new(read_body(response).await))
+      .await
+      .expect("Failed to read archive");
+
+  let names = archive
+    .file()
+    .entries()
+    .iter()
+    .map(|entry| entry.filename().as_str().unwrap_or_default().to_string())
+    .collect::<Vec<_>>();
+  let expected = ["Day 1/Beach.mp4", "Intro.mp4"];
+  assert!(
+    names == expected,
+    "Expected entries {expected:?}, instead got {names:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_zips_unsafe_and_duplicate_names_inside_the_folder() {
+  let trip = File::new_folder(USER_ID1.into(), "Trip".into(), None)
+    .expect("Failed to create folder");
+  let escape =
+    File::new_folder(USER_ID1.into(), "..".into(), Some(trip.id.clone()))
+      .expect("Failed to create folder");
+  let files = [
+    seeded_video("Intro", &trip.id),
+    seeded_video("Intro.mp4", &trip.id),
+    seeded_video("..", &escape.id),
+    escape.clone(),
+  ];
+  let paths = archive_entries(&trip.id, &files)
+    .into_iter()
+    .map(|entry| entry.path)
+    .collect::<Vec<_>>();
+
+  let expected = ["Intro (1).mp4", "Intro.mp4", "_/...mp4"];
+  assert!(
+    paths == expected,
+    "Expected entries {expected:?}, instead got {paths:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_leaves_other_users_files_out_of_zipped_folders() {
+  let (file_sys, database) = get_database().await;
+  let trip = File::new_folder(USER_ID1.into(), "Trip".into(), None)
+    .expect("Failed to create folder");
+  let files = [
+    trip.clone(),
+    seeded_video("Intro", &trip.id),
+    seeded_video_of(USER_ID2, "Planted", &trip.id),
+  ];
+  insert_many(&database, &files).await;
+  let family = file_sys.find_descendants(USER_ID1, &trip.id).await;
+  cleanup_files_collection(&database).await;
+
+  let family = family
+    .unwrap_or_exit("Failed to find descendants")
+    .expect("Expected to find the folder");
+  let entries = archive_entries(&family.folder.id, &family.descendants);
+  let response = zip_response(Arc::new(mock_file(None)), entries, "Trip.zip")
+    .expect("Failed to zip folder");
+  let archive =
+    ZipFileReader::with_tokio(std::io::Cursor::new(read_body(response).await))
+      .await
+      .expect("Failed to read archive");
+  let names = archive
+    .file()
+    .entries()
+    .iter()
+    .map(|entry| entry.filename().as_str().unwrap_or_default().to_string())
+    .collect::<Vec<_>>();
+  assert!(
+    names == ["Intro.mp4"],
+    "Expected only the user's own videos, instead got {names:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_fails_the_zip_body_when_a_video_fails() {
+  let trip = File::new_folder(USER_ID1.into(), "Trip".into(), None)
+    .expect("Failed to create folder");
+  let mut missing = seeded_video("Missing", &trip.id);
+  if let FileMetadata::Video(video) = &mut missing.metadata {
+    video.play_id = "missing-id".into();
+  }
+  let files = [seeded_video("Intro", &trip.id), missing];
+  let entries = archive_entries(&trip.id, &files);
+  let response = zip_response(Arc::new(mock_file(None)), entries, "Trip.zip")
+    .expect("Failed to zip folder");
+
+  let mut body = response.into_body();
+  let mut read = 0;
+  let mut error = None;
+  while let Some(chunk) = body.data().await {
+    match chunk {
+      Ok(chunk) => read += chunk.len(),
+      Err(e) => {
+        error = Some(e);
+        break;
+      }
+    }
+  }
+  assert!(
+    error.is_some(),
+    "Expected the archive body to fail, instead it ended after {read} bytes"
+  );
+}
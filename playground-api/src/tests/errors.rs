@@ -0,0 +1,195 @@
+#![cfg(test)]
+use super::{read_body, serve};
+use crate::{
+  api::{json::ValidJson, APIError},
+  check_env_vars,
+  db::{files::system::FileSystemError, DBError},
+  routes::files::{CreateFolderBody, MoveFilesBody},
+  string::{NonEmptyString, StringError},
+  AppError, GracefulExit,
+};
+use axum::{http::StatusCode, response::IntoResponse, routing::post, Router};
+
+fn status_of(error: FileSystemError) -> StatusCode {
+  APIError::from(error).into_response().status()
+}
+
+#[test]
+fn it_maps_file_system_errors_to_statuses() {
+  let name = NonEmptyString::try_from("Movies").expect("valid name");
+  let cases = [
+    (FileSystemError::FolderLoop, StatusCode::CONFLICT),
+    (FileSystemError::NotEmpty, StatusCode::CONFLICT),
+    (FileSystemError::ReadOnly, StatusCode::FORBIDDEN),
+    (FileSystemError::NotFound, StatusCode::NOT_FOUND),
+    (
+      FileSystemError::NameConflict(name, "root".into()),
+      StatusCode::CONFLICT,
+    ),
+  ];
+  for (error, expected) in cases {
+    let message = error.to_string();
+    let status = status_of(error);
+    assert!(
+      status == expected,
+      "Expected {expected} for {message:?}, instead got {status}"
+    );
+  }
+}
+
+#[test]
+fn it_maps_converted_errors_to_statuses() {
+  fn fail_with(error: impl Into<APIError>) -> Result<(), APIError> {
+    Err(error.into())
+  }
+  let results = [
+    (
+      fail_with(StringError::Empty),
+      StatusCode::UNPROCESSABLE_ENTITY,
+    ),
+    (
+      fail_with(DBError::String(StringError::InvalidChar('/'))),
+      StatusCode::UNPROCESSABLE_ENTITY,
+    ),
+    (
+      fail_with(FileSystemError::BadString(StringError::Empty)),
+      StatusCode::UNPROCESSABLE_ENTITY,
+    ),
+    (
+      fail_with(FileSystemError::Internal(DBError::String(
+        StringError::TooLong { max: 1, actual: 2 },
+      ))),
+      StatusCode::UNPROCESSABLE_ENTITY,
+    ),
+    (
+      fail_with(FileSystemError::NotVideo),
+      StatusCode::BAD_REQUEST,
+    ),
+    (
+      fail_with(FileSystemError::Conflict {
+        expected: 1,
+        actual: 2,
+      }),
+      StatusCode::CONFLICT,
+    ),
+  ];
+  for (result, expected) in results {
+    let error = result.expect_err("Expected an error");
+    let message = error.to_string();
+    let status = error.into_response().status();
+    assert!(
+      status == expected,
+      "Expected {expected} for {message:?}, instead got {status}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_includes_name_conflict_details() {
+  let name = NonEmptyString::try_from("Movies").expect("valid name");
+  let response =
+    APIError::from(FileSystemError::NameConflict(name, "folder-id".into()))
+      .into_response();
+  let body = read_body(response).await;
+  let json = serde_json::from_slice::<serde_json::Value>(&body)
+    .expect("Response body should be JSON");
+  let details = &json["details"];
+  assert!(
+    details["name"] == "Movies" && details["folderId"] == "folder-id",
+    "Expected conflict details, instead got {json}"
+  );
+}
+
+#[tokio::test]
+async fn it_includes_path_of_badly_typed_json_field() {
+  let app = Router::new().route(
+    "/",
+    post(|ValidJson(_): ValidJson<MoveFilesBody>| async { "OK" }),
+  );
+  let response = reqwest::Client::new()
+    .post(serve(app))
+    .json(&serde_json::json!({
+      "files": ["file-id"],
+      "folder": "root",
+      "dryRun": "yes",
+    }))
+    .send()
+    .await
+    .expect("Request failed");
+  let status = response.status();
+  let json = response
+    .json::<serde_json::Value>()
+    .await
+    .expect("Response body should be JSON");
+  let details = &json["details"];
+  assert!(
+    status == StatusCode::BAD_REQUEST
+      && details["path"] == "dryRun"
+      && details["error"]
+        .as_str()
+        .is_some_and(|error| error.contains("expected a boolean")),
+    "Expected the bad field's path and type, instead got {status} {json}"
+  );
+}
+
+#[tokio::test]
+async fn it_flags_invalid_folder_names() {
+  let app = Router::new().route(
+    "/",
+    post(|ValidJson(body): ValidJson<CreateFolderBody>| async move {
+      body.validate()
+    }),
+  );
+  let response = reqwest::Client::new()
+    .post(serve(app))
+    .json(&serde_json::json!({ "name": "" }))
+    .send()
+    .await
+    .expect("Request failed");
+  let status = response.status();
+  let json = response
+    .json::<serde_json::Value>()
+    .await
+    .expect("Response body should be JSON");
+  let details = &json["details"];
+  assert!(
+    status == StatusCode::UNPROCESSABLE_ENTITY
+      && details.as_array().is_some_and(|fields| fields.len() == 1)
+      && details[0]["field"] == "name"
+      && details[0]["message"] == "String cannot be empty",
+    "Expected the name field to be flagged, instead got {status} {json}"
+  );
+}
+
+#[test]
+fn it_exits_with_failure_code_on_error() {
+  let code = Err::<(), _>("boom").unwrap_or_exit_code("Fatal error");
+  assert!(
+    matches!(code, Err(code) if code != 0),
+    "Expected a non-zero exit code, instead got {code:?}"
+  );
+}
+
+#[test]
+fn it_keeps_ok_values_when_not_exiting() {
+  let value = Ok::<_, String>(7).unwrap_or_exit_code("Fatal error");
+  assert!(value == Ok(7), "Expected Ok(7), instead got {value:?}");
+}
+
+#[test]
+fn it_reports_every_missing_env_var() {
+  std::env::set_var("PLAYGROUND_TEST_PRESENT", "set");
+  let result = check_env_vars(&[
+    "PLAYGROUND_TEST_MISSING_A",
+    "PLAYGROUND_TEST_PRESENT",
+    "PLAYGROUND_TEST_MISSING_B",
+  ]);
+  assert!(
+    matches!(
+      result,
+      Err(AppError::MissingEnv(ref missing))
+        if missing == &["PLAYGROUND_TEST_MISSING_A", "PLAYGROUND_TEST_MISSING_B"]
+    ),
+    "Expected both missing vars to be reported, instead got {result:?}"
+  );
+}
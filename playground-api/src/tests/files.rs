@@ -1,15 +1,49 @@
 #![cfg(test)]
 use super::{
-  cleanup_files_collection, create_dummy_folder_structure,
-  create_nested_folders, get_database, NestedFolderOptions, USER_ID1,
+  cleanup_files_collection, cleanup_shares_collection,
+  create_dummy_folder_structure, create_folder_with_custom_id, create_many,
+  create_nested_folders, get_database, insert_many, read_body, set_test_env,
+  NestedFolderOptions, USER_ID1, USER_ID2,
 };
 use crate::{
-  db::files::{system::FileSystemError, ROOT_FOLDER_ALIAS},
+  api::{json::ValidJson, APIError},
+  auth::{
+    jwt,
+    session::{FileIdVecQuery, ReadSession, Session, SESSION_COOKIE},
+  },
+  db::{
+    files::{
+      aggregations::FileTree,
+      system::{
+        FileSort, FileSystem, FileSystemError, OnConflict, SortBy, SortOrder,
+      },
+      File, FileMetadata, PartialFile, SubtitleTrack, Video, ROOT_FOLDER_ALIAS,
+    },
+    Database,
+  },
+  routes::files::{
+    create_idempotent, delete_files, download_name, get_folder_family,
+    revoke_share_link, share_folder, update_folder, IDEMPOTENCY_KEY,
+  },
   tests::{fill_folder, FillFolderOptions},
+  websockets::{channel::EventMessage, file_watcher, WebSocketState},
   GracefulExit,
 };
+use axum::{
+  extract::{FromRequestParts, Path, Query, State},
+  http::{HeaderMap, HeaderValue, Request, StatusCode},
+};
 use format as f;
-use std::collections::HashSet;
+use mongodb::{
+  bson::{doc, to_document, Document},
+  options::FindOptions,
+};
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+  time::Duration,
+};
+use tokio::time::timeout;
 
 #[tokio::test]
 async fn it_fails_to_move_folder_inside_itself() {
@@ -24,6 +58,8 @@ async fn it_fails_to_move_folder_inside_itself() {
         USER_ID1,
         &vec![folder_to_move.to_string()].into_iter().collect(),
         destination,
+        &HashMap::new(),
+        false,
       )
       .await;
     assert!(
@@ -40,7 +76,13 @@ async fn it_fails_to_move_root_folder() {
   use FileSystemError::ReadOnly;
   for id in [USER_ID1, ROOT_FOLDER_ALIAS] {
     let result = file_sys
-      .move_many(id, &vec![id.to_string()].into_iter().collect(), id)
+      .move_many(
+        id,
+        &vec![id.to_string()].into_iter().collect(),
+        id,
+        &HashMap::new(),
+        false,
+      )
       .await;
     assert!(
       matches!(result, Err(ReadOnly)),
@@ -53,22 +95,36 @@ async fn it_fails_to_move_root_folder() {
 async fn it_moves_files_successfully() {
   let (file_sys, database) = get_database().await;
   let ids = create_nested_folders(&database, None).await;
-  let ids_set = ids.clone().into_iter().collect();
-  let (result, changes) = file_sys
-    .move_many(USER_ID1, &ids_set, ROOT_FOLDER_ALIAS)
+  let [id0, id1, id2, ..] = &ids[..] else {
+    unreachable!("There should be at least 3 ids, instead got {ids:#?}");
+  };
+  let options = FillFolderOptions {
+    parent_id: id1,
+    ..Default::default()
+  };
+  let mut ids_set = fill_folder(&database, Some(options))
+    .await
+    .into_iter()
+    .collect::<HashSet<_>>();
+  ids_set.insert(id2.clone());
+  let (moved_count, changes) = file_sys
+    .move_many(
+      USER_ID1,
+      &ids_set,
+      ROOT_FOLDER_ALIAS,
+      &HashMap::new(),
+      false,
+    )
     .await
     .unwrap_or_exit("Failed to move files to root folder");
-  let moved_count = result.modified_count;
   cleanup_files_collection(&database).await;
   assert!(
-    moved_count == 2,
-    "Expected to move 2 files, instead moved {moved_count}"
+    moved_count == ids_set.len() as u64,
+    "Expected to move {} files, instead moved {moved_count}",
+    ids_set.len()
   );
   let changes = changes.expect("There should be changes");
-  let [id1, id2, ..] = &ids[..] else {
-    unreachable!("There should be more than 2 ids, but there were not. {ids:#?}");
-  };
-  for id in [USER_ID1, id1, id2] {
+  for id in [USER_ID1, id1] {
     assert!(
       changes.iter().any(|change| change.id == id),
       "Expected changes to contain {id} but it did not.\n\nChanges => {changes:#?}"
@@ -81,13 +137,15 @@ async fn it_moves_files_successfully() {
         .iter()
         .map(|file| file.id.clone())
         .collect::<HashSet<_>>();
+      let mut expected = ids_set.clone();
+      expected.insert(id0.clone());
       assert!(
-        files.eq(&ids_set),
-        "Expected folder change to be {ids_set:?}, instead got {files:?}"
+        files.eq(&expected),
+        "Expected folder change to be {expected:?}, instead got {files:?}"
       );
       continue;
     }
-    if &change.id == id1 || &change.id == id2 {
+    if &change.id == id1 {
       assert!(
         change.children.is_empty(),
         "Expected folder change to be empty, instead got {:?}",
@@ -98,6 +156,79 @@ async fn it_moves_files_successfully() {
   }
 }
 
+#[tokio::test]
+async fn it_keeps_the_structure_of_moved_folders() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let files = [ids[1].clone(), ids[2].clone()].into_iter().collect();
+  let (moved_count, _) = file_sys
+    .move_many(USER_ID1, &files, ROOT_FOLDER_ALIAS, &HashMap::new(), false)
+    .await
+    .unwrap_or_exit("Failed to move files to root folder");
+  let stored = file_sys
+    .find_many(
+      &PartialFile {
+        user_id: Some(USER_ID1.into()),
+        ..Default::default()
+      },
+      FileSort::default(),
+    )
+    .await
+    .unwrap_or_exit("Failed to find moved files")
+    .into_iter()
+    .map(|file| (file.id, file.folder_id))
+    .collect::<Vec<_>>();
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    moved_count == 1,
+    "Expected to move only the parent folder, instead moved {moved_count}"
+  );
+  for (id, folder_id) in [(&ids[1], USER_ID1), (&ids[2], &ids[1])] {
+    let stored_folder_id = stored
+      .iter()
+      .find(|(stored_id, _)| stored_id == id)
+      .map(|(_, folder_id)| folder_id);
+    assert!(
+      stored_folder_id.is_some_and(|stored| stored == folder_id),
+      "Expected {id:?} to be in {folder_id:?}, instead got {stored_folder_id:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_finds_only_the_users_own_files() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let found = file_sys
+    .find_file(USER_ID1, &ids[1])
+    .await
+    .unwrap_or_exit("Failed to find file");
+  let missing = file_sys
+    .find_file(USER_ID1, "missing-file")
+    .await
+    .unwrap_or_exit("Failed to find missing file");
+  let other_user = file_sys
+    .find_file(USER_ID2, &ids[1])
+    .await
+    .unwrap_or_exit("Failed to find other user's file");
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    found.as_ref().is_some_and(|file| file.id == ids[1]),
+    "Expected to find {:?}, instead got {found:#?}",
+    ids[1]
+  );
+  assert!(
+    missing.is_none(),
+    "Expected missing file not to be found, instead got {missing:#?}"
+  );
+  assert!(
+    other_user.is_none(),
+    "Expected other user's file not to be found, instead got {other_user:#?}"
+  );
+}
+
 #[tokio::test]
 async fn it_fails_to_delete_root_folder() {
   let (file_sys, ..) = get_database().await;
@@ -113,6 +244,36 @@ async fn it_fails_to_delete_root_folder() {
   }
 }
 
+#[tokio::test]
+async fn it_returns_the_ids_of_deleted_files() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let options = FillFolderOptions {
+    parent_id: &ids[1],
+    ..Default::default()
+  };
+  let expected = fill_folder(&database, Some(options))
+    .await
+    .into_iter()
+    .chain(ids[1..].iter().cloned())
+    .collect::<HashSet<_>>();
+  let (deleted_count, deleted_ids, _) = file_sys
+    .delete_many(USER_ID1, &[ids[1].clone()].into_iter().collect())
+    .await
+    .unwrap_or_exit("Failed to delete files");
+  let untouched = ids[0].clone();
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    deleted_count == expected.len() as u64 && deleted_ids == expected,
+    "Expected to delete {expected:?}, instead deleted {deleted_count} files {deleted_ids:?}"
+  );
+  assert!(
+    !deleted_ids.contains(&untouched),
+    "Expected {untouched:?} to be kept"
+  );
+}
+
 #[tokio::test]
 async fn it_deletes_files_successfully() {
   let (file_sys, database) = get_database().await;
@@ -122,7 +283,7 @@ async fn it_deletes_files_successfully() {
     vec![file_ids[0].clone(), ids_one[3].clone(), ids_two[2].clone()]
       .into_iter()
       .collect();
-  let (deleted_count, changes) = file_sys
+  let (deleted_count, _, changes) = file_sys
     .delete_many(USER_ID1, &ids_set)
     .await
     .unwrap_or_exit("Failed to delete files");
@@ -158,10 +319,7 @@ async fn it_deletes_files_successfully() {
       .map(|file| file.id.clone())
       .collect::<HashSet<_>>();
     if change.id == change_one {
-      let set = vec![ids_three[0].clone()]
-        .iter()
-        .map(String::from)
-        .collect();
+      let set = [ids_three[0].clone()].iter().map(String::from).collect();
       assert!(
         files.eq(&set),
         "Expected folder change to be {set:?}, instead got {files:?}"
@@ -176,7 +334,7 @@ async fn it_deletes_files_successfully() {
       continue;
     }
     if change.id == change_three {
-      let set = vec![
+      let set = [
         file_ids[1].clone(),
         file_ids[2].clone(),
         file_ids[3].clone(),
@@ -204,6 +362,7 @@ async fn it_fails_to_update_root_folder() {
       USER_ID1,
       Some("new-folder-id".into()),
       Some("New Name".into()),
+      None,
     )
     .await;
   assert!(
@@ -223,6 +382,7 @@ async fn it_fails_to_update_folder_to_be_inside_itself() {
       &ids[1],
       Some(ids[2].clone()),
       Some("New Name".into()),
+      None,
     )
     .await;
   cleanup_files_collection(&database).await;
@@ -252,6 +412,7 @@ async fn it_updates_file_successfully() {
         id,
         Some(new_folder.clone()),
         Some(f!("New Name {i}")),
+        None,
       )
       .await
       .unwrap_or_exit(f!("Expected file #{i} {id} update to succeed"));
@@ -359,3 +520,1356 @@ async fn it_finds_children_and_ancestors() {
     "Expected ancestors to be {ancestors:?}, instead got {result_ancestors:?}"
   );
 }
+
+#[tokio::test]
+async fn it_keeps_folder_families_of_users_apart() {
+  let (file_sys, database) = get_database().await;
+  // Both users have a "Movies" folder with a "Series" folder, but the second
+  // user's "Series" and the first user's "Stray" point at the other's folder.
+  let folders = [
+    ("Family-1", USER_ID1, "Movies", "root"),
+    ("Family-1a", USER_ID1, "Series", "Family-1"),
+    ("Family-1b", USER_ID1, "Stray", "Family-2"),
+    ("Family-2", USER_ID2, "Movies", "root"),
+    ("Family-2a", USER_ID2, "Series", "Family-1"),
+  ]
+  .map(|(id, user_id, name, folder_id)| {
+    create_folder_with_custom_id(
+      id.into(),
+      user_id.into(),
+      name.into(),
+      Some(folder_id.into()),
+    )
+    .unwrap_or_exit(f!("Could not create folder {id}"))
+  });
+  insert_many(&database, &folders).await;
+  let family = file_sys
+    .find_children_and_ancestors(USER_ID1, "Family-1")
+    .await
+    .unwrap_or_exit("Failed to find children and ancestors");
+  let other_user_family = file_sys
+    .find_children_and_ancestors(USER_ID2, "Family-1")
+    .await
+    .unwrap_or_exit("Failed to find other user's children and ancestors");
+  let stray_family = file_sys
+    .find_children_and_ancestors(USER_ID1, "Family-1b")
+    .await
+    .unwrap_or_exit("Failed to find stray children and ancestors");
+  cleanup_files_collection(&database).await;
+
+  let family = family.expect("Expected to find the user's folder");
+  let children = family
+    .children
+    .iter()
+    .map(|child| child.id.as_str())
+    .collect::<Vec<_>>();
+  assert!(
+    children == ["Family-1a"],
+    "Expected only the user's children, instead got {children:?}"
+  );
+  let ancestors = family
+    .ancestors
+    .iter()
+    .map(|ancestor| ancestor.id.as_str())
+    .collect::<Vec<_>>();
+  assert!(
+    ancestors == [USER_ID1],
+    "Expected only the user's root as ancestor, instead got {ancestors:?}"
+  );
+  assert!(
+    other_user_family.is_none(),
+    "Expected other user's folder not to be found, instead got {other_user_family:#?}"
+  );
+  let stray_family = stray_family.expect("Expected to find the stray folder");
+  assert!(
+    stray_family.ancestors.is_empty(),
+    "Expected no ancestors from other users, instead got {:#?}",
+    stray_family.ancestors
+  );
+}
+
+#[tokio::test]
+async fn it_reads_back_folder_colors_and_icons() {
+  let (file_sys, database) = get_database().await;
+  let folders =
+    [("Styled", "root"), ("Styled-child", "Styled")].map(|(id, folder_id)| {
+      create_folder_with_custom_id(
+        id.into(),
+        USER_ID1.into(),
+        id.into(),
+        Some(folder_id.into()),
+      )
+      .unwrap_or_exit(f!("Could not create folder {id}"))
+    });
+  insert_many(&database, &folders).await;
+  for id in ["Styled", "Styled-child"] {
+    file_sys
+      .update_folder_style(
+        USER_ID1,
+        id,
+        Some(Some("#ff8800")),
+        Some(Some("🎬")),
+      )
+      .await
+      .unwrap_or_exit(f!("Failed to style folder {id}"));
+  }
+  let family = file_sys
+    .find_children_and_ancestors(USER_ID1, "Styled")
+    .await
+    .unwrap_or_exit("Failed to find children and ancestors");
+  cleanup_files_collection(&database).await;
+
+  let family = family.expect("Expected to find the styled folder");
+  assert!(
+    family.color.as_deref() == Some("#ff8800")
+      && family.icon.as_deref() == Some("🎬"),
+    "Expected the folder's color and icon, instead got {:?} {:?}",
+    family.color,
+    family.icon
+  );
+  let child = family.children.first().map(|child| &child.metadata);
+  assert!(
+    matches!(
+      child,
+      Some(FileMetadata::Folder { color: Some(color), icon: Some(icon) })
+        if color == "#ff8800" && icon == "🎬"
+    ),
+    "Expected the child's color and icon, instead got {child:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_only_changes_the_folder_style_fields_sent() {
+  let (file_sys, database) = get_database().await;
+  let folder = create_folder_with_custom_id(
+    "Patched".into(),
+    USER_ID1.into(),
+    "Patched".into(),
+    Some("root".into()),
+  )
+  .unwrap_or_exit("Could not create folder Patched");
+  insert_many(&database, &[folder]).await;
+  let mut styles = Vec::new();
+  for body in [
+    serde_json::json!({ "color": "#ff8800", "icon": "🎬" }),
+    serde_json::json!({ "color": "#000000" }),
+    serde_json::json!({ "icon": null }),
+  ] {
+    let body = serde_json::from_value(body).unwrap_or_exit("Invalid body");
+    let style = update_folder(
+      Session {
+        user_id: USER_ID1.to_string(),
+      },
+      State(file_sys.clone()),
+      Path("Patched".to_string()),
+      ValidJson(body),
+    )
+    .await
+    .map(|file| match &file.metadata {
+      FileMetadata::Folder { color, icon } => (color.clone(), icon.clone()),
+      _ => (None, None),
+    });
+    styles.push(style);
+  }
+  cleanup_files_collection(&database).await;
+
+  let style = |color: &str, icon: Option<&str>| {
+    (Some(color.to_string()), icon.map(String::from))
+  };
+  let expected = [
+    style("#ff8800", Some("🎬")),
+    style("#000000", Some("🎬")),
+    style("#000000", None),
+  ];
+  for (style, expected) in styles.iter().zip(expected) {
+    assert!(
+      style.as_ref().is_ok_and(|style| *style == expected),
+      "Expected style {expected:?}, instead got {style:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_counts_the_children_of_child_folders() {
+  let (file_sys, database) = get_database().await;
+  let folders = [
+    ("Counted", "root"),
+    ("Counted-full", "Counted"),
+    ("Counted-empty", "Counted"),
+    ("Counted-full-1", "Counted-full"),
+    ("Counted-full-2", "Counted-full"),
+    ("Counted-full-3", "Counted-full"),
+  ]
+  .map(|(id, folder_id)| {
+    create_folder_with_custom_id(
+      id.into(),
+      USER_ID1.into(),
+      id.into(),
+      Some(folder_id.into()),
+    )
+    .unwrap_or_exit(f!("Could not create folder {id}"))
+  });
+  let mut video = video_file("counted");
+  video.folder_id = "Counted".into();
+  let video_id = video.id.clone();
+  insert_many(&database, &folders).await;
+  insert_many(&database, &[video]).await;
+  let family = file_sys
+    .find_children_and_ancestors(USER_ID1, "Counted")
+    .await
+    .unwrap_or_exit("Failed to find children and ancestors");
+  cleanup_files_collection(&database).await;
+
+  let family = family.expect("Expected to find the seeded folder");
+  let counts = family
+    .children
+    .iter()
+    .map(|child| (child.id.as_str(), child.child_count))
+    .collect::<HashSet<_>>();
+  let expected = [
+    ("Counted-full", Some(3)),
+    ("Counted-empty", Some(0)),
+    (video_id.as_str(), None),
+  ]
+  .into_iter()
+  .collect::<HashSet<_>>();
+  assert!(
+    counts == expected,
+    "Expected child counts {expected:?}, instead got {counts:?}"
+  );
+}
+
+#[test]
+fn it_reads_folders_saved_without_color_or_icon() {
+  let metadata = serde_json::from_value::<FileMetadata>(
+    serde_json::json!({ "type": "folder" }),
+  );
+  assert!(
+    matches!(
+      metadata,
+      Ok(FileMetadata::Folder {
+        color: None,
+        icon: None
+      })
+    ),
+    "Expected a plain folder, instead got {metadata:?}"
+  );
+}
+
+#[test]
+fn it_names_downloads_after_their_mime_type() {
+  let cases = [
+    ("Trip", "video/quicktime", "Trip.mov"),
+    ("My.Video", "video/x-matroska", "My.Video.mkv"),
+    ("Clip.MP4", "video/mp4", "Clip.MP4"),
+    ("Clip.mp4", "video/webm", "Clip.mp4.webm"),
+    ("Clip", "application/octet-stream", "Clip"),
+  ];
+  for (name, mime_type, expected) in cases {
+    let file_name = download_name(name, mime_type);
+    assert!(
+      file_name == expected,
+      "Expected {expected:?} for {name:?} as {mime_type}, instead got {file_name:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_finds_breadcrumbs_from_root() {
+  let (file_sys, database) = get_database().await;
+  let (ids_one, ids_two, ..) = create_dummy_folder_structure(&database).await;
+  let folder_id = &ids_two[4];
+
+  let result = file_sys
+    .find_ancestors(USER_ID1, folder_id)
+    .await
+    .unwrap_or_exit("Failed to find ancestors")
+    .unwrap();
+  cleanup_files_collection(&database).await;
+
+  let breadcrumbs = [USER_ID1, &ids_one[0], &ids_one[1]]
+    .into_iter()
+    .map(String::from)
+    .chain(ids_two.iter().cloned())
+    .collect::<Vec<_>>();
+  let result = result.into_iter().map(|file| file.id).collect::<Vec<_>>();
+  assert!(
+    breadcrumbs == result,
+    "Expected breadcrumbs to be {breadcrumbs:?}, instead got {result:?}"
+  );
+}
+
+fn video_file(play_id: &str) -> File {
+  let video = Video {
+    name: "Clip".into(),
+    play_id: play_id.into(),
+    ..Default::default()
+  };
+  File::from_video(video, USER_ID1.into(), None, None)
+    .unwrap_or_exit("Failed to create video file")
+}
+
+/// Insert `file` the way files were stored before they had a version.
+async fn insert_unversioned(database: &Database, file: &File) {
+  let mut document = to_document(file).unwrap_or_exit("Invalid file");
+  document.remove(File::version());
+  database
+    .collection::<File>()
+    .clone_with_type::<Document>()
+    .insert_one(document, None)
+    .await
+    .unwrap_or_exit("Failed to insert file");
+}
+
+#[tokio::test]
+async fn it_fails_on_name_conflict_by_default() {
+  let (file_sys, database) = get_database().await;
+  file_sys
+    .create_one(&video_file("one"), OnConflict::Error)
+    .await
+    .unwrap_or_exit("Failed to create video");
+  let result = file_sys
+    .create_one(&video_file("two"), OnConflict::Error)
+    .await;
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    matches!(result, Err(FileSystemError::NameConflict(..))),
+    "Expected name conflict, instead got {result:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_creates_one_file_per_idempotency_key() {
+  let (file_sys, database) = get_database().await;
+  let mut headers = HeaderMap::new();
+  headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("create-once"));
+  let mut created = Vec::new();
+  for _ in 0..2 {
+    let file_sys = file_sys.clone();
+    let create = |name| async move {
+      // Renaming on conflict so only the key prevents a duplicate
+      let (file, _) = file_sys
+        .create_one(&video_file(name), OnConflict::Rename)
+        .await?;
+      Ok(file)
+    };
+    created.push(
+      create_idempotent(USER_ID1, &headers, "once", create)
+        .await
+        .unwrap_or_exit("Failed to create video"),
+    );
+  }
+  let stored = file_sys
+    .find_many(
+      &PartialFile {
+        folder_id: Some(USER_ID1.into()),
+        ..Default::default()
+      },
+      FileSort::default(),
+    )
+    .await
+    .unwrap_or_exit("Failed to find created videos");
+  cleanup_files_collection(&database).await;
+
+  let ids = created.iter().map(|file| &file.id).collect::<Vec<_>>();
+  assert!(
+    ids[0] == ids[1] && stored.len() == 1,
+    "Expected one video to be created, instead got {ids:?} and {} stored",
+    stored.len()
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_idempotency_keys_reused_with_another_body() {
+  let mut headers = HeaderMap::new();
+  headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_static("reused"));
+  let create = |name: &str| {
+    let file = File::new_folder(USER_ID1.into(), name.into(), None);
+    async move { Ok(file?) }
+  };
+  let first = create_idempotent(USER_ID1, &headers, "First", create).await;
+  let second = create_idempotent(USER_ID1, &headers, "Second", create).await;
+
+  assert!(
+    first.is_ok()
+      && matches!(&second, Err(APIError::Validation(fields))
+        if fields.iter().any(|field| field.field == IDEMPOTENCY_KEY)),
+    "Expected the reused key to be rejected, instead got {first:?} and {second:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_renames_on_name_conflict() {
+  let (file_sys, database) = get_database().await;
+  let mut names = Vec::new();
+  for play_id in ["one", "two", "three"] {
+    let (file, _) = file_sys
+      .create_one(&video_file(play_id), OnConflict::Rename)
+      .await
+      .unwrap_or_exit("Failed to create video");
+    names.push(file.name.to_string());
+  }
+  cleanup_files_collection(&database).await;
+
+  let expected = ["Clip", "Clip (1)", "Clip (2)"];
+  assert!(
+    names == expected,
+    "Expected names {expected:?}, instead got {names:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_replaces_on_name_conflict() {
+  let (file_sys, database) = get_database().await;
+  let (original, _) = file_sys
+    .create_one(&video_file("one"), OnConflict::Replace)
+    .await
+    .unwrap_or_exit("Failed to create video");
+  let (replaced, _) = file_sys
+    .create_one(&video_file("two"), OnConflict::Replace)
+    .await
+    .unwrap_or_exit("Failed to replace video");
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    replaced.id == original.id,
+    "Expected replaced file to keep id {:?}, instead got {:?}",
+    original.id,
+    replaced.id
+  );
+  assert!(
+    matches!(
+      &replaced.metadata,
+      FileMetadata::Video(video) if video.play_id == "two"
+    ),
+    "Expected replaced play id \"two\", instead got {:#?}",
+    replaced.metadata
+  );
+}
+
+#[tokio::test]
+async fn it_finds_empty_folders() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let options = FillFolderOptions {
+    prefix: "FileOne",
+    count: 2,
+    parent_id: &ids[0],
+  };
+  fill_folder(&database, Some(options)).await;
+  // Files of other users don't count as children
+  let planted = create_folder_with_custom_id(
+    "Planted".into(),
+    USER_ID2.into(),
+    "Planted".into(),
+    Some(ids[2].clone()),
+  )
+  .unwrap_or_exit("Could not create folder Planted");
+  insert_many(&database, &[planted]).await;
+
+  let result = file_sys
+    .find_empty_folders(USER_ID1)
+    .await
+    .unwrap_or_exit("Failed to find empty folders");
+  cleanup_files_collection(&database).await;
+
+  let result = result.into_iter().map(|file| file.id).collect::<Vec<_>>();
+  let expected = vec![ids[2].clone()];
+  assert!(
+    result == expected,
+    "Expected empty folders to be {expected:?}, instead got {result:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_previews_moves_without_writing() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let files = [ids[2].clone()].into_iter().collect();
+  let (moved_count, changes) = file_sys
+    .move_many(USER_ID1, &files, ROOT_FOLDER_ALIAS, &HashMap::new(), true)
+    .await
+    .unwrap_or_exit("Failed to preview move");
+  let stored = file_sys
+    .find_many(
+      &PartialFile {
+        id: Some(ids[2].clone()),
+        ..Default::default()
+      },
+      FileSort::default(),
+    )
+    .await
+    .unwrap_or_exit("Failed to find moved file");
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    moved_count == 1,
+    "Expected to preview 1 moved file, instead got {moved_count}"
+  );
+  let changes = changes.expect("There should be changes");
+  for change in &changes {
+    let has_moved_file = change.children.iter().any(|file| file.id == ids[2]);
+    let expected = change.id == USER_ID1;
+    assert!(
+      has_moved_file == expected,
+      "Expected {:?} to contain moved file: {expected}, instead got {:?}",
+      change.id,
+      change.children
+    );
+  }
+  let folder_id = stored.first().map(|file| file.folder_id.as_str());
+  assert!(
+    folder_id == Some(ids[1].as_str()),
+    "Expected dry run to leave file in {:?}, instead got {folder_id:?}",
+    ids[1]
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_updates_with_stale_version() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let id = &ids[2];
+  let rename = |name: &str, version| {
+    file_sys.update_one(USER_ID1, id, None, Some(name.into()), Some(version))
+  };
+  let first = rename("First", 0).await;
+  let stale = rename("Second", 0).await;
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    first.is_ok(),
+    "Expected update at current version to succeed, instead got {first:#?}"
+  );
+  assert!(
+    matches!(
+      stale,
+      Err(FileSystemError::Conflict {
+        expected: 0,
+        actual: 1
+      })
+    ),
+    "Expected stale update to conflict, instead got {stale:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_updates_files_saved_without_a_version() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let id = &ids[2];
+  database
+    .collection::<File>()
+    .update_one(
+      doc! { "_id": id },
+      doc! { "$unset": { "version": "" } },
+      None,
+    )
+    .await
+    .unwrap_or_exit("Failed to unset version");
+  let renamed = file_sys
+    .update_one(USER_ID1, id, None, Some("Renamed".into()), Some(0))
+    .await;
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    renamed.is_ok(),
+    "Expected a file without a version to be at version 0, instead got {renamed:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_moves_with_stale_version() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let files = [ids[2].clone()].into_iter().collect();
+  file_sys
+    .update_one(USER_ID1, &ids[2], None, Some("Renamed".into()), None)
+    .await
+    .unwrap_or_exit("Failed to rename file");
+  let versions = [(ids[2].clone(), 0)].into_iter().collect();
+  let stale = file_sys
+    .move_many(USER_ID1, &files, ROOT_FOLDER_ALIAS, &versions, false)
+    .await;
+  let stored = database
+    .find_by_id::<File>(&ids[2])
+    .await
+    .unwrap_or_exit("Failed to find file");
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    matches!(
+      stale,
+      Err(FileSystemError::Conflict {
+        expected: 0,
+        actual: 1
+      })
+    ),
+    "Expected stale move to conflict, instead got {stale:#?}"
+  );
+  let folder_id = stored.as_ref().map(|file| file.folder_id.as_str());
+  assert!(
+    folder_id == Some(ids[1].as_str()),
+    "Expected the file to stay in {:?}, instead got {folder_id:?}",
+    ids[1]
+  );
+}
+
+#[tokio::test]
+async fn it_moves_files_at_their_expected_version() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let files = [ids[2].clone()].into_iter().collect();
+  database
+    .collection::<File>()
+    .update_one(
+      doc! { "_id": &ids[2] },
+      doc! { "$unset": { "version": "" } },
+      None,
+    )
+    .await
+    .unwrap_or_exit("Failed to unset version");
+  let versions = [(ids[2].clone(), 0)].into_iter().collect();
+  let moved = file_sys
+    .move_many(USER_ID1, &files, ROOT_FOLDER_ALIAS, &versions, false)
+    .await;
+  let stored = database
+    .find_by_id::<File>(&ids[2])
+    .await
+    .unwrap_or_exit("Failed to find file");
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    matches!(moved, Ok((1, Some(_)))),
+    "Expected the file to be moved, instead got {moved:#?}"
+  );
+  let folder_id = stored.as_ref().map(|file| file.folder_id.as_str());
+  assert!(
+    folder_id == Some(USER_ID1),
+    "Expected the file in the root folder, instead got {folder_id:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_broadcasts_direct_db_writes() {
+  let (_, database) = get_database().await;
+  let sockets = WebSocketState::new();
+  let mut event_receiver = sockets.event_sender.subscribe();
+  let watcher = file_watcher::spawn(&database, sockets.clone())
+    .await
+    .expect("Failed to watch files, MongoDB must be a replica set");
+  let folder = create_folder_with_custom_id(
+    "Watched".into(),
+    USER_ID1.into(),
+    "Watched".into(),
+    None,
+  )
+  .unwrap_or_exit("Failed to create folder");
+  insert_many(&database, &[folder]).await;
+
+  let message = timeout(Duration::from_secs(5), event_receiver.recv()).await;
+  watcher.abort();
+  cleanup_files_collection(&database).await;
+
+  let Ok(Ok(EventMessage::FolderChange(change))) = message else {
+    panic!("Expected a folder change, instead got {message:#?}");
+  };
+  assert!(
+    change.id == USER_ID1
+      && change.children.iter().any(|file| file.id == "Watched"),
+    "Expected root folder change with the new folder, instead got {change:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_broadcasts_the_folder_of_directly_deleted_files() {
+  let (_, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let sockets = WebSocketState::new();
+  let mut event_receiver = sockets.event_sender.subscribe();
+  let watcher = file_watcher::spawn(&database, sockets.clone())
+    .await
+    .expect("Failed to watch files, MongoDB must be a 6.0+ replica set");
+  database
+    .delete::<File>(doc! { "_id": &ids[2] })
+    .await
+    .unwrap_or_exit("Failed to delete folder");
+
+  let message = timeout(Duration::from_secs(5), event_receiver.recv()).await;
+  watcher.abort();
+  cleanup_files_collection(&database).await;
+
+  let Ok(Ok(EventMessage::FolderChange(change))) = message else {
+    panic!("Expected a folder change, instead got {message:#?}");
+  };
+  assert!(
+    change.id == ids[1] && change.children.is_empty(),
+    "Expected {:?} to be left empty, instead got {change:#?}",
+    ids[1]
+  );
+}
+
+#[tokio::test]
+async fn it_creates_file_indexes_on_init() {
+  let (_, database) = get_database().await;
+  database
+    .ensure_indexes()
+    .await
+    .unwrap_or_exit("Expected creating existing indexes to succeed");
+  let indexes = database
+    .collection::<File>()
+    .list_index_names()
+    .await
+    .unwrap_or_exit("Failed to list indexes");
+
+  for index in ["userId_1_folderId_1", "userId_1_name_1"] {
+    assert!(
+      indexes.iter().any(|name| name == index),
+      "Expected index {index:?}, instead got {indexes:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_rolls_back_failed_transactions() {
+  let (_, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let folder_id = &ids[0];
+
+  let result = database
+    .with_transaction(|mut transaction| async {
+      let result = database
+        .update_many_in::<File>(
+          &mut transaction,
+          doc! { "$set": { File::name(): "Renamed" } },
+          doc! { "_id": folder_id },
+        )
+        .await;
+      let result = match result {
+        Ok(_) => Err(FileSystemError::ReadOnly),
+        Err(error) => Err(error.into()),
+      };
+      (transaction, result as Result<(), _>)
+    })
+    .await;
+  let folder = database
+    .find_by_id::<File>(folder_id)
+    .await
+    .unwrap_or_exit("Failed to find folder");
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    matches!(result, Err(FileSystemError::ReadOnly)),
+    "Expected the transaction to fail, instead got {result:#?}"
+  );
+  assert!(
+    folder
+      .as_ref()
+      .is_some_and(|folder| folder.name != "Renamed"),
+    "Expected the update to be rolled back, instead got {folder:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_reports_duplicates_when_creating_many() {
+  let (_, database) = get_database().await;
+  let folders = ["Existing", "New"]
+    .map(|id| {
+      create_folder_with_custom_id(id.into(), USER_ID1.into(), id.into(), None)
+        .unwrap_or_exit(f!("Failed to create folder {id}"))
+    })
+    .to_vec();
+  insert_many(&database, &folders[..1]).await;
+  let result = create_many(&database, &folders).await;
+  cleanup_files_collection(&database).await;
+
+  let result = result.unwrap_or_exit("Expected duplicates not to fail");
+  assert!(
+    result.inserted == ["New"] && result.duplicates == ["Existing"],
+    "Expected to insert New and skip Existing, instead got {result:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_attaches_subtitles_to_videos() {
+  let (file_sys, database) = get_database().await;
+  let (video, _) = file_sys
+    .create_one(&video_file("one"), OnConflict::Error)
+    .await
+    .unwrap_or_exit("Failed to create video");
+  let track = SubtitleTrack {
+    lang: "en".into(),
+    label: "English".into(),
+    url: "https://example.com/en.vtt".into(),
+  };
+  file_sys
+    .attach_subtitle(USER_ID1, &video.id, track.clone())
+    .await
+    .unwrap_or_exit("Failed to attach subtitle");
+  let file = file_sys
+    .find_many(
+      &PartialFile {
+        id: Some(video.id.clone()),
+        ..Default::default()
+      },
+      FileSort::default(),
+    )
+    .await
+    .unwrap_or_exit("Failed to find video")
+    .pop();
+  cleanup_files_collection(&database).await;
+
+  let subtitles = file.and_then(|file| match file.metadata {
+    FileMetadata::Video(video) => Some(video.subtitles),
+    FileMetadata::Folder { .. } => None,
+  });
+  assert!(
+    subtitles == Some(vec![track]),
+    "Expected the attached English track, instead got {subtitles:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_updates_subtitles_of_videos_saved_without_a_version() {
+  let (file_sys, database) = get_database().await;
+  let video = video_file("one");
+  insert_unversioned(&database, &video).await;
+  let track = SubtitleTrack {
+    lang: "en".into(),
+    label: "English".into(),
+    url: "https://example.com/en.vtt".into(),
+  };
+  let attached = file_sys.attach_subtitle(USER_ID1, &video.id, track).await;
+  let removed = file_sys.remove_subtitle(USER_ID1, &video.id, "en").await;
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    attached.as_ref().is_ok_and(|(file, _)| file.version == 1),
+    "Expected the subtitle to be attached at version 1, instead got {attached:#?}"
+  );
+  assert!(
+    removed.as_ref().is_ok_and(|(file, _)| file.version == 2),
+    "Expected the subtitle to be removed at version 2, instead got {removed:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_updates_thumbnails_and_notifies_the_parent_folder() {
+  let (_, database) = get_database().await;
+  let sockets = WebSocketState::new();
+  let mut event_receiver = sockets.event_sender.subscribe();
+  let file_sys =
+    FileSystem::with_fallback(&database, Arc::new(sockets.clone()));
+  file_sys
+    .create_one(&video_file("one"), OnConflict::Error)
+    .await
+    .unwrap_or_exit("Failed to create video");
+  file_sys
+    .update_thumbnail(USER_ID1, "one", "https://example.com/thumb.jpg")
+    .await
+    .unwrap_or_exit("Failed to update thumbnail");
+  let message = timeout(Duration::from_secs(5), event_receiver.recv()).await;
+  cleanup_files_collection(&database).await;
+
+  let Ok(Ok(EventMessage::FolderChange(change))) = message else {
+    panic!("Expected a folder change, instead got {message:#?}");
+  };
+  let video = change
+    .children
+    .iter()
+    .find_map(|file| match &file.metadata {
+      FileMetadata::Video(video) if video.play_id == "one" => Some(video),
+      _ => None,
+    });
+  let thumbnail = video.map(|video| video.thumbnail.as_str());
+  assert!(
+    change.id == USER_ID1 && thumbnail == Some("https://example.com/thumb.jpg"),
+    "Expected root folder change with the new thumbnail, instead got {change:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_sorts_files() {
+  let (file_sys, database) = get_database().await;
+  let videos = [
+    ("one", "beach", 3),
+    ("two", "Alps", 1),
+    ("three", "city", 2),
+  ];
+  for (play_id, name, size_bytes) in videos {
+    let mut video = video_file(play_id);
+    video.name = name.try_into().unwrap_or_exit("Invalid name");
+    if let FileMetadata::Video(video) = &mut video.metadata {
+      video.size_bytes = size_bytes;
+    }
+    file_sys
+      .create_one(&video, OnConflict::Error)
+      .await
+      .unwrap_or_exit("Failed to create video");
+  }
+  let query = PartialFile {
+    user_id: Some(USER_ID1.into()),
+    folder_id: Some(USER_ID1.into()),
+    ..Default::default()
+  };
+  let by_name = file_sys
+    .find_many(&query, FileSort::default())
+    .await
+    .unwrap_or_exit("Failed to find files");
+  let by_size = file_sys
+    .find_many(
+      &query,
+      FileSort {
+        by: SortBy::Size,
+        order: SortOrder::Desc,
+      },
+    )
+    .await
+    .unwrap_or_exit("Failed to find files");
+  cleanup_files_collection(&database).await;
+
+  let names = |files: Vec<File>| {
+    files
+      .into_iter()
+      .map(|file| file.name.to_string())
+      .collect::<Vec<_>>()
+  };
+  let (by_name, by_size) = (names(by_name), names(by_size));
+  assert!(
+    by_name == ["Alps", "beach", "city"] && by_size == ["beach", "city", "Alps"],
+    "Expected case-insensitive name and descending size order, instead got {by_name:?} and {by_size:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_limits_found_documents() {
+  let (_, database) = get_database().await;
+  fill_folder(&database, None).await;
+  let files = database
+    .find_many::<File>(
+      doc! { File::user_id(): USER_ID1 },
+      FindOptions::builder().limit(2).build(),
+    )
+    .await
+    .unwrap_or_exit("Failed to find files");
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    files.len() == 2,
+    "Expected 2 of the 5 files, instead got {}",
+    files.len()
+  );
+}
+
+#[tokio::test]
+async fn it_lists_only_starred_files() {
+  let (file_sys, database) = get_database().await;
+  let mut ids = Vec::new();
+  for play_id in ["one", "two"] {
+    let mut video = video_file(play_id);
+    video.name = play_id.try_into().unwrap_or_exit("Invalid name");
+    let (file, _) = file_sys
+      .create_one(&video, OnConflict::Error)
+      .await
+      .unwrap_or_exit("Failed to create video");
+    ids.push(file.id);
+  }
+  let (starred, changes) = file_sys
+    .toggle_star(USER_ID1, &ids[0])
+    .await
+    .unwrap_or_exit("Failed to star file");
+  let listed = file_sys
+    .find_many(
+      &PartialFile {
+        user_id: Some(USER_ID1.into()),
+        starred: Some(true),
+        ..Default::default()
+      },
+      FileSort::default(),
+    )
+    .await
+    .unwrap_or_exit("Failed to find files");
+  cleanup_files_collection(&database).await;
+
+  let listed = listed.into_iter().map(|file| file.id).collect::<Vec<_>>();
+  assert!(
+    starred.starred && listed == ids[..1],
+    "Expected only {:?} to be starred, instead got {listed:?}",
+    ids[0]
+  );
+  assert!(
+    changes.iter().any(|change| change.id == USER_ID1
+      && change.children.iter().any(|file| file.starred)),
+    "Expected root folder change with the starred file, instead got {changes:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_stars_files_saved_without_a_version() {
+  let (file_sys, database) = get_database().await;
+  let video = video_file("one");
+  insert_unversioned(&database, &video).await;
+  let starred = file_sys.toggle_star(USER_ID1, &video.id).await;
+  let unstarred = file_sys.toggle_star(USER_ID1, &video.id).await;
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    starred
+      .as_ref()
+      .is_ok_and(|(file, _)| file.starred && file.version == 1),
+    "Expected the file to be starred at version 1, instead got {starred:#?}"
+  );
+  assert!(
+    unstarred
+      .as_ref()
+      .is_ok_and(|(file, _)| !file.starred && file.version == 2),
+    "Expected the file to be unstarred at version 2, instead got {unstarred:#?}"
+  );
+}
+
+/// Names in `tree` nested like `{ "name": [children] }`.
+fn tree_shape(tree: &FileTree) -> serde_json::Value {
+  let children = tree.children.iter().map(tree_shape).collect::<Vec<_>>();
+  serde_json::json!({ tree.name.to_string(): children })
+}
+
+#[tokio::test]
+async fn it_builds_the_folder_tree() {
+  let (file_sys, database) = get_database().await;
+  create_dummy_folder_structure(&database).await;
+  let tree = file_sys.find_tree(USER_ID1, 100).await;
+  let shallow_tree = file_sys.find_tree(USER_ID1, 2).await;
+  cleanup_files_collection(&database).await;
+
+  let folder = |tree: Option<FileTree>| {
+    tree.and_then(|tree| {
+      let folder = tree.children.iter().find(|file| file.id == "FolderOne-0");
+      folder.map(tree_shape)
+    })
+  };
+  let tree = folder(tree.unwrap_or_exit("Failed to build tree"));
+  let expected = serde_json::json!({ "FolderOne 0": [
+    { "FileOne 0": [] },
+    { "FileOne 1": [] },
+    { "FileOne 2": [] },
+    { "FileOne 3": [] },
+    { "FolderOne 1": [
+      { "FolderOne 2": [
+        { "FolderOne 3": [] },
+        { "FolderThree 0": [{ "FolderThree 1": [] }] },
+      ] },
+      { "FolderTwo 0": [{ "FolderTwo 1": [{ "FolderTwo 2": [
+        { "FolderTwo 3": [{ "FolderTwo 4": [] }] },
+      ] }] }] },
+    ] },
+  ] });
+  assert!(
+    tree.as_ref() == Some(&expected),
+    "Expected tree {expected:#}, instead got {tree:#?}"
+  );
+
+  let shallow_tree =
+    folder(shallow_tree.unwrap_or_exit("Failed to build shallow tree"));
+  let expected = serde_json::json!({ "FolderOne 0": [
+    { "FileOne 0": [] },
+    { "FileOne 1": [] },
+    { "FileOne 2": [] },
+    { "FileOne 3": [] },
+    { "FolderOne 1": [] },
+  ] });
+  assert!(
+    shallow_tree.as_ref() == Some(&expected),
+    "Expected tree {expected:#}, instead got {shallow_tree:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_leaves_other_users_files_out_of_the_tree() {
+  let (file_sys, database) = get_database().await;
+  let folders = [
+    ("Tree-1", USER_ID1, "Movies", "root"),
+    ("Tree-2", USER_ID2, "Planted", "Tree-1"),
+  ]
+  .map(|(id, user_id, name, folder_id)| {
+    create_folder_with_custom_id(
+      id.into(),
+      user_id.into(),
+      name.into(),
+      Some(folder_id.into()),
+    )
+    .unwrap_or_exit(f!("Could not create folder {id}"))
+  });
+  insert_many(&database, &folders).await;
+  let tree = file_sys.find_tree(USER_ID1, 100).await;
+  cleanup_files_collection(&database).await;
+
+  let folder = tree
+    .unwrap_or_exit("Failed to build tree")
+    .and_then(|tree| tree.children.into_iter().find(|file| file.id == "Tree-1"))
+    .map(|folder| tree_shape(&folder));
+  let expected = serde_json::json!({ "Movies": [] });
+  assert!(
+    folder.as_ref() == Some(&expected),
+    "Expected tree {expected:#}, instead got {folder:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_deletes_only_empty_folders() {
+  let (file_sys, database) = get_database().await;
+  let (ids, ..) = create_dummy_folder_structure(&database).await;
+  let (parent_id, empty_id) = (&ids[2], &ids[3]);
+  let not_empty = file_sys.delete_empty_folder(USER_ID1, parent_id).await;
+  let kept = file_sys.find_file(USER_ID1, parent_id).await;
+  let root = file_sys
+    .delete_empty_folder(USER_ID1, ROOT_FOLDER_ALIAS)
+    .await;
+  let deleted = file_sys.delete_empty_folder(USER_ID1, empty_id).await;
+  let deleted_again = file_sys.delete_empty_folder(USER_ID1, empty_id).await;
+  cleanup_files_collection(&database).await;
+
+  use FileSystemError::{NotEmpty, NotFound, ReadOnly};
+  assert!(
+    matches!(not_empty, Err(NotEmpty)),
+    "Expected deleting {parent_id:?} to fail with {NotEmpty}, instead got {not_empty:#?}"
+  );
+  assert!(
+    kept.as_ref().is_ok_and(|folder| folder.is_some()),
+    "Expected {parent_id:?} to be kept, instead got {kept:#?}"
+  );
+  assert!(
+    matches!(root, Err(ReadOnly)),
+    "Expected deleting the root folder to fail with {ReadOnly}, instead got {root:#?}"
+  );
+  let (folder, changes) = deleted.unwrap_or_exit("Failed to delete folder");
+  assert!(
+    &folder.id == empty_id,
+    "Expected {empty_id:?} to be deleted, instead got {folder:#?}"
+  );
+  assert!(
+    changes.iter().any(|change| &change.id == parent_id
+      && change.children.iter().all(|file| &file.id != empty_id)),
+    "Expected {parent_id:?} change without {empty_id:?}, instead got {changes:#?}"
+  );
+  assert!(
+    matches!(deleted_again, Err(NotFound)),
+    "Expected deleting {empty_id:?} again to fail with {NotFound}, instead got {deleted_again:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_notifies_deletion_of_nested_files() {
+  let (file_sys, database) = get_database().await;
+  let ids = create_nested_folders(&database, None).await;
+  let sockets = WebSocketState::new();
+  let mut event_receiver = sockets.event_sender.subscribe();
+  let deleted = delete_files(
+    Session {
+      user_id: USER_ID1.to_string(),
+    },
+    State(sockets.clone()),
+    State(file_sys),
+    FileIdVecQuery(HashSet::from([ids[0].clone()])),
+  )
+  .await;
+  let deleted_event = timeout(Duration::from_secs(5), async {
+    loop {
+      match event_receiver.recv().await {
+        Ok(EventMessage::FileDelete(event)) => break Some(event),
+        Ok(_) => continue,
+        Err(_) => break None,
+      }
+    }
+  })
+  .await;
+  cleanup_files_collection(&database).await;
+
+  assert!(
+    deleted.is_ok(),
+    "Expected files to be deleted, got {deleted:?}"
+  );
+  let expected = HashSet::from_iter(ids);
+  assert!(
+    deleted_event.as_ref().is_ok_and(|event| event
+      .as_ref()
+      .is_some_and(|event| { event.deleted_ids == expected })),
+    "Expected a delete event with {expected:?}, instead got {deleted_event:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_lets_guests_read_only_within_the_shared_folder() {
+  let (file_sys, database) = get_database().await;
+  let (ids, ..) = create_dummy_folder_structure(&database).await;
+  let guest = || ReadSession {
+    user_id: USER_ID1.to_string(),
+    shared_folder_id: Some(ids[1].clone()),
+  };
+  let read = |folder_id: &String| {
+    get_folder_family(
+      guest(),
+      State(file_sys.clone()),
+      HeaderMap::new(),
+      Path(folder_id.clone()),
+    )
+  };
+  let nested = read(&ids[2]).await;
+  let outside = read(&ids[0]).await;
+  cleanup_files_collection(&database).await;
+
+  let nested =
+    read_body(nested.unwrap_or_exit("Failed to read nested folder")).await;
+  let nested: serde_json::Value =
+    serde_json::from_slice(&nested).unwrap_or_exit("Invalid folder json");
+  let ancestors = nested["ancestors"]
+    .as_array()
+    .map(|ancestors| {
+      ancestors
+        .iter()
+        .map(|ancestor| ancestor["_id"].clone())
+        .collect::<Vec<_>>()
+    })
+    .unwrap_or_default();
+  assert!(
+    ancestors == [serde_json::json!(ids[1])],
+    "Expected only the shared folder above {:?}, instead got {ancestors:?}",
+    ids[2]
+  );
+  assert!(
+    matches!(outside, Err(APIError::NotFound(_))),
+    "Expected {:?} outside the shared folder to be hidden, instead got {outside:?}",
+    ids[0]
+  );
+}
+
+/// Session the share link `link` gets its holder.
+async fn follow_share_link(
+  link: &str,
+  database: &Database,
+) -> Result<ReadSession, APIError> {
+  follow_share_link_with_cookie(link, None, database).await
+}
+
+/// Like `follow_share_link` from a browser sending `session_token` as its
+/// session cookie.
+async fn follow_share_link_with_cookie(
+  link: &str,
+  session_token: Option<&str>,
+  database: &Database,
+) -> Result<ReadSession, APIError> {
+  let mut request = Request::builder().uri(link);
+  if let Some(token) = session_token {
+    request = request.header("Cookie", f!("{SESSION_COOKIE}={token}"));
+  }
+  let request = request.body(()).expect("Failed to build request");
+  let (mut parts, _) = request.into_parts();
+  ReadSession::from_request_parts(&mut parts, database).await
+}
+
+#[tokio::test]
+async fn it_opens_share_links_despite_other_session_cookies() {
+  set_test_env();
+  let (file_sys, database) = get_database().await;
+  let (ids, ..) = create_dummy_folder_structure(&database).await;
+  let query = serde_json::from_value(serde_json::json!({ "expiresIn": 3600 }))
+    .unwrap_or_exit("Invalid share query");
+  let shared = share_folder(
+    Session {
+      user_id: USER_ID1.to_string(),
+    },
+    State(database.clone()),
+    State(file_sys.clone()),
+    Path(ids[1].clone()),
+    Query(query),
+  )
+  .await
+  .unwrap_or_exit("Failed to share folder");
+  let shared = serde_json::to_value(&*shared).unwrap_or_exit("Invalid share");
+  let link = shared["link"].as_str().unwrap_or_default();
+  let other_token =
+    jwt::sign_token(USER_ID2).unwrap_or_exit("Failed to sign token");
+  Session::save(&other_token, USER_ID2).await;
+
+  let logged_in =
+    follow_share_link_with_cookie(link, Some(&other_token), &database).await;
+  let stale =
+    follow_share_link_with_cookie(link, Some("stale-token"), &database).await;
+  Session::invalidate(&other_token).await;
+  cleanup_files_collection(&database).await;
+  cleanup_shares_collection(&database).await;
+
+  for (cookie, guest) in [("another user's", logged_in), ("a stale", stale)] {
+    assert!(
+      guest.as_ref().is_ok_and(|guest| guest.user_id == USER_ID1
+        && guest.shared_folder_id.as_ref() == Some(&ids[1])),
+      "Expected the share link with {cookie} cookie to read the shared folder, instead got {guest:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_reads_shared_folders_until_the_share_is_revoked() {
+  set_test_env();
+  let (file_sys, database) = get_database().await;
+  let (ids, ..) = create_dummy_folder_structure(&database).await;
+  let owner = || Session {
+    user_id: USER_ID1.to_string(),
+  };
+  let query = serde_json::from_value(serde_json::json!({ "expiresIn": 3600 }))
+    .unwrap_or_exit("Invalid share query");
+  let shared = share_folder(
+    owner(),
+    State(database.clone()),
+    State(file_sys.clone()),
+    Path(ids[1].clone()),
+    Query(query),
+  )
+  .await
+  .unwrap_or_exit("Failed to share folder");
+  let shared = serde_json::to_value(&*shared).unwrap_or_exit("Invalid share");
+  let link = shared["link"].as_str().unwrap_or_default();
+  let share_id = shared["shareId"].as_str().unwrap_or_default();
+
+  let guest = follow_share_link(link, &database).await;
+  let nested = match guest {
+    Ok(guest) => Some(
+      get_folder_family(
+        guest,
+        State(file_sys.clone()),
+        HeaderMap::new(),
+        Path(ids[2].clone()),
+      )
+      .await,
+    ),
+    Err(_) => None,
+  };
+  let revoked = revoke_share_link(
+    owner(),
+    State(database.clone()),
+    Path(share_id.to_string()),
+  )
+  .await;
+  let after_revoking = follow_share_link(link, &database).await;
+  cleanup_files_collection(&database).await;
+  cleanup_shares_collection(&database).await;
+
+  let token = shared["token"].as_str().unwrap_or_default();
+  assert!(
+    !token.is_empty() && !link.contains(token),
+    "Expected share link {link:?} to not hold the token {token:?}"
+  );
+  assert!(
+    nested.as_ref().is_some_and(|nested| nested.is_ok()),
+    "Expected share link {link:?} to read {:?}, instead got {nested:?}",
+    ids[2]
+  );
+  assert!(
+    matches!(revoked, Ok(StatusCode::NO_CONTENT)),
+    "Expected share {share_id:?} to be revoked, instead got {revoked:?}"
+  );
+  assert!(
+    matches!(after_revoking, Err(APIError::UnauthorizedMessage(_))),
+    "Expected revoked share link to fail, instead got {after_revoking:?}"
+  );
+}
@@ -0,0 +1,17 @@
+#![cfg(test)]
+use super::{get_database, read_body};
+use crate::health;
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+
+#[tokio::test]
+async fn it_reports_healthy_when_database_is_reachable() {
+  let (_, database) = get_database().await;
+  let (status, report) = health(State(database)).await;
+  let body = read_body(report.into_response()).await;
+  let json = serde_json::from_slice::<serde_json::Value>(&body)
+    .expect("Health report should be JSON");
+  assert!(
+    status == StatusCode::OK && json == serde_json::json!({ "status": "ok" }),
+    "Expected healthy report, instead got {status} {json}"
+  );
+}
@@ -0,0 +1,703 @@
+#![cfg(test)]
+use super::{read_body, serve};
+use crate::{
+  api::{google::DriveFile, APIError},
+  http::{
+    chunk_size, get_range, json_response, json_with_etag, proxy_image,
+    send_with_retry, send_with_timeout, stream_video, upstream_log_line,
+    video_head_response, ClientPool, RetryPolicy,
+  },
+};
+use axum::{
+  extract::{ConnectInfo, Path, State},
+  http::{HeaderMap, StatusCode},
+  response::IntoResponse,
+  routing::get,
+  Router,
+};
+use format as f;
+use std::{
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+use tokio::sync::Mutex;
+
+const VIDEO_LENGTH: usize = 4096;
+const VIDEO_ETAG: &str = "\"video-v1\"";
+
+fn video_bytes() -> Vec<u8> {
+  (0..VIDEO_LENGTH).map(|i| (i % 251) as u8).collect()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Upstream {
+  /// Remote address of every request received.
+  pub peers: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+/// Minimal stand-in for Google Drive's ranged video downloads.
+async fn upstream_video(
+  ConnectInfo(peer): ConnectInfo<SocketAddr>,
+  State(upstream): State<Upstream>,
+  headers: HeaderMap,
+) -> impl IntoResponse {
+  upstream.peers.lock().await.push(peer);
+  let range = headers
+    .get("Range")
+    .and_then(|range| range.to_str().ok())
+    .and_then(|range| range.strip_prefix("bytes="))
+    .and_then(|range| range.split_once('-'))
+    .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok())));
+  // Like Drive, a stale `If-Range` gets the whole video
+  let stale = headers
+    .get("If-Range")
+    .is_some_and(|if_range| if_range != VIDEO_ETAG);
+  let mut headers = HeaderMap::new();
+  headers.insert("ETag", VIDEO_ETAG.parse().unwrap());
+  headers.insert("Content-Type", "video/mp4".parse().unwrap());
+  let Some((start, end)) = range.filter(|_| !stale) else {
+    return (StatusCode::OK, headers, video_bytes());
+  };
+  let end = end.unwrap_or(VIDEO_LENGTH - 1).min(VIDEO_LENGTH - 1);
+  headers.insert(
+    "Content-Range",
+    f!("bytes {start}-{end}/{VIDEO_LENGTH}").parse().unwrap(),
+  );
+  (
+    StatusCode::PARTIAL_CONTENT,
+    headers,
+    video_bytes()[start..=end].to_vec(),
+  )
+}
+
+async fn upstream_status(Path(status): Path<u16>) -> StatusCode {
+  StatusCode::from_u16(status).expect("Invalid upstream status")
+}
+
+/// Upstream that takes longer than any test timeout to answer.
+async fn upstream_slow() -> &'static str {
+  tokio::time::sleep(Duration::from_secs(5)).await;
+  "Too late"
+}
+
+async fn upstream_thumbnail() -> impl IntoResponse {
+  (
+    [("Content-Type", "image/jpeg")],
+    vec![0xff, 0xd8, 0xff, 0xd9],
+  )
+}
+
+/// Drive's answer for thumbnails it won't serve.
+async fn upstream_html() -> impl IntoResponse {
+  ([("Content-Type", "text/html")], "<html></html>")
+}
+
+/// Serve the upstream stub on a random port and return its video url.
+pub async fn spawn_upstream() -> (String, Upstream) {
+  let upstream = Upstream::default();
+  let app = Router::new()
+    .route("/video", get(upstream_video))
+    .route("/status/:status", get(upstream_status))
+    .route("/slow", get(upstream_slow))
+    .route("/thumbnail", get(upstream_thumbnail))
+    .route("/html", get(upstream_html))
+    .with_state(upstream.clone());
+  (f!("{}/video", serve(app)), upstream)
+}
+
+/// Serve an upstream answering its first `failures` requests with `status`
+/// and `OK` after that, returns its url and the requests it got.
+fn spawn_flaky_upstream(
+  failures: usize,
+  status: StatusCode,
+) -> (String, Arc<AtomicUsize>) {
+  let hits = Arc::new(AtomicUsize::new(0));
+  let counter = hits.clone();
+  let app = Router::new().route(
+    "/",
+    get(move || async move {
+      let hit = counter.fetch_add(1, Ordering::SeqCst);
+      if hit < failures {
+        (status, "Failed")
+      } else {
+        (StatusCode::OK, "OK")
+      }
+    }),
+  );
+  (serve(app), hits)
+}
+
+const TEST_RETRY_POLICY: RetryPolicy = RetryPolicy {
+  max_attempts: 3,
+  base_delay: Duration::from_millis(10),
+  max_elapsed: Duration::from_secs(5),
+};
+
+fn range_headers(range: &str) -> HeaderMap {
+  let mut headers = HeaderMap::new();
+  headers.insert("Range", range.parse().unwrap());
+  headers
+}
+
+#[tokio::test]
+async fn it_streams_partial_content() {
+  let (url, _) = spawn_upstream().await;
+  let response = stream_video(
+    ClientPool::new(1).get(),
+    &url,
+    range_headers("bytes=0-99"),
+    None,
+    None,
+  )
+  .await
+  .expect("Failed to stream video")
+  .into_response();
+
+  let status = response.status();
+  assert!(
+    status == StatusCode::PARTIAL_CONTENT,
+    "Expected 206 Partial Content, instead got {status}"
+  );
+  let content_range = response.headers().get("Content-Range");
+  assert!(
+    content_range.is_some_and(|range| range == "bytes 0-99/4096"),
+    "Expected upstream Content-Range, instead got {content_range:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_reuses_connections_between_streams() {
+  let (url, upstream) = spawn_upstream().await;
+  let clients = ClientPool::new(1);
+  for range in ["bytes=0-99", "bytes=100-199"] {
+    let response =
+      stream_video(clients.get(), &url, range_headers(range), None, None)
+        .await
+        .expect("Failed to stream video");
+    // Connections are only reused once the body is consumed
+    read_body(response).await;
+  }
+
+  let peers = upstream.peers.lock().await;
+  assert!(
+    peers.len() == 2 && peers[0] == peers[1],
+    "Expected both streams to share a connection, instead got {peers:?}"
+  );
+}
+
+#[test]
+fn it_parses_open_ended_ranges() {
+  let headers = range_headers("bytes=500-");
+  let range = get_range(&headers, Some(1000), None);
+  assert!(
+    matches!(range, Ok((500, 999))),
+    "Expected open ended range to reach the end, instead got {range:?}"
+  );
+  let range = get_range(&headers, None, None);
+  assert!(
+    matches!(range, Ok((500, end)) if end > 500),
+    "Expected open ended range to span a chunk, instead got {range:?}"
+  );
+}
+
+#[test]
+fn it_honors_requested_chunk_sizes_up_to_the_cap() {
+  let chunk = chunk_size(Some(1000)).expect("Chunk size rejected");
+  let range = get_range(&range_headers("bytes=500-"), None, chunk);
+  assert!(
+    matches!(range, Ok((500, 1500))),
+    "Expected open ended range to span the requested chunk, instead got {range:?}"
+  );
+  let range = get_range(&HeaderMap::new(), None, chunk);
+  assert!(
+    matches!(range, Ok((0, 1000))),
+    "Expected first chunk to span the requested chunk, instead got {range:?}"
+  );
+
+  let max_chunk = 32 * 1024 * 1024;
+  let chunk = chunk_size(Some(usize::MAX));
+  assert!(
+    matches!(chunk, Ok(Some(size)) if size == max_chunk),
+    "Expected chunk size to be capped to {max_chunk}, instead got {chunk:?}"
+  );
+  let chunk = chunk_size(Some(0));
+  assert!(
+    matches!(chunk, Err(APIError::BadRequest(_))),
+    "Expected empty chunks to be rejected, instead got {chunk:?}"
+  );
+}
+
+#[test]
+fn it_rejects_inverted_ranges() {
+  let range = get_range(&range_headers("bytes=100-50"), None, None);
+  assert!(
+    matches!(range, Err(APIError::BadRequest(_))),
+    "Expected inverted range to be rejected, instead got {range:?}"
+  );
+}
+
+#[test]
+fn it_rejects_malformed_ranges() {
+  let range = get_range(&range_headers("bytes=abc"), None, None);
+  assert!(
+    matches!(range, Err(APIError::BadRequest(_))),
+    "Expected malformed range to be rejected, instead got {range:?}"
+  );
+}
+
+#[test]
+fn it_clamps_ranges_to_content_length() {
+  let range =
+    get_range(&range_headers("bytes=0-5000"), Some(VIDEO_LENGTH), None);
+  assert!(
+    matches!(range, Ok((0, end)) if end == VIDEO_LENGTH - 1),
+    "Expected range to be clamped to {VIDEO_LENGTH}, instead got {range:?}"
+  );
+}
+
+#[test]
+fn it_parses_suffix_ranges() {
+  let range = get_range(&range_headers("bytes=-500"), Some(VIDEO_LENGTH), None);
+  assert!(
+    matches!(range, Ok((start, end)) if start == VIDEO_LENGTH - 500 && end == VIDEO_LENGTH - 1),
+    "Expected suffix range to cover the last 500 bytes, instead got {range:?}"
+  );
+}
+
+#[test]
+fn it_doesnt_overflow_open_ended_ranges() {
+  let header = f!("bytes={}-", usize::MAX - 1);
+  let range = get_range(&range_headers(&header), None, None);
+  assert!(
+    matches!(range, Ok((start, usize::MAX)) if start == usize::MAX - 1),
+    "Expected open ended range to saturate, instead got {range:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_clamps_streamed_ranges_to_content_length() {
+  let (url, _) = spawn_upstream().await;
+  let response = stream_video(
+    ClientPool::new(1).get(),
+    &url,
+    range_headers("bytes=4000-"),
+    Some(VIDEO_LENGTH),
+    None,
+  )
+  .await
+  .expect("Failed to stream video");
+
+  let content_range = response.headers().get("Content-Range").cloned();
+  assert!(
+    content_range
+      .as_ref()
+      .is_some_and(|range| range == "bytes 4000-4095/4096"),
+    "Expected range to end at the content length, instead got {content_range:?}"
+  );
+  let body = read_body(response).await;
+  assert!(
+    body == video_bytes()[4000..],
+    "Expected the rest of the video, instead got {} bytes",
+    body.len()
+  );
+}
+
+#[test]
+fn it_rejects_multiple_ranges() {
+  let range = get_range(&range_headers("bytes=0-99,200-299"), None, None);
+  assert!(
+    matches!(range, Err(APIError::RangeNotSatisfiable(_))),
+    "Expected multiple ranges to be unsatisfiable, instead got {range:?}"
+  );
+  let status = range.unwrap_err().into_response().status();
+  assert!(
+    status == StatusCode::RANGE_NOT_SATISFIABLE,
+    "Expected 416 Range Not Satisfiable, instead got {status}"
+  );
+}
+
+#[tokio::test]
+async fn it_streams_upstream_bytes() {
+  let (url, _) = spawn_upstream().await;
+  let response = stream_video(
+    ClientPool::new(1).get(),
+    &url,
+    range_headers("bytes=1000-2999"),
+    None,
+    None,
+  )
+  .await
+  .expect("Failed to stream video");
+
+  let body = read_body(response).await;
+  let expected = &video_bytes()[1000..=2999];
+  assert!(
+    body == expected,
+    "Expected {} streamed bytes to match upstream, instead got {}",
+    expected.len(),
+    body.len()
+  );
+}
+
+#[tokio::test]
+async fn it_maps_upstream_error_statuses() {
+  let (url, _) = spawn_upstream().await;
+  let base_url = url.trim_end_matches("/video");
+  let clients = ClientPool::new(1);
+  for (upstream_status, expected) in [
+    (StatusCode::NOT_FOUND, StatusCode::NOT_FOUND),
+    (StatusCode::FORBIDDEN, StatusCode::UNAUTHORIZED),
+  ] {
+    let status_url = f!("{base_url}/status/{}", upstream_status.as_u16());
+    let result = stream_video(
+      clients.get(),
+      &status_url,
+      range_headers("bytes=0-99"),
+      None,
+      None,
+    )
+    .await;
+    let Err(error) = result else {
+      panic!("Expected upstream {upstream_status} to fail");
+    };
+    let status = error.into_response().status();
+    assert!(
+      status == expected,
+      "Expected upstream {upstream_status} to map to {expected}, instead got {status}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_honors_matching_if_range() {
+  let (url, _) = spawn_upstream().await;
+  let mut headers = range_headers("bytes=100-199");
+  headers.insert("If-Range", VIDEO_ETAG.parse().unwrap());
+  let response =
+    stream_video(ClientPool::new(1).get(), &url, headers, None, None)
+      .await
+      .expect("Failed to stream video");
+
+  let status = response.status();
+  assert!(
+    status == StatusCode::PARTIAL_CONTENT,
+    "Expected matching If-Range to return 206, instead got {status}"
+  );
+  let etag = response.headers().get("ETag").cloned();
+  assert!(
+    etag.as_ref().is_some_and(|etag| etag == VIDEO_ETAG),
+    "Expected upstream ETag to be forwarded, instead got {etag:?}"
+  );
+  let body = read_body(response).await;
+  assert!(
+    body == video_bytes()[100..=199],
+    "Expected requested range to be streamed"
+  );
+}
+
+#[tokio::test]
+async fn it_sends_the_first_window_on_stale_if_range() {
+  let (url, upstream) = spawn_upstream().await;
+  let mut headers = range_headers("bytes=100-199");
+  headers.insert("If-Range", "\"video-v0\"".parse().unwrap());
+  let response =
+    stream_video(ClientPool::new(1).get(), &url, headers, None, Some(1023))
+      .await
+      .expect("Failed to stream video");
+
+  let status = response.status();
+  assert!(
+    status == StatusCode::OK,
+    "Expected stale If-Range to return 200, instead got {status}"
+  );
+  let content_range = response.headers().get("Content-Range").cloned();
+  assert!(
+    content_range.is_none(),
+    "Expected no Content-Range on a 200, instead got {content_range:?}"
+  );
+  let content_length = response.headers().get("Content-Length").cloned();
+  assert!(
+    content_length
+      .as_ref()
+      .is_some_and(|length| length == "1024"),
+    "Expected the length of the first window, instead got {content_length:?}"
+  );
+  let body = read_body(response).await;
+  assert!(
+    body == video_bytes()[..1024],
+    "Expected only the first window, instead got {} bytes",
+    body.len()
+  );
+  let requests = upstream.peers.lock().await.len();
+  assert!(
+    requests == 2,
+    "Expected the first window to be requested again, instead got {requests} requests"
+  );
+}
+
+#[tokio::test]
+async fn it_times_out_waiting_for_upstream() {
+  let (url, _) = spawn_upstream().await;
+  let slow_url = url.replace("/video", "/slow");
+  let request = reqwest::Client::new().get(&slow_url);
+  let result = send_with_timeout(request, Duration::from_millis(50)).await;
+  let Err(error) = result else {
+    panic!("Expected slow upstream to time out");
+  };
+  let status = error.into_response().status();
+  assert!(
+    status == StatusCode::GATEWAY_TIMEOUT,
+    "Expected 504 for slow upstream, instead got {status}"
+  );
+}
+
+#[tokio::test]
+async fn it_maps_client_timeouts_to_gateway_timeout() {
+  let (url, _) = spawn_upstream().await;
+  let client = reqwest::Client::builder()
+    .timeout(Duration::from_millis(50))
+    .build()
+    .expect("Failed to build client");
+  let result = client.get(url.replace("/video", "/slow")).send().await;
+  let Err(error) = result else {
+    panic!("Expected slow upstream to time out");
+  };
+  let status = APIError::from(error).into_response().status();
+  assert!(
+    status == StatusCode::GATEWAY_TIMEOUT,
+    "Expected 504 for client timeout, instead got {status}"
+  );
+}
+
+#[tokio::test]
+async fn it_proxies_images_with_caching_headers() {
+  let (url, _) = spawn_upstream().await;
+  let url = url.replace("/video", "/thumbnail");
+  let response = proxy_image(&reqwest::Client::new(), &url)
+    .await
+    .expect("Failed to proxy image");
+
+  let content_type = response.headers().get("Content-Type");
+  assert!(
+    content_type.is_some_and(|value| value == "image/jpeg"),
+    "Expected image content type, instead got {content_type:?}"
+  );
+  let cache_control = response.headers().get("Cache-Control");
+  assert!(
+    cache_control.is_some_and(|value| value
+      .to_str()
+      .is_ok_and(|value| value.starts_with("public, max-age="))),
+    "Expected public Cache-Control, instead got {cache_control:?}"
+  );
+  let body = read_body(response).await;
+  assert!(
+    body == [0xff, 0xd8, 0xff, 0xd9],
+    "Expected upstream image bytes, instead got {body:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_reports_non_image_upstreams_as_not_found() {
+  let (url, _) = spawn_upstream().await;
+  for path in ["/html", "/status/404"] {
+    let result =
+      proxy_image(&reqwest::Client::new(), &url.replace("/video", path)).await;
+    assert!(
+      matches!(result, Err(APIError::NotFound(_))),
+      "Expected {path} to be not found, instead got {result:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_answers_head_with_headers_and_no_body() {
+  let app = Router::new().route(
+    "/video",
+    get(|| async { "Video content" })
+      .head(|| async { video_head_response(Some(4096), "video/mp4") }),
+  );
+  let url = f!("{}/video", serve(app));
+  let response = reqwest::Client::new()
+    .head(&url)
+    .send()
+    .await
+    .expect("Failed to send HEAD request");
+
+  let headers = response.headers();
+  for (name, expected) in [
+    ("Content-Length", "4096"),
+    ("Content-Type", "video/mp4"),
+    ("Accept-Ranges", "bytes"),
+  ] {
+    let value = headers.get(name);
+    assert!(
+      value.is_some_and(|value| value == expected),
+      "Expected {name} {expected:?}, instead got {value:?}"
+    );
+  }
+  let body = response.bytes().await.expect("Failed to read body");
+  assert!(body.is_empty(), "Expected empty body, instead got {body:?}");
+}
+
+#[tokio::test]
+async fn it_retries_transient_upstream_errors() {
+  let (url, hits) = spawn_flaky_upstream(2, StatusCode::SERVICE_UNAVAILABLE);
+  let response =
+    send_with_retry(reqwest::Client::new().get(url), &TEST_RETRY_POLICY)
+      .await
+      .expect("Failed to send request");
+
+  let (status, hits) = (response.status(), hits.load(Ordering::SeqCst));
+  assert!(
+    status == StatusCode::OK && hits == 3,
+    "Expected success on the third attempt, instead got {status} after {hits}"
+  );
+}
+
+#[tokio::test]
+async fn it_does_not_retry_permanent_upstream_errors() {
+  let (url, hits) = spawn_flaky_upstream(2, StatusCode::NOT_FOUND);
+  let response =
+    send_with_retry(reqwest::Client::new().get(url), &TEST_RETRY_POLICY)
+      .await
+      .expect("Failed to send request");
+
+  let (status, hits) = (response.status(), hits.load(Ordering::SeqCst));
+  assert!(
+    status == StatusCode::NOT_FOUND && hits == 1,
+    "Expected a single failed attempt, instead got {status} after {hits}"
+  );
+}
+
+#[tokio::test]
+async fn it_hides_unexpected_upstream_json_from_clients() {
+  let app = Router::new().route(
+    "/",
+    get(|| async { axum::Json(serde_json::json!({ "secret": "upstream" })) }),
+  );
+  let response = reqwest::get(serve(app))
+    .await
+    .expect("Failed to reach upstream");
+  let Err(error) = json_response::<DriveFile>(response).await else {
+    panic!("Expected the unexpected JSON shape to fail");
+  };
+
+  let response = error.into_response();
+  let status = response.status();
+  let body = String::from_utf8(read_body(response).await)
+    .expect("Response body should be text");
+  assert!(
+    status == StatusCode::BAD_GATEWAY && !body.contains("secret"),
+    "Expected a sanitized 502, instead got {status} {body}"
+  );
+}
+
+#[tokio::test]
+async fn it_hides_upstream_errors_from_clients() {
+  let app = Router::new().route(
+    "/",
+    get(|| async {
+      let error = serde_json::json!({ "error": { "message": "secret" } });
+      (StatusCode::FORBIDDEN, axum::Json(error))
+    }),
+  );
+  let url = f!("{}/?key=api-key", serve(app));
+  let response = reqwest::get(url).await.expect("Failed to reach upstream");
+  let Err(error) = json_response::<DriveFile>(response).await else {
+    panic!("Expected the upstream error to fail");
+  };
+
+  let response = error.into_response();
+  let status = response.status();
+  let body = String::from_utf8(read_body(response).await)
+    .expect("Response body should be text");
+  assert!(
+    status == StatusCode::FORBIDDEN
+      && !body.contains("secret")
+      && !body.contains("api-key"),
+    "Expected a sanitized 403, instead got {status} {body}"
+  );
+}
+
+#[tokio::test]
+async fn it_hides_failed_request_urls_from_clients() {
+  let Err(error) = reqwest::get("http://127.0.0.1:1/?key=api-key").await else {
+    panic!("Expected the request to fail");
+  };
+
+  let response = APIError::from(error).into_response();
+  let body = String::from_utf8(read_body(response).await)
+    .expect("Response body should be text");
+  assert!(
+    !body.contains("api-key") && !body.contains("127.0.0.1"),
+    "Expected the url to be left out, instead got {body}"
+  );
+}
+
+#[test]
+fn it_redacts_credentials_from_upstream_logs() {
+  let url = reqwest::Url::parse(
+    "https://www.googleapis.com/drive/v3/files/id?fields=id&key=secret-key&access_token=secret-token",
+  )
+  .expect("Invalid url");
+  let line = upstream_log_line(
+    &reqwest::Method::GET,
+    &url,
+    Ok(StatusCode::OK),
+    Duration::from_millis(42),
+  );
+
+  assert!(
+    !line.contains("secret-key") && !line.contains("secret-token"),
+    "Expected credentials to be redacted, instead got {line:?}"
+  );
+  let expected = "GET https://www.googleapis.com/drive/v3/files/id?fields=id&key=REDACTED&access_token=REDACTED 200 OK in 42ms";
+  assert!(
+    line == expected,
+    "Expected {expected:?}, instead got {line:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_answers_unchanged_listings_with_not_modified() {
+  let listing = vec!["FileOne", "FileTwo"];
+  let response = json_with_etag(&HeaderMap::new(), &listing)
+    .expect("Failed to build listing response");
+  let etag = response
+    .headers()
+    .get("ETag")
+    .cloned()
+    .expect("Expected listing response to have an ETag");
+  assert!(
+    response.status() == StatusCode::OK,
+    "Expected first listing to be {}, instead got {}",
+    StatusCode::OK,
+    response.status()
+  );
+
+  let mut headers = HeaderMap::new();
+  headers.insert("If-None-Match", etag.clone());
+  let unchanged = json_with_etag(&headers, &listing)
+    .expect("Failed to build unchanged listing response");
+  let status = unchanged.status();
+  let body = read_body(unchanged).await;
+  assert!(
+    status == StatusCode::NOT_MODIFIED && body.is_empty(),
+    "Expected unchanged listing to be an empty {}, instead got {status} with {body:?}",
+    StatusCode::NOT_MODIFIED
+  );
+
+  let changed = json_with_etag(&headers, &vec!["FileOne"])
+    .expect("Failed to build changed listing response");
+  let changed_etag = changed.headers().get("ETag").cloned();
+  assert!(
+    changed.status() == StatusCode::OK && changed_etag != Some(etag),
+    "Expected changed listing to be {} with a new ETag, instead got {} with {changed_etag:?}",
+    StatusCode::OK,
+    changed.status()
+  );
+}
@@ -0,0 +1,76 @@
+#![cfg(test)]
+use super::serve;
+use crate::{
+  metrics::{self, Metrics},
+  websockets::WebSocketState,
+};
+use axum::{routing::get, Router};
+use format as f;
+use std::time::Duration;
+
+#[tokio::test]
+async fn it_counts_requests_by_route() {
+  let app = metrics::tracked(
+    Router::new()
+      .route("/counted/:id", get(|| async { "COUNTED" }))
+      .route("/metrics", get(metrics::metrics)),
+  )
+  .with_state(WebSocketState::new());
+  let url = serve(app);
+  let client = reqwest::Client::new();
+
+  for id in ["1", "2"] {
+    client
+      .get(f!("{url}/counted/{id}"))
+      .send()
+      .await
+      .unwrap_or_else(|e| panic!("Request to {id:?} failed: {e}"));
+  }
+  let response = client
+    .get(f!("{url}/metrics"))
+    .send()
+    .await
+    .expect("Request to metrics failed");
+  let content_type = response.headers().get("content-type").cloned();
+  let body = response.text().await.expect("Failed to read metrics");
+
+  let counter =
+    r#"http_requests_total{method="GET",route="/counted/:id",status="200"} 2"#;
+  assert!(
+    body.lines().any(|line| line == counter),
+    "Expected {counter:?} in metrics, instead got {body}"
+  );
+  assert!(
+    body.contains("websocket_connections 0"),
+    "Expected websocket connections in metrics, instead got {body}"
+  );
+  assert!(
+    content_type
+      .as_ref()
+      .is_some_and(|value| value == "text/plain; version=0.0.4"),
+    "Expected Prometheus content type, instead got {content_type:?}"
+  );
+}
+
+#[test]
+fn it_renders_query_latency_histograms() {
+  let metrics = Metrics::default();
+  metrics.observe_query("find_many", "files", Duration::from_millis(20));
+  metrics.observe_query("find_many", "files", Duration::from_secs(10));
+  let text = metrics.render(0);
+
+  let labels = r#"operation="find_many",collection="files""#;
+  for (le, count) in [("0.01", 0), ("0.025", 1), ("5", 1), ("+Inf", 2)] {
+    let bucket =
+      f!("db_query_duration_seconds_bucket{{{labels},le=\"{le}\"}} {count}");
+    assert!(
+      text.lines().any(|line| line == bucket),
+      "Expected {bucket:?} in metrics, instead got {text}"
+    );
+  }
+  let count = f!("db_query_duration_seconds_count{{{labels}}} 2");
+  assert!(
+    text.lines().any(|line| line == count),
+    "Expected {count:?} in metrics, instead got {text}"
+  );
+}
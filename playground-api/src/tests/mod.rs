@@ -1,20 +1,79 @@
 #![cfg(test)]
+mod auth;
+mod cache;
+mod console;
+mod cors;
+mod database;
+mod drive;
+mod errors;
 mod files;
+mod health;
+mod http;
+mod metrics;
+mod oauth;
+mod rate_limit;
+mod routes;
+mod sessions;
+mod string;
+mod users;
+mod websockets;
 
 use crate::{
   console::Colorize,
   db::{
     files::{system::FileSystem, File, FileMetadata, Video},
-    Database,
+    shares::Share,
+    Collection, Database,
   },
-  log, GracefulExit,
+  log,
+  string::StringResult,
+  GracefulExit,
 };
+use axum::{body::HttpBody, response::IntoResponse, Router};
 use format as f;
-use mongodb::bson::doc;
+use mongodb::{
+  bson::doc,
+  error::{BulkWriteFailure, ErrorKind},
+  options::InsertManyOptions,
+};
+use std::{
+  collections::HashSet,
+  net::{SocketAddr, TcpListener},
+};
 
 pub const USER_ID1: &str = "google@test1";
 pub const USER_ID2: &str = "google@test2";
 
+/// Server error code for writes that violate a unique index.
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+/// Collect the full body of `response`.
+pub async fn read_body(response: impl IntoResponse) -> Vec<u8> {
+  let mut body = response.into_response().into_body();
+  let mut bytes = Vec::new();
+  while let Some(chunk) = body.data().await {
+    bytes.extend(chunk.expect("Failed to read body chunk"));
+  }
+  bytes
+}
+
+/// Serve `app` on a random local port and return its base url.
+pub fn serve(app: Router) -> String {
+  let listener =
+    TcpListener::bind("127.0.0.1:0").expect("Failed to bind test server");
+  let address = listener.local_addr().expect("Test server has no address");
+  let server = axum::Server::from_tcp(listener)
+    .expect("Failed to create test server")
+    .serve(app.into_make_service_with_connect_info::<SocketAddr>());
+  tokio::spawn(server);
+  f!("http://{address}")
+}
+
+/// Env vars needed by code paths that don't touch the database.
+pub fn set_test_env() {
+  std::env::set_var("JWT_SECRET", "playground-api-test-secret");
+}
+
 pub async fn get_database() -> (FileSystem, Database) {
   let database = Database::new("test")
     .await
@@ -31,6 +90,15 @@ pub async fn cleanup_files_collection(database: &Database) {
   log!(success@"Removed {deleted_count} documents from files collection");
 }
 
+pub async fn cleanup_shares_collection(database: &Database) {
+  log!(info@"Cleaning up shares collection");
+  let deleted_count = database
+    .delete_many::<Share>(doc! {})
+    .await
+    .unwrap_or_exit("Failed to cleanup shares collection");
+  log!(success@"Removed {deleted_count} documents from shares collection");
+}
+
 #[derive(Clone)]
 pub struct NestedFolderOptions<'a> {
   pub depth: usize,
@@ -69,6 +137,7 @@ pub async fn create_nested_folders<'a>(
           parent_id.to_string()
         }),
       )
+      .unwrap_or_exit(f!("Could not create folder {prefix}-{i}"))
     })
     .collect::<Vec<_>>();
   let ids = insert_many(database, files.as_slice()).await;
@@ -161,25 +230,62 @@ pub async fn create_dummy_folder_structure(
   (ids_one, ids_two, ids_three, files)
 }
 
-pub async fn insert_many(database: &Database, files: &[File]) -> Vec<String> {
-  let inserted_ids = database
-    .create_many(files)
-    .await
-    .unwrap_or_exit("create_many database call failed")
-    .into_values()
-    .map(|id| {
-      id.as_str()
-        .map(String::from)
-        .unwrap_or_else(|| id.to_string())
-    })
-    .collect::<Vec<_>>();
+/// Ids of the docs `create_many` inserted and of those it skipped because
+/// their id was taken, in the order they were given.
+#[derive(Debug, Default)]
+pub struct CreateManyResult {
+  pub inserted: Vec<String>,
+  pub duplicates: Vec<String>,
+}
+
+/// Insert docs only if they don't exist, docs whose id is taken are skipped.
+pub async fn create_many<T: Collection>(
+  database: &Database,
+  docs: &[T],
+) -> mongodb::error::Result<CreateManyResult> {
+  let options = InsertManyOptions::builder().ordered(false).build();
+  let error = match database.collection::<T>().insert_many(docs, options).await
+  {
+    Ok(_) => {
+      return Ok(CreateManyResult {
+        inserted: docs.iter().map(|doc| doc.id().to_string()).collect(),
+        duplicates: Vec::new(),
+      })
+    }
+    Err(error) => error,
+  };
+  let ErrorKind::BulkWrite(BulkWriteFailure {
+    write_errors: Some(write_errors),
+    write_concern_error: None,
+    ..
+  }) = &*error.kind
+  else {
+    return Err(error);
+  };
+  if write_errors.iter().any(|e| e.code != DUPLICATE_KEY_CODE) {
+    return Err(error);
+  }
 
-  files
+  let duplicates = write_errors.iter().map(|e| e.index).collect::<HashSet<_>>();
+  let (duplicates, inserted) = docs
     .iter()
-    .filter_map(|file| {
-      inserted_ids.contains(&file.id).then_some(file.id.clone())
-    })
-    .collect()
+    .enumerate()
+    .map(|(i, doc)| (i, doc.id().to_string()))
+    .partition::<Vec<_>, _>(|(i, _)| duplicates.contains(i));
+  Ok(CreateManyResult {
+    inserted: inserted.into_iter().map(|(_, id)| id).collect(),
+    duplicates: duplicates.into_iter().map(|(_, id)| id).collect(),
+  })
+}
+
+pub async fn insert_many(database: &Database, files: &[File]) -> Vec<String> {
+  let result = create_many(database, files)
+    .await
+    .unwrap_or_exit("create_many database call failed");
+  if !result.duplicates.is_empty() {
+    log!(err@"Skipped files that already exist: {:?}", result.duplicates);
+  }
+  result.inserted
 }
 
 pub fn create_folder_with_custom_id(
@@ -187,14 +293,16 @@ pub fn create_folder_with_custom_id(
   user_id: String,
   name: String,
   folder_id: Option<String>,
-) -> File {
-  File {
+) -> StringResult<File> {
+  Ok(File {
     id,
     folder_id: folder_id
       .map(|folder_id| File::map_folder_id(&user_id, &folder_id).to_string())
       .unwrap_or_else(|| user_id.clone()),
     user_id,
-    name: name.try_into().unwrap_or_default(),
-    metadata: FileMetadata::Folder,
-  }
+    name: name.try_into()?,
+    metadata: FileMetadata::folder(),
+    starred: false,
+    version: 0,
+  })
 }
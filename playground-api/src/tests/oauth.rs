@@ -0,0 +1,303 @@
+#![cfg(test)]
+use super::{get_database, serve, set_test_env};
+use crate::{
+  api::APIError,
+  auth::{self, google::pick_login_redirect, oauth::Token},
+  db::oauth_states::{save_oauth_state, take_oauth_state, OAuthState},
+  AppState, GracefulExit,
+};
+use axum::{http::StatusCode, routing::post, Form, Json, Router};
+use chrono::Utc;
+use format as f;
+use mongodb::{
+  bson::{doc, DateTime},
+  options::IndexOptions,
+  IndexModel,
+};
+use oauth2::{
+  basic::{BasicClient, BasicTokenType},
+  AccessToken, AuthUrl, ClientId, ClientSecret, EmptyExtraTokenFields,
+  PkceCodeVerifier, StandardTokenResponse, TokenUrl,
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+#[tokio::test]
+async fn it_shares_oauth_state_between_instances() {
+  let (_, login_instance) = get_database().await;
+  let (_, callback_instance) = get_database().await;
+  let csrf_token = "shared-csrf-token";
+  save_oauth_state(
+    &OAuthState::new(csrf_token, "pkce-verifier"),
+    &login_instance,
+  )
+  .await
+  .unwrap_or_exit("Failed to save OAuth state");
+
+  let state = take_oauth_state(csrf_token, &callback_instance)
+    .await
+    .unwrap_or_exit("Failed to take OAuth state");
+  assert!(
+    state.is_some_and(|state| state._id == csrf_token),
+    "Expected state saved by another instance, instead got nothing"
+  );
+  let state = take_oauth_state(csrf_token, &login_instance)
+    .await
+    .unwrap_or_exit("Failed to take OAuth state");
+  assert!(
+    state.is_none(),
+    "Expected state to be usable once, instead got {state:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_oauth_states_past_their_expiry() {
+  let (_, database) = get_database().await;
+  let csrf_token = "expired-csrf-token";
+  let expired = OAuthState {
+    expires_at: DateTime::from_millis(DateTime::now().timestamp_millis() - 1),
+    ..OAuthState::new(csrf_token, "pkce-verifier")
+  };
+  save_oauth_state(&expired, &database)
+    .await
+    .unwrap_or_exit("Failed to save OAuth state");
+
+  let state = take_oauth_state(csrf_token, &database)
+    .await
+    .unwrap_or_exit("Failed to take OAuth state");
+  assert!(
+    state.is_none(),
+    "Expected the expired state to be rejected, instead got {state:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_replaces_the_oauth_state_index_holding_a_ttl() {
+  let (_, database) = get_database().await;
+  let states = database.collection::<OAuthState>();
+  let legacy = IndexModel::builder()
+    .keys(doc! { "createdAt": 1 })
+    .options(
+      IndexOptions::builder()
+        .expire_after(Duration::from_secs(1))
+        .build(),
+    )
+    .build();
+  states
+    .create_index(legacy, None)
+    .await
+    .unwrap_or_exit("Failed to create the legacy index");
+
+  let ensured = database.ensure_indexes().await;
+  let indexes = states
+    .list_index_names()
+    .await
+    .unwrap_or_exit("Failed to list indexes");
+  assert!(
+    ensured.is_ok()
+      && !indexes.iter().any(|name| name == "createdAt_1")
+      && indexes.iter().any(|name| name == "expiresAt_1"),
+    "Expected the TTL index to be replaced, instead got {ensured:?} {indexes:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_login_callbacks_with_unknown_state() {
+  set_test_env();
+  for (name, value) in [
+    ("LOGIN_REDIRECT", "http://localhost/login"),
+    ("GOOGLE_CLIENT_ID", "client-id"),
+    ("GOOGLE_CLIENT_SECRET", "client-secret"),
+    (
+      "GOOGLE_REDIRECT_URL",
+      "http://localhost/auth/google/authorized",
+    ),
+  ] {
+    std::env::set_var(name, value);
+  }
+  let (_, database) = get_database().await;
+  let state = AppState::new(&database).unwrap_or_exit("Failed to create state");
+  let app = Router::new()
+    .nest(
+      "/auth",
+      auth::api().unwrap_or_exit("Failed to create auth API"),
+    )
+    .with_state(state);
+
+  let url = f!("{}/auth/google/authorized", serve(app));
+  let status = reqwest::Client::new()
+    .get(url)
+    .query(&[("code", "code"), ("state", "unknown-csrf-token")])
+    .send()
+    .await
+    .expect("Callback request failed")
+    .status();
+  assert!(
+    status == StatusCode::UNAUTHORIZED,
+    "Expected unknown state to be rejected, instead got {status}"
+  );
+}
+
+type Received = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+/// OAuth client whose token endpoint is a stub that records every form it
+/// gets and hands out a new access token.
+fn token_endpoint_client() -> (BasicClient, Received) {
+  let received = Received::default();
+  let forms = received.clone();
+  let app = Router::new().route(
+    "/token",
+    post(
+      move |Form(params): Form<HashMap<String, String>>| async move {
+        forms.lock().await.push(params);
+        Json(serde_json::json!({
+          "access_token": "access-token",
+          "token_type": "Bearer",
+          "expires_in": 3600,
+        }))
+      },
+    ),
+  );
+  let token_url = f!("{}/token", serve(app));
+  let client = BasicClient::new(
+    ClientId::new("client-id".into()),
+    Some(ClientSecret::new("client-secret".into())),
+    AuthUrl::new("http://localhost/auth".into()).expect("Valid auth url"),
+    Some(TokenUrl::new(token_url).expect("Valid token url")),
+  );
+  (client, received)
+}
+
+#[tokio::test]
+async fn it_sends_pkce_verifier_when_exchanging_code() {
+  let (client, received) = token_endpoint_client();
+  let token = Token::exchange(
+    &client,
+    "code".into(),
+    PkceCodeVerifier::new("pkce-verifier".into()),
+  )
+  .await
+  .unwrap_or_exit("Failed to exchange code");
+  let received = received.lock().await;
+  let params = &received[0];
+  assert!(
+    params
+      .get("code_verifier")
+      .is_some_and(|verifier| verifier == "pkce-verifier"),
+    "Expected the PKCE verifier to be sent, instead got {params:?}"
+  );
+  assert!(
+    token.access_token == "access-token",
+    "Expected exchanged token, instead got {token:?}"
+  );
+}
+
+fn token_expiring_in(seconds: i64) -> Token {
+  Token {
+    access_token: "old-access-token".into(),
+    refresh_token: Some("refresh-token".into()),
+    expires_seconds: 3600,
+    expires_at: Utc::now().timestamp() + seconds,
+  }
+}
+
+#[tokio::test]
+async fn it_refreshes_tokens_about_to_expire() {
+  let (client, received) = token_endpoint_client();
+  let buffer = Duration::from_secs(300);
+
+  let token = token_expiring_in(10)
+    .refresh_if_expiring(&client, buffer)
+    .await
+    .unwrap_or_exit("Failed to refresh token");
+  assert!(
+    token.as_ref().is_some_and(|token| token.access_token == "access-token"
+      && token.refresh_token.as_deref() == Some("refresh-token")
+      && !token.expires_within(buffer)),
+    "Expected a refreshed token keeping the refresh token, instead got {token:?}"
+  );
+  let params = received.lock().await.pop();
+  assert!(
+    params.as_ref().is_some_and(|params| params
+      .get("refresh_token")
+      .is_some_and(|token| token == "refresh-token")),
+    "Expected the refresh token to be sent, instead got {params:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_keeps_tokens_far_from_expiring() {
+  let (client, received) = token_endpoint_client();
+  let token = token_expiring_in(3600)
+    .refresh_if_expiring(&client, Duration::from_secs(300))
+    .await
+    .unwrap_or_exit("Failed to check token");
+
+  let requests = received.lock().await.len();
+  assert!(
+    token.is_none() && requests == 0,
+    "Expected no refresh, instead got {token:?} after {requests} requests"
+  );
+}
+
+#[test]
+fn it_computes_token_expiry_from_response() {
+  let mut response = StandardTokenResponse::new(
+    AccessToken::new("access-token".into()),
+    BasicTokenType::Bearer,
+    EmptyExtraTokenFields {},
+  );
+  response.set_expires_in(Some(&Duration::from_secs(3600)));
+
+  let before = Utc::now().timestamp();
+  let token = Token::from(response);
+  let after = Utc::now().timestamp();
+  assert!(
+    (before + 3600..=after + 3600).contains(&token.expires_at)
+      && token.expires_seconds == 3600,
+    "Expected expiry an hour from now, instead got {token:?}"
+  );
+}
+
+#[test]
+fn it_treats_tokens_without_expiry_as_expired() {
+  let token: Token = serde_json::from_value(serde_json::json!({
+    "accessToken": "access-token",
+    "expiresSeconds": 3600,
+  }))
+  .expect("Failed to deserialize token");
+  assert!(
+    token.expires_within(Duration::ZERO),
+    "Expected a token saved without expiresAt to be expired, instead got {token:?}"
+  );
+}
+
+#[test]
+fn it_rejects_login_redirects_off_the_allowlist() {
+  let default = "http://localhost/login";
+  let allowed = "http://localhost:3000/login";
+  let allowlist = vec![allowed.to_string()];
+  for (requested, expected) in [
+    (None, default),
+    (Some(default), default),
+    (Some(allowed), allowed),
+  ] {
+    let redirect = pick_login_redirect(default, &allowlist, requested);
+    assert!(
+      redirect.as_deref().is_ok_and(|redirect| redirect == expected),
+      "Expected redirect {requested:?} to be {expected:?}, instead got {redirect:?}"
+    );
+  }
+
+  for requested in [
+    "https://evil.example/login",
+    "http://localhost:3000/login/../admin",
+    "http://localhost:3000",
+  ] {
+    let redirect = pick_login_redirect(default, &allowlist, Some(requested));
+    assert!(
+      matches!(redirect, Err(APIError::BadRequest(_))),
+      "Expected redirect {requested:?} to be rejected, instead got {redirect:?}"
+    );
+  }
+}
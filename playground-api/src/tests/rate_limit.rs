@@ -0,0 +1,63 @@
+#![cfg(test)]
+use crate::{
+  api::APIError,
+  rate_limit::{client_ip, RateLimiter},
+};
+use axum::http::HeaderMap;
+use std::time::Duration;
+
+#[tokio::test]
+async fn it_rejects_requests_over_the_limit() {
+  let limit = 3;
+  let limiter = RateLimiter::new(limit, Duration::from_secs(60));
+  for i in 0..limit {
+    let result = limiter.check("client").await;
+    assert!(
+      result.is_ok(),
+      "Expected request #{i} to be allowed, instead got {result:?}"
+    );
+  }
+
+  let result = limiter.check("client").await;
+  assert!(
+    matches!(result, Err(APIError::TooManyRequests(_))),
+    "Expected request over the limit to be rejected, instead got {result:?}"
+  );
+  let result = limiter.check("other-client").await;
+  assert!(
+    result.is_ok(),
+    "Expected other clients to have their own limit, instead got {result:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_refills_after_the_window() {
+  let limiter = RateLimiter::new(1, Duration::from_millis(50));
+  let _ = limiter.check("client").await;
+  tokio::time::sleep(Duration::from_millis(60)).await;
+  let result = limiter.check("client").await;
+  assert!(
+    result.is_ok(),
+    "Expected request after the window to be allowed, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_trusts_only_the_last_forwarded_address() {
+  let address = "10.0.0.1:4000".parse().unwrap();
+  let mut headers = HeaderMap::new();
+  headers.insert("X-Forwarded-For", "1.1.1.1, 2.2.2.2".parse().unwrap());
+
+  let cases = [
+    (Some("X-Forwarded-For"), "2.2.2.2"),
+    (None, "10.0.0.1"),
+    (Some("X-Real-IP"), "10.0.0.1"),
+  ];
+  for (trusted_header, expected) in cases {
+    let ip = client_ip(&headers, trusted_header, address).to_string();
+    assert!(
+      ip == expected,
+      "Expected {expected} trusting {trusted_header:?}, instead got {ip}"
+    );
+  }
+}
@@ -0,0 +1,102 @@
+#![cfg(test)]
+use super::serve;
+use crate::{cors, routes};
+use axum::{routing::get, Router};
+use format as f;
+use reqwest::StatusCode;
+
+#[test]
+fn it_normalizes_api_prefix() {
+  for (prefix, expected) in [
+    ("", ""),
+    ("/", ""),
+    ("v1", "/v1"),
+    ("/v1/", "/v1"),
+    (" /v1 ", "/v1"),
+  ] {
+    let actual = routes::normalize_prefix(prefix);
+    assert!(
+      actual == expected,
+      "Expected {prefix:?} to become {expected:?}, instead got {actual:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_serves_routes_under_api_prefix() {
+  let cors = cors::build(Some("https://app.example.com"), None, true)
+    .expect("Valid CORS config");
+  let app = Router::new()
+    .route("/ping", get(|| async { "PONG" }))
+    .layer(cors);
+  let url = serve(routes::with_prefix(app, "/v1"));
+  let client = reqwest::Client::new();
+
+  let response = client
+    .get(f!("{url}/v1/ping"))
+    .header("Origin", "https://app.example.com")
+    .send()
+    .await
+    .expect("Prefixed request failed");
+  let status = response.status();
+  let origin = response
+    .headers()
+    .get("access-control-allow-origin")
+    .and_then(|value| value.to_str().ok())
+    .map(String::from);
+  assert!(
+    status == StatusCode::OK
+      && origin.as_deref() == Some("https://app.example.com"),
+    "Expected prefixed route with CORS headers, instead got {status} {origin:?}"
+  );
+
+  let status = client
+    .get(f!("{url}/ping"))
+    .send()
+    .await
+    .expect("Unprefixed request failed")
+    .status();
+  assert!(
+    status == StatusCode::NOT_FOUND,
+    "Expected unprefixed route to be missing, instead got {status}"
+  );
+}
+
+#[tokio::test]
+async fn it_serves_versioned_and_deprecated_routes() {
+  let api = Router::new().route("/files", get(|| async { "FILES" }));
+  let url = serve(routes::versioned(api));
+  let client = reqwest::Client::new();
+
+  for (path, deprecated) in [("/api/v1/files", false), ("/api/files", true)] {
+    let response = client
+      .get(f!("{url}{path}"))
+      .send()
+      .await
+      .unwrap_or_else(|e| panic!("Request to {path:?} failed: {e}"));
+    let status = response.status();
+    let deprecation = response.headers().get("deprecation").cloned();
+    assert!(
+      status == StatusCode::OK && deprecation.is_some() == deprecated,
+      "Expected {path:?} to respond with deprecated {deprecated}, instead got {status} {deprecation:?}"
+    );
+  }
+}
+
+#[tokio::test]
+async fn it_links_to_the_current_api_version() {
+  let api = Router::new().route("/files", get(|| async { "FILES" }));
+  let app = routes::with_prefix(routes::versioned(api), &routes::API_PREFIX);
+  let url = serve(app);
+  let path = routes::api_path("/files");
+
+  let response = reqwest::get(f!("{url}{path}"))
+    .await
+    .unwrap_or_else(|e| panic!("Request to {path:?} failed: {e}"));
+  let status = response.status();
+  let deprecation = response.headers().get("deprecation").cloned();
+  assert!(
+    status == StatusCode::OK && deprecation.is_none(),
+    "Expected {path:?} to be the current version, instead got {status} {deprecation:?}"
+  );
+}
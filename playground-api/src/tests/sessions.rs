@@ -0,0 +1,63 @@
+#![cfg(test)]
+use super::{get_database, set_test_env};
+use crate::{
+  auth::{
+    jwt,
+    session::{Session, SessionChanges, SESSIONS_CACHE},
+  },
+  GracefulExit,
+};
+use std::collections::HashSet;
+
+#[tokio::test]
+async fn it_restores_saved_sessions_after_restart() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let user_id = "google@restart";
+  let token = jwt::sign_token(user_id).unwrap_or_exit("Failed to sign token");
+  Session::save(&token, user_id).await;
+  database
+    .flush_session_changes()
+    .await
+    .unwrap_or_exit("Failed to flush session changes");
+
+  // Simulate a restart by forgetting the session before loading from db
+  SESSIONS_CACHE.lock().await.remove(&token);
+  database.load_sessions().await;
+  let restored = SESSIONS_CACHE.lock().await.get(&token).cloned();
+
+  Session::invalidate(&token).await;
+  database
+    .flush_session_changes()
+    .await
+    .unwrap_or_exit("Failed to flush session changes");
+  assert!(
+    restored.as_deref() == Some(user_id),
+    "Expected session for {user_id:?} to be restored, instead got {restored:?}"
+  );
+}
+
+#[test]
+fn it_keeps_newer_changes_when_restoring_unsaved_ones() {
+  let tokens = |tokens: &[&str]| {
+    tokens
+      .iter()
+      .map(|token| token.to_string())
+      .collect::<HashSet<_>>()
+  };
+  let unsaved = SessionChanges {
+    saved: tokens(&["kept", "invalidated since"]),
+    invalidated: tokens(&["gone", "saved since"]),
+  };
+  let mut pending = SessionChanges {
+    saved: tokens(&["saved since"]),
+    invalidated: tokens(&["invalidated since"]),
+  };
+  pending.restore(unsaved);
+
+  assert!(
+    pending.saved == tokens(&["kept", "saved since"])
+      && pending.invalidated == tokens(&["gone", "invalidated since"]),
+    "Expected changes made since to win, instead got {pending:?}"
+  );
+}
@@ -0,0 +1,97 @@
+#![cfg(test)]
+use super::USER_ID1;
+use crate::{
+  db::{files::File, DBError},
+  string::{NonEmptyString, StringError, MAX_LENGTH},
+};
+
+#[test]
+fn it_rejects_empty_strings() {
+  let result = NonEmptyString::try_from("");
+  assert!(
+    matches!(result, Err(StringError::Empty)),
+    "Expected empty string to be rejected, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_accepts_strings_at_max_length() {
+  let name = "a".repeat(*MAX_LENGTH);
+  let result = NonEmptyString::try_from(&name);
+  assert!(
+    result.as_deref().is_ok_and(|value| *value == name),
+    "Expected max length string to be accepted, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_rejects_strings_over_max_length() {
+  let name = "a".repeat(*MAX_LENGTH + 1);
+  let result = NonEmptyString::try_from(name);
+  assert!(
+    matches!(
+      result,
+      Err(StringError::TooLong { max, actual })
+        if max == *MAX_LENGTH && actual == *MAX_LENGTH + 1
+    ),
+    "Expected over length string to be rejected, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_rejects_path_separators() {
+  let result = NonEmptyString::try_from("a/b");
+  assert!(
+    matches!(result, Err(StringError::InvalidChar('/'))),
+    "Expected path separator to be rejected, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_rejects_whitespace_only_strings() {
+  let result = NonEmptyString::try_from("   ");
+  assert!(
+    matches!(result, Err(StringError::Empty)),
+    "Expected whitespace only string to be rejected, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_trims_valid_names() {
+  let result = NonEmptyString::try_from("  My Videos ");
+  assert!(
+    result.as_deref().is_ok_and(|value| value == "My Videos"),
+    "Expected trimmed name, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_fails_to_create_files_with_empty_names() {
+  let result = File::new_folder(USER_ID1.into(), "".into(), None);
+  assert!(
+    matches!(result, Err(DBError::String(StringError::Empty))),
+    "Expected empty folder name to be an error, instead got {result:?}"
+  );
+}
+
+#[test]
+fn it_formats_and_compares_as_a_str() {
+  let name = NonEmptyString::try_from("Movies").expect("valid name");
+  let formatted = format!("Folder {name}");
+  assert!(
+    formatted == "Folder Movies",
+    "Expected name to format as its value, instead got {formatted:?}"
+  );
+  assert!(name == "Movies", "Expected name to equal \"Movies\"");
+  assert!(name != "Series", "Expected name to differ from \"Series\"");
+}
+
+#[test]
+fn it_serializes_as_a_plain_string() {
+  let name = NonEmptyString::try_from("Movies").expect("valid name");
+  let json = serde_json::to_string(&name);
+  assert!(
+    json.as_deref().is_ok_and(|json| json == "\"Movies\""),
+    "Expected name to serialize as a plain string, instead got {json:?}"
+  );
+}
@@ -0,0 +1,395 @@
+#![cfg(test)]
+use super::{get_database, set_test_env};
+use crate::{
+  api::APIError,
+  auth::{google::save_login, oauth::Token, session::Session},
+  db::{
+    files::{File, Video},
+    providers::{save_provider, LinkedAccount, Provider},
+    users::{add_provider_to_user, save_user, User},
+    Database,
+  },
+  routes::users::{delete_current_user, linked_accounts, unlink_provider},
+};
+use axum::{
+  extract::{Path, State},
+  http::StatusCode,
+};
+use format as f;
+use mongodb::bson::doc;
+use std::collections::HashSet;
+
+const LINKED_USER_ID: &str = "google@linked-primary";
+const SECOND_PROVIDER_ID: &str = "google@linked-secondary";
+
+fn provider(id: &str) -> Provider {
+  Provider {
+    _id: id.into(),
+    email: "test@example.com".into(),
+    picture: f!("https://example.com/{id}.png"),
+    token: Token {
+      access_token: "access".into(),
+      refresh_token: None,
+      expires_seconds: 3600,
+      expires_at: 0,
+    },
+  }
+}
+
+async fn cleanup_user(database: &Database, user_id: &str) {
+  database
+    .delete::<User>(doc! { "_id": user_id })
+    .await
+    .expect("Failed to delete test user");
+  database
+    .delete_many::<File>(doc! { "userId": user_id })
+    .await
+    .expect("Failed to delete test user files");
+}
+
+#[tokio::test]
+async fn it_links_a_second_provider_to_an_existing_user() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  cleanup_user(&database, LINKED_USER_ID).await;
+  save_user(&User::new(LINKED_USER_ID, "Linked", ""), &database)
+    .await
+    .expect("Failed to save user");
+
+  let user =
+    add_provider_to_user(LINKED_USER_ID, SECOND_PROVIDER_ID, &database)
+      .await
+      .expect("Failed to link provider")
+      .expect("User should exist");
+  cleanup_user(&database, LINKED_USER_ID).await;
+
+  let expected =
+    HashSet::from([LINKED_USER_ID.to_string(), SECOND_PROVIDER_ID.to_string()]);
+  assert!(
+    user.linked_accounts == expected,
+    "Expected {expected:?} linked accounts, instead got {:?}",
+    user.linked_accounts
+  );
+}
+
+#[tokio::test]
+async fn it_links_accounts_logged_in_with_during_a_session() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let (user_id, secondary_id) = ("google@login-primary", "google@login-other");
+  cleanup_user(&database, user_id).await;
+  cleanup_user(&database, secondary_id).await;
+  save_user(&User::new(user_id, "Login", ""), &database)
+    .await
+    .expect("Failed to save user");
+
+  let session = Session {
+    user_id: user_id.into(),
+  };
+  let token =
+    save_login(&provider(secondary_id), "Other", Some(&session), &database)
+      .await;
+  let accounts = linked_accounts(session, State(database.clone()))
+    .await
+    .map(|accounts| accounts.0);
+  let secondary_user = database
+    .find_by_id::<User>(secondary_id)
+    .await
+    .expect("Failed to find user");
+  cleanup_user(&database, user_id).await;
+  cleanup_user(&database, secondary_id).await;
+  database
+    .delete::<Provider>(doc! { "_id": secondary_id })
+    .await
+    .expect("Failed to delete test provider");
+
+  assert!(
+    matches!(token, Ok(None)),
+    "Expected the session to be kept, instead got {token:?}"
+  );
+  let expected = vec![LinkedAccount {
+    id: secondary_id.into(),
+    picture: f!("https://example.com/{secondary_id}.png"),
+  }];
+  assert!(
+    accounts
+      .as_ref()
+      .is_ok_and(|accounts| *accounts == expected),
+    "Expected {expected:#?}, instead got {accounts:#?}"
+  );
+  assert!(
+    secondary_user.is_none(),
+    "Expected no user to be created for the linked account"
+  );
+}
+
+#[tokio::test]
+async fn it_refuses_to_link_accounts_of_other_users() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let (user_id, other_id) = ("google@link-taker", "google@link-owner");
+  for id in [user_id, other_id] {
+    cleanup_user(&database, id).await;
+    save_user(&User::new(id, "Link", ""), &database)
+      .await
+      .expect("Failed to save user");
+  }
+
+  let session = Session {
+    user_id: user_id.into(),
+  };
+  let result =
+    save_login(&provider(other_id), "Owner", Some(&session), &database).await;
+  let user = database
+    .find_by_id::<User>(user_id)
+    .await
+    .expect("Failed to find user");
+  for id in [user_id, other_id] {
+    cleanup_user(&database, id).await;
+  }
+
+  assert!(
+    matches!(result, Err(APIError::BadRequest(_))),
+    "Expected linking another user's account to fail, instead got {result:?}"
+  );
+  assert!(
+    user.is_some_and(|user| !user.linked_accounts.contains(other_id)),
+    "Expected the account to stay unlinked"
+  );
+}
+
+#[tokio::test]
+async fn it_unlinks_a_secondary_provider() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let (user_id, secondary_id) =
+    ("google@unlink-primary", "google@unlink-other");
+  cleanup_user(&database, user_id).await;
+  save_user(&User::new(user_id, "Unlink", ""), &database)
+    .await
+    .expect("Failed to save user");
+  save_provider(&provider(secondary_id), &database)
+    .await
+    .expect("Failed to save provider");
+  add_provider_to_user(user_id, secondary_id, &database)
+    .await
+    .expect("Failed to link provider");
+
+  let status = unlink_provider(
+    Session {
+      user_id: user_id.into(),
+    },
+    State(database.clone()),
+    Path(secondary_id.into()),
+  )
+  .await;
+  let user = database
+    .find_by_id::<User>(user_id)
+    .await
+    .expect("Failed to find user");
+  let provider = database
+    .find_by_id::<Provider>(secondary_id)
+    .await
+    .expect("Failed to find provider");
+  cleanup_user(&database, user_id).await;
+
+  assert!(
+    matches!(status, Ok(StatusCode::NO_CONTENT)),
+    "Expected provider to be unlinked, instead got {status:?}"
+  );
+  assert!(
+    user.is_some_and(|user| !user.linked_accounts.contains(secondary_id)),
+    "Expected provider to be removed from linked accounts"
+  );
+  assert!(
+    provider.is_none(),
+    "Expected provider document to be deleted"
+  );
+}
+
+#[tokio::test]
+async fn it_refuses_to_unlink_the_last_account() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let user_id = "google@unlink-last";
+  cleanup_user(&database, user_id).await;
+  save_user(&User::new(user_id, "Last", ""), &database)
+    .await
+    .expect("Failed to save user");
+
+  let result = unlink_provider(
+    Session {
+      user_id: user_id.into(),
+    },
+    State(database.clone()),
+    Path(user_id.into()),
+  )
+  .await;
+  let user = database
+    .find_by_id::<User>(user_id)
+    .await
+    .expect("Failed to find user");
+  cleanup_user(&database, user_id).await;
+
+  assert!(
+    matches!(result, Err(APIError::BadRequest(_))),
+    "Expected unlinking the last account to fail, instead got {result:?}"
+  );
+  assert!(
+    user.is_some_and(|user| user.linked_accounts.contains(user_id)),
+    "Expected last account to stay linked"
+  );
+}
+
+#[tokio::test]
+async fn it_returns_every_linked_account() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let (user_id, secondary_id) =
+    ("google@accounts-primary", "google@accounts-other");
+  cleanup_user(&database, user_id).await;
+  save_user(&User::new(user_id, "Accounts", ""), &database)
+    .await
+    .expect("Failed to save user");
+  for id in [user_id, secondary_id] {
+    save_provider(&provider(id), &database)
+      .await
+      .expect("Failed to save provider");
+  }
+  add_provider_to_user(user_id, secondary_id, &database)
+    .await
+    .expect("Failed to link provider");
+
+  let accounts = linked_accounts(
+    Session {
+      user_id: user_id.into(),
+    },
+    State(database.clone()),
+  )
+  .await
+  .map(|accounts| accounts.0);
+  cleanup_user(&database, user_id).await;
+  database
+    .delete_many::<Provider>(doc! { "_id": { "$in": [user_id, secondary_id] } })
+    .await
+    .expect("Failed to delete test providers");
+
+  let expected = [secondary_id, user_id]
+    .into_iter()
+    .map(|id| LinkedAccount {
+      id: id.into(),
+      picture: f!("https://example.com/{id}.png"),
+    })
+    .collect::<Vec<_>>();
+  assert!(
+    accounts
+      .as_ref()
+      .is_ok_and(|accounts| *accounts == expected),
+    "Expected {expected:#?}, instead got {accounts:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_deletes_the_user_with_files_and_providers() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let user_id = "google@delete-account";
+  cleanup_user(&database, user_id).await;
+  let token = save_user(&User::new(user_id, "Delete", ""), &database)
+    .await
+    .expect("Failed to save user");
+  Session::save(&token, user_id).await;
+  save_provider(&provider(user_id), &database)
+    .await
+    .expect("Failed to save provider");
+  let folder = File::new_folder(user_id.into(), "Folder".into(), None)
+    .expect("Failed to create folder");
+  let video = File::from_video(
+    Video::default(),
+    user_id.into(),
+    Some(folder.id.clone()),
+    Some("Video".into()),
+  )
+  .expect("Failed to create video");
+  for file in [&folder, &video] {
+    database
+      .create(file, None)
+      .await
+      .expect("Failed to save file");
+  }
+
+  let status = delete_current_user(
+    Session {
+      user_id: user_id.into(),
+    },
+    State(database.clone()),
+  )
+  .await;
+  let user = database.find_by_id::<User>(user_id).await;
+  let provider = database.find_by_id::<Provider>(user_id).await;
+  let files = database
+    .find_many::<File>(doc! { "userId": user_id }, None)
+    .await
+    .expect("Failed to find files");
+  let session = Session::from_token(&token).await;
+
+  assert!(
+    matches!(status, Ok(StatusCode::NO_CONTENT)),
+    "Expected account to be deleted, instead got {status:?}"
+  );
+  assert!(
+    matches!(user, Ok(None)) && matches!(provider, Ok(None)),
+    "Expected user and provider to be deleted, instead got {user:?} and {provider:?}"
+  );
+  assert!(
+    files.is_empty(),
+    "Expected every file to be deleted, instead got {files:#?}"
+  );
+  assert!(
+    session.is_err(),
+    "Expected sessions to be invalidated, instead got {session:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_deletes_the_primary_provider_of_users_without_linked_accounts() {
+  set_test_env();
+  let (_, database) = get_database().await;
+  let user_id = "google@delete-untracked";
+  cleanup_user(&database, user_id).await;
+  save_user(&User::new(user_id, "Untracked", ""), &database)
+    .await
+    .expect("Failed to save user");
+  save_provider(&provider(user_id), &database)
+    .await
+    .expect("Failed to save provider");
+  // Like users saved before linked accounts were tracked
+  database
+    .collection::<User>()
+    .update_one(
+      doc! { "_id": user_id },
+      doc! { "$unset": { "linkedAccounts": "" } },
+      None,
+    )
+    .await
+    .expect("Failed to unset linked accounts");
+
+  let status = delete_current_user(
+    Session {
+      user_id: user_id.into(),
+    },
+    State(database.clone()),
+  )
+  .await;
+  let provider = database.find_by_id::<Provider>(user_id).await;
+  cleanup_user(&database, user_id).await;
+
+  assert!(
+    matches!(status, Ok(StatusCode::NO_CONTENT)),
+    "Expected account to be deleted, instead got {status:?}"
+  );
+  assert!(
+    matches!(provider, Ok(None)),
+    "Expected the primary provider to be deleted, instead got {provider:?}"
+  );
+}
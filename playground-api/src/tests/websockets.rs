@@ -0,0 +1,654 @@
+#![cfg(test)]
+use super::{USER_ID1, USER_ID2};
+use crate::{
+  api::APIError,
+  db::files::aggregations::FolderChildren,
+  websockets::{
+    batch::FolderChangeBatcher,
+    channel::{
+      EventChannel, EventMessage, FileDelete, SocketChannel, SocketMessage,
+      SocketReceiver,
+    },
+    event::EventManager,
+    heartbeat, receive_client_messages_task, send_client_messages_task,
+    shutdown, WebSocketState, MAX_USER_CONNECTIONS,
+  },
+};
+use axum::extract::ws::{close_code, Message};
+use format as f;
+use futures::{channel::mpsc, StreamExt};
+use std::{collections::HashSet, time::Duration};
+use tokio::time::timeout;
+
+const SOCKET_ID: &str = "127.0.0.1:3000";
+
+fn file_delete(user_id: &str, ids: &[&str]) -> EventMessage {
+  EventMessage::FileDelete(FileDelete {
+    user_id: user_id.into(),
+    deleted_ids: ids.iter().map(|id| id.to_string()).collect(),
+  })
+}
+
+/// Next message sent to the socket, parsed as JSON.
+async fn next_json(socket_receiver: &mut SocketReceiver) -> serde_json::Value {
+  let message = timeout(Duration::from_secs(1), socket_receiver.recv())
+    .await
+    .expect("Timed out waiting for socket message")
+    .expect("Socket channel closed");
+  let SocketMessage::Message(Message::Text(json)) = message else {
+    panic!("Expected a text message, instead got {message:#?}");
+  };
+  serde_json::from_str(&json).expect("Invalid JSON message")
+}
+
+#[tokio::test]
+async fn it_acknowledges_event_commands() {
+  let event_channel = EventChannel::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let mut event_manager = EventManager::default();
+  let mut acks = Vec::new();
+  for message in [
+    "event:add:file-delete",
+    "event:remove:file-delete",
+    "event:add:bogus",
+  ] {
+    event_manager.process_event(
+      message,
+      &socket_channel.sender,
+      &event_channel.sender,
+      USER_ID1.into(),
+      SOCKET_ID.into(),
+    );
+    acks.push(next_json(&mut socket_receiver).await);
+  }
+
+  let expected = [
+    serde_json::json!({ "ack": "file-delete", "status": "added" }),
+    serde_json::json!({ "ack": "file-delete", "status": "removed" }),
+    serde_json::json!({
+      "ack": "bogus",
+      "status": "error",
+      "error": "Unknown event \"bogus\"",
+    }),
+  ];
+  assert!(
+    acks == expected,
+    "Expected acks {expected:?}, instead got {acks:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_rejects_malformed_event_commands() {
+  let event_channel = EventChannel::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let mut event_manager = EventManager::default();
+  for (message, expected_error) in [
+    ("event:add:bogus", "Unknown event \"bogus\""),
+    (
+      "event:subscribe:file-delete",
+      "Unknown event action \"subscribe\"",
+    ),
+    (
+      "event:file-delete",
+      "Missing colon in event command \"file-delete\"",
+    ),
+  ] {
+    event_manager.process_event(
+      message,
+      &socket_channel.sender,
+      &event_channel.sender,
+      USER_ID1.into(),
+      SOCKET_ID.into(),
+    );
+    let reply = next_json(&mut socket_receiver).await;
+    let error = reply["error"].as_str().unwrap_or_default();
+    assert!(
+      reply["status"] == "error" && error.starts_with(expected_error),
+      "Expected {message:?} to fail with {expected_error:?}, instead got {reply}"
+    );
+  }
+
+  event_manager.process_event(
+    "hello",
+    &socket_channel.sender,
+    &event_channel.sender,
+    USER_ID1.into(),
+    SOCKET_ID.into(),
+  );
+  let reply = socket_receiver.try_recv();
+  assert!(
+    reply.is_err(),
+    "Expected text without the event prefix to be ignored, instead got {reply:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_delivers_file_delete_events_to_subscribers() {
+  let event_channel = EventChannel::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let mut event_manager = EventManager::default();
+  event_manager.process_event(
+    "event:add:file-delete",
+    &socket_channel.sender,
+    &event_channel.sender,
+    USER_ID1.into(),
+    SOCKET_ID.into(),
+  );
+  next_json(&mut socket_receiver).await; // Subscription ack
+
+  let deleted_ids = ["file-1", "file-2"]
+    .map(String::from)
+    .into_iter()
+    .collect::<HashSet<_>>();
+  for user_id in [USER_ID2, USER_ID1] {
+    event_channel
+      .sender
+      .send(EventMessage::FileDelete(FileDelete {
+        user_id: user_id.into(),
+        deleted_ids: deleted_ids.clone(),
+      }))
+      .expect("Failed to send file-delete event");
+  }
+
+  let message = timeout(Duration::from_secs(1), socket_receiver.recv())
+    .await
+    .expect("Timed out waiting for file-delete event")
+    .expect("Socket channel closed");
+  let SocketMessage::Message(Message::Text(json)) = message else {
+    panic!("Expected a text message, instead got {message:#?}");
+  };
+  let payload = serde_json::from_str::<serde_json::Value>(&json)
+    .expect("Invalid file-delete payload");
+  assert!(
+    payload["userId"] == USER_ID1,
+    "Expected payload for {USER_ID1:?}, instead got {payload:#?}"
+  );
+  let result_ids = payload["deletedIds"]
+    .as_array()
+    .expect("Missing deletedIds")
+    .iter()
+    .filter_map(|id| id.as_str().map(String::from))
+    .collect::<HashSet<_>>();
+  assert!(
+    result_ids.eq(&deleted_ids),
+    "Expected deleted ids to be {deleted_ids:?}, instead got {result_ids:?}"
+  );
+}
+
+#[tokio::test]
+async fn it_keeps_subscribers_alive_after_lagging() {
+  let event_channel = EventChannel::with_capacity(4);
+  let socket_channel = SocketChannel::with_capacity(64);
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let mut event_manager = EventManager::default();
+  event_manager.process_event(
+    "event:add:file-delete",
+    &socket_channel.sender,
+    &event_channel.sender,
+    USER_ID1.into(),
+    SOCKET_ID.into(),
+  );
+
+  // The dispatcher doesn't run until we yield so this burst overflows it
+  for i in 0..20 {
+    event_channel
+      .sender
+      .send(file_delete(USER_ID1, &[&i.to_string()]))
+      .expect("Failed to send burst event");
+  }
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  while socket_receiver.try_recv().is_ok() {}
+
+  event_channel
+    .sender
+    .send(file_delete(USER_ID1, &["after-burst"]))
+    .expect("Failed to send event after burst");
+  let message = timeout(Duration::from_secs(1), socket_receiver.recv())
+    .await
+    .expect("Subscriber did not survive the burst")
+    .expect("Socket channel closed");
+  let SocketMessage::Message(Message::Text(json)) = message else {
+    panic!("Expected a text message, instead got {message:#?}");
+  };
+  assert!(
+    json.contains("after-burst"),
+    "Expected event sent after burst, instead got {json}"
+  );
+}
+
+#[tokio::test]
+async fn it_pings_idle_connections() {
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let (_pong_sender, pong_receiver) = heartbeat::pong_channel();
+  let heartbeat_task = heartbeat::spawn(
+    socket_channel.sender.clone(),
+    pong_receiver,
+    Duration::from_millis(10),
+    Duration::from_secs(5),
+    SOCKET_ID.into(),
+  );
+
+  for i in 0..3 {
+    let message = timeout(Duration::from_secs(1), socket_receiver.recv())
+      .await
+      .unwrap_or_else(|_| panic!("Timed out waiting for ping #{i}"))
+      .expect("Socket channel closed");
+    assert!(
+      matches!(message, SocketMessage::Message(Message::Ping(_))),
+      "Expected ping #{i}, instead got {message:#?}"
+    );
+  }
+  heartbeat_task.abort();
+}
+
+#[tokio::test]
+async fn it_stops_heartbeat_when_pongs_time_out() {
+  let socket_channel = SocketChannel::new();
+  let (_pong_sender, pong_receiver) = heartbeat::pong_channel();
+  let heartbeat_task = heartbeat::spawn(
+    socket_channel.sender.clone(),
+    pong_receiver,
+    Duration::from_millis(10),
+    Duration::from_millis(30),
+    SOCKET_ID.into(),
+  );
+
+  let pings = timeout(Duration::from_secs(1), heartbeat_task)
+    .await
+    .expect("Heartbeat did not time out")
+    .expect("Heartbeat task failed");
+  assert!(
+    pings > 0,
+    "Expected pings to be sent before timing out, instead sent {pings}"
+  );
+}
+
+#[tokio::test]
+async fn it_tracks_active_connections() {
+  let state = WebSocketState::new();
+  let first = state.connect(USER_ID1).expect("Connection rejected");
+  let second = state.connect(USER_ID1).expect("Connection rejected");
+  let connections = state.connection_count();
+  assert!(
+    connections == 2,
+    "Expected 2 connections, instead got {connections}"
+  );
+
+  drop(first);
+  let connections = state.clone().connection_count();
+  assert!(
+    connections == 1,
+    "Expected 1 connection after disconnecting, instead got {connections}"
+  );
+
+  drop(second);
+  let connections = state.connection_count();
+  assert!(
+    connections == 0,
+    "Expected no connections left, instead got {connections}"
+  );
+}
+
+#[tokio::test]
+async fn it_limits_connections_per_user() {
+  let state = WebSocketState::new();
+  let connections = (0..*MAX_USER_CONNECTIONS)
+    .map(|_| state.connect(USER_ID1))
+    .collect::<Result<Vec<_>, _>>()
+    .expect("Connection under the limit rejected");
+
+  let rejected = state.connect(USER_ID1);
+  assert!(
+    matches!(rejected, Err(APIError::TooManyRequests(_))),
+    "Expected connection over the limit to be rejected, instead got {:?}",
+    rejected.map(|_| ())
+  );
+  let other_user = state.connect(USER_ID2);
+  assert!(
+    other_user.is_ok(),
+    "Expected other users to connect, instead got {:?}",
+    other_user.map(|_| ())
+  );
+
+  drop(connections);
+  let reconnected = state.connect(USER_ID1);
+  assert!(
+    reconnected.is_ok(),
+    "Expected to connect after disconnecting, instead got {:?}",
+    reconnected.map(|_| ())
+  );
+}
+
+#[tokio::test]
+async fn it_closes_sockets_on_shutdown() {
+  let state = WebSocketState::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let connection = state.connect(USER_ID1).expect("Connection rejected");
+  let shutdown_task = shutdown::spawn(
+    socket_channel.sender.clone(),
+    state.shutdown_receiver(),
+    SOCKET_ID.into(),
+  );
+
+  let shutdown = tokio::spawn({
+    let state = state.clone();
+    async move { state.shutdown(Duration::from_secs(1)).await }
+  });
+  let message = timeout(Duration::from_secs(1), socket_receiver.recv())
+    .await
+    .expect("Timed out waiting for close frame")
+    .expect("Socket channel closed");
+  assert!(
+    matches!(
+      message,
+      SocketMessage::Message(Message::Close(Some(ref frame)))
+        if frame.code == close_code::AWAY
+    ),
+    "Expected close frame, instead got {message:#?}"
+  );
+  let message = socket_receiver.recv().await;
+  assert!(
+    matches!(message, Ok(SocketMessage::Exit)),
+    "Expected exit after close frame, instead got {message:#?}"
+  );
+
+  drop(connection);
+  let drained = shutdown.await.expect("Shutdown task failed");
+  assert!(drained, "Expected connections to be drained on shutdown");
+  shutdown_task.await.expect("Socket shutdown task failed");
+}
+
+#[tokio::test]
+async fn it_closes_sockets_sending_oversized_messages() {
+  let event_channel = EventChannel::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let (pong_sender, _pong_receiver) = heartbeat::pong_channel();
+  // What the socket yields once a frame exceeds its configured max size.
+  let too_big = tungstenite::Error::Capacity(
+    tungstenite::error::CapacityError::MessageTooLong {
+      size: 2048,
+      max_size: 1024,
+    },
+  );
+  let client_messages = futures::stream::iter([
+    Ok(Message::Text("hello".into())),
+    Err(axum::Error::new(too_big)),
+    Ok(Message::Text("event:add:file-delete".into())),
+  ]);
+  let recv_task = receive_client_messages_task(
+    client_messages,
+    socket_channel.sender.clone(),
+    event_channel.sender.clone(),
+    pong_sender,
+    USER_ID1.into(),
+    SOCKET_ID.into(),
+  );
+
+  let count = timeout(Duration::from_secs(1), recv_task)
+    .await
+    .expect("Timed out waiting for the receive task")
+    .expect("Receive task failed");
+  assert!(
+    count == 1,
+    "Expected 1 message received, instead got {count}"
+  );
+  let message = socket_receiver.try_recv();
+  assert!(
+    matches!(
+      message,
+      Ok(SocketMessage::Message(Message::Close(Some(ref frame))))
+        if frame.code == close_code::SIZE
+    ),
+    "Expected close frame, instead got {message:#?}"
+  );
+  let message = socket_receiver.try_recv();
+  assert!(
+    message.is_err(),
+    "Expected no messages after closing, instead got {message:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_keeps_sending_messages_after_lagging_behind() {
+  let socket_channel = SocketChannel::with_capacity(1);
+  let socket_receiver = socket_channel.sender.subscribe();
+  for text in ["skipped", "sent"] {
+    socket_channel
+      .sender
+      .send(SocketMessage::Message(Message::Text(text.into())))
+      .expect("Failed to queue socket message");
+  }
+  let (raw_socket_sender, mut client) = mpsc::unbounded();
+  let send_task = send_client_messages_task(
+    socket_receiver,
+    SOCKET_ID.into(),
+    raw_socket_sender,
+  );
+
+  let message = timeout(Duration::from_secs(1), client.next())
+    .await
+    .expect("Timed out waiting for a client message");
+  assert!(
+    matches!(message, Some(Message::Text(ref text)) if text == "sent"),
+    "Expected the message after the lag, instead got {message:#?}"
+  );
+  socket_channel
+    .sender
+    .send(SocketMessage::Exit)
+    .expect("Failed to send exit");
+  let count = timeout(Duration::from_secs(1), send_task)
+    .await
+    .expect("Timed out waiting for the send task")
+    .expect("Send task failed");
+  assert!(count == 1, "Expected 1 message sent, instead got {count}");
+}
+
+#[tokio::test]
+async fn it_stops_waiting_for_sockets_after_timeout() {
+  let state = WebSocketState::new();
+  let _connection = state.connect(USER_ID1).expect("Connection rejected");
+  let drained = state.shutdown(Duration::from_millis(20)).await;
+  assert!(
+    !drained,
+    "Expected shutdown to time out with open connections"
+  );
+}
+
+fn folder_change(folder_id: &str, children: usize) -> FolderChildren {
+  let children = (0..children)
+    .map(|i| {
+      serde_json::json!({
+        "_id": f!("{folder_id}-child-{i}"),
+        "folderId": folder_id,
+        "userId": USER_ID1,
+        "name": f!("Child {i}"),
+        "metadata": { "type": "folder" },
+      })
+    })
+    .collect::<Vec<_>>();
+  serde_json::from_value(serde_json::json!({
+    "_id": folder_id,
+    "folderId": USER_ID1,
+    "userId": USER_ID1,
+    "name": folder_id,
+    "children": children,
+  }))
+  .expect("Invalid folder change")
+}
+
+#[tokio::test]
+async fn it_removes_every_event_at_once() {
+  let event_channel = EventChannel::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let mut event_manager = EventManager::default();
+  for message in [
+    "event:add:file-delete",
+    "event:add:folder-change",
+    "event:remove:all",
+  ] {
+    event_manager.process_event(
+      message,
+      &socket_channel.sender,
+      &event_channel.sender,
+      USER_ID1.into(),
+      SOCKET_ID.into(),
+    );
+    next_json(&mut socket_receiver).await; // Command ack
+  }
+
+  // Dispatchers drop their receivers when they return, leaving the channel's
+  let dispatchers_exited = timeout(Duration::from_secs(1), async {
+    while event_channel.sender.receiver_count() > 1 {
+      tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+  })
+  .await;
+  event_channel
+    .sender
+    .send(file_delete(USER_ID1, &["file-1"]))
+    .expect("Failed to send file-delete event");
+  let message =
+    timeout(Duration::from_millis(200), socket_receiver.recv()).await;
+  assert!(
+    dispatchers_exited.is_ok() && message.is_err(),
+    "Expected every dispatcher to exit, instead {} are running and got {message:?}",
+    event_channel.sender.receiver_count() - 1
+  );
+}
+
+#[tokio::test]
+async fn it_encodes_payloads_in_the_negotiated_format() {
+  let event_channel = EventChannel::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let mut event_manager = EventManager::default();
+  event_manager.process_event(
+    "event:add:folder-change?format=msgpack",
+    &socket_channel.sender,
+    &event_channel.sender,
+    USER_ID1.into(),
+    SOCKET_ID.into(),
+  );
+  next_json(&mut socket_receiver).await; // Subscription ack
+
+  let change = folder_change("first", 2);
+  event_channel
+    .sender
+    .send(EventMessage::FolderChange(change.clone()))
+    .expect("Failed to send folder-change event");
+  let message = timeout(Duration::from_secs(1), socket_receiver.recv()).await;
+  let Ok(Ok(SocketMessage::Message(Message::Binary(bytes)))) = message else {
+    panic!("Expected a binary message, instead got {message:#?}");
+  };
+  let received = rmp_serde::from_slice::<FolderChildren>(&bytes)
+    .expect("Invalid MessagePack payload");
+  let received = serde_json::to_value(received);
+  let expected = serde_json::to_value(change);
+  assert!(
+    received.is_ok() && received.as_ref().ok() == expected.as_ref().ok(),
+    "Expected {expected:#?}, instead got {received:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_only_delivers_changes_of_subscribed_folders() {
+  let event_channel = EventChannel::new();
+  let socket_channel = SocketChannel::new();
+  let mut socket_receiver = socket_channel.sender.subscribe();
+  let mut event_manager = EventManager::default();
+  for folder_id in ["first", "second"] {
+    event_manager.process_event(
+      &f!("event:add:folder-change:{folder_id}"),
+      &socket_channel.sender,
+      &event_channel.sender,
+      USER_ID1.into(),
+      SOCKET_ID.into(),
+    );
+    next_json(&mut socket_receiver).await; // Subscription ack
+  }
+
+  for change in [
+    EventMessage::FolderChange(folder_change("unsubscribed", 1)),
+    EventMessage::FolderChangeBatch(vec![
+      folder_change("unsubscribed", 2),
+      folder_change("first", 1),
+    ]),
+    EventMessage::FolderChange(folder_change("second", 2)),
+  ] {
+    event_channel
+      .sender
+      .send(change)
+      .expect("Failed to send folder-change event");
+  }
+
+  let mut received = Vec::new();
+  while let Ok(message) =
+    timeout(Duration::from_millis(200), socket_receiver.recv()).await
+  {
+    let Ok(SocketMessage::Message(Message::Text(json))) = message else {
+      panic!("Expected a text message, instead got {message:#?}");
+    };
+    received.push(
+      serde_json::from_str::<serde_json::Value>(&json)
+        .expect("Invalid folder-change payload"),
+    );
+  }
+  let expected = [
+    serde_json::json!([serde_json::to_value(folder_change("first", 1))
+      .expect("Failed to serialize folder change")]),
+    serde_json::to_value(folder_change("second", 2))
+      .expect("Failed to serialize folder change"),
+  ];
+  let all_expected = expected.iter().all(|change| received.contains(change));
+  assert!(
+    received.len() == expected.len() && all_expected,
+    "Expected only the subscribed folders' changes, instead got {received:#?}"
+  );
+}
+
+#[tokio::test]
+async fn it_batches_folder_changes_into_one_message() {
+  let event_channel = EventChannel::new();
+  let mut event_receiver = event_channel.sender.subscribe();
+  let batcher = FolderChangeBatcher::spawn(
+    event_channel.sender.clone(),
+    Duration::from_millis(50),
+  );
+
+  // A move touches the source and destination folders, a second change to the
+  // destination within the window replaces the first
+  batcher.send(vec![folder_change("source", 0), folder_change("target", 1)]);
+  batcher.send(vec![folder_change("target", 2), folder_change("other", 0)]);
+
+  let message = timeout(Duration::from_secs(1), event_receiver.recv())
+    .await
+    .expect("Timed out waiting for folder change batch")
+    .expect("Event channel closed");
+  let EventMessage::FolderChangeBatch(changes) = message else {
+    panic!("Expected a folder change batch, instead got {message:#?}");
+  };
+  let folders = changes
+    .iter()
+    .map(|change| (change.id.as_str(), change.children.len()))
+    .collect::<HashSet<_>>();
+  let expected = [("source", 0), ("target", 2), ("other", 0)]
+    .into_iter()
+    .collect::<HashSet<_>>();
+  assert!(
+    folders == expected,
+    "Expected coalesced folders {expected:?}, instead got {folders:?}"
+  );
+
+  let next = timeout(Duration::from_millis(200), event_receiver.recv()).await;
+  assert!(
+    next.is_err(),
+    "Expected a single batched message, instead got {next:#?}"
+  );
+}
@@ -0,0 +1,82 @@
+use super::channel::{EventMessage, EventSender};
+use crate::{
+  console::Colorize, db::files::aggregations::FolderChildren, env_var,
+  env_var_or, log,
+};
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+  sync::mpsc,
+  time::{timeout_at, Instant},
+};
+
+/// Send folder changes as one `FolderChangeBatch` per user instead of one
+/// `FolderChange` per folder. Off by default so existing clients keep working.
+pub static BATCH_FOLDER_CHANGES: Lazy<bool> = Lazy::new(|| {
+  env_var("WS_BATCH_FOLDER_CHANGES").is_ok_and(|value| value == "1")
+});
+
+/// How long changes are collected after the first one before a batch is sent.
+pub static BATCH_WINDOW: Lazy<Duration> =
+  Lazy::new(|| Duration::from_millis(env_var_or("WS_BATCH_WINDOW_MS", 50)));
+
+/// Collects folder changes over a short window, keeping only the latest
+/// change per folder, and broadcasts them as a single message per user.
+#[derive(Debug, Clone)]
+pub struct FolderChangeBatcher(mpsc::UnboundedSender<Vec<FolderChildren>>);
+
+impl FolderChangeBatcher {
+  pub fn spawn(event_sender: EventSender, window: Duration) -> Self {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      while let Some(changes) = receiver.recv().await {
+        let mut pending = Pending::default();
+        pending.merge(changes);
+        let deadline = Instant::now() + window;
+        while let Ok(Some(changes)) =
+          timeout_at(deadline, receiver.recv()).await
+        {
+          pending.merge(changes);
+        }
+        pending.flush(&event_sender);
+      }
+    });
+    Self(sender)
+  }
+
+  pub fn send(&self, changes: Vec<FolderChildren>) {
+    if let Err(error) = self.0.send(changes) {
+      log!(err@"Folder change batcher stopped, dropping {} changes", error.0.len());
+    }
+  }
+}
+
+/// Latest change of every folder by user id and folder id.
+#[derive(Debug, Default)]
+struct Pending(HashMap<String, HashMap<String, FolderChildren>>);
+
+impl Pending {
+  fn merge(&mut self, changes: Vec<FolderChildren>) {
+    for change in changes {
+      self
+        .0
+        .entry(change.user_id.clone())
+        .or_default()
+        .insert(change.id.clone(), change);
+    }
+  }
+
+  fn flush(self, event_sender: &EventSender) {
+    if event_sender.receiver_count() == 0 {
+      log!(info@"There's a folder change batch but no one's listening. Message will not be sent");
+      return;
+    }
+    for changes in self.0.into_values() {
+      let batch =
+        EventMessage::FolderChangeBatch(changes.into_values().collect());
+      if let Err(error) = event_sender.send(batch) {
+        log!(err@"Failed to send folder change batch: {error}");
+      }
+    }
+  }
+}
@@ -1,27 +1,63 @@
 use super::event::EventExitRequest;
 use crate::db::files::aggregations::FolderChildren;
 use axum::extract::ws::Message;
+use serde::Serialize;
+use std::collections::HashSet;
 use tokio::sync::broadcast;
 
 #[derive(Debug)]
 pub struct BroadcastChannel<T: Clone> {
   pub sender: broadcast::Sender<T>,
-  pub receiver: broadcast::Receiver<T>,
+  /// Keeps the channel open while nothing else is subscribed.
+  _receiver: broadcast::Receiver<T>,
 }
 
+pub const DEFAULT_CAPACITY: usize = 16;
+
 impl<T: Clone> BroadcastChannel<T> {
   pub fn new() -> Self {
-    let (sender, receiver) = broadcast::channel(16);
-    Self { sender, receiver }
+    Self::with_capacity(DEFAULT_CAPACITY)
+  }
+
+  pub fn with_capacity(capacity: usize) -> Self {
+    let (sender, receiver) = broadcast::channel(capacity.max(1));
+    Self {
+      sender,
+      _receiver: receiver,
+    }
   }
 }
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileDelete {
+  pub user_id: String,
+  pub deleted_ids: HashSet<String>,
+}
+
 #[derive(Debug, Clone)]
 pub enum EventMessage {
   FolderChange(FolderChildren),
+  /// Changes of several folders owned by the same user, sent as an array.
+  FolderChangeBatch(Vec<FolderChildren>),
+  FileDelete(FileDelete),
   Exit(EventExitRequest),
 }
 
+impl EventMessage {
+  /// Owner of the event payload, `None` for control messages.
+  pub fn user_id(&self) -> Option<&str> {
+    match self {
+      Self::FolderChange(change) => Some(&change.user_id),
+      Self::FolderChangeBatch(changes) => {
+        changes.first().map(|change| change.user_id.as_str())
+      }
+      Self::FileDelete(delete) => Some(&delete.user_id),
+      Self::Exit(_) => None,
+    }
+  }
+}
+
 #[derive(Debug, Clone)]
 pub enum SocketMessage {
   Message(Message),
@@ -32,7 +68,9 @@ pub type EventChannel = BroadcastChannel<EventMessage>;
 pub type EventReceiver = broadcast::Receiver<EventMessage>;
 pub type EventSender = broadcast::Sender<EventMessage>;
 pub type EventSendError = broadcast::error::SendError<EventMessage>;
+pub type EventRecvError = broadcast::error::RecvError;
 
 pub type SocketChannel = BroadcastChannel<SocketMessage>;
 pub type SocketReceiver = broadcast::Receiver<SocketMessage>;
 pub type SocketSender = broadcast::Sender<SocketMessage>;
+pub type SocketRecvError = broadcast::error::RecvError;
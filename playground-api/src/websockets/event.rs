@@ -1,13 +1,18 @@
 use super::channel::{
-  EventMessage, EventReceiver, EventSender, SocketMessage, SocketSender,
+  EventMessage, EventReceiver, EventRecvError, EventSender, SocketMessage,
+  SocketSender,
 };
 use crate::{console::Colorize, log};
 use axum::extract::ws::Message;
-use std::collections::HashSet;
+use serde::Serialize;
+use std::{collections::HashSet, fmt::Display};
+use thiserror::Error;
 
 pub enum Event {
-  Add(EventType),
+  Add(EventType, PayloadFormat),
   Remove(EventExitRequest),
+  /// Remove every event the socket subscribed to, sent as `event:remove:all`.
+  RemoveAll,
 }
 
 #[derive(Debug, Clone)]
@@ -19,34 +24,176 @@ pub struct EventExitRequest {
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub enum EventType {
   FolderChange,
+  /// Changes of a single folder, subscribed to with
+  /// `folder-change:<folder_id>`. A socket can subscribe to several folders.
+  FolderChangeIn(String),
+  FileDelete,
+}
+
+/// Encoding of the payloads sent to a socket, picked when subscribing with
+/// e.g. `event:add:folder-change?format=msgpack`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+  /// JSON text messages.
+  #[default]
+  Json,
+  /// MessagePack binary messages, maps keep their field names.
+  MsgPack,
+}
+
+impl PayloadFormat {
+  /// Format requested by the `options` after `?` in an event name.
+  fn from_options(options: &str) -> Option<Self> {
+    match options.strip_prefix("format=")? {
+      "json" => Some(Self::Json),
+      "msgpack" => Some(Self::MsgPack),
+      _ => None,
+    }
+  }
+
+  fn encode<T: Serialize>(self, payload: &T) -> PayloadResult<Message> {
+    Ok(match self {
+      Self::Json => Message::Text(serde_json::to_string(payload)?),
+      Self::MsgPack => Message::Binary(rmp_serde::to_vec_named(payload)?),
+    })
+  }
 }
 
 impl EventType {
   pub fn new(name: &str) -> Option<Self> {
     match name {
       "folder-change" => Some(Self::FolderChange),
+      "file-delete" => Some(Self::FileDelete),
+      _ => name
+        .strip_prefix(FOLDER_CHANGE_PREFIX)
+        .filter(|folder_id| !folder_id.is_empty())
+        .map(|folder_id| Self::FolderChangeIn(folder_id.to_string())),
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::FolderChange | Self::FolderChangeIn(_) => "folder-change",
+      Self::FileDelete => "file-delete",
+    }
+  }
+
+  /// Message sent to the socket if `message` carries a payload for this event
+  /// type.
+  fn payload(
+    &self,
+    message: &EventMessage,
+    format: PayloadFormat,
+  ) -> Option<PayloadResult<Message>> {
+    match (self, message) {
+      (Self::FolderChange, EventMessage::FolderChange(change)) => {
+        Some(format.encode(change))
+      }
+      (Self::FolderChange, EventMessage::FolderChangeBatch(changes)) => {
+        Some(format.encode(changes))
+      }
+      (Self::FolderChangeIn(id), EventMessage::FolderChange(change)) => {
+        (&change.id == id).then(|| format.encode(change))
+      }
+      (Self::FolderChangeIn(id), EventMessage::FolderChangeBatch(changes)) => {
+        let changes = changes
+          .iter()
+          .filter(|change| &change.id == id)
+          .collect::<Vec<_>>();
+        (!changes.is_empty()).then(|| format.encode(&changes))
+      }
+      (Self::FileDelete, EventMessage::FileDelete(delete)) => {
+        Some(format.encode(delete))
+      }
       _ => None,
     }
   }
 }
 
+impl Display for EventType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::FolderChangeIn(folder_id) => {
+        write!(f, "{FOLDER_CHANGE_PREFIX}{folder_id}")
+      }
+      _ => write!(f, "{}", self.name()),
+    }
+  }
+}
+
 impl Event {
-  pub fn new(message: &str, socket_id: String) -> Option<Self> {
-    if !message.starts_with(EVENT_IDENTIFIER) {
-      return None;
+  /// `None` unless `message` starts with `event:`, an error if the rest isn't
+  /// a valid `<action>:<event>` command.
+  pub fn new(message: &str, socket_id: String) -> Option<EventResult<Self>> {
+    let command = message.strip_prefix(EVENT_IDENTIFIER)?;
+    Some(Self::parse(command, socket_id))
+  }
+
+  fn parse(command: &str, socket_id: String) -> EventResult<Self> {
+    let Some((action, name)) = command.split_once(':') else {
+      return Err(EventError::MissingColon(command.to_string()));
+    };
+    if !matches!(action, "add" | "remove") {
+      return Err(EventError::UnknownAction {
+        action: action.to_string(),
+        name: name.to_string(),
+      });
     }
-    let mut parts = message[EVENT_IDENTIFIER.len()..].splitn(2, ':');
-    let Some(action) = parts.next() else {return None};
-    let Some(name) = parts.next() else {return None};
-    let Some(event_type) = EventType::new(name) else {return None};
-
-    match action {
-      "add" => Some(Event::Add(event_type)),
-      "remove" => Some(Event::Remove(EventExitRequest {
+    if (action, name) == ("remove", "all") {
+      return Ok(Event::RemoveAll);
+    }
+    let (name, format) = match name.split_once('?') {
+      Some((name, options)) if action == "add" => {
+        let Some(format) = PayloadFormat::from_options(options) else {
+          return Err(EventError::InvalidOptions {
+            options: options.to_string(),
+            name: name.to_string(),
+          });
+        };
+        (name, format)
+      }
+      _ => (name, PayloadFormat::default()),
+    };
+    let Some(event_type) = EventType::new(name) else {
+      return Err(EventError::UnknownType(name.to_string()));
+    };
+
+    if action == "add" {
+      Ok(Event::Add(event_type, format))
+    } else {
+      Ok(Event::Remove(EventExitRequest {
         socket_id,
         event_type,
-      })),
-      _ => None,
+      }))
+    }
+  }
+}
+
+/// Reply to an event command, e.g. `{"ack":"file-delete","status":"added"}`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventAck {
+  /// Event name the command was for.
+  pub ack: String,
+  pub status: AckStatus,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AckStatus {
+  Added,
+  Removed,
+  Error,
+}
+
+impl EventAck {
+  fn new(event_name: impl Display, status: AckStatus) -> Self {
+    Self {
+      ack: event_name.to_string(),
+      status,
+      error: None,
     }
   }
 }
@@ -57,6 +204,8 @@ pub struct EventManager {
 }
 
 impl EventManager {
+  /// Run the event command in `message`, if any, and acknowledge it to the
+  /// socket.
   pub fn process_event(
     &mut self,
     message: &str,
@@ -65,76 +214,195 @@ impl EventManager {
     user_id: String,
     socket_id: String,
   ) {
-    let Some(event) = &Event::new(message, socket_id.clone()) else {return};
-    match event {
-      Event::Add(event_type) => {
-        if self.events.contains(event_type) {
-          log!(info@">>> {socket_id} Ignoring folder-change event add request since is already added.");
-          return;
+    let ack = match Event::new(message, socket_id.clone()) {
+      None => return,
+      Some(Ok(event)) => {
+        self.run(event, socket_sender, event_sender, user_id, &socket_id)
+      }
+      Some(Err(error)) => {
+        log!(err@">>> {socket_id} Bad event {message:?}: {error}");
+        EventAck {
+          error: Some(error.to_string()),
+          ..EventAck::new(error.event_name(), AckStatus::Error)
         }
-        match event_type {
-          EventType::FolderChange => {
-            let mut socket_sender = socket_sender.clone();
-            let mut event_receiver = event_sender.subscribe();
-            log!(info@">>> {socket_id} Adding folder-change event for {user_id:?}");
-            tokio::spawn(async move {
-              file_change_event_dispatcher(
-                &mut socket_sender,
-                &mut event_receiver,
-                &user_id,
-                &socket_id,
-              )
-              .await;
-            });
-            self.events.insert(event_type.clone());
-          }
+      }
+    };
+    let json = match serde_json::to_string(&ack) {
+      Ok(json) => json,
+      Err(error) => {
+        log!(err@">>> {socket_id} Failed to serialize {ack:?}: {error}");
+        return;
+      }
+    };
+    if let Err(error) =
+      socket_sender.send(SocketMessage::Message(Message::Text(json)))
+    {
+      log!(err@">>> {socket_id} Could not send {ack:?}: {error}");
+    }
+  }
+
+  fn run(
+    &mut self,
+    event: Event,
+    socket_sender: &SocketSender,
+    event_sender: &EventSender,
+    user_id: String,
+    socket_id: &str,
+  ) -> EventAck {
+    match event {
+      Event::Add(event_type, format) => {
+        if self.events.contains(&event_type) {
+          log!(info@">>> {socket_id} Ignoring {event_type} event add request since is already added.");
+          return EventAck::new(event_type, AckStatus::Added);
         }
+        let mut socket_sender = socket_sender.clone();
+        let mut event_receiver = event_sender.subscribe();
+        log!(info@">>> {socket_id} Adding {event_type} event for {user_id:?} as {format:?}");
+        let dispatched_type = event_type.clone();
+        let dispatched_socket_id = socket_id.to_string();
+        tokio::spawn(async move {
+          event_dispatcher(
+            &dispatched_type,
+            format,
+            &mut socket_sender,
+            &mut event_receiver,
+            &user_id,
+            &dispatched_socket_id,
+          )
+          .await;
+        });
+        let ack = EventAck::new(&event_type, AckStatus::Added);
+        self.events.insert(event_type);
+        ack
       }
       Event::Remove(exit_request) => {
-        if let Some(event_type) = self.events.take(&exit_request.event_type) {
-          if let Err(error) =
-            event_sender.send(EventMessage::Exit(exit_request.clone()))
-          {
-            log!(err@">>> {socket_id} Failed to remove event {event_type:?}: {error}");
-          }
+        if self.events.remove(&exit_request.event_type) {
+          exit_dispatcher(&exit_request, event_sender);
+        }
+        EventAck::new(exit_request.event_type, AckStatus::Removed)
+      }
+      Event::RemoveAll => {
+        log!(info@">>> {socket_id} Removing {} events", self.events.len());
+        for event_type in self.events.drain() {
+          let exit_request = EventExitRequest {
+            socket_id: socket_id.to_string(),
+            event_type,
+          };
+          exit_dispatcher(&exit_request, event_sender);
         }
+        EventAck::new("all", AckStatus::Removed)
       }
     }
   }
 }
 
-async fn file_change_event_dispatcher(
+#[derive(Error, Debug)]
+pub enum EventError {
+  #[error("Unknown event {0:?}")]
+  UnknownType(String),
+  #[error("Unknown event action {action:?}, expected \"add\" or \"remove\"")]
+  UnknownAction { action: String, name: String },
+  #[error("Missing colon in event command {0:?}, expected <action>:<event>")]
+  MissingColon(String),
+  #[error(
+    "Invalid event options {options:?}, expected format=json or format=msgpack"
+  )]
+  InvalidOptions { options: String, name: String },
+}
+
+impl EventError {
+  /// Event the failed command was for, or the whole command if it couldn't
+  /// be split.
+  fn event_name(&self) -> &str {
+    match self {
+      Self::UnknownType(name)
+      | Self::UnknownAction { name, .. }
+      | Self::InvalidOptions { name, .. } => name,
+      Self::MissingColon(command) => command,
+    }
+  }
+}
+
+pub type EventResult<T = ()> = Result<T, EventError>;
+
+#[derive(Error, Debug)]
+pub enum PayloadError {
+  #[error("Failed to encode JSON payload: {0}")]
+  Json(#[from] serde_json::Error),
+  #[error("Failed to encode MessagePack payload: {0}")]
+  MsgPack(#[from] rmp_serde::encode::Error),
+}
+
+pub type PayloadResult<T> = Result<T, PayloadError>;
+
+/// Ask the dispatcher task of `exit_request` to return.
+fn exit_dispatcher(
+  exit_request: &EventExitRequest,
+  event_sender: &EventSender,
+) {
+  if let Err(error) =
+    event_sender.send(EventMessage::Exit(exit_request.clone()))
+  {
+    let EventExitRequest {
+      socket_id,
+      event_type,
+    } = exit_request;
+    log!(err@">>> {socket_id} Failed to remove event {event_type:?}: {error}");
+  }
+}
+
+/// Forward every `event_type` payload owned by `user_id` to the socket, encoded
+/// as `format`, until an exit request for this socket and event type is
+/// received.
+async fn event_dispatcher(
+  event_type: &EventType,
+  format: PayloadFormat,
   socket_sender: &mut SocketSender,
   event_receiver: &mut EventReceiver,
   user_id: &str,
   socket_id: &str,
 ) {
-  while let Ok(event) = event_receiver.recv().await {
-    match event {
-      EventMessage::Exit(EventExitRequest {
-        event_type,
-        socket_id: id,
-      }) => {
-        if id == socket_id && event_type == EventType::FolderChange {
-          log!(info@">>> {socket_id} exiting folder-change event task");
-          return;
-        }
-        log!(info@">>> {socket_id} folder-change event received exit for {id} which is not us so we ignore");
+  loop {
+    let event = match event_receiver.recv().await {
+      Ok(event) => event,
+      Err(EventRecvError::Lagged(skipped)) => {
+        log!(err@">>> {socket_id} {event_type} event task lagged behind, {skipped} messages skipped");
         continue;
       }
-      EventMessage::FolderChange(change) => {
-        if change.user_id != user_id {
-          continue;
-        }
-        let Ok(json) = serde_json::to_string(&change) else {return};
-        let message = SocketMessage::Message(Message::Text(json));
-        if let Err(error) = socket_sender.send(message) {
-          log!(err@">>> {socket_id} Could not send server message {change:#?}: {error}");
-          return;
-        }
+      Err(EventRecvError::Closed) => return,
+    };
+    if let EventMessage::Exit(EventExitRequest {
+      event_type: ref exit_type,
+      socket_id: ref id,
+    }) = event
+    {
+      if id == socket_id && exit_type == event_type {
+        log!(info@">>> {socket_id} exiting {event_type} event task");
+        return;
+      }
+      log!(info@">>> {socket_id} {event_type} event received exit for {id} which is not us so we ignore");
+      continue;
+    }
+    if event.user_id() != Some(user_id) {
+      continue;
+    }
+    let Some(payload) = event_type.payload(&event, format) else {
+      continue;
+    };
+    let payload = match payload {
+      Ok(payload) => payload,
+      Err(error) => {
+        log!(err@">>> {socket_id} {event_type} event task stopped: {error}");
+        return;
       }
+    };
+    let message = SocketMessage::Message(payload);
+    if let Err(error) = socket_sender.send(message) {
+      log!(err@">>> {socket_id} Could not send server message {event:#?}: {error}");
+      return;
     }
   }
 }
 
 const EVENT_IDENTIFIER: &str = "event:";
+const FOLDER_CHANGE_PREFIX: &str = "folder-change:";
@@ -0,0 +1,100 @@
+use super::WebSocketState;
+use crate::{
+  console::Colorize,
+  db::{
+    files::{system::FileSystem, File},
+    Database,
+  },
+  log,
+};
+use futures::{FutureExt, StreamExt};
+use mongodb::change_stream::event::ChangeStreamEvent;
+use std::collections::{HashMap, HashSet};
+use tokio::task::JoinHandle;
+
+/// Most changes whose folders are looked up together.
+const MAX_BATCHED_CHANGES: usize = 100;
+
+/// Watch the files collection and broadcast the folders every change touches,
+/// so writes made outside the API are picked up too. Needs a replica set on
+/// MongoDB 6.0 or newer, which keeps the pre-images that tell which folder a
+/// moved or deleted file was in.
+///
+/// Where that's not available, e.g. standalone servers, the file system falls
+/// back to broadcasting the changes of its own writes, see
+/// `FolderChangeFallback`. It also does while the watcher isn't running, until
+/// the change stream is opened or once it ends.
+pub async fn spawn(
+  database: &Database,
+  sockets: WebSocketState,
+) -> Option<JoinHandle<()>> {
+  if let Err(error) = database.keep_pre_images::<File>().await {
+    log!(info@"Could not keep file pre-images, folder changes will be sent by the file system: {error}");
+    return None;
+  }
+  let mut changes = match database.watch::<File>().await {
+    Ok(changes) => changes,
+    Err(error) => {
+      log!(info@"Could not watch files, folder changes will be sent by the file system: {error}");
+      return None;
+    }
+  };
+  let file_system = FileSystem::from(database);
+  sockets.set_watching(true);
+  log!(success@"Watching files for folder changes");
+
+  Some(tokio::spawn(async move {
+    while let Some(change) = changes.next().await {
+      let mut batch = vec![change];
+      // Changes already received are looked up along with this one
+      while batch.len() < MAX_BATCHED_CHANGES {
+        match changes.next().now_or_never() {
+          Some(Some(change)) => batch.push(change),
+          _ => break,
+        }
+      }
+      let batch = match batch.into_iter().collect::<Result<Vec<_>, _>>() {
+        Ok(batch) => batch,
+        Err(error) => {
+          log!(err@"File watcher failed: {error}");
+          break;
+        }
+      };
+      let folders = file_system
+        .find_folders_with_children(&affected_folders(batch))
+        .await;
+      match folders {
+        Ok(folders) => {
+          if let Err(error) = sockets.broadcast_folder_changes(folders) {
+            log!(err@"Failed to broadcast watched folder changes: {error}");
+          }
+        }
+        Err(error) => {
+          log!(err@"Failed to find watched folder changes: {error}")
+        }
+      }
+    }
+    log!(err@"File watcher stopped, folder changes will be sent by the file system");
+    sockets.set_watching(false);
+  }))
+}
+
+/// Parent folders of the documents before and after `changes` by user id.
+fn affected_folders(
+  changes: Vec<ChangeStreamEvent<File>>,
+) -> HashMap<String, HashSet<String>> {
+  let mut folders = HashMap::<String, HashSet<String>>::new();
+  for file in changes
+    .into_iter()
+    .flat_map(|change| {
+      [change.full_document_before_change, change.full_document]
+    })
+    .flatten()
+  {
+    folders
+      .entry(file.user_id)
+      .or_default()
+      .insert(file.folder_id);
+  }
+  folders
+}
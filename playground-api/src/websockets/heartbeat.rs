@@ -0,0 +1,49 @@
+use super::channel::{SocketMessage, SocketSender};
+use crate::{console::Colorize, env_var_or, log};
+use axum::extract::ws::Message;
+use once_cell::sync::Lazy;
+use std::time::Duration;
+use tokio::{sync::watch, task::JoinHandle, time::Instant};
+
+pub static PING_INTERVAL: Lazy<Duration> =
+  Lazy::new(|| Duration::from_secs(env_var_or("WS_PING_INTERVAL_SECS", 30)));
+pub static PONG_TIMEOUT: Lazy<Duration> =
+  Lazy::new(|| Duration::from_secs(env_var_or("WS_PONG_TIMEOUT_SECS", 60)));
+
+pub type PongSender = watch::Sender<Instant>;
+pub type PongReceiver = watch::Receiver<Instant>;
+
+/// Channel used by the receiver task to report when the last pong arrived.
+pub fn pong_channel() -> (PongSender, PongReceiver) {
+  watch::channel(Instant::now())
+}
+
+/// Ping the client every `interval` and exit once no pong has been seen for
+/// longer than `timeout`. Resolves to the amount of pings sent.
+pub fn spawn(
+  socket_sender: SocketSender,
+  last_pong: PongReceiver,
+  interval: Duration,
+  timeout: Duration,
+  socket_id: String,
+) -> JoinHandle<i32> {
+  tokio::spawn(async move {
+    let mut ticker =
+      tokio::time::interval_at(Instant::now() + interval, interval);
+    let mut count = 0;
+    loop {
+      ticker.tick().await;
+      let elapsed = last_pong.borrow().elapsed();
+      if elapsed > timeout {
+        log!(err@">>> {socket_id} No pong received in {elapsed:?}, closing connection");
+        return count;
+      }
+      let ping = SocketMessage::Message(Message::Ping(Vec::new()));
+      if let Err(error) = socket_sender.send(ping) {
+        log!(err@">>> {socket_id} Could not send heartbeat ping: {error}");
+        return count;
+      }
+      count += 1;
+    }
+  })
+}
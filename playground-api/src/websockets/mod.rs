@@ -1,9 +1,18 @@
+pub mod batch;
 pub mod channel;
-mod event;
+pub mod event;
+pub mod file_watcher;
+pub mod heartbeat;
+pub mod shutdown;
 
 use crate::{
-  auth::session::SessionQuery, console::Colorize, db::DBError, log,
-  websockets::channel::SocketMessage, AppState,
+  api::{APIError, APIResult},
+  auth::session::SessionQuery,
+  console::Colorize,
+  db::files::{aggregations::FolderChildren, system::FolderChangeFallback},
+  env_var_or, log,
+  websockets::channel::SocketMessage,
+  AppState,
 };
 use axum::{
   extract::{
@@ -13,36 +22,213 @@ use axum::{
   },
   response::IntoResponse,
   routing::get,
-  Router,
+  Json, Router,
 };
+use batch::FolderChangeBatcher;
 use channel::{
-  EventChannel, EventSender, SocketChannel, SocketReceiver, SocketSender,
+  EventChannel, EventMessage, EventSender, SocketChannel, SocketReceiver,
+  SocketRecvError, SocketSender,
 };
 use event::EventManager;
+use format as f;
 use futures::{
-  sink::SinkExt,
-  stream::{SplitSink, SplitStream, StreamExt},
+  sink::{Sink, SinkExt},
+  stream::{Stream, StreamExt},
 };
-use std::{borrow::Cow, net::SocketAddr, ops::ControlFlow};
-use thiserror::Error;
-use tokio::task::JoinHandle;
+use heartbeat::PongSender;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use shutdown::{ShutdownReceiver, ShutdownSender};
+use std::{
+  borrow::Cow,
+  collections::HashMap,
+  net::SocketAddr,
+  ops::ControlFlow,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::{sync::Notify, task::JoinHandle, time::Instant};
+
+/// Largest message a client can send, in bytes. Sockets sending more are
+/// closed.
+pub static MAX_MESSAGE_SIZE: Lazy<usize> =
+  Lazy::new(|| env_var_or("WS_MAX_MESSAGE_SIZE", 64 * 1024));
+
+/// Most sockets a single user can have open at once.
+pub static MAX_USER_CONNECTIONS: Lazy<usize> =
+  Lazy::new(|| env_var_or("WS_MAX_USER_CONNECTIONS", 10));
 
 #[derive(Debug, Clone)]
 pub struct WebSocketState {
   pub event_sender: EventSender,
+  /// Set when folder changes are batched, see `batch::BATCH_FOLDER_CHANGES`.
+  folder_changes: Option<FolderChangeBatcher>,
+  /// Set while `file_watcher` broadcasts folder changes.
+  watching: Arc<AtomicBool>,
+  connections: Arc<Connections>,
+  shutdown: Arc<ShutdownSender>,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+  count: AtomicUsize,
+  drained: Notify,
+  /// Open sockets of every connected user.
+  per_user: Mutex<HashMap<String, usize>>,
+}
+
+impl Connections {
+  /// Resolves once there are no connections left.
+  async fn drained(&self) {
+    loop {
+      let drained = self.drained.notified();
+      if self.count.load(Ordering::SeqCst) == 0 {
+        return;
+      }
+      drained.await;
+    }
+  }
+}
+
+/// Broadcasts the folder changes of `FileSystem` writes while the file
+/// watcher isn't running, see `file_watcher::spawn`.
+impl FolderChangeFallback for WebSocketState {
+  fn folders_changed(&self, changes: Vec<FolderChildren>) {
+    if self.watching.load(Ordering::SeqCst) {
+      return;
+    }
+    if let Err(error) = self.broadcast_folder_changes(changes) {
+      log!(err@"Failed to broadcast folder changes: {error}");
+    }
+  }
 }
 
 impl WebSocketState {
   pub fn new() -> Self {
-    let event_channel = EventChannel::new();
+    let event_channel = EventChannel::with_capacity(env_var_or(
+      "WS_BROADCAST_CAPACITY",
+      channel::DEFAULT_CAPACITY,
+    ));
+    let (shutdown_sender, _) = shutdown::shutdown_channel();
+    let folder_changes = batch::BATCH_FOLDER_CHANGES.then(|| {
+      FolderChangeBatcher::spawn(
+        event_channel.sender.clone(),
+        *batch::BATCH_WINDOW,
+      )
+    });
     Self {
       event_sender: event_channel.sender,
+      folder_changes,
+      watching: Arc::new(AtomicBool::new(false)),
+      connections: Arc::new(Connections::default()),
+      shutdown: Arc::new(shutdown_sender),
+    }
+  }
+
+  /// Broadcast folder changes, one message per folder unless batching is on.
+  pub fn broadcast_folder_changes(
+    &self,
+    changes: Vec<FolderChildren>,
+  ) -> APIResult {
+    if let Some(batcher) = &self.folder_changes {
+      batcher.send(changes);
+      return Ok(());
+    }
+    if self.event_sender.receiver_count() == 0 {
+      log!(info@"There's {} folder changes but no one's listening. Message will not be sent", changes.len());
+    } else {
+      log!(info@"Sending message to {} listeners", self.event_sender.receiver_count());
+      for change in changes.into_iter() {
+        self.event_sender.send(EventMessage::FolderChange(change))?;
+      }
+    }
+    Ok(())
+  }
+
+  pub fn set_watching(&self, watching: bool) {
+    self.watching.store(watching, Ordering::SeqCst);
+  }
+
+  /// Count a new connection of `user_id` until the returned guard is dropped.
+  /// Fails with `TooManyRequests` if the user already has
+  /// `MAX_USER_CONNECTIONS` open.
+  pub fn connect(&self, user_id: &str) -> APIResult<ConnectionGuard> {
+    {
+      let mut per_user = self.connections.per_user.lock().unwrap();
+      let user_connections = per_user.entry(user_id.to_string()).or_default();
+      if *user_connections >= *MAX_USER_CONNECTIONS {
+        return Err(APIError::TooManyRequests(f!(
+          "Can't open more than {} websocket connections",
+          *MAX_USER_CONNECTIONS
+        )));
+      }
+      *user_connections += 1;
+    }
+    self.connections.count.fetch_add(1, Ordering::SeqCst);
+    Ok(ConnectionGuard {
+      connections: self.connections.clone(),
+      user_id: user_id.to_string(),
+    })
+  }
+
+  pub fn connection_count(&self) -> usize {
+    self.connections.count.load(Ordering::SeqCst)
+  }
+
+  pub fn shutdown_receiver(&self) -> ShutdownReceiver {
+    self.shutdown.subscribe()
+  }
+
+  /// Ask every socket to close and wait up to `timeout` for them to finish.
+  /// Returns whether all connections were drained in time.
+  pub async fn shutdown(&self, timeout: Duration) -> bool {
+    self.shutdown.send_replace(true);
+    tokio::time::timeout(timeout, self.connections.drained())
+      .await
+      .is_ok()
+  }
+}
+
+pub struct ConnectionGuard {
+  connections: Arc<Connections>,
+  user_id: String,
+}
+
+impl Drop for ConnectionGuard {
+  fn drop(&mut self) {
+    {
+      let mut per_user = self.connections.per_user.lock().unwrap();
+      if let Some(user_connections) = per_user.get_mut(&self.user_id) {
+        *user_connections -= 1;
+        if *user_connections == 0 {
+          per_user.remove(&self.user_id);
+        }
+      }
+    }
+    if self.connections.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+      self.connections.drained.notify_waiters();
     }
   }
 }
 
 pub fn api() -> Router<AppState> {
-  Router::new().route("/", get(ws_handler))
+  Router::new()
+    .route("/", get(ws_handler))
+    .route("/stats", get(ws_stats))
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebSocketStats {
+  connections: usize,
+}
+
+async fn ws_stats(State(state): State<WebSocketState>) -> Json<WebSocketStats> {
+  Json(WebSocketStats {
+    connections: state.connection_count(),
+  })
 }
 
 /// The handler for the HTTP request (this gets called when the HTTP GET lands at the start
@@ -55,17 +241,22 @@ async fn ws_handler(
   SessionQuery(session): SessionQuery,
   ConnectInfo(socket_id): ConnectInfo<SocketAddr>,
   State(state): State<WebSocketState>,
-) -> impl IntoResponse {
+) -> APIResult<impl IntoResponse> {
   log!(info@">>> {socket_id} Requested connection");
+  let connection = state.connect(&session.user_id)?;
 
-  ws.on_upgrade(move |socket| {
+  let ws = ws
+    .max_message_size(*MAX_MESSAGE_SIZE)
+    .max_frame_size(*MAX_MESSAGE_SIZE);
+  Ok(ws.on_upgrade(move |socket| {
     handle_socket(
       socket,
       socket_id.to_string(),
       session.user_id,
-      state.event_sender,
+      state,
+      connection,
     )
-  })
+  }))
 }
 
 /// WebSocket state machine (one will be spawned per connection)
@@ -73,7 +264,8 @@ async fn handle_socket(
   mut socket: WebSocket,
   socket_id: String,
   user_id: String,
-  event_sender: EventSender,
+  state: WebSocketState,
+  _connection: ConnectionGuard,
 ) {
   if let Err(error) = socket.send(Message::Ping(vec![1, 2, 3])).await {
     log!(err@">>> {socket_id} Ping send failed: {error}");
@@ -84,6 +276,7 @@ async fn handle_socket(
   let (raw_socket_sender, raw_socket_receiver) = socket.split();
   let socket_channel = SocketChannel::new();
   let socket_receiver = socket_channel.sender.subscribe();
+  let (pong_sender, pong_receiver) = heartbeat::pong_channel();
 
   let mut send_task = send_client_messages_task(
     socket_receiver,
@@ -94,11 +287,26 @@ async fn handle_socket(
   let mut recv_task = receive_client_messages_task(
     raw_socket_receiver,
     socket_channel.sender.clone(),
-    event_sender,
+    state.event_sender.clone(),
+    pong_sender,
     user_id,
     socket_id.clone(),
   );
 
+  let mut heartbeat_task = heartbeat::spawn(
+    socket_channel.sender.clone(),
+    pong_receiver,
+    *heartbeat::PING_INTERVAL,
+    *heartbeat::PONG_TIMEOUT,
+    socket_id.clone(),
+  );
+
+  let shutdown_task = shutdown::spawn(
+    socket_channel.sender.clone(),
+    state.shutdown_receiver(),
+    socket_id.clone(),
+  );
+
   // If any one of the tasks exits, send a signal to the other to exit too.
   tokio::select! {
     rv_a = (&mut send_task) => {
@@ -116,27 +324,67 @@ async fn handle_socket(
         log!(err@">>> {socket_id} Error sending exit from message receiver task: {error}");
       }
     }
+    rv_c = (&mut heartbeat_task) => {
+      match rv_c {
+        Ok(count) => log!(info@">>> {socket_id} Heartbeat timed out after {count} pings"),
+        Err(error) => log!(err@">>> {socket_id} Error sending heartbeat: {error:?}")
+      }
+      let close = SocketMessage::Message(Message::Close(Some(CloseFrame {
+        code: close_code::AWAY,
+        reason: Cow::from("Heartbeat timeout"),
+      })));
+      if let Err(error) = socket_channel
+        .sender
+        .send(close)
+        .and_then(|_| socket_channel.sender.send(SocketMessage::Exit))
+      {
+        log!(err@">>> {socket_id} Error sending exit from heartbeat task: {error}");
+      }
+      recv_task.abort();
+    }
   }
+  heartbeat_task.abort();
+  shutdown_task.abort();
 
   // Returning from the handler closes the websocket connection
   log!(success@">>> {socket_id} Websocket context destroyed");
 }
 
-fn receive_client_messages_task(
-  mut raw_socket_receiver: SplitStream<WebSocket>,
+/// Handle messages from the client until it closes the socket or sends one
+/// larger than `MAX_MESSAGE_SIZE`, which closes the socket with
+/// `close_code::SIZE`.
+pub fn receive_client_messages_task(
+  mut raw_socket_receiver: impl Stream<Item = Result<Message, axum::Error>>
+    + Unpin
+    + Send
+    + 'static,
   socket_sender: SocketSender,
   event_sender: EventSender,
+  pong_sender: PongSender,
   user_id: String,
   socket_id: String,
 ) -> JoinHandle<i32> {
   tokio::spawn(async move {
     let mut event_manager = EventManager::default();
     let mut count = 0;
-    while let Some(Ok(msg)) = raw_socket_receiver.next().await {
+    while let Some(msg) = raw_socket_receiver.next().await {
+      let msg = match msg {
+        Ok(msg) => msg,
+        Err(error) => {
+          log!(err@">>> {socket_id} Could not receive client message: {error}");
+          if is_message_too_big(error) {
+            close_socket(&socket_sender, close_code::SIZE, "Message too big");
+          }
+          break;
+        }
+      };
       count += 1;
       if process_message(&msg, &socket_id).is_break() {
         break;
       }
+      if let Message::Pong(_) = msg {
+        pong_sender.send_replace(Instant::now());
+      }
       if let Message::Text(ref message) = msg {
         event_manager.process_event(
           message,
@@ -151,14 +399,45 @@ fn receive_client_messages_task(
   })
 }
 
-fn send_client_messages_task(
+fn is_message_too_big(error: axum::Error) -> bool {
+  matches!(
+    error.into_inner().downcast_ref::<tungstenite::Error>(),
+    Some(tungstenite::Error::Capacity(_))
+  )
+}
+
+fn close_socket(socket_sender: &SocketSender, code: u16, reason: &'static str) {
+  let close = SocketMessage::Message(Message::Close(Some(CloseFrame {
+    code,
+    reason: Cow::from(reason),
+  })));
+  if let Err(error) = socket_sender.send(close) {
+    log!(err@"Could not close socket: {error}");
+  }
+}
+
+/// Send every socket message to the client until an exit is received or the
+/// socket channel closes. Messages skipped because the task lagged behind are
+/// logged and the task carries on with the next one.
+pub fn send_client_messages_task<E: std::fmt::Display>(
   mut socket_receiver: SocketReceiver,
   socket_id: String,
-  mut raw_socket_sender: SplitSink<WebSocket, Message>,
+  mut raw_socket_sender: impl Sink<Message, Error = E> + Unpin + Send + 'static,
 ) -> JoinHandle<i32> {
   tokio::spawn(async move {
     let mut count = 0;
-    while let Ok(event) = socket_receiver.recv().await {
+    loop {
+      let event = match socket_receiver.recv().await {
+        Ok(event) => event,
+        Err(SocketRecvError::Lagged(skipped)) => {
+          log!(err@">>> {socket_id} Main socket task lagged behind, {skipped} messages skipped");
+          continue;
+        }
+        Err(SocketRecvError::Closed) => {
+          log!(info@">>> {socket_id} Socket channel closed, exiting...");
+          break;
+        }
+      };
       match event {
         SocketMessage::Exit => {
           log!(info@">>> {socket_id} Main socket task received exit signal, exiting...");
@@ -218,11 +497,3 @@ fn process_message(msg: &Message, socket_id: &str) -> ControlFlow<(), ()> {
   }
   ControlFlow::Continue(())
 }
-
-#[derive(Error, Debug)]
-pub enum WebSocketError {
-  #[error("A JSON error occurred in a WebSocket: {0}")]
-  Json(#[from] serde_json::Error),
-  #[error("A database error occurred in a WebSocket: {0}")]
-  Database(#[from] DBError),
-}
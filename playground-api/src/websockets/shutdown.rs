@@ -0,0 +1,44 @@
+use super::channel::{SocketMessage, SocketSender};
+use crate::{console::Colorize, env_var_or, log};
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use once_cell::sync::Lazy;
+use std::{borrow::Cow, time::Duration};
+use tokio::{sync::watch, task::JoinHandle};
+
+pub static DRAIN_TIMEOUT: Lazy<Duration> =
+  Lazy::new(|| Duration::from_secs(env_var_or("WS_SHUTDOWN_TIMEOUT_SECS", 5)));
+
+pub type ShutdownSender = watch::Sender<bool>;
+pub type ShutdownReceiver = watch::Receiver<bool>;
+
+/// Channel used to tell every connected socket the server is shutting down.
+pub fn shutdown_channel() -> (ShutdownSender, ShutdownReceiver) {
+  watch::channel(false)
+}
+
+/// Wait for the shutdown signal, then send the client a close frame and stop
+/// the socket sender task.
+pub fn spawn(
+  socket_sender: SocketSender,
+  mut shutdown: ShutdownReceiver,
+  socket_id: String,
+) -> JoinHandle<()> {
+  tokio::spawn(async move {
+    while !*shutdown.borrow_and_update() {
+      if shutdown.changed().await.is_err() {
+        return;
+      }
+    }
+    log!(info@">>> {socket_id} Server shutting down, closing connection");
+    let close = SocketMessage::Message(Message::Close(Some(CloseFrame {
+      code: close_code::AWAY,
+      reason: Cow::from("Server shutting down"),
+    })));
+    if let Err(error) = socket_sender
+      .send(close)
+      .and_then(|_| socket_sender.send(SocketMessage::Exit))
+    {
+      log!(err@">>> {socket_id} Error sending shutdown close frame: {error}");
+    }
+  })
+}